@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE custom_domains;
+DROP TYPE IF EXISTS custom_domain_status;
+DROP TYPE IF EXISTS custom_domain_method;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+CREATE TYPE custom_domain_status AS ENUM (
+    'pending', 'verified', 'failed'
+);
+
+CREATE TYPE custom_domain_method AS ENUM (
+    'txt', 'cname'
+);
+
+CREATE TABLE custom_domains (
+  uuid VARCHAR(50) NOT NULL UNIQUE PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays(uuid),
+  domain VARCHAR(100) NOT NULL,
+  method custom_domain_method NOT NULL,
+  token VARCHAR(100) NOT NULL,
+  status custom_domain_status NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  last_checked_at TIMESTAMP,
+  verified_at TIMESTAMP,
+  failed_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX custom_domains_verified_domain ON custom_domains (domain) WHERE status = 'verified';
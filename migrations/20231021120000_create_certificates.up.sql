@@ -0,0 +1,19 @@
+-- Add up migration script here
+CREATE TYPE certificate_status AS ENUM (
+    'active', 'replaced'
+);
+
+CREATE TABLE certificates (
+  uuid VARCHAR(50) NOT NULL UNIQUE PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays(uuid),
+  hostnames TEXT[] NOT NULL,
+  certificate_pem TEXT NOT NULL,
+  private_key_pem TEXT NOT NULL,
+  status certificate_status NOT NULL,
+  issued_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX certificates_active_relay ON certificates (relay_uuid) WHERE status = 'active';
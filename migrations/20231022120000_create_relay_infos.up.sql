@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE relay_infos (
+  relay_uuid VARCHAR(50) NOT NULL UNIQUE PRIMARY KEY REFERENCES relays(uuid),
+  contact VARCHAR(255),
+  pubkey VARCHAR(64),
+  supported_nips JSONB NOT NULL,
+  limitation JSONB NOT NULL,
+  payments_url VARCHAR(255),
+  icon VARCHAR(255),
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
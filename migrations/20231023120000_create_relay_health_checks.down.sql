@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP TABLE relay_health_checks;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE relay_health_checks (
+  id BIGSERIAL PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays(uuid),
+  available BOOLEAN NOT NULL,
+  latency_ms INT,
+  nip11_available BOOLEAN NOT NULL,
+  error TEXT,
+  checked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX relay_health_checks_relay_checked_at ON relay_health_checks (relay_uuid, checked_at DESC);
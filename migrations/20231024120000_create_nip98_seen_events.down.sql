@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS nip98_seen_events;
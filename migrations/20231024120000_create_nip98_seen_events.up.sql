@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE nip98_seen_events (
+    event_id TEXT PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX nip98_seen_events_expires_at_idx ON nip98_seen_events (expires_at);
@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE refresh_tokens (
+  token_hash VARCHAR(64) NOT NULL PRIMARY KEY,
+  hexpub VARCHAR(100) NOT NULL,
+  npub VARCHAR(100) NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX refresh_tokens_hexpub_idx ON refresh_tokens (hexpub);
+
+CREATE TABLE revoked_tokens (
+  jti VARCHAR(50) NOT NULL PRIMARY KEY,
+  expires_at TIMESTAMP NOT NULL
+);
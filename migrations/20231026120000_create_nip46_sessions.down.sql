@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS nip46_sessions;
+DROP TYPE IF EXISTS nip46_session_status;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TYPE nip46_session_status AS ENUM (
+    'pending', 'completed', 'consumed'
+);
+
+CREATE TABLE nip46_sessions (
+  uuid VARCHAR(50) NOT NULL UNIQUE PRIMARY KEY,
+  session_pubkey VARCHAR(64) NOT NULL,
+  challenge VARCHAR(64) NOT NULL,
+  status nip46_session_status NOT NULL DEFAULT 'pending',
+  hexpub VARCHAR(100),
+  expires_at TIMESTAMP NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
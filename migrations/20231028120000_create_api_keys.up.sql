@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE api_keys (
+  uuid VARCHAR(50) NOT NULL UNIQUE PRIMARY KEY,
+  hexpub VARCHAR(100) NOT NULL,
+  npub VARCHAR(100) NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  key_prefix VARCHAR(20) NOT NULL,
+  key_hash VARCHAR(64) NOT NULL UNIQUE,
+  scopes TEXT[] NOT NULL,
+  expires_at TIMESTAMP,
+  last_used_at TIMESTAMP,
+  revoked_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX api_keys_npub_idx ON api_keys (npub);
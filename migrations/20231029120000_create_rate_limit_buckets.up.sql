@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE rate_limit_buckets (
+  key VARCHAR(255) NOT NULL PRIMARY KEY,
+  tokens FLOAT8 NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE user_profiles (
+  hexpub VARCHAR(100) NOT NULL PRIMARY KEY REFERENCES users (hexpub) ON DELETE CASCADE,
+  name TEXT,
+  picture TEXT,
+  nip05 TEXT,
+  nip05_verified BOOLEAN NOT NULL DEFAULT FALSE,
+  metadata_created_at TIMESTAMP,
+  fetched_at TIMESTAMP NOT NULL
+);
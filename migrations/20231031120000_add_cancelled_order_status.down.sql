@@ -0,0 +1,10 @@
+-- Add down migration script here
+UPDATE relay_orders SET status = 'expired' WHERE status = 'cancelled';
+
+ALTER TYPE relay_order_status RENAME TO relay_order_status_old;
+CREATE TYPE relay_order_status AS ENUM (
+    'pending', 'paid', 'redeemed', 'expired'
+);
+ALTER TABLE relay_orders
+    ALTER COLUMN status TYPE relay_order_status USING status::text::relay_order_status;
+DROP TYPE relay_order_status_old;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TYPE relay_order_status ADD VALUE IF NOT EXISTS 'cancelled';
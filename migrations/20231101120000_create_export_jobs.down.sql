@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS export_jobs;
+DROP TYPE IF EXISTS export_job_status;
+DROP TYPE IF EXISTS export_format;
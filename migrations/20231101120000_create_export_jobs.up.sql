@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TYPE export_format AS ENUM ('json', 'zip');
+CREATE TYPE export_job_status AS ENUM ('pending', 'completed', 'failed');
+
+CREATE TABLE export_jobs (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  npub VARCHAR(100) NOT NULL,
+  format export_format NOT NULL,
+  status export_job_status NOT NULL,
+  data BYTEA,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX export_jobs_npub_idx ON export_jobs (npub);
@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE relay_orders DROP COLUMN IF EXISTS organization_uuid;
+ALTER TABLE relays DROP COLUMN IF EXISTS organization_uuid;
+DROP TABLE IF EXISTS organization_invitations;
+DROP TABLE IF EXISTS organization_members;
+DROP TABLE IF EXISTS organizations;
+DROP TYPE IF EXISTS organization_role;
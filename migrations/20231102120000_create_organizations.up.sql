@@ -0,0 +1,39 @@
+-- Add up migration script here
+CREATE TYPE organization_role AS ENUM ('viewer', 'manager', 'owner');
+
+CREATE TABLE organizations (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE organization_members (
+  organization_uuid VARCHAR(50) NOT NULL REFERENCES organizations (uuid) ON DELETE CASCADE,
+  npub VARCHAR(100) NOT NULL,
+  role organization_role NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (organization_uuid, npub)
+);
+
+CREATE INDEX organization_members_npub_idx ON organization_members (npub);
+
+CREATE TABLE organization_invitations (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  organization_uuid VARCHAR(50) NOT NULL REFERENCES organizations (uuid) ON DELETE CASCADE,
+  npub VARCHAR(100) NOT NULL,
+  role organization_role NOT NULL,
+  invited_by VARCHAR(100) NOT NULL,
+  event_id VARCHAR(64) NOT NULL UNIQUE,
+  event JSONB NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL,
+  accepted_at TIMESTAMP
+);
+
+CREATE INDEX organization_invitations_npub_idx ON organization_invitations (npub);
+
+ALTER TABLE relays
+  ADD COLUMN organization_uuid VARCHAR(50) REFERENCES organizations (uuid) ON DELETE SET NULL;
+ALTER TABLE relay_orders
+  ADD COLUMN organization_uuid VARCHAR(50) REFERENCES organizations (uuid) ON DELETE SET NULL;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE relay_announcements (
+  relay_uuid VARCHAR(50) NOT NULL PRIMARY KEY REFERENCES relays (uuid) ON DELETE CASCADE,
+  enabled BOOLEAN NOT NULL,
+  url TEXT,
+  event_id VARCHAR(64),
+  published_at TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
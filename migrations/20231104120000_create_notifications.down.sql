@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS notification_deliveries;
+DROP TABLE IF EXISTS notification_preferences;
+DROP TYPE IF EXISTS notification_status;
+DROP TYPE IF EXISTS notification_kind;
@@ -0,0 +1,37 @@
+-- Add up migration script here
+CREATE TYPE notification_kind AS ENUM (
+  'order_paid',
+  'relay_online',
+  'provisioning_failed',
+  'relay_expiring',
+  'relay_terminated'
+);
+CREATE TYPE notification_status AS ENUM ('pending', 'sent', 'failed', 'skipped');
+
+CREATE TABLE notification_preferences (
+  npub VARCHAR(100) NOT NULL PRIMARY KEY,
+  order_paid BOOLEAN NOT NULL DEFAULT TRUE,
+  relay_online BOOLEAN NOT NULL DEFAULT TRUE,
+  provisioning_failed BOOLEAN NOT NULL DEFAULT TRUE,
+  relay_expiring BOOLEAN NOT NULL DEFAULT TRUE,
+  relay_terminated BOOLEAN NOT NULL DEFAULT TRUE,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE notification_deliveries (
+  id BIGSERIAL PRIMARY KEY,
+  npub VARCHAR(100) NOT NULL,
+  kind notification_kind NOT NULL,
+  dedupe_key VARCHAR(200) NOT NULL UNIQUE,
+  message TEXT NOT NULL,
+  status notification_status NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  event_id VARCHAR(64),
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  sent_at TIMESTAMP
+);
+
+CREATE INDEX notification_deliveries_npub_idx ON notification_deliveries (npub);
+CREATE INDEX notification_deliveries_pending_idx ON notification_deliveries (status)
+  WHERE status = 'pending';
@@ -0,0 +1,18 @@
+-- Add down migration script here
+DELETE FROM notification_deliveries WHERE channel <> 'nostr';
+
+ALTER TABLE notification_deliveries
+  DROP CONSTRAINT notification_deliveries_dedupe_key_channel_key,
+  ADD CONSTRAINT notification_deliveries_dedupe_key_key UNIQUE (dedupe_key),
+  DROP COLUMN destination,
+  DROP COLUMN channel;
+
+ALTER TABLE notification_preferences
+  DROP COLUMN webhook_enabled,
+  DROP COLUMN webhook_secret,
+  DROP COLUMN webhook_url,
+  DROP COLUMN email_enabled,
+  DROP COLUMN email,
+  DROP COLUMN nostr_enabled;
+
+DROP TYPE notification_channel;
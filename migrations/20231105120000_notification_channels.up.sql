@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TYPE notification_channel AS ENUM ('nostr', 'email', 'webhook');
+
+ALTER TABLE notification_preferences
+  ADD COLUMN nostr_enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  ADD COLUMN email VARCHAR(320),
+  ADD COLUMN email_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+  ADD COLUMN webhook_url TEXT,
+  ADD COLUMN webhook_secret VARCHAR(64),
+  ADD COLUMN webhook_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE notification_deliveries
+  ADD COLUMN channel notification_channel NOT NULL DEFAULT 'nostr',
+  ADD COLUMN destination TEXT,
+  DROP CONSTRAINT notification_deliveries_dedupe_key_key,
+  ADD CONSTRAINT notification_deliveries_dedupe_key_channel_key UNIQUE (dedupe_key, channel);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS relay_restores;
+DROP TABLE IF EXISTS relay_backups;
+DROP TYPE IF EXISTS relay_backup_status;
@@ -0,0 +1,32 @@
+-- Add up migration script here
+CREATE TYPE relay_backup_status AS ENUM ('pending', 'completed', 'failed');
+
+CREATE TABLE relay_backups (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays (uuid) ON DELETE CASCADE,
+  scheduled BOOLEAN NOT NULL,
+  status relay_backup_status NOT NULL,
+  object_key TEXT NOT NULL,
+  event_count BIGINT,
+  size_bytes BIGINT,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  completed_at TIMESTAMP
+);
+
+CREATE INDEX relay_backups_relay_uuid_idx ON relay_backups (relay_uuid, created_at);
+
+CREATE TABLE relay_restores (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  backup_uuid VARCHAR(50) NOT NULL REFERENCES relay_backups (uuid) ON DELETE CASCADE,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays (uuid) ON DELETE CASCADE,
+  npub VARCHAR(100) NOT NULL,
+  status relay_backup_status NOT NULL,
+  restored_count BIGINT NOT NULL DEFAULT 0,
+  failed_count BIGINT NOT NULL DEFAULT 0,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  completed_at TIMESTAMP
+);
+
+CREATE INDEX relay_restores_relay_uuid_idx ON relay_restores (relay_uuid);
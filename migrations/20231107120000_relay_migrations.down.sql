@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS relay_migrations;
+DROP TYPE IF EXISTS relay_migration_step;
+DROP TYPE IF EXISTS relay_migration_status;
@@ -0,0 +1,34 @@
+-- Add up migration script here
+CREATE TYPE relay_migration_status AS ENUM ('running', 'completed', 'failed', 'rolled_back');
+CREATE TYPE relay_migration_step AS ENUM (
+  'provisioning',
+  'copying',
+  'switching_dns',
+  'verifying',
+  'terminating',
+  'done'
+);
+
+CREATE TABLE relay_migrations (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays (uuid) ON DELETE CASCADE,
+  npub VARCHAR(100) NOT NULL,
+  status relay_migration_status NOT NULL,
+  step relay_migration_step NOT NULL,
+  from_implementation relay_implementation NOT NULL,
+  from_instance_type relay_instance_type NOT NULL,
+  from_instance_id VARCHAR(50) NOT NULL,
+  from_instance_ip VARCHAR(50) NOT NULL,
+  to_implementation relay_implementation NOT NULL,
+  to_instance_type relay_instance_type NOT NULL,
+  new_instance_id VARCHAR(50),
+  new_instance_ip VARCHAR(50),
+  copied_events BIGINT NOT NULL DEFAULT 0,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  completed_at TIMESTAMP
+);
+
+CREATE UNIQUE INDEX relay_migrations_running_idx ON relay_migrations (relay_uuid)
+  WHERE status = 'running';
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS event_import_seen;
+DROP TABLE IF EXISTS event_import_sources;
+DROP TABLE IF EXISTS event_imports;
+DROP TYPE IF EXISTS event_import_status;
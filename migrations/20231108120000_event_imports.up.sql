@@ -0,0 +1,40 @@
+-- Add up migration script here
+CREATE TYPE event_import_status AS ENUM ('running', 'completed', 'failed');
+
+CREATE TABLE event_imports (
+  uuid VARCHAR(50) NOT NULL PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays (uuid) ON DELETE CASCADE,
+  npub VARCHAR(100) NOT NULL,
+  status event_import_status NOT NULL,
+  authors TEXT[] NOT NULL,
+  kinds BIGINT[] NOT NULL,
+  since BIGINT,
+  fetched_count BIGINT NOT NULL DEFAULT 0,
+  imported_count BIGINT NOT NULL DEFAULT 0,
+  duplicate_count BIGINT NOT NULL DEFAULT 0,
+  invalid_count BIGINT NOT NULL DEFAULT 0,
+  rejected_count BIGINT NOT NULL DEFAULT 0,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  completed_at TIMESTAMP
+);
+
+CREATE UNIQUE INDEX event_imports_running_idx ON event_imports (relay_uuid)
+  WHERE status = 'running';
+
+CREATE TABLE event_import_sources (
+  import_uuid VARCHAR(50) NOT NULL REFERENCES event_imports (uuid) ON DELETE CASCADE,
+  url TEXT NOT NULL,
+  cursor BIGINT,
+  fetched_count BIGINT NOT NULL DEFAULT 0,
+  done BOOLEAN NOT NULL DEFAULT FALSE,
+  error TEXT,
+  PRIMARY KEY (import_uuid, url)
+);
+
+CREATE TABLE event_import_seen (
+  import_uuid VARCHAR(50) NOT NULL REFERENCES event_imports (uuid) ON DELETE CASCADE,
+  event_id VARCHAR(64) NOT NULL,
+  PRIMARY KEY (import_uuid, event_id)
+);
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE relay_metrics (
+  id BIGSERIAL PRIMARY KEY,
+  relay_uuid VARCHAR(50) NOT NULL REFERENCES relays (uuid) ON DELETE CASCADE,
+  sampled_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  event_count BIGINT NOT NULL,
+  storage_bytes BIGINT NOT NULL,
+  connections BIGINT NOT NULL,
+  events_received BIGINT NOT NULL,
+  bytes_in BIGINT NOT NULL,
+  bytes_out BIGINT NOT NULL,
+  events_per_sec DOUBLE PRECISION,
+  bytes_in_per_sec DOUBLE PRECISION,
+  bytes_out_per_sec DOUBLE PRECISION
+);
+
+CREATE INDEX relay_metrics_relay_uuid_idx ON relay_metrics (relay_uuid, sampled_at);
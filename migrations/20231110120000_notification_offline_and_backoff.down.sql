@@ -0,0 +1,20 @@
+-- Add down migration script here
+DELETE FROM notification_deliveries WHERE kind = 'relay_offline';
+
+ALTER TABLE notification_deliveries
+  DROP COLUMN next_attempt_at;
+
+ALTER TABLE notification_preferences
+  DROP COLUMN relay_offline;
+
+ALTER TYPE notification_kind RENAME TO notification_kind_old;
+CREATE TYPE notification_kind AS ENUM (
+  'order_paid',
+  'relay_online',
+  'provisioning_failed',
+  'relay_expiring',
+  'relay_terminated'
+);
+ALTER TABLE notification_deliveries
+  ALTER COLUMN kind TYPE notification_kind USING kind::TEXT::notification_kind;
+DROP TYPE notification_kind_old;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+ALTER TYPE notification_kind ADD VALUE 'relay_offline';
+
+ALTER TABLE notification_preferences
+  ADD COLUMN relay_offline BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE notification_deliveries
+  ADD COLUMN next_attempt_at TIMESTAMP;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE relay_restores DROP COLUMN started_at;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE relay_restores ADD COLUMN started_at TIMESTAMP;
+
+UPDATE relay_restores SET started_at = created_at;
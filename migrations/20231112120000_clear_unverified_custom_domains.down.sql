@@ -0,0 +1,2 @@
+-- Add down migration script here
+-- The cleared domains were never verified, so there is nothing to restore.
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- Relays created before domain verification kept whatever domain was
+-- requested. Only verified domains may be routed to a relay.
+UPDATE relays r SET custom_domain = ''
+WHERE r.custom_domain <> ''
+AND NOT EXISTS (
+  SELECT 1 FROM custom_domains d
+  WHERE d.relay_uuid = r.uuid AND d.domain = r.custom_domain AND d.status = 'verified'
+);
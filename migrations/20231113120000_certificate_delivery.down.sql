@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS relay_agent_tokens;
+
+ALTER TABLE certificates RENAME COLUMN private_key_sealed TO private_key_pem;
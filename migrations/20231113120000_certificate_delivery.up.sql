@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE certificates RENAME COLUMN private_key_pem TO private_key_sealed;
+
+CREATE TABLE relay_agent_tokens (
+    token_hash TEXT PRIMARY KEY,
+    relay_uuid VARCHAR(50) NOT NULL REFERENCES relays(uuid) ON DELETE CASCADE,
+    enrollment BOOLEAN NOT NULL,
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX relay_agent_tokens_relay_uuid_idx ON relay_agent_tokens (relay_uuid);
@@ -0,0 +1,7 @@
+-- Add down migration script here
+DELETE FROM relay_announcements a
+WHERE NOT EXISTS (SELECT 1 FROM relays r WHERE r.uuid = a.relay_uuid);
+
+ALTER TABLE relay_announcements
+  ADD CONSTRAINT relay_announcements_relay_uuid_fkey
+  FOREIGN KEY (relay_uuid) REFERENCES relays (uuid) ON DELETE CASCADE;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Announcements must outlive hard-deleted relays until the announcer has
+-- retracted them, so they no longer cascade.
+ALTER TABLE relay_announcements DROP CONSTRAINT relay_announcements_relay_uuid_fkey;
@@ -0,0 +1,7 @@
+-- Add down migration script here
+DELETE FROM export_jobs;
+ALTER TABLE export_jobs DROP COLUMN object_key;
+ALTER TABLE export_jobs ADD COLUMN data BYTEA;
+
+DROP TABLE IF EXISTS audit_events;
+DROP TYPE IF EXISTS audit_action;
@@ -0,0 +1,24 @@
+-- Add up migration script here
+CREATE TYPE audit_action AS ENUM (
+  'login',
+  'account_restored',
+  'api_key_created',
+  'api_key_revoked',
+  'relay_deleted',
+  'export_requested'
+);
+
+CREATE TABLE audit_events (
+  id BIGSERIAL PRIMARY KEY,
+  npub VARCHAR(100) NOT NULL,
+  action audit_action NOT NULL,
+  subject VARCHAR(100),
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_events_npub_idx ON audit_events (npub, created_at);
+
+-- Finished exports now live in the object store.
+DELETE FROM export_jobs;
+ALTER TABLE export_jobs DROP COLUMN data;
+ALTER TABLE export_jobs ADD COLUMN object_key TEXT;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+-- The released relays are deleted, so there is nothing to restore.
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- Terminated relays kept their domain claims, so the domains could never be
+-- verified for another relay.
+UPDATE custom_domains SET status = 'failed', failed_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+WHERE status IN ('pending', 'verified')
+AND relay_uuid IN (SELECT uuid FROM relays WHERE deleted_at IS NOT NULL);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP INDEX relay_health_checks_checked_at;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Old checks are pruned across all relays by age.
+CREATE INDEX relay_health_checks_checked_at ON relay_health_checks (checked_at);
@@ -0,0 +1,632 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    custom_domain::verified_custom_domain,
+    health::relay_url,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayRepository, RelayState},
+    relay_client::{publish_to_any, service_keys, RelayClient},
+    relay_info::{get_relay_info, render_nip11},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// NIP-66 relay discovery event, addressed by the relay URL.
+pub const RELAY_DISCOVERY_KIND: u64 = 30_166;
+
+/// Whether the operator opted in to announcing a relay, and what was last
+/// published for it.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RelayAnnouncement {
+    pub relay_uuid: String,
+    pub enabled: bool,
+    /// URL the current discovery event announces, if any.
+    pub url: Option<String>,
+    pub event_id: Option<String>,
+    pub published_at: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateAnnouncementDto {
+    pub enabled: bool,
+}
+
+pub struct AnnouncementConfig {
+    /// Service keypair that signs the announcements.
+    pub keys: Keys,
+    /// Relays the announcements are published to.
+    pub relay_urls: Vec<String>,
+    pub interval: Duration,
+}
+
+impl AnnouncementConfig {
+    /// `None` unless the service keypair is configured.
+    pub fn from_env() -> Option<Self> {
+        let keys = service_keys()?;
+        let relay_urls = dotenvy::var("ANNOUNCE_RELAY_URLS")
+            .unwrap_or_else(|_| "wss://relay.damus.io,wss://nos.lol".to_string());
+        let interval = dotenvy::var("ANNOUNCE_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(300);
+
+        Some(AnnouncementConfig {
+            keys,
+            relay_urls: relay_urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect(),
+            interval: Duration::from_secs(interval),
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct AnnouncementRepository {
+    pub pool: PgPool,
+}
+
+impl AnnouncementRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get_one(&self, relay_uuid: &str) -> Option<RelayAnnouncement> {
+        sqlx::query_as::<_, RelayAnnouncement>(
+            "SELECT * FROM relay_announcements WHERE relay_uuid = $1",
+        )
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn get_all(&self) -> Vec<RelayAnnouncement> {
+        sqlx::query_as::<_, RelayAnnouncement>("SELECT * FROM relay_announcements")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn set_enabled(
+        &self,
+        relay_uuid: &str,
+        enabled: bool,
+    ) -> Result<RelayAnnouncement, sqlx::Error> {
+        sqlx::query_as::<_, RelayAnnouncement>(
+            "INSERT INTO relay_announcements (relay_uuid, enabled) VALUES ($1, $2)
+            ON CONFLICT (relay_uuid) DO UPDATE SET enabled = EXCLUDED.enabled, updated_at = $3
+            RETURNING *",
+        )
+        .bind(relay_uuid)
+        .bind(enabled)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn set_published(
+        &self,
+        relay_uuid: &str,
+        url: Option<&str>,
+        event_id: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        sqlx::query(
+            "UPDATE relay_announcements SET url = $2, event_id = $3, published_at = $4, updated_at = $4
+            WHERE relay_uuid = $1",
+        )
+        .bind(relay_uuid)
+        .bind(url)
+        .bind(event_id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, relay_uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM relay_announcements WHERE relay_uuid = $1")
+            .bind(relay_uuid)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn announced_urls(&self) -> Vec<String> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT url FROM relay_announcements WHERE url IS NOT NULL ORDER BY url",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+fn url_for(relay: &Relay, custom_domain: Option<String>) -> String {
+    match custom_domain {
+        Some(domain) => format!("wss://{}", domain),
+        None => relay_url(relay),
+    }
+}
+
+/// The verified custom domain if there is one, the subdomain otherwise.
+pub async fn announcement_url(pool: &PgPool, relay: &Relay) -> String {
+    url_for(relay, verified_custom_domain(pool, relay).await)
+}
+
+/// Where the relay should be announced right now, given its verified custom
+/// domain. Relays are first announced once they come online, and stay
+/// announced through outages until they are deleted or the operator opts out.
+fn desired_url(
+    announcement: &RelayAnnouncement,
+    relay: &Relay,
+    custom_domain: Option<String>,
+) -> Option<String> {
+    let live = announcement.enabled
+        && relay.deleted_at.is_none()
+        && relay.state != RelayState::Deleted
+        && !(relay.subdomain.is_empty() && custom_domain.is_none());
+    let ready = announcement.url.is_some() || relay.state == RelayState::Online;
+
+    (live && ready).then(|| url_for(relay, custom_domain))
+}
+
+fn tag(values: Vec<String>) -> Result<Tag, String> {
+    Tag::parse(values).map_err(|err| err.to_string())
+}
+
+/// NIP-66 discovery event carrying the relay's NIP-11 document.
+pub fn discovery_event(keys: &Keys, url: &str, nip11: &Value) -> Result<Event, String> {
+    let mut tags = vec![
+        tag(vec!["d".to_string(), url.to_string()])?,
+        tag(vec!["n".to_string(), "clearnet".to_string()])?,
+    ];
+    if let Some(nips) = nip11["supported_nips"].as_array() {
+        for nip in nips {
+            tags.push(tag(vec!["N".to_string(), nip.to_string()])?);
+        }
+    }
+
+    EventBuilder::new(Kind::from(RELAY_DISCOVERY_KIND), nip11.to_string(), &tags)
+        .to_event(keys)
+        .map_err(|err| err.to_string())
+}
+
+/// NIP-09 deletion of the discovery event for `url`.
+pub fn retraction_event(keys: &Keys, url: &str, event_id: Option<&str>) -> Result<Event, String> {
+    // Built by hand since the URL's colons trip up `Tag::parse`.
+    let mut tags = vec![Tag::Generic(
+        TagKind::Custom("a".to_string()),
+        vec![format!(
+            "{}:{}:{}",
+            RELAY_DISCOVERY_KIND,
+            keys.public_key(),
+            url
+        )],
+    )];
+    if let Some(event_id) = event_id {
+        tags.push(tag(vec!["e".to_string(), event_id.to_string()])?);
+    }
+
+    EventBuilder::new(Kind::EventDeletion, "Relay no longer operated here", &tags)
+        .to_event(keys)
+        .map_err(|err| err.to_string())
+}
+
+/// NIP-65 relay list of every announced relay.
+pub fn relay_list_event(keys: &Keys, urls: &[String]) -> Result<Event, String> {
+    let tags = urls
+        .iter()
+        .map(|url| tag(vec!["r".to_string(), url.clone()]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    EventBuilder::new(Kind::RelayList, "", &tags)
+        .to_event(keys)
+        .map_err(|err| err.to_string())
+}
+
+/// Brings the published events of one relay in line with its state. Returns
+/// whether anything was published. Announcements outlive hard-deleted relays
+/// so that they can be retracted here before being removed.
+async fn sync_announcement(
+    pool: &PgPool,
+    relay_client: &dyn RelayClient,
+    config: &AnnouncementConfig,
+    announcement: &RelayAnnouncement,
+) -> Result<bool, String> {
+    let repo = AnnouncementRepository::new(pool.clone());
+    let relay = RelayRepository::new(pool.clone())
+        .get_one(&announcement.relay_uuid)
+        .await;
+    let desired = match &relay {
+        Some(relay) => {
+            let custom_domain = verified_custom_domain(pool, relay).await;
+            desired_url(announcement, relay, custom_domain)
+        }
+        None => None,
+    };
+    if announcement.url == desired {
+        if relay.is_none() {
+            repo.delete(&announcement.relay_uuid)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        return Ok(false);
+    }
+
+    if let Some(url) = &announcement.url {
+        let event = retraction_event(&config.keys, url, announcement.event_id.as_deref())?;
+        publish_to_any(relay_client, &config.relay_urls, &event).await?;
+        repo.set_published(&announcement.relay_uuid, None, None)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    match (relay, desired) {
+        (Some(relay), Some(url)) => {
+            let nip11 = render_nip11(&relay, &get_relay_info(pool, &relay).await);
+            let event = discovery_event(&config.keys, &url, &nip11)?;
+            publish_to_any(relay_client, &config.relay_urls, &event).await?;
+            repo.set_published(&relay.uuid, Some(&url), Some(event.id.to_hex()))
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        (None, _) => repo
+            .delete(&announcement.relay_uuid)
+            .await
+            .map_err(|err| err.to_string())?,
+        _ => {}
+    }
+
+    Ok(true)
+}
+
+/// Announces, moves and retracts relays as needed. Returns whether any
+/// announcement changed, in which case the relay list is out of date.
+pub async fn reconcile_announcements(
+    pool: &PgPool,
+    relay_client: &dyn RelayClient,
+    config: &AnnouncementConfig,
+) -> bool {
+    let mut changed = false;
+
+    for announcement in AnnouncementRepository::new(pool.clone()).get_all().await {
+        match sync_announcement(pool, relay_client, config, &announcement).await {
+            Ok(published) => changed |= published,
+            Err(err) => {
+                // A retraction may have gone out before the failure.
+                changed = true;
+                eprintln!(
+                    "Failed to announce relay {}: {}",
+                    announcement.relay_uuid, err
+                );
+            }
+        }
+    }
+
+    changed
+}
+
+pub async fn publish_relay_list(
+    pool: &PgPool,
+    relay_client: &dyn RelayClient,
+    config: &AnnouncementConfig,
+) -> Result<(), String> {
+    let urls = AnnouncementRepository::new(pool.clone())
+        .announced_urls()
+        .await;
+    let event = relay_list_event(&config.keys, &urls)?;
+
+    publish_to_any(relay_client, &config.relay_urls, &event).await
+}
+
+pub async fn run_announcer(
+    pool: PgPool,
+    relay_client: Arc<dyn RelayClient>,
+    config: AnnouncementConfig,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    // Published on startup too, in case the last attempt failed.
+    let mut list_pending = true;
+
+    loop {
+        interval.tick().await;
+
+        if reconcile_announcements(&pool, relay_client.as_ref(), &config).await {
+            list_pending = true;
+        }
+        if list_pending {
+            match publish_relay_list(&pool, relay_client.as_ref(), &config).await {
+                Ok(_) => list_pending = false,
+                Err(err) => eprintln!("Failed to publish relay list: {}", err),
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_announcement_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    match AnnouncementRepository::new(relay_repo.pool.clone())
+        .get_one(&relay.uuid)
+        .await
+    {
+        Some(announcement) => HttpResponse::Ok().json(DataResponse::new(announcement)),
+        None => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Relay is not announced".to_string()))
+        }
+    }
+}
+
+/// Opts the relay in or out. The announcer publishes or retracts on its next
+/// run.
+async fn update_announcement_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+    body: web::Json<UpdateAnnouncementDto>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Manager,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    match AnnouncementRepository::new(relay_repo.pool.clone())
+        .set_enabled(&relay.uuid, body.enabled)
+        .await
+    {
+        Ok(announcement) => HttpResponse::Ok().json(DataResponse::new(announcement)),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/announcement")
+            .route(web::get().to(get_announcement_handler))
+            .route(web::put().to(update_announcement_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::custom_domain::{CustomDomainMethod, CustomDomainRepository, CustomDomainStatus};
+    use crate::relay_client::LocalRelayClient;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use nostr::Filter;
+
+    fn tag_values(event: &Event, name: &str) -> Vec<String> {
+        event
+            .tags
+            .iter()
+            .map(Tag::as_vec)
+            .filter(|tag| tag[0] == name)
+            .map(|tag| tag[1].clone())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_announce_move_and_retract_relay() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_state(&relay.uuid, RelayState::Online)
+            .await
+            .unwrap();
+
+        let client = LocalRelayClient::new();
+        let config = AnnouncementConfig {
+            keys: Keys::generate(),
+            relay_urls: vec!["wss://relay.example".to_string()],
+            interval: Duration::from_secs(60),
+        };
+        let published = |kind: Kind| {
+            let client = client.clone();
+            let filter = Filter::new()
+                .author(config.keys.public_key().to_string())
+                .kind(kind);
+            async move {
+                client
+                    .fetch("wss://relay.example", vec![filter])
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Nothing is published until the operator opts in.
+        assert!(!reconcile_announcements(&test_utils.pool, &client, &config).await);
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(configure_routes),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::put()
+            .uri(&format!("/relays/{}/announcement", relay.uuid))
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", generate_jwt_by_hex(&user.hexpub).unwrap()),
+            ))
+            .set_json(serde_json::json!({"enabled": true}))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let url = announcement_url(&test_utils.pool, &relay).await;
+        assert!(reconcile_announcements(&test_utils.pool, &client, &config).await);
+        publish_relay_list(&test_utils.pool, &client, &config)
+            .await
+            .unwrap();
+        let discovery = published(Kind::from(RELAY_DISCOVERY_KIND)).await;
+        assert_eq!(discovery.len(), 1);
+        assert_eq!(tag_values(&discovery[0], "d"), vec![url.clone()]);
+        assert!(tag_values(&discovery[0], "N").contains(&"11".to_string()));
+        let nip11: Value = serde_json::from_str(&discovery[0].content).unwrap();
+        assert_eq!(nip11["name"], "test relay");
+        let lists = published(Kind::RelayList).await;
+        assert_eq!(tag_values(lists.last().unwrap(), "r"), vec![url.clone()]);
+
+        // Unchanged relays aren't republished.
+        assert!(!reconcile_announcements(&test_utils.pool, &client, &config).await);
+
+        // An unverified custom domain isn't announced.
+        test_utils
+            .relay_repo
+            .update_custom_domain(&relay.uuid, "relay.team.example")
+            .await
+            .unwrap();
+        assert!(!reconcile_announcements(&test_utils.pool, &client, &config).await);
+
+        // Moving to a verified custom domain retracts the old URL.
+        let domains = CustomDomainRepository::new(test_utils.pool.clone());
+        let claim = domains
+            .create(
+                &relay.uuid,
+                "relay.team.example",
+                CustomDomainMethod::Txt,
+                "token",
+            )
+            .await
+            .unwrap();
+        domains
+            .record_check(&claim.uuid, CustomDomainStatus::Verified)
+            .await
+            .unwrap();
+        assert!(reconcile_announcements(&test_utils.pool, &client, &config).await);
+        let deletions = published(Kind::EventDeletion).await;
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(
+            tag_values(&deletions[0], "e"),
+            vec![discovery[0].id.to_hex()]
+        );
+        assert!(tag_values(&deletions[0], "a")[0].ends_with(&url));
+        let announcement = AnnouncementRepository::new(test_utils.pool.clone())
+            .get_one(&relay.uuid)
+            .await
+            .unwrap();
+        assert_eq!(
+            announcement.url.as_deref(),
+            Some("wss://relay.team.example")
+        );
+
+        // Deleting the relay retracts it and empties the relay list.
+        test_utils
+            .relay_repo
+            .soft_delete(relay.uuid.clone())
+            .await
+            .unwrap();
+        assert!(reconcile_announcements(&test_utils.pool, &client, &config).await);
+        publish_relay_list(&test_utils.pool, &client, &config)
+            .await
+            .unwrap();
+        assert_eq!(published(Kind::EventDeletion).await.len(), 2);
+        let lists = published(Kind::RelayList).await;
+        assert!(tag_values(lists.last().unwrap(), "r").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retract_hard_deleted_relay() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        test_utils
+            .relay_repo
+            .update_state(&relay.uuid, RelayState::Online)
+            .await
+            .unwrap();
+
+        let client = LocalRelayClient::new();
+        let config = AnnouncementConfig {
+            keys: Keys::generate(),
+            relay_urls: vec!["wss://relay.example".to_string()],
+            interval: Duration::from_secs(60),
+        };
+        let repo = AnnouncementRepository::new(test_utils.pool.clone());
+        repo.set_enabled(&relay.uuid, true).await.unwrap();
+        assert!(reconcile_announcements(&test_utils.pool, &client, &config).await);
+
+        test_utils.relay_repo.delete(&relay.uuid).await.unwrap();
+        assert!(repo.get_one(&relay.uuid).await.is_some());
+
+        assert!(reconcile_announcements(&test_utils.pool, &client, &config).await);
+        let filter = Filter::new()
+            .author(config.keys.public_key().to_string())
+            .kind(Kind::EventDeletion);
+        let deletions = client
+            .fetch("wss://relay.example", vec![filter])
+            .await
+            .unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert!(repo.get_one(&relay.uuid).await.is_none());
+    }
+}
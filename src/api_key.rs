@@ -0,0 +1,371 @@
+use actix_web::http::Method;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use validator::Validate;
+
+use crate::{
+    audit::{audit, AuditAction},
+    middleware::AuthorizationService,
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Prefix of every API key, so keys are easy to tell apart from JWTs and to
+/// spot in leaked logs.
+pub const API_KEY_PREFIX: &str = "rly_";
+
+pub const SCOPES: [&str; 3] = ["relays:read", "relays:write", "orders:write"];
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ApiKey {
+    pub uuid: String,
+    pub hexpub: String,
+    pub npub: String,
+    pub name: String,
+    /// The first characters of the key, shown so users can tell keys apart.
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CreateApiKeyDto {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    #[validate(length(min = 1), custom = "validate_scopes")]
+    pub scopes: Vec<String>,
+    #[validate(range(min = 1, max = 3650))]
+    pub expires_in_days: Option<i64>,
+}
+
+/// Returned once on creation; only the hash of `key` is stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+fn validate_scopes(scopes: &[String]) -> Result<(), validator::ValidationError> {
+    match scopes.iter().all(|scope| SCOPES.contains(&scope.as_str())) {
+        true => Ok(()),
+        false => Err(validator::ValidationError::new("unknown scope")),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct ApiKeyRepository {
+    pub pool: PgPool,
+}
+
+impl ApiKeyRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        hexpub: &str,
+        npub: &str,
+        dto: &CreateApiKeyDto,
+    ) -> Result<CreatedApiKey, sqlx::Error> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let key = format!("{}{}", API_KEY_PREFIX, hex::encode(bytes));
+        let expires_at = dto
+            .expires_in_days
+            .map(|days| Utc::now().naive_utc() + chrono::Duration::days(days));
+
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            "INSERT INTO api_keys (uuid, hexpub, npub, name, key_prefix, key_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(hexpub)
+        .bind(npub)
+        .bind(&dto.name)
+        .bind(&key[..API_KEY_PREFIX.len() + 8])
+        .bind(hash_key(&key))
+        .bind(&dto.scopes)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedApiKey { key, api_key })
+    }
+
+    pub async fn get_user_keys(&self, npub: &str) -> Vec<ApiKey> {
+        sqlx::query_as::<_, ApiKey>(
+            "SELECT * FROM api_keys WHERE npub = $1 AND revoked_at IS NULL ORDER BY created_at DESC",
+        )
+        .bind(npub)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Revokes one of the user's keys. Returns false if there was none.
+    pub async fn revoke(&self, uuid: &str, npub: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP
+            WHERE uuid = $1 AND npub = $2 AND revoked_at IS NULL",
+        )
+        .bind(uuid)
+        .bind(npub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Revokes every key of the user, e.g. when they close their account.
+    pub async fn revoke_user_keys(&self, hexpub: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP
+            WHERE hexpub = $1 AND revoked_at IS NULL",
+        )
+        .bind(hexpub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Looks up a usable key of an open account and records that it was used.
+    pub async fn authenticate(&self, key: &str) -> Option<ApiKey> {
+        sqlx::query_as::<_, ApiKey>(
+            "UPDATE api_keys SET last_used_at = $2
+            WHERE key_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2)
+            AND NOT EXISTS (
+                SELECT 1 FROM users u WHERE u.hexpub = api_keys.hexpub AND u.deleted_at IS NOT NULL
+            )
+            RETURNING *",
+        )
+        .bind(hash_key(key))
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The scope an API key needs for a route. Routes without one, such as user
+/// and key management, can't be called with an API key at all.
+pub fn required_scope(method: &Method, path: &str) -> Option<&'static str> {
+    let read = method == Method::GET || method == Method::HEAD;
+
+    if path == "/relays" || path.starts_with("/relays/") {
+        return Some(if read { "relays:read" } else { "relays:write" });
+    }
+    if path == "/relay_orders" && !read {
+        return Some("orders:write");
+    }
+
+    None
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn create_api_key_handler(
+    auth: AuthorizationService,
+    api_key_repo: web::Data<ApiKeyRepository>,
+    body: web::Json<CreateApiKeyDto>,
+) -> impl Responder {
+    if let Err(err) = body.validate() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(err.to_string()));
+    }
+
+    match api_key_repo
+        .create(auth.hexpub().unwrap(), auth.npub().unwrap(), &body)
+        .await
+    {
+        Ok(api_key) => {
+            let subject = Some(api_key.api_key.uuid.as_str());
+            audit(
+                &api_key_repo.pool,
+                auth.npub().unwrap(),
+                AuditAction::ApiKeyCreated,
+                subject,
+            )
+            .await;
+            HttpResponse::Created().json(DataResponse::new(api_key))
+        }
+        Err(err) => {
+            eprintln!("Failed to create API key: {}", err);
+            HttpResponse::InternalServerError()
+                .json(ErrorResponse::new("Failed to create API key".to_string()))
+        }
+    }
+}
+
+async fn get_api_keys_handler(
+    auth: AuthorizationService,
+    api_key_repo: web::Data<ApiKeyRepository>,
+) -> impl Responder {
+    let api_keys = api_key_repo.get_user_keys(auth.npub().unwrap()).await;
+    HttpResponse::Ok().json(DataResponse::new(api_keys))
+}
+
+async fn revoke_api_key_handler(
+    auth: AuthorizationService,
+    api_key_repo: web::Data<ApiKeyRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match api_key_repo.revoke(&path, auth.npub().unwrap()).await {
+        Ok(true) => {
+            let subject = Some(path.as_str());
+            audit(
+                &api_key_repo.pool,
+                auth.npub().unwrap(),
+                AuditAction::ApiKeyRevoked,
+                subject,
+            )
+            .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().json(ErrorResponse::new("API key not found".to_string()))
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/api_keys")
+            .route(web::get().to(get_api_keys_handler))
+            .route(web::post().to(create_api_key_handler)),
+    )
+    .service(web::resource("/api_keys/{uuid}").route(web::delete().to(revoke_api_key_handler)));
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use serde_json::json;
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(required_scope(&Method::GET, "/relays"), Some("relays:read"));
+        assert_eq!(
+            required_scope(&Method::DELETE, "/relays/abc"),
+            Some("relays:write")
+        );
+        assert_eq!(
+            required_scope(&Method::PUT, "/relays/abc/info"),
+            Some("relays:write")
+        );
+        assert_eq!(
+            required_scope(&Method::POST, "/relay_orders"),
+            Some("orders:write")
+        );
+        assert_eq!(required_scope(&Method::POST, "/api_keys"), None);
+        assert_eq!(required_scope(&Method::GET, "/users"), None);
+        assert_eq!(required_scope(&Method::GET, "/relaysx"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_api_key_lifecycle() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(ApiKeyRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .configure(configure_routes)
+                .route("/relays", web::get().to(crate::relay::get_relays_handler)),
+        )
+        .await;
+        let request = |method: Method, uri: &str, authorization: &str| {
+            actix_web::test::TestRequest::default()
+                .method(method)
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", authorization)))
+        };
+
+        let req = request(Method::POST, "/api_keys", &jwt)
+            .set_json(json!({ "name": "ci", "scopes": ["relays:admin"] }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        let req = request(Method::POST, "/api_keys", &jwt)
+            .set_json(json!({ "name": "ci", "scopes": ["relays:read"], "expires_in_days": 30 }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let created: DataResponse<CreatedApiKey> = actix_web::test::read_body_json(resp).await;
+        let key = created.data.key;
+        assert!(key.starts_with(API_KEY_PREFIX));
+        assert!(key.starts_with(&created.data.api_key.key_prefix));
+
+        // The key works for its scope only, and never for key management.
+        let resp =
+            actix_web::test::call_service(&app, request(Method::GET, "/relays", &key).to_request())
+                .await;
+        assert_eq!(resp.status(), 200);
+        let resp = actix_web::test::call_service(
+            &app,
+            request(Method::GET, "/api_keys", &key).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 403);
+
+        let req = request(Method::GET, "/api_keys", &jwt).to_request();
+        let keys: DataResponse<Vec<ApiKey>> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(keys.data.len(), 1);
+        assert!(keys.data[0].last_used_at.is_some());
+
+        let uri = format!("/api_keys/{}", keys.data[0].uuid);
+        let resp =
+            actix_web::test::call_service(&app, request(Method::DELETE, &uri, &jwt).to_request())
+                .await;
+        assert_eq!(resp.status(), 204);
+
+        let resp =
+            actix_web::test::call_service(&app, request(Method::GET, "/relays", &key).to_request())
+                .await;
+        assert_eq!(resp.status(), 401);
+    }
+}
@@ -0,0 +1,88 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "audit_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    AccountRestored,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    RelayDeleted,
+    ExportRequested,
+}
+
+/// A security relevant action taken by a user. `subject` is the uuid of what
+/// it was taken on, if anything.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub npub: String,
+    pub action: AuditAction,
+    pub subject: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct AuditRepository {
+    pub pool: PgPool,
+}
+
+impl AuditRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        npub: &str,
+        action: AuditAction,
+        subject: Option<&str>,
+    ) -> Result<AuditEvent, sqlx::Error> {
+        sqlx::query_as::<_, AuditEvent>(
+            "INSERT INTO audit_events (npub, action, subject) VALUES ($1, $2, $3) RETURNING *",
+        )
+        .bind(npub)
+        .bind(action)
+        .bind(subject)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// The user's events, oldest first.
+    pub async fn get_user_events(&self, npub: &str) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        sqlx::query_as::<_, AuditEvent>(
+            "SELECT * FROM audit_events WHERE npub = $1 ORDER BY created_at, id",
+        )
+        .bind(npub)
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Records an audit event. Failing to record never fails the action itself.
+pub async fn audit(pool: &PgPool, npub: &str, action: AuditAction, subject: Option<&str>) {
+    if let Err(err) = AuditRepository::new(pool.clone())
+        .create(npub, action, subject)
+        .await
+    {
+        eprintln!(
+            "Failed to record audit event {:?} for {}: {}",
+            action, npub, err
+        );
+    }
+}
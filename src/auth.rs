@@ -1,12 +1,16 @@
-use crate::util::{ErrorResponse, bech32_encode};
+use crate::audit::{audit, AuditAction};
+use crate::replay_guard::ReplayGuard;
+use crate::token::{issue_access_token, issue_tokens, TokenRepository};
+use crate::user::{is_restorable, register_user, restore_account, User, UserRepository, UserRole};
+use crate::util::ErrorResponse;
 use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse};
 use base64::{engine::general_purpose, Engine};
 use chrono::{TimeZone, Utc};
-use futures::future::{ready, Ready};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use nostr::{Event, Kind};
+use futures::future::LocalBoxFuture;
+use nostr::{Event, Kind, Tag, TagKind, Url};
 use secp256k1::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 // -----------------------------------------------------------------------------
 // Models & DTOs
@@ -17,6 +21,11 @@ pub struct Claims {
     pub hexpub: String,
     pub npub: String,
     pub exp: usize,
+    /// Token id, used to revoke the token on logout.
+    #[serde(default)]
+    pub jti: String,
+    #[serde(default)]
+    pub role: UserRole,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,160 +37,543 @@ pub struct LoginInfo {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+}
+
+/// Proxy headers trusted when rebuilding the URL a NIP-98 event signed.
+/// Unset headers are ignored, so clients can't spoof scheme or host.
+#[derive(Debug, Clone, Default)]
+pub struct Nip98Config {
+    pub proxy_proto_header: Option<String>,
+    pub proxy_host_header: Option<String>,
+}
+
+impl Nip98Config {
+    pub fn from_env() -> Self {
+        let var = |name: &str| dotenvy::var(name).ok().filter(|value| !value.is_empty());
+
+        Nip98Config {
+            proxy_proto_header: var("NIP98_PROXY_PROTO_HEADER"),
+            proxy_host_header: var("NIP98_PROXY_HOST_HEADER"),
+        }
+    }
 }
 
 const SCHEME: &str = "Nostr";
+const NIP98_KIND: u16 = 27_235;
+/// Maximum age, in either direction, of a NIP-98 event's `created_at`.
+const NIP98_WINDOW_SECS: i64 = 10;
 
 // -----------------------------------------------------------------------------
 // Functions
 // -----------------------------------------------------------------------------
 
-pub fn generate_token(hexpub: &secp256k1::XOnlyPublicKey) -> Result<String, Error> {
-    let expiration = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::hours(24))
-        .expect("Could not set expiration time.");
-
-    let hexpub = hexpub.to_string();
+pub fn generate_jwt_by_hex(hexpub: &str) -> Result<String, Error> {
+    issue_access_token(hexpub, UserRole::User).map_err(actix_web::error::ErrorInternalServerError)
+}
 
-    let claims = Claims {
-        hexpub: hexpub.to_string(),
-        npub: bech32_encode(&hexpub).unwrap(),
-        exp: expiration.timestamp() as usize,
-    };
+fn unauthorized(message: &str) -> actix_web::Error {
+    actix_web::error::ErrorUnauthorized(serde_json::json!(ErrorResponse::new(
+        message.to_string()
+    )))
+}
 
-    let secret = dotenvy::var("JWT_SECRET").unwrap();
-    let encoding_key = EncodingKey::from_secret(secret.as_ref());
-    encode(&Header::default(), &claims, &encoding_key)
-        .map_err(|_| actix_web::error::ErrorInternalServerError("Failed to generate token"))
+fn tag_value<'a>(ev: &'a Event, name: &str) -> Option<&'a str> {
+    ev.tags.iter().find_map(|tag| match tag {
+        Tag::Generic(TagKind::Custom(kind), values) if kind == name => {
+            values.first().map(|value| value.as_str())
+        }
+        _ => None,
+    })
 }
 
-pub fn generate_jwt_by_hex(hexpub: &str) -> Result<String, Error> {
-    let expiration = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::hours(24))
-        .expect("Could not set expiration time.");
+fn header_value(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
 
-    let npub = bech32_encode(&hexpub.to_string()).unwrap();
+/// Reconstructs the absolute URL the client signed. Scheme and host are only
+/// taken from the proxy headers named by the app's `Nip98Config`.
+pub fn request_url(req: &HttpRequest) -> String {
+    let config = req
+        .app_data::<web::Data<Nip98Config>>()
+        .map(|config| config.get_ref().clone())
+        .unwrap_or_default();
+    let proxy_header =
+        |name: &Option<String>| name.as_deref().and_then(|name| header_value(req, name));
 
-    let claims = Claims {
-        hexpub: hexpub.to_string(),
-        npub: npub.to_string(),
-        exp: expiration.timestamp() as usize,
-    };
+    let scheme = proxy_header(&config.proxy_proto_header).unwrap_or_else(|| {
+        if req.app_config().secure() {
+            "https".to_string()
+        } else {
+            "http".to_string()
+        }
+    });
+    let host = proxy_header(&config.proxy_host_header)
+        .or_else(|| header_value(req, "Host"))
+        .unwrap_or_else(|| req.app_config().host().to_string());
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
 
-    let secret = dotenvy::var("JWT_SECRET").unwrap();
-    let encoding_key = EncodingKey::from_secret(secret.as_ref());
-    encode(&Header::default(), &claims, &encoding_key)
-        .map_err(|_| actix_web::error::ErrorInternalServerError("Failed to generate token"))
+    format!("{}://{}{}", scheme, host, path)
 }
 
-pub fn validate_nip98(req: &HttpRequest) -> Result<Event, actix_web::Error> {
-    let auth_header = req.headers().get("Authorization");
-    if auth_header.is_none() {
-        return Err(actix_web::error::ErrorInternalServerError(
-            "Failed to generate token",
-        ));
+fn urls_match(signed: &str, actual: &str) -> bool {
+    match (Url::parse(signed), Url::parse(actual)) {
+        (Ok(signed), Ok(actual)) => signed == actual,
+        _ => false,
     }
+}
 
-    let auth = auth_header.unwrap().to_str().unwrap().trim().to_string();
-    if !auth.starts_with(SCHEME) {
-        return Err(actix_web::error::ErrorInternalServerError(
-            "No scheme defined",
-        ));
-    }
+/// Validates a NIP-98 `Authorization: Nostr <base64 event>` header against
+/// the request and its body.
+pub fn validate_nip98(req: &HttpRequest, body: &[u8]) -> Result<Event, actix_web::Error> {
+    let auth = req
+        .headers()
+        .get("Authorization")
+        .ok_or_else(|| unauthorized("Missing authorization header"))?
+        .to_str()
+        .map_err(|_| unauthorized("Invalid authorization header"))?
+        .trim();
 
-    let token = auth[SCHEME.len()..].trim().to_string();
-    println!("the unecoded token is: {}", token);
-    let b_token = match general_purpose::STANDARD.decode(&token) {
-        Ok(token) => token,
-        Err(error) => {
-            println!("Failed to decode token. token: {}", error);
+    let token = auth
+        .strip_prefix(SCHEME)
+        .ok_or_else(|| unauthorized("No scheme defined"))?
+        .trim();
 
-            return Err(actix_web::error::ErrorBadRequest(serde_json::json!(
-                ErrorResponse {
-                    error: "invalid token".to_string(),
-                }
-            )));
-        }
-    };
+    let b_token = general_purpose::STANDARD
+        .decode(token)
+        .map_err(|_| unauthorized("invalid token"))?;
 
-    if b_token.is_empty() || b_token[0] != b'{' {
-        return Err(actix_web::error::ErrorInternalServerError(
-            "Failed to generate token",
-        ));
-    }
+    let ev: Event = serde_json::from_slice(&b_token)
+        .map_err(|err| unauthorized(&format!("invalid nostr event. err: {}", err)))?;
 
-    let ev: nostr::Event = match serde_json::from_slice(&b_token) {
-        Ok(ev) => ev,
-        Err(err) => {
-            return Err(actix_web::error::ErrorInternalServerError(
-                "invalid nostr event. err: ".to_string() + &err.to_string(),
-            ))
-        }
-    };
+    ev.verify()
+        .map_err(|_| unauthorized("invalid event signature"))?;
 
-    match ev.kind {
-        Kind::Ephemeral(27_235) => {
-            println!("the event is a nip98 event");
-        }
-        _ => {
-            return Err(actix_web::error::ErrorInternalServerError(
-                "wrong nostr kind",
-            ))
-        }
+    if ev.kind != Kind::Ephemeral(NIP98_KIND) {
+        return Err(unauthorized("wrong nostr kind"));
     }
 
     let created_at_utc = match Utc.timestamp_opt(ev.created_at.as_i64(), 0) {
         chrono::LocalResult::Single(time) => time,
-        chrono::LocalResult::None => {
-            return Err(actix_web::error::ErrorInternalServerError(
-                "Invalid timestamp",
-            ));
-        }
-        chrono::LocalResult::Ambiguous(_, _) => {
-            return Err(actix_web::error::ErrorInternalServerError(
-                "Ambiguous timestamp encountered",
-            ));
-        }
+        _ => return Err(unauthorized("Invalid timestamp")),
     };
 
     let diff_time = Utc::now()
         .signed_duration_since(created_at_utc)
         .num_seconds();
 
-    if diff_time.abs() > 10 {
-        return Err(actix_web::error::ErrorInternalServerError(
-            "timestamp out of range",
-        ));
+    if diff_time.abs() > NIP98_WINDOW_SECS {
+        return Err(unauthorized("timestamp out of range"));
+    }
+
+    let url = tag_value(&ev, "u").ok_or_else(|| unauthorized("missing u tag"))?;
+    if !urls_match(url, &request_url(req)) {
+        return Err(unauthorized("u tag does not match request url"));
+    }
+
+    let method = tag_value(&ev, "method").ok_or_else(|| unauthorized("missing method tag"))?;
+    if !method.eq_ignore_ascii_case(req.method().as_str()) {
+        return Err(unauthorized("method tag does not match request method"));
+    }
+
+    // Without a payload tag, a header captured from one request could be
+    // replayed with a different body inside the window.
+    match tag_value(&ev, "payload") {
+        Some(payload) if !payload.eq_ignore_ascii_case(&hex::encode(Sha256::digest(body))) => {
+            return Err(unauthorized("payload tag does not match request body"));
+        }
+        None if !body.is_empty() => return Err(unauthorized("missing payload tag")),
+        _ => {}
     }
 
     Ok(ev)
 }
 
+/// Validates the NIP-98 header and rejects events that were already used.
+pub async fn authenticate_nip98(
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<Event, actix_web::Error> {
+    let ev = validate_nip98(req, body)?;
+
+    let guard = req
+        .app_data::<web::Data<dyn ReplayGuard>>()
+        .ok_or_else(|| {
+            actix_web::error::ErrorInternalServerError("Replay guard not configured")
+        })?;
+    // The window check truncates to whole seconds, so an event stays
+    // acceptable until just before `created_at + NIP98_WINDOW_SECS + 1`.
+    let expires_at = Utc
+        .timestamp_opt(ev.created_at.as_i64() + NIP98_WINDOW_SECS + 1, 0)
+        .unwrap()
+        .naive_utc();
+
+    guard
+        .check_and_remember(&ev.id.to_hex(), expires_at)
+        .await
+        .map_err(|err| unauthorized(&err))?;
+
+    Ok(ev)
+}
+
+/// Builds a signed NIP-98 `Authorization` header value.
+#[cfg(test)]
+pub fn nip98_header(
+    keys: &nostr::Keys,
+    method: &str,
+    url: &str,
+    body: Option<&[u8]>,
+) -> String {
+    let tag = |name: &str, value: String| {
+        Tag::Generic(TagKind::Custom(name.to_string()), vec![value])
+    };
+    let mut tags = vec![tag("u", url.to_string()), tag("method", method.to_string())];
+    if let Some(body) = body {
+        tags.push(tag("payload", hex::encode(Sha256::digest(body))));
+    }
+
+    let event = nostr::EventBuilder::new(Kind::Ephemeral(NIP98_KIND), "", &tags)
+        .to_event(keys)
+        .unwrap();
+    format!("{} {}", SCHEME, general_purpose::STANDARD.encode(event.as_json()))
+}
+
 // -----------------------------------------------------------------------------
 // Handlers
 // -----------------------------------------------------------------------------
 
-async fn auth_handler(Nip98PubKey(pubkey): Nip98PubKey) -> Result<HttpResponse, Error> {
-    match generate_token(&pubkey) {
-        Ok(token) => Ok(HttpResponse::Ok().json(LoginResponse { token })),
-        Err(e) => Err(actix_web::error::ErrorInternalServerError(e.to_string())),
+/// Returned when the owner of a closed account tries to log in. Past the
+/// restore window the account can't be reopened, so they are told so.
+pub fn account_closed(user: &User) -> HttpResponse {
+    let message = if is_restorable(user) {
+        "Account closed. Restore it with POST /login/restore"
+    } else {
+        "Account permanently closed"
+    };
+    HttpResponse::Forbidden().json(ErrorResponse::new(message.to_string()))
+}
+
+async fn auth_handler(
+    Nip98PubKey(pubkey): Nip98PubKey,
+    user_repo: web::Data<UserRepository>,
+    token_repo: web::Data<TokenRepository>,
+) -> Result<HttpResponse, Error> {
+    let user = register_user(&user_repo, &pubkey.to_string())
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if user.deleted_at.is_some() {
+        return Ok(account_closed(&user));
+    }
+
+    match issue_tokens(&token_repo, &pubkey.to_string()).await {
+        Ok(tokens) => {
+            audit(&user_repo.pool, &user.npub, AuditAction::Login, None).await;
+            Ok(HttpResponse::Ok().json(tokens))
+        }
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
+    }
+}
+
+/// Reopens a closed account and logs its owner in.
+async fn restore_handler(
+    Nip98PubKey(pubkey): Nip98PubKey,
+    user_repo: web::Data<UserRepository>,
+    token_repo: web::Data<TokenRepository>,
+) -> Result<HttpResponse, Error> {
+    let restored = restore_account(&user_repo, &pubkey.to_string())
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let user = match restored {
+        Some(user) => user,
+        None => {
+            return match user_repo.get_by_hexpub(&pubkey.to_string()).await {
+                Some(user) if user.deleted_at.is_some() => Ok(account_closed(&user)),
+                _ => Ok(HttpResponse::NotFound().json(ErrorResponse::new(
+                    "No account to restore".to_string(),
+                ))),
+            };
+        }
+    };
+    audit(&user_repo.pool, &user.npub, AuditAction::AccountRestored, None).await;
+
+    match issue_tokens(&token_repo, &pubkey.to_string()).await {
+        Ok(tokens) => Ok(HttpResponse::Ok().json(tokens)),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
     }
 }
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::resource("/login").route(web::post().to(auth_handler)));
+    cfg.service(web::resource("/login").route(web::post().to(auth_handler)))
+        .service(web::resource("/login/restore").route(web::post().to(restore_handler)));
 }
 
 // -----------------------------------------------------------------------------
 // Extractor
 // -----------------------------------------------------------------------------
+/// Extracts the pubkey from a valid NIP-98 header. It consumes the request
+/// body to check the `payload` tag, so handlers can't extract the body too.
 pub struct Nip98PubKey(XOnlyPublicKey);
 
 impl FromRequest for Nip98PubKey {
     type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        let body = web::Bytes::from_request(&req, payload);
+
+        Box::pin(async move {
+            let body = body.await?;
+            authenticate_nip98(&req, &body)
+                .await
+                .map(|e| Nip98PubKey(e.pubkey))
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use nostr::{EventBuilder, EventId, Keys, Timestamp};
+
+    const URL: &str = "http://api.relaying.io/login";
+
+    fn tag(name: &str, value: &str) -> Tag {
+        Tag::Generic(TagKind::Custom(name.to_string()), vec![value.to_string()])
+    }
+
+    fn nip98_event(keys: &Keys, kind: Kind, offset_secs: i64, tags: Vec<Tag>) -> Event {
+        let mut unsigned = EventBuilder::new(kind, "", &tags).to_unsigned_event(keys.public_key());
+        unsigned.created_at = Timestamp::from((Timestamp::now().as_i64() + offset_secs) as u64);
+        unsigned.id = EventId::new(
+            &unsigned.pubkey,
+            unsigned.created_at,
+            &unsigned.kind,
+            &unsigned.tags,
+            &unsigned.content,
+        );
+        unsigned.sign(keys).unwrap()
+    }
+
+    fn header(event: &Event) -> String {
+        format!(
+            "{} {}",
+            SCHEME,
+            general_purpose::STANDARD.encode(event.as_json())
+        )
+    }
+
+    fn request(method: &str, authorization: &str) -> HttpRequest {
+        TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri("/login")
+            .insert_header(("Host", "api.relaying.io"))
+            .insert_header(("Authorization", authorization))
+            .to_http_request()
+    }
+
+    fn assert_rejected(result: Result<Event, actix_web::Error>, message: &str) {
+        let err = result.expect_err(message);
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(err.to_string().contains(message), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_nip98_vectors() {
+        let keys = Keys::generate();
+        let kind = Kind::Ephemeral(NIP98_KIND);
+        let body = br#"{"name":"relay"}"#;
+        let body_hash = hex::encode(Sha256::digest(body));
+        let valid_tags = || vec![tag("u", URL), tag("method", "POST")];
+
+        // Valid requests, without and with a payload tag.
+        let event = nip98_event(&keys, kind, 0, valid_tags());
+        let validated = validate_nip98(&request("POST", &header(&event)), b"").unwrap();
+        assert_eq!(validated.pubkey, keys.public_key());
+
+        let mut tags = valid_tags();
+        tags.push(tag("payload", &body_hash));
+        let event = nip98_event(&keys, kind, 0, tags);
+        assert!(validate_nip98(&request("POST", &header(&event)), body).is_ok());
+
+        // Default ports and host case don't matter.
+        let event = nip98_event(
+            &keys,
+            kind,
+            0,
+            vec![tag("u", "http://API.relaying.io:80/login"), tag("method", "post")],
+        );
+        assert!(validate_nip98(&request("POST", &header(&event)), b"").is_ok());
+
+        let cases: Vec<(&str, Vec<Tag>, Kind, i64, &str)> = vec![
+            ("wrong kind", valid_tags(), Kind::TextNote, 0, "wrong nostr kind"),
+            ("too old", valid_tags(), kind, -30, "timestamp out of range"),
+            ("too new", valid_tags(), kind, 30, "timestamp out of range"),
+            ("no u tag", vec![tag("method", "POST")], kind, 0, "missing u tag"),
+            (
+                "other url",
+                vec![tag("u", "http://api.relaying.io/relays"), tag("method", "POST")],
+                kind,
+                0,
+                "u tag does not match request url",
+            ),
+            (
+                "other scheme",
+                vec![tag("u", "https://api.relaying.io/login"), tag("method", "POST")],
+                kind,
+                0,
+                "u tag does not match request url",
+            ),
+            ("no method tag", vec![tag("u", URL)], kind, 0, "missing method tag"),
+            (
+                "other method",
+                vec![tag("u", URL), tag("method", "GET")],
+                kind,
+                0,
+                "method tag does not match request method",
+            ),
+            (
+                "other payload",
+                vec![
+                    tag("u", URL),
+                    tag("method", "POST"),
+                    tag("payload", &hex::encode(Sha256::digest(b"other"))),
+                ],
+                kind,
+                0,
+                "payload tag does not match request body",
+            ),
+            ("no payload", valid_tags(), kind, 0, "missing payload tag"),
+        ];
+
+        for (name, tags, kind, offset, message) in cases {
+            let event = nip98_event(&keys, kind, offset, tags);
+            let result = validate_nip98(&request("POST", &header(&event)), body);
+            assert!(result.is_err(), "{} should be rejected", name);
+            assert_rejected(result, message);
+        }
+    }
+
+    #[test]
+    fn test_validate_nip98_malformed_headers() {
+        let keys = Keys::generate();
+        let event = nip98_event(
+            &keys,
+            Kind::Ephemeral(NIP98_KIND),
+            0,
+            vec![tag("u", URL), tag("method", "POST")],
+        );
+
+        let req = TestRequest::post().uri("/login").to_http_request();
+        assert_rejected(validate_nip98(&req, b""), "Missing authorization header");
+
+        let bearer = format!("Bearer {}", general_purpose::STANDARD.encode(event.as_json()));
+        assert_rejected(validate_nip98(&request("POST", &bearer), b""), "No scheme defined");
+
+        assert_rejected(
+            validate_nip98(&request("POST", "Nostr not-base64!"), b""),
+            "invalid token",
+        );
+
+        let not_json = format!("Nostr {}", general_purpose::STANDARD.encode("[]"));
+        assert_rejected(
+            validate_nip98(&request("POST", &not_json), b""),
+            "invalid nostr event",
+        );
+
+        // Changing the content after signing breaks the signature.
+        let mut tampered: serde_json::Value = serde_json::from_str(&event.as_json()).unwrap();
+        tampered["content"] = serde_json::json!("tampered");
+        let tampered = format!(
+            "Nostr {}",
+            general_purpose::STANDARD.encode(tampered.to_string())
+        );
+        assert_rejected(
+            validate_nip98(&request("POST", &tampered), b""),
+            "invalid event signature",
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_login_rejects_replayed_event() {
+        let test_utils = crate::util::TestUtils::new().await;
+        let guard: std::sync::Arc<dyn ReplayGuard> =
+            std::sync::Arc::new(crate::replay_guard::MemoryReplayGuard::new(10));
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::from(guard))
+                .app_data(web::Data::new(TokenRepository::new(test_utils.pool.clone())))
+                .app_data(web::Data::new(test_utils.user_repo.clone()))
+                .configure(configure_routes),
+        )
+        .await;
+
+        let keys = Keys::generate();
+        let event = nip98_event(
+            &keys,
+            Kind::Ephemeral(NIP98_KIND),
+            0,
+            vec![tag("u", URL), tag("method", "POST")],
+        );
+        let login = || {
+            TestRequest::post()
+                .uri("/login")
+                .insert_header(("Host", "api.relaying.io"))
+                .insert_header(("Authorization", header(&event)))
+                .to_request()
+        };
+
+        let resp = actix_web::test::call_service(&app, login()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let npub = crate::util::bech32_encode(&keys.public_key().to_string()).unwrap();
+        assert!(test_utils.user_repo.user_exists(&npub).await);
+
+        let resp = actix_web::test::call_service(&app, login()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = actix_web::test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("event already used"));
+    }
+
+    #[test]
+    fn test_request_url_proxy_headers() {
+        let req = TestRequest::get()
+            .uri("/relays?page=2")
+            .insert_header(("Host", "internal:8080"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "api.relaying.io, proxy"))
+            .to_http_request();
+
+        // Proxy headers are ignored unless configured.
+        assert_eq!(request_url(&req), "http://internal:8080/relays?page=2");
 
-    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
-        ready(validate_nip98(req).map(|e| Nip98PubKey(e.pubkey)))
+        let req = TestRequest::get()
+            .uri("/relays?page=2")
+            .insert_header(("Host", "internal:8080"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "api.relaying.io, proxy"))
+            .app_data(web::Data::new(Nip98Config {
+                proxy_proto_header: Some("X-Forwarded-Proto".to_string()),
+                proxy_host_header: Some("X-Forwarded-Host".to_string()),
+            }))
+            .to_http_request();
+        assert_eq!(request_url(&req), "https://api.relaying.io/relays?page=2");
     }
 }
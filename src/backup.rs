@@ -0,0 +1,1476 @@
+use actix_web::{web, HttpResponse, Responder};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use futures::StreamExt;
+use nostr::{Event, EventId, Filter, Timestamp};
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, Region};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    health::relay_url,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayRepository, RelayState},
+    relay_client::RelayClient,
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Events requested per REQ while dumping a relay. Most relays cap a REQ at
+/// 500 events, and a page this long is taken to be truncated.
+const DUMP_PAGE_SIZE: usize = 500;
+/// Events published concurrently during a restore.
+const RESTORE_CONCURRENCY: usize = 8;
+/// How often the scheduler looks for relays that are due.
+const SCHEDULER_TICK: Duration = Duration::from_secs(300);
+/// How often queued restores are checked for a target that came online.
+const RESTORE_TICK: Duration = Duration::from_secs(30);
+/// Size of each part of a multipart S3 upload. S3 requires at least 5 MiB
+/// for every part but the last.
+const S3_PART_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "relay_backup_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum BackupStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RelayBackup {
+    pub uuid: String,
+    pub relay_uuid: String,
+    /// `false` for backups the user asked for.
+    pub scheduled: bool,
+    pub status: BackupStatus,
+    /// Key of the JSONL dump in the object store.
+    pub object_key: String,
+    pub event_count: Option<i64>,
+    pub size_bytes: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RelayRestore {
+    pub uuid: String,
+    pub backup_uuid: String,
+    /// Relay the events are restored into.
+    pub relay_uuid: String,
+    pub npub: String,
+    pub status: BackupStatus,
+    pub restored_count: i64,
+    /// Events the target relay rejected, e.g. because of its whitelist.
+    pub failed_count: i64,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// Unset while the restore waits for its relay to come online.
+    pub started_at: Option<NaiveDateTime>,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct RestoreBackupDto {
+    /// Defaults to the relay the backup was taken from.
+    pub target_relay_uuid: Option<String>,
+}
+
+pub struct BackupConfig {
+    /// Time between scheduled backups of a relay.
+    pub interval: chrono::Duration,
+    /// Completed backups kept per relay.
+    pub retention_count: i64,
+    /// Backups older than this are deleted, except a relay's newest one.
+    pub retention_age: chrono::Duration,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: i64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        BackupConfig {
+            interval: chrono::Duration::hours(var("BACKUP_INTERVAL_HOURS", 24)),
+            retention_count: var("BACKUP_RETENTION_COUNT", 7),
+            retention_age: chrono::Duration::days(var("BACKUP_RETENTION_DAYS", 30)),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Object store
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String>;
+
+    /// Starts writing an object piece by piece, so large dumps never sit in
+    /// memory whole. Nothing appears under `key` until the upload finishes.
+    async fn start_upload(&self, key: &str) -> Result<Box<dyn ObjectUpload>, String>;
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, String>;
+
+    /// Succeeds if the object is already gone.
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+#[async_trait]
+pub trait ObjectUpload: Send {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), String>;
+
+    async fn finish(self: Box<Self>) -> Result<(), String>;
+
+    /// Discards everything written so far.
+    async fn abort(self: Box<Self>);
+}
+
+/// Picks the store from `BACKUP_STORE` (`s3` or `filesystem`).
+pub fn store_from_env() -> Arc<dyn ObjectStore> {
+    match dotenvy::var("BACKUP_STORE").unwrap_or_default().as_str() {
+        "s3" => Arc::new(S3ObjectStore::from_env()),
+        _ => Arc::new(FilesystemObjectStore::from_env()),
+    }
+}
+
+/// S3 or any S3-compatible store such as MinIO, addressed path-style.
+#[derive(Clone)]
+pub struct S3ObjectStore {
+    client: Client,
+    region: Region,
+    bucket: String,
+}
+
+impl S3ObjectStore {
+    /// Setting `BACKUP_S3_ENDPOINT` points the store at a non-AWS endpoint.
+    pub fn from_env() -> Self {
+        let region_name =
+            dotenvy::var("BACKUP_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let region = match dotenvy::var("BACKUP_S3_ENDPOINT") {
+            Ok(endpoint) => Region::Custom {
+                name: region_name,
+                endpoint,
+            },
+            Err(_) => region_name.parse().unwrap_or(Region::UsEast1),
+        };
+        let env_provider = rusoto_credential::EnvironmentProvider::default();
+
+        Self {
+            client: Client::new_with(env_provider, HttpClient::new().unwrap()),
+            region,
+            bucket: dotenvy::var("BACKUP_S3_BUCKET").unwrap_or_default(),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        key: &str,
+        params: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<BufferedHttpResponse, String> {
+        let path = format!("/{}/{}", self.bucket, key);
+        let mut request = SignedRequest::new(method, "s3", &self.region, &path);
+        for (name, value) in params {
+            request.add_param(*name, *value);
+        }
+        request.set_payload(body);
+
+        let mut response = self
+            .client
+            .sign_and_dispatch(request)
+            .await
+            .map_err(|err| format!("S3 {} {} failed: {:?}", method, key, err))?;
+        response
+            .buffer()
+            .await
+            .map_err(|err| format!("S3 {} {} failed: {}", method, key, err))
+    }
+
+    /// Like `request`, but fails unless S3 responded with a success.
+    async fn request_ok(
+        &self,
+        method: &str,
+        key: &str,
+        params: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<BufferedHttpResponse, String> {
+        let response = self.request(method, key, params, body).await?;
+        if !response.status.is_success() {
+            return Err(format!(
+                "S3 {} {} responded {}: {}",
+                method,
+                key,
+                response.status.as_u16(),
+                String::from_utf8_lossy(&response.body)
+            ));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String> {
+        self.request_ok("PUT", key, &[], Some(body)).await?;
+        Ok(())
+    }
+
+    async fn start_upload(&self, key: &str) -> Result<Box<dyn ObjectUpload>, String> {
+        Ok(Box::new(S3Upload {
+            store: self.clone(),
+            key: key.to_string(),
+            upload_id: None,
+            buffer: vec![],
+            etags: vec![],
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let response = self.request_ok("GET", key, &[], None).await?;
+        Ok(response.body.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let response = self.request("DELETE", key, &[], None).await?;
+        match response.status.as_u16() {
+            200..=299 | 404 => Ok(()),
+            status => Err(format!(
+                "S3 DELETE {} responded {}: {}",
+                key,
+                status,
+                String::from_utf8_lossy(&response.body)
+            )),
+        }
+    }
+}
+
+/// A multipart upload that is only started once the object outgrows one
+/// part. Smaller objects go up with a single PUT on `finish`.
+struct S3Upload {
+    store: S3ObjectStore,
+    key: String,
+    upload_id: Option<String>,
+    buffer: Vec<u8>,
+    etags: Vec<String>,
+}
+
+impl S3Upload {
+    async fn upload_part(&mut self) -> Result<(), String> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let response = self
+                    .store
+                    .request_ok("POST", &self.key, &[("uploads", "")], None)
+                    .await?;
+                let upload_id = xml_value(&response.body, "UploadId")
+                    .ok_or_else(|| format!("S3 returned no upload id for {}", self.key))?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+
+        let part_number = (self.etags.len() + 1).to_string();
+        let body = std::mem::take(&mut self.buffer);
+        let response = self
+            .store
+            .request_ok(
+                "PUT",
+                &self.key,
+                &[("partNumber", &part_number), ("uploadId", &upload_id)],
+                Some(body),
+            )
+            .await?;
+        let etag = response
+            .headers
+            .get("etag")
+            .ok_or_else(|| format!("S3 returned no ETag for part {}", part_number))?;
+        self.etags.push(etag.clone());
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectUpload for S3Upload {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.buffer.extend_from_slice(chunk);
+        if self.buffer.len() >= S3_PART_SIZE {
+            self.upload_part().await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), String> {
+        if self.upload_id.is_none() {
+            let body = std::mem::take(&mut self.buffer);
+            return self.store.put(&self.key, body).await;
+        }
+        if !self.buffer.is_empty() {
+            self.upload_part().await?;
+        }
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in self.etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                index + 1,
+                etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let upload_id = self.upload_id.clone().unwrap_or_default();
+        let response = self
+            .store
+            .request_ok(
+                "POST",
+                &self.key,
+                &[("uploadId", &upload_id)],
+                Some(body.into_bytes()),
+            )
+            .await?;
+        // Completion can fail after S3 has already answered 200.
+        if xml_value(&response.body, "Code").is_some() {
+            return Err(format!(
+                "S3 failed to complete {}: {}",
+                self.key,
+                String::from_utf8_lossy(&response.body)
+            ));
+        }
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) {
+        if let Some(upload_id) = &self.upload_id {
+            if let Err(err) = self
+                .store
+                .request_ok("DELETE", &self.key, &[("uploadId", upload_id)], None)
+                .await
+            {
+                eprintln!("Failed to abort upload of {}: {}", self.key, err);
+            }
+        }
+    }
+}
+
+/// The text of the first `<tag>` element in an S3 XML response.
+fn xml_value(body: &[u8], tag: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let start = body.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + body[start..].find(&format!("</{}>", tag))?;
+    Some(body[start..end].to_string())
+}
+
+/// Keeps objects as files under a directory, for development and tests.
+pub struct FilesystemObjectStore {
+    root: PathBuf,
+}
+
+impl FilesystemObjectStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(PathBuf::from(
+            dotenvy::var("BACKUP_DIR").unwrap_or_else(|_| "./backups".to_string()),
+        ))
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        if key.split('/').any(|part| part.is_empty() || part == "..") {
+            return Err(format!("Invalid object key: {}", key));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FilesystemObjectStore {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|err| format!("Failed to create {}: {}", parent.display(), err))?;
+        }
+        tokio::fs::write(&path, body)
+            .await
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+
+    async fn start_upload(&self, key: &str) -> Result<Box<dyn ObjectUpload>, String> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|err| format!("Failed to create {}: {}", parent.display(), err))?;
+        }
+        let partial = path.with_extension("partial");
+        let file = tokio::fs::File::create(&partial)
+            .await
+            .map_err(|err| format!("Failed to create {}: {}", partial.display(), err))?;
+
+        Ok(Box::new(FilesystemUpload {
+            file,
+            partial,
+            path,
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let path = self.path(key)?;
+        tokio::fs::read(&path)
+            .await
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(format!("Failed to delete {}: {}", path.display(), err)),
+        }
+    }
+}
+
+/// Writes to a sibling `.partial` file that is renamed into place on finish.
+struct FilesystemUpload {
+    file: tokio::fs::File,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl ObjectUpload for FilesystemUpload {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.file
+            .write_all(chunk)
+            .await
+            .map_err(|err| format!("Failed to write {}: {}", self.partial.display(), err))
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.file
+            .flush()
+            .await
+            .map_err(|err| format!("Failed to write {}: {}", self.partial.display(), err))?;
+        tokio::fs::rename(&self.partial, &self.path)
+            .await
+            .map_err(|err| format!("Failed to write {}: {}", self.path.display(), err))
+    }
+
+    async fn abort(self: Box<Self>) {
+        let _ = tokio::fs::remove_file(&self.partial).await;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct BackupRepository {
+    pub pool: PgPool,
+}
+
+impl BackupRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_backup(
+        &self,
+        relay_uuid: &str,
+        scheduled: bool,
+    ) -> Result<RelayBackup, sqlx::Error> {
+        let uuid = uuid::Uuid::new_v4().to_string();
+
+        sqlx::query_as::<_, RelayBackup>(
+            "INSERT INTO relay_backups (uuid, relay_uuid, scheduled, status, object_key)
+            VALUES ($1, $2, $3, 'pending', $4)
+            RETURNING *",
+        )
+        .bind(&uuid)
+        .bind(relay_uuid)
+        .bind(scheduled)
+        .bind(format!("relays/{}/{}.jsonl", relay_uuid, uuid))
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// `result` is the event count and size of the stored dump.
+    pub async fn finish_backup(
+        &self,
+        uuid: &str,
+        result: Result<(i64, i64), String>,
+    ) -> Result<(), sqlx::Error> {
+        let (status, event_count, size_bytes, error) = match result {
+            Ok((event_count, size_bytes)) => (
+                BackupStatus::Completed,
+                Some(event_count),
+                Some(size_bytes),
+                None,
+            ),
+            Err(err) => (BackupStatus::Failed, None, None, Some(err)),
+        };
+
+        sqlx::query(
+            "UPDATE relay_backups
+            SET status = $2, event_count = $3, size_bytes = $4, error = $5, completed_at = $6
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(status)
+        .bind(event_count)
+        .bind(size_bytes)
+        .bind(error)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The relay's backups, newest first.
+    pub async fn get_backups(&self, relay_uuid: &str) -> Vec<RelayBackup> {
+        sqlx::query_as::<_, RelayBackup>(
+            "SELECT * FROM relay_backups WHERE relay_uuid = $1 ORDER BY created_at DESC",
+        )
+        .bind(relay_uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn find_backup(&self, uuid: &str) -> Option<RelayBackup> {
+        sqlx::query_as::<_, RelayBackup>("SELECT * FROM relay_backups WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn get_backup(&self, uuid: &str, relay_uuid: &str) -> Option<RelayBackup> {
+        sqlx::query_as::<_, RelayBackup>(
+            "SELECT * FROM relay_backups WHERE uuid = $1 AND relay_uuid = $2",
+        )
+        .bind(uuid)
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Active relays without a backup attempt since `since`.
+    pub async fn due_relays(&self, since: NaiveDateTime) -> Vec<Relay> {
+        sqlx::query_as::<_, Relay>(
+            "SELECT * FROM relays r
+            WHERE r.deleted_at IS NULL AND r.state = 'online'
+            AND NOT EXISTS (
+                SELECT 1 FROM relay_backups b WHERE b.relay_uuid = r.uuid AND b.created_at > $1
+            )",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Completed backups beyond the newest `keep` of their relay, or older
+    /// than `cutoff` unless they are the relay's newest. Failed backups older
+    /// than `cutoff` are included too.
+    pub async fn get_expired(&self, keep: i64, cutoff: NaiveDateTime) -> Vec<RelayBackup> {
+        sqlx::query_as::<_, RelayBackup>(
+            "SELECT * FROM (
+                SELECT b.*, ROW_NUMBER() OVER (
+                    PARTITION BY b.relay_uuid ORDER BY b.created_at DESC
+                ) AS position
+                FROM relay_backups b WHERE b.status = 'completed'
+            ) ranked
+            WHERE ranked.position > $1 OR (ranked.position > 1 AND ranked.created_at < $2)
+            UNION ALL
+            SELECT *, 0 FROM relay_backups WHERE status = 'failed' AND created_at < $2",
+        )
+        .bind(keep)
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn delete_backup(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM relay_backups WHERE uuid = $1")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Restores that don't `start` right away are queued until their relay
+    /// is online.
+    pub async fn create_restore(
+        &self,
+        backup_uuid: &str,
+        relay_uuid: &str,
+        npub: &str,
+        start: bool,
+    ) -> Result<RelayRestore, sqlx::Error> {
+        sqlx::query_as::<_, RelayRestore>(
+            "INSERT INTO relay_restores (uuid, backup_uuid, relay_uuid, npub, status, started_at)
+            VALUES ($1, $2, $3, $4, 'pending', $5)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(backup_uuid)
+        .bind(relay_uuid)
+        .bind(npub)
+        .bind(start.then(|| Utc::now().naive_utc()))
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Marks queued restores whose relay is now online as started and
+    /// returns them.
+    pub async fn claim_ready_restores(&self) -> Result<Vec<RelayRestore>, sqlx::Error> {
+        sqlx::query_as::<_, RelayRestore>(
+            "UPDATE relay_restores SET started_at = $1
+            WHERE status = 'pending' AND started_at IS NULL
+            AND relay_uuid IN (
+                SELECT uuid FROM relays WHERE state = 'online' AND deleted_at IS NULL
+            )
+            RETURNING *",
+        )
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// `result` is the number of events restored and rejected.
+    pub async fn finish_restore(
+        &self,
+        uuid: &str,
+        result: Result<(i64, i64), String>,
+    ) -> Result<(), sqlx::Error> {
+        let (status, restored_count, failed_count, error) = match result {
+            Ok((restored, failed)) => (BackupStatus::Completed, restored, failed, None),
+            Err(err) => (BackupStatus::Failed, 0, 0, Some(err)),
+        };
+
+        sqlx::query(
+            "UPDATE relay_restores
+            SET status = $2, restored_count = $3, failed_count = $4, error = $5, completed_at = $6
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(status)
+        .bind(restored_count)
+        .bind(failed_count)
+        .bind(error)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Restores into the relay, newest first.
+    pub async fn get_restores(&self, relay_uuid: &str) -> Vec<RelayRestore> {
+        sqlx::query_as::<_, RelayRestore>(
+            "SELECT * FROM relay_restores WHERE relay_uuid = $1 ORDER BY created_at DESC",
+        )
+        .bind(relay_uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// One event JSON per line, the same format `strfry export` writes.
+pub fn render_backup(events: &[Event]) -> Vec<u8> {
+    let mut body = String::new();
+    for event in events {
+        body.push_str(&event.as_json());
+        body.push('\n');
+    }
+    body.into_bytes()
+}
+
+/// Parses a dump, checking every event's signature.
+pub fn parse_backup(body: &[u8]) -> Result<Vec<Event>, String> {
+    let body = std::str::from_utf8(body).map_err(|err| err.to_string())?;
+
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            let event = Event::from_json(line)
+                .map_err(|err| format!("Invalid event on line {}: {}", index + 1, err))?;
+            event
+                .verify()
+                .map_err(|err| format!("Invalid signature on line {}: {}", index + 1, err))?;
+            Ok(event)
+        })
+        .collect()
+}
+
+/// Pages backwards through a relay's events with `until`. Timestamps are
+/// only precise to the second, so every page asks for its oldest second again
+/// and skips the events it already returned from it.
+pub struct EventPager<'a> {
+    relay_client: &'a dyn RelayClient,
+    url: &'a str,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+    /// Events already returned from the `until` second.
+    boundary: HashSet<EventId>,
+    done: bool,
+}
+
+impl<'a> EventPager<'a> {
+    pub fn new(relay_client: &'a dyn RelayClient, url: &'a str, since: Option<Timestamp>) -> Self {
+        Self {
+            relay_client,
+            url,
+            since,
+            until: None,
+            boundary: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// The next events not returned yet, or `None` once the relay has nothing
+    /// older. A full page from a single second can't be paged past with REQ
+    /// filters, so that fails instead of skipping the rest of the second.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<Event>>, String> {
+        while !self.done {
+            let mut filter = Filter::new().limit(DUMP_PAGE_SIZE);
+            if let Some(since) = self.since {
+                filter = filter.since(since);
+            }
+            if let Some(until) = self.until {
+                filter = filter.until(until);
+            }
+
+            let page = self.relay_client.fetch(self.url, vec![filter]).await?;
+            let full = page.len() >= DUMP_PAGE_SIZE;
+            let oldest = match page.iter().map(|event| event.created_at).min() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let fresh: Vec<Event> = page
+                .into_iter()
+                .filter(|event| !self.boundary.contains(&event.id))
+                .collect();
+
+            if fresh.is_empty() {
+                // Only the `until` second came back, and it's all been seen.
+                if full {
+                    return Err(format!(
+                        "More than {} events share the timestamp {}",
+                        DUMP_PAGE_SIZE,
+                        oldest.as_u64()
+                    ));
+                }
+                match oldest.as_u64().checked_sub(1) {
+                    Some(before) => self.until = Some(Timestamp::from(before)),
+                    None => self.done = true,
+                }
+                self.boundary.clear();
+                continue;
+            }
+
+            if self.until != Some(oldest) {
+                self.boundary.clear();
+            }
+            self.boundary.extend(
+                fresh
+                    .iter()
+                    .filter(|event| event.created_at == oldest)
+                    .map(|event| event.id),
+            );
+            self.until = Some(oldest);
+            return Ok(Some(fresh));
+        }
+
+        self.done = true;
+        Ok(None)
+    }
+}
+
+/// Every stored event, or those since `since`.
+pub async fn dump_events(
+    relay_client: &dyn RelayClient,
+    url: &str,
+    since: Option<Timestamp>,
+) -> Result<Vec<Event>, String> {
+    let mut pager = EventPager::new(relay_client, url, since);
+    let mut events = vec![];
+    while let Some(page) = pager.next_page().await? {
+        events.extend(page);
+    }
+    Ok(events)
+}
+
+/// Streams every stored event into the upload, a page at a time. Returns the
+/// event count and the bytes written.
+pub async fn write_dump(
+    relay_client: &dyn RelayClient,
+    url: &str,
+    upload: &mut dyn ObjectUpload,
+) -> Result<(i64, i64), String> {
+    let mut pager = EventPager::new(relay_client, url, None);
+    let (mut count, mut size) = (0, 0);
+    while let Some(page) = pager.next_page().await? {
+        let body = render_backup(&page);
+        count += page.len() as i64;
+        size += body.len() as i64;
+        upload.write(&body).await?;
+    }
+    Ok((count, size))
+}
+
+/// Publishes the events into the relay. Returns how many it accepted and
+/// rejected.
+pub async fn restore_events(
+    relay_client: &dyn RelayClient,
+    url: &str,
+    events: Vec<Event>,
+) -> (i64, i64) {
+    let results: Vec<bool> = futures::stream::iter(events)
+        .map(|event| async move { relay_client.publish(url, &event).await.is_ok() })
+        .buffer_unordered(RESTORE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let restored = results.iter().filter(|accepted| **accepted).count() as i64;
+    (restored, results.len() as i64 - restored)
+}
+
+pub async fn run_backup(
+    backup_repo: BackupRepository,
+    relay_client: Arc<dyn RelayClient>,
+    store: Arc<dyn ObjectStore>,
+    backup: RelayBackup,
+    url: String,
+) {
+    let result = async {
+        let mut upload = store.start_upload(&backup.object_key).await?;
+        match write_dump(relay_client.as_ref(), &url, upload.as_mut()).await {
+            Ok(written) => upload.finish().await.map(|_| written),
+            Err(err) => {
+                upload.abort().await;
+                Err(err)
+            }
+        }
+    }
+    .await;
+    if let Err(err) = &result {
+        eprintln!(
+            "Backup {} of relay {} failed: {}",
+            backup.uuid, backup.relay_uuid, err
+        );
+    }
+
+    if let Err(err) = backup_repo.finish_backup(&backup.uuid, result).await {
+        eprintln!("Failed to record backup {}: {}", backup.uuid, err);
+    }
+}
+
+pub async fn run_restore(
+    backup_repo: BackupRepository,
+    relay_client: Arc<dyn RelayClient>,
+    store: Arc<dyn ObjectStore>,
+    backup: RelayBackup,
+    restore: RelayRestore,
+    url: String,
+) {
+    let result = async {
+        let body = store.get(&backup.object_key).await?;
+        let events = parse_backup(&body)?;
+        Ok(restore_events(relay_client.as_ref(), &url, events).await)
+    }
+    .await;
+    if let Err(err) = &result {
+        eprintln!("Restore {} failed: {}", restore.uuid, err);
+    }
+
+    if let Err(err) = backup_repo.finish_restore(&restore.uuid, result).await {
+        eprintln!("Failed to record restore {}: {}", restore.uuid, err);
+    }
+}
+
+/// Starts the queued restores whose relay has come online.
+pub async fn start_ready_restores(
+    backup_repo: &BackupRepository,
+    relay_client: Arc<dyn RelayClient>,
+    store: Arc<dyn ObjectStore>,
+) {
+    let restores = match backup_repo.claim_ready_restores().await {
+        Ok(restores) => restores,
+        Err(err) => {
+            eprintln!("Failed to claim queued restores: {}", err);
+            return;
+        }
+    };
+    let relay_repo = RelayRepository::new(backup_repo.pool.clone());
+
+    for restore in restores {
+        let backup = backup_repo.find_backup(&restore.backup_uuid).await;
+        let relay = relay_repo.get_one(&restore.relay_uuid).await;
+        match (backup, relay) {
+            (Some(backup), Some(relay)) => {
+                tokio::spawn(run_restore(
+                    backup_repo.clone(),
+                    relay_client.clone(),
+                    store.clone(),
+                    backup,
+                    restore,
+                    relay_url(&relay),
+                ));
+            }
+            _ => {
+                let result = Err("The backup or relay no longer exists".to_string());
+                if let Err(err) = backup_repo.finish_restore(&restore.uuid, result).await {
+                    eprintln!("Failed to record restore {}: {}", restore.uuid, err);
+                }
+            }
+        }
+    }
+}
+
+pub async fn run_restore_queue(
+    pool: PgPool,
+    relay_client: Arc<dyn RelayClient>,
+    store: Arc<dyn ObjectStore>,
+) {
+    let backup_repo = BackupRepository::new(pool);
+    let mut interval = tokio::time::interval(RESTORE_TICK);
+
+    loop {
+        interval.tick().await;
+        start_ready_restores(&backup_repo, relay_client.clone(), store.clone()).await;
+    }
+}
+
+/// Deletes backups that fell out of the retention policy, object first so a
+/// failed delete is retried on the next run.
+pub async fn apply_retention(
+    backup_repo: &BackupRepository,
+    store: &dyn ObjectStore,
+    config: &BackupConfig,
+) {
+    let cutoff = Utc::now().naive_utc() - config.retention_age;
+
+    for backup in backup_repo
+        .get_expired(config.retention_count, cutoff)
+        .await
+    {
+        if let Err(err) = store.delete(&backup.object_key).await {
+            eprintln!("Failed to delete backup {}: {}", backup.uuid, err);
+            continue;
+        }
+        if let Err(err) = backup_repo.delete_backup(&backup.uuid).await {
+            eprintln!("Failed to delete backup record {}: {}", backup.uuid, err);
+        }
+    }
+}
+
+pub async fn run_backup_scheduler(
+    pool: PgPool,
+    relay_client: Arc<dyn RelayClient>,
+    store: Arc<dyn ObjectStore>,
+    config: BackupConfig,
+) {
+    let backup_repo = BackupRepository::new(pool);
+    let mut interval = tokio::time::interval(SCHEDULER_TICK);
+
+    loop {
+        interval.tick().await;
+
+        let since = Utc::now().naive_utc() - config.interval;
+        for relay in backup_repo.due_relays(since).await {
+            match backup_repo.create_backup(&relay.uuid, true).await {
+                Ok(backup) => {
+                    run_backup(
+                        backup_repo.clone(),
+                        relay_client.clone(),
+                        store.clone(),
+                        backup,
+                        relay_url(&relay),
+                    )
+                    .await
+                }
+                Err(err) => eprintln!("Failed to schedule backup of {}: {}", relay.uuid, err),
+            }
+        }
+
+        apply_retention(&backup_repo, store.as_ref(), &config).await;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_backups_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let backups = BackupRepository::new(relay_repo.pool.clone())
+        .get_backups(&relay.uuid)
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(backups))
+}
+
+async fn create_backup_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    relay_client: web::Data<dyn RelayClient>,
+    store: web::Data<dyn ObjectStore>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Manager,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) if relay.deleted_at.is_none() => relay,
+        _ => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let backup_repo = BackupRepository::new(relay_repo.pool.clone());
+    let backup = match backup_repo.create_backup(&relay.uuid, false).await {
+        Ok(backup) => backup,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    tokio::spawn(run_backup(
+        backup_repo,
+        relay_client.into_inner(),
+        store.into_inner(),
+        backup.clone(),
+        relay_url(&relay),
+    ));
+
+    HttpResponse::Accepted().json(DataResponse::new(backup))
+}
+
+/// Restores a completed backup into the relay it was taken from, or into
+/// another relay the user manages, such as a replacement for a dead one.
+/// A relay that is still being provisioned gets the restore once it's online.
+async fn restore_backup_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    relay_client: web::Data<dyn RelayClient>,
+    store: web::Data<dyn ObjectStore>,
+    path: web::Path<(String, String)>,
+    body: Option<web::Json<RestoreBackupDto>>,
+) -> impl Responder {
+    let (relay_uuid, backup_uuid) = path.into_inner();
+    let npub = auth.npub().unwrap().to_string();
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+
+    let source = relay_repo
+        .get_one_for_member(relay_uuid, npub.clone(), OrganizationRole::Manager)
+        .await;
+    let source = match source {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let backup_repo = BackupRepository::new(relay_repo.pool.clone());
+    let backup = match backup_repo.get_backup(&backup_uuid, &source.uuid).await {
+        Some(backup) if backup.status == BackupStatus::Completed => backup,
+        Some(_) => {
+            return HttpResponse::Conflict()
+                .json(ErrorResponse::new("Backup is not completed".to_string()))
+        }
+        None => {
+            return HttpResponse::NotFound()
+                .json(ErrorResponse::new("Backup not found".to_string()))
+        }
+    };
+
+    let target = match body.target_relay_uuid {
+        Some(target_uuid) => {
+            relay_repo
+                .get_one_for_member(target_uuid, npub.clone(), OrganizationRole::Manager)
+                .await
+        }
+        None => Some(source),
+    };
+    let target = match target {
+        Some(relay) if relay.deleted_at.is_none() => relay,
+        Some(_) => {
+            return HttpResponse::Conflict()
+                .json(ErrorResponse::new("Target relay is deleted".to_string()))
+        }
+        None => {
+            return HttpResponse::NotFound()
+                .json(ErrorResponse::new("Target relay not found".to_string()))
+        }
+    };
+
+    let online = target.state == RelayState::Online;
+    let restore = match backup_repo
+        .create_restore(&backup.uuid, &target.uuid, &npub, online)
+        .await
+    {
+        Ok(restore) => restore,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    if online {
+        tokio::spawn(run_restore(
+            backup_repo,
+            relay_client.into_inner(),
+            store.into_inner(),
+            backup,
+            restore.clone(),
+            relay_url(&target),
+        ));
+    }
+
+    HttpResponse::Accepted().json(DataResponse::new(restore))
+}
+
+async fn get_restores_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let restores = BackupRepository::new(relay_repo.pool.clone())
+        .get_restores(&relay.uuid)
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(restores))
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/backups")
+            .route(web::get().to(get_backups_handler))
+            .route(web::post().to(create_backup_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/backups/{backup_uuid}/restore")
+            .route(web::post().to(restore_backup_handler)),
+    )
+    .service(web::resource("/relays/{uuid}/restores").route(web::get().to(get_restores_handler)));
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::relay_client::LocalRelayClient;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use nostr::{EventBuilder, Keys};
+
+    async fn wait_for<T, F, Fut>(mut check: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Option<T>>,
+    {
+        for _ in 0..50 {
+            if let Some(value) = check().await {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Timed out waiting for the background job");
+    }
+
+    #[actix_web::test]
+    async fn test_backup_and_restore_into_new_relay() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let replacement = test_utils.create_relay(order).await;
+
+        let client = LocalRelayClient::new();
+        let keys = Keys::generate();
+        for index in 0..3 {
+            let event = EventBuilder::new_text_note(format!("note {}", index), &[])
+                .to_event(&keys)
+                .unwrap();
+            client.publish(&relay_url(&relay), &event).await.unwrap();
+        }
+
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store: Arc<dyn ObjectStore> = Arc::new(FilesystemObjectStore::new(root.clone()));
+        let relay_client: Arc<dyn RelayClient> = Arc::new(client.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::from(relay_client.clone()))
+                .app_data(web::Data::from(store.clone()))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/relays/{}/backups", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let backup: DataResponse<RelayBackup> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(backup.data.status, BackupStatus::Pending);
+        assert!(!backup.data.scheduled);
+
+        let backup_repo = BackupRepository::new(test_utils.pool.clone());
+        let backup = wait_for(|| async {
+            backup_repo
+                .get_backup(&backup.data.uuid, &relay.uuid)
+                .await
+                .filter(|backup| backup.status != BackupStatus::Pending)
+        })
+        .await;
+        assert_eq!(backup.status, BackupStatus::Completed);
+        assert_eq!(backup.event_count, Some(3));
+        let stored = store.get(&backup.object_key).await.unwrap();
+        assert_eq!(backup.size_bytes, Some(stored.len() as i64));
+        assert_eq!(parse_backup(&stored).unwrap().len(), 3);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!(
+                "/relays/{}/backups/{}/restore",
+                relay.uuid, backup.uuid
+            ))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "target_relay_uuid": replacement.uuid }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::ACCEPTED);
+        let restore: RelayRestore = actix_web::test::read_body_json::<DataResponse<_>, _>(resp)
+            .await
+            .data;
+
+        // The replacement is still provisioning, so the restore waits for it.
+        start_ready_restores(&backup_repo, relay_client.clone(), store.clone()).await;
+        let queued = backup_repo.get_restores(&replacement.uuid).await;
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].uuid, restore.uuid);
+        assert!(queued[0].started_at.is_none());
+        test_utils
+            .relay_repo
+            .update_state(&replacement.uuid, RelayState::Online)
+            .await
+            .unwrap();
+        start_ready_restores(&backup_repo, relay_client.clone(), store.clone()).await;
+
+        let restore = wait_for(|| async {
+            backup_repo
+                .get_restores(&replacement.uuid)
+                .await
+                .into_iter()
+                .find(|restore| restore.status != BackupStatus::Pending)
+        })
+        .await;
+        assert_eq!(restore.status, BackupStatus::Completed);
+        assert_eq!(restore.restored_count, 3);
+        assert_eq!(restore.failed_count, 0);
+        let restored = client
+            .fetch(&relay_url(&replacement), vec![Filter::new()])
+            .await
+            .unwrap();
+        assert_eq!(restored.len(), 3);
+
+        // Someone else can't restore into the replacement.
+        let other = test_utils.create_user().await;
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!(
+                "/relays/{}/backups/{}/restore",
+                relay.uuid, backup.uuid
+            ))
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", generate_jwt_by_hex(&other.hexpub).unwrap()),
+            ))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[actix_web::test]
+    async fn test_dump_pages_through_shared_timestamps() {
+        let client = LocalRelayClient::new();
+        let keys = Keys::generate();
+        let url = "wss://93.184.216.34";
+        let publish_at = |count: usize, created_at: u64| {
+            let client = client.clone();
+            let keys = keys.clone();
+            async move {
+                for index in 0..count {
+                    let mut unsigned = EventBuilder::new_text_note(index.to_string(), &[])
+                        .to_unsigned_event(keys.public_key());
+                    unsigned.created_at = Timestamp::from(created_at);
+                    unsigned.id = EventId::new(
+                        &unsigned.pubkey,
+                        unsigned.created_at,
+                        &unsigned.kind,
+                        &unsigned.tags,
+                        &unsigned.content,
+                    );
+                    client
+                        .publish(url, &unsigned.sign(&keys).unwrap())
+                        .await
+                        .unwrap();
+                }
+            }
+        };
+
+        // Pages straddle seconds holding hundreds of events each.
+        publish_at(300, 1_700_000_002).await;
+        publish_at(300, 1_700_000_001).await;
+        publish_at(50, 1_700_000_000).await;
+        let events = dump_events(&client, url, None).await.unwrap();
+        assert_eq!(events.len(), 650);
+        let ids: HashSet<EventId> = events.iter().map(|event| event.id).collect();
+        assert_eq!(ids.len(), 650);
+
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FilesystemObjectStore::new(root.clone());
+        let mut upload = store.start_upload("relays/dump.jsonl").await.unwrap();
+        let (count, size) = write_dump(&client, url, upload.as_mut()).await.unwrap();
+        upload.finish().await.unwrap();
+        let stored = store.get("relays/dump.jsonl").await.unwrap();
+        assert_eq!((count, size), (650, stored.len() as i64));
+        assert_eq!(parse_backup(&stored).unwrap().len(), 650);
+
+        // A second holding more than a page can't be paged through.
+        publish_at(DUMP_PAGE_SIZE + 1, 1_600_000_000).await;
+        assert!(dump_events(&client, url, None).await.is_err());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[actix_web::test]
+    async fn test_apply_retention() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FilesystemObjectStore::new(root.clone());
+        let backup_repo = BackupRepository::new(test_utils.pool.clone());
+
+        let mut backups = vec![];
+        for days_ago in [40, 3, 2, 1] {
+            let backup = backup_repo.create_backup(&relay.uuid, true).await.unwrap();
+            store
+                .put(&backup.object_key, b"{}\n".to_vec())
+                .await
+                .unwrap();
+            backup_repo
+                .finish_backup(&backup.uuid, Ok((1, 3)))
+                .await
+                .unwrap();
+            sqlx::query("UPDATE relay_backups SET created_at = $2 WHERE uuid = $1")
+                .bind(&backup.uuid)
+                .bind(Utc::now().naive_utc() - chrono::Duration::days(days_ago))
+                .execute(&test_utils.pool)
+                .await
+                .unwrap();
+            backups.push(backup);
+        }
+
+        let config = BackupConfig {
+            interval: chrono::Duration::hours(24),
+            retention_count: 2,
+            retention_age: chrono::Duration::days(30),
+        };
+        apply_retention(&backup_repo, &store, &config).await;
+
+        let kept: Vec<String> = backup_repo
+            .get_backups(&relay.uuid)
+            .await
+            .into_iter()
+            .map(|backup| backup.uuid)
+            .collect();
+        assert_eq!(kept, vec![backups[3].uuid.clone(), backups[2].uuid.clone()]);
+        assert!(store.get(&backups[0].object_key).await.is_err());
+        assert!(store.get(&backups[1].object_key).await.is_err());
+        assert!(store.get(&backups[3].object_key).await.is_ok());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -1,4 +1,6 @@
 use super::relay::RelayImplementation;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
 use rusoto_core::HttpClient;
 use rusoto_credential::{InstanceMetadataProvider, ProvideAwsCredentials};
 use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client};
@@ -21,9 +23,11 @@ pub struct LaunchCloudInstance {
     pub image_id: String,
     pub instance_type: InstanceType,
     pub implementation: RelayImplementation,
+    /// Script run by cloud-init on first boot.
+    pub user_data: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "relay_cloud_provider", rename_all = "lowercase")]
 pub enum CloudProvider {
     AWS,
@@ -55,7 +59,7 @@ impl TryFrom<&str> for CloudProvider {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "relay_instance_type", rename_all = "lowercase")]
 pub enum InstanceType {
     AwsT2Micro,
@@ -90,6 +94,23 @@ impl InstanceType {
         }
     }
 
+    pub fn cloud_provider(&self) -> CloudProvider {
+        match self {
+            InstanceType::AwsT2Micro
+            | InstanceType::AwsT2Nano
+            | InstanceType::AwsT2Small
+            | InstanceType::AwsT2Medium
+            | InstanceType::AwsT2Large => CloudProvider::AWS,
+            InstanceType::GcpN1Standard1
+            | InstanceType::GcpN1Standard2
+            | InstanceType::GcpN1Standard4 => CloudProvider::GCP,
+            InstanceType::AzureB1S
+            | InstanceType::AzureB1MS
+            | InstanceType::AzureB2S
+            | InstanceType::AzureB2MS => CloudProvider::Azure,
+        }
+    }
+
     pub fn provider_key(&self) -> String {
         match self {
             InstanceType::AwsT2Micro => "t2.micro".to_string(),
@@ -182,6 +203,34 @@ pub async fn terminate_instance(instance_id: &str) -> Result<(), String> {
     Err("Failed to terminate the instance.".to_string())
 }
 
+/// Returns the current public IP of an instance, or `None` while it has none
+/// (e.g. stopped).
+pub async fn describe_instance_ip(instance_id: &str) -> Result<Option<String>, String> {
+    dotenvy::dotenv().ok();
+    let env_provider = rusoto_credential::EnvironmentProvider::default();
+    let ec2_client = Ec2Client::new_with(HttpClient::new().unwrap(), env_provider, Region::UsEast1);
+
+    let describe_instances_req = DescribeInstancesRequest {
+        instance_ids: Some(vec![instance_id.to_string()]),
+        ..Default::default()
+    };
+
+    let result = ec2_client
+        .describe_instances(describe_instances_req)
+        .await
+        .map_err(|err| format!("Error describing instance: {:?}", err))?;
+
+    let ip_address = result
+        .reservations
+        .and_then(|reservations| reservations.into_iter().next())
+        .and_then(|reservation| reservation.instances)
+        .and_then(|instances| instances.into_iter().next())
+        .and_then(|instance| instance.public_ip_address)
+        .filter(|ip| !ip.is_empty());
+
+    Ok(ip_address)
+}
+
 fn create_instance_request(launch: LaunchCloudInstance) -> rusoto_ec2::RunInstancesRequest {
     // Create tags for the instance with a name
     let mut tags = HashMap::new();
@@ -193,6 +242,9 @@ fn create_instance_request(launch: LaunchCloudInstance) -> rusoto_ec2::RunInstan
         instance_type: Some(launch.instance_type.provider_key()),
         min_count: 1,
         max_count: 1,
+        user_data: launch
+            .user_data
+            .map(|user_data| general_purpose::STANDARD.encode(user_data)),
         tag_specifications: Some(vec![rusoto_ec2::TagSpecification {
             resource_type: Some("instance".to_string()),
             tags: Some(
@@ -240,6 +292,100 @@ async fn wait_for_instance_ready(
     }
 }
 
+// -----------------------------------------------------------------------------
+// Instance provider
+// -----------------------------------------------------------------------------
+
+/// Manages running instances, so callers can be tested without a cloud
+/// account.
+#[async_trait]
+pub trait InstanceProvider: Send + Sync {
+    /// Launches the instance and waits for its public IP.
+    async fn launch(&self, launch: LaunchCloudInstance) -> Result<CloudInstance, String>;
+
+    async fn terminate(&self, instance_id: &str) -> Result<(), String>;
+
+    /// The instance's current public IP, or `None` while it has none.
+    async fn describe_ip(&self, instance_id: &str) -> Result<Option<String>, String>;
+
+    /// Whether instances can be launched on `provider`.
+    fn supports(&self, provider: CloudProvider) -> bool;
+}
+
+pub struct Ec2InstanceProvider;
+
+#[async_trait]
+impl InstanceProvider for Ec2InstanceProvider {
+    async fn launch(&self, launch: LaunchCloudInstance) -> Result<CloudInstance, String> {
+        let provider = launch.instance_type.cloud_provider();
+        if provider != CloudProvider::AWS {
+            return Err(format!("{} instances are not supported yet", provider.as_str()));
+        }
+        if launch.image_id.is_empty() {
+            return Err(format!(
+                "No image configured for {}",
+                launch.implementation.as_str()
+            ));
+        }
+
+        launch_instance(launch).await
+    }
+
+    async fn terminate(&self, instance_id: &str) -> Result<(), String> {
+        terminate_instance(instance_id).await
+    }
+
+    async fn describe_ip(&self, instance_id: &str) -> Result<Option<String>, String> {
+        describe_instance_ip(instance_id).await
+    }
+
+    /// Only EC2 is implemented so far.
+    fn supports(&self, provider: CloudProvider) -> bool {
+        provider == CloudProvider::AWS
+    }
+}
+
+/// Records launched and terminated instances instead of calling a cloud.
+/// Launched instances get the address 127.0.0.1; `ips` overrides the address
+/// reported for an instance.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct LocalInstanceProvider {
+    pub launched: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    pub terminated: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    pub ips: std::sync::Arc<std::sync::Mutex<HashMap<String, String>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl InstanceProvider for LocalInstanceProvider {
+    async fn launch(&self, launch: LaunchCloudInstance) -> Result<CloudInstance, String> {
+        let mut launched = self.launched.lock().unwrap();
+        launched.push(launch.name);
+
+        Ok(CloudInstance {
+            id: format!("i-local-{}", launched.len()),
+            ip_address: "127.0.0.1".to_string(),
+        })
+    }
+
+    async fn terminate(&self, instance_id: &str) -> Result<(), String> {
+        self.terminated
+            .lock()
+            .unwrap()
+            .push(instance_id.to_string());
+        Ok(())
+    }
+
+    async fn describe_ip(&self, instance_id: &str) -> Result<Option<String>, String> {
+        Ok(self.ips.lock().unwrap().get(instance_id).cloned())
+    }
+
+    fn supports(&self, _provider: CloudProvider) -> bool {
+        true
+    }
+}
+
 // -----------------------------------------------------------------------------
 // Tests
 // -----------------------------------------------------------------------------
@@ -257,6 +403,7 @@ mod tests {
             image_id: dotenvy::var("STRFRY_AMI").unwrap(),
             instance_type: InstanceType::AwsT2Nano,
             implementation: RelayImplementation::Strfry,
+            user_data: None,
         };
 
         let instance = launch_instance(launch)
@@ -0,0 +1,726 @@
+use actix_web::{web, HttpResponse, Responder};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    certificate::challenge_record_name,
+    dns::{relay_domain, relay_hostname},
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayRepository},
+    util::{generate_random_string, DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct CustomDomain {
+    pub uuid: String,
+    pub relay_uuid: String,
+    pub domain: String,
+    pub method: CustomDomainMethod,
+    pub token: String,
+    pub status: CustomDomainStatus,
+    pub attempts: i32,
+    pub last_checked_at: Option<NaiveDateTime>,
+    pub verified_at: Option<NaiveDateTime>,
+    pub failed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl CustomDomain {
+    /// Name of the TXT record the user has to publish.
+    pub fn challenge_name(&self) -> String {
+        format!("_relaying-challenge.{}", self.domain)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "custom_domain_status", rename_all = "lowercase")]
+pub enum CustomDomainStatus {
+    Pending,
+    Verified,
+    Failed,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "custom_domain_method", rename_all = "lowercase")]
+pub enum CustomDomainMethod {
+    Txt,
+    Cname,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterCustomDomainDto {
+    pub domain: String,
+    pub method: Option<CustomDomainMethod>,
+}
+
+/// What the user has to publish in their zone to prove ownership.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CustomDomainChallenge {
+    pub domain: String,
+    pub method: CustomDomainMethod,
+    pub status: CustomDomainStatus,
+    pub record_type: String,
+    pub record_name: String,
+    pub record_value: String,
+    /// CNAME that lets us answer ACME DNS-01 challenges for the domain.
+    pub acme_record_name: String,
+    pub acme_record_value: String,
+    pub verified_at: Option<NaiveDateTime>,
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+impl CustomDomainChallenge {
+    pub fn new(domain: &CustomDomain, subdomain: &str) -> Self {
+        let (record_type, record_name, record_value) = match domain.method {
+            CustomDomainMethod::Txt => ("TXT", domain.challenge_name(), domain.token.clone()),
+            CustomDomainMethod::Cname => {
+                ("CNAME", domain.domain.clone(), relay_hostname(subdomain))
+            }
+        };
+
+        CustomDomainChallenge {
+            domain: domain.domain.clone(),
+            method: domain.method,
+            status: domain.status,
+            record_type: record_type.to_string(),
+            record_name,
+            record_value,
+            acme_record_name: format!("_acme-challenge.{}", domain.domain),
+            acme_record_value: challenge_record_name(&domain.domain),
+            verified_at: domain.verified_at,
+            failed_at: domain.failed_at,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Resolver
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait DomainResolver: Send + Sync {
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, String>;
+
+    async fn cname_target(&self, name: &str) -> Result<Option<String>, String>;
+}
+
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer")]
+    answer: Option<Vec<DohAnswer>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// Resolves records over DNS-over-HTTPS using the JSON API offered by
+/// Cloudflare and Google.
+pub struct DohResolver {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl DohResolver {
+    pub fn from_env() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: dotenvy::var("DNS_RESOLVER_URL")
+                .unwrap_or_else(|_| "https://cloudflare-dns.com/dns-query".to_string()),
+        }
+    }
+
+    async fn query(&self, name: &str, record_type: u16) -> Result<Vec<String>, String> {
+        let response: DohResponse = self
+            .client
+            .get(&self.url)
+            .header("Accept", "application/dns-json")
+            .query(&[("name", name), ("type", &record_type.to_string())])
+            .send()
+            .await
+            .map_err(|err| format!("DNS query for {} failed: {}", name, err))?
+            .json()
+            .await
+            .map_err(|err| format!("Invalid DNS response for {}: {}", name, err))?;
+
+        Ok(response
+            .answer
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|answer| answer.record_type == record_type)
+            .map(|answer| answer.data)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl DomainResolver for DohResolver {
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, String> {
+        let records = self.query(name, 16).await?;
+        Ok(records
+            .into_iter()
+            .map(|record| record.trim_matches('"').to_string())
+            .collect())
+    }
+
+    async fn cname_target(&self, name: &str) -> Result<Option<String>, String> {
+        let records = self.query(name, 5).await?;
+        Ok(records.into_iter().next())
+    }
+}
+
+/// Resolver backed by a fixed set of records, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct StaticResolver {
+    txt: Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<String>>>>,
+    cname: Arc<std::sync::Mutex<std::collections::HashMap<String, String>>>,
+}
+
+#[cfg(test)]
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_txt(&self, name: &str, value: &str) {
+        self.txt
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    pub fn set_cname(&self, name: &str, target: &str) {
+        self.cname
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), target.to_string());
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl DomainResolver for StaticResolver {
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, String> {
+        Ok(self
+            .txt
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn cname_target(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.cname.lock().unwrap().get(name).cloned())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct CustomDomainRepository {
+    pub pool: PgPool,
+}
+
+impl CustomDomainRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        relay_uuid: &str,
+        domain: &str,
+        method: CustomDomainMethod,
+        token: &str,
+    ) -> Result<CustomDomain, sqlx::Error> {
+        sqlx::query_as::<_, CustomDomain>(
+            "INSERT INTO custom_domains (uuid, relay_uuid, domain, method, token, status)
+            VALUES ($1, $2, $3, $4::custom_domain_method, $5, $6::custom_domain_status)
+            RETURNING *",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(relay_uuid)
+        .bind(domain)
+        .bind(method)
+        .bind(token)
+        .bind(CustomDomainStatus::Pending)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// The most recent claim for the relay.
+    pub async fn get_for_relay(&self, relay_uuid: &str) -> Option<CustomDomain> {
+        sqlx::query_as::<_, CustomDomain>(
+            "SELECT * FROM custom_domains WHERE relay_uuid = $1 ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn get_verified(&self, domain: &str) -> Option<CustomDomain> {
+        sqlx::query_as::<_, CustomDomain>(
+            "SELECT * FROM custom_domains WHERE domain = $1 AND status = 'verified'",
+        )
+        .bind(domain)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn get_pending(&self) -> Vec<CustomDomain> {
+        sqlx::query_as::<_, CustomDomain>("SELECT * FROM custom_domains WHERE status = 'pending'")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Supersedes any earlier claim for the relay so that only one is active.
+    pub async fn fail_pending_for_relay(&self, relay_uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE custom_domains SET status = 'failed', failed_at = $1, updated_at = $1
+            WHERE relay_uuid = $2 AND status = 'pending'",
+        )
+        .bind(chrono::Local::now().naive_utc())
+        .bind(relay_uuid)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gives up every claim of a terminated relay, so its domain can be
+    /// verified for another relay.
+    pub async fn release_for_relay(&self, relay_uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE custom_domains SET status = 'failed', failed_at = $1, updated_at = $1
+            WHERE relay_uuid = $2 AND status IN ('pending', 'verified')",
+        )
+        .bind(chrono::Local::now().naive_utc())
+        .bind(relay_uuid)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_check(
+        &self,
+        uuid: &str,
+        status: CustomDomainStatus,
+    ) -> Result<CustomDomain, sqlx::Error> {
+        let now = chrono::Local::now().naive_utc();
+        sqlx::query_as::<_, CustomDomain>(
+            "UPDATE custom_domains
+            SET status = $1::custom_domain_status,
+                attempts = attempts + 1,
+                last_checked_at = $2,
+                verified_at = CASE WHEN $1::custom_domain_status = 'verified' THEN $2 ELSE verified_at END,
+                failed_at = CASE WHEN $1::custom_domain_status = 'failed' THEN $2 ELSE failed_at END,
+                updated_at = $2
+            WHERE uuid = $3
+            RETURNING *",
+        )
+        .bind(status)
+        .bind(now)
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Checks that `domain` is a plausible hostname the user may claim.
+pub fn validate_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+
+    if domain.len() > 100 || !domain.contains('.') {
+        return Err("Invalid domain".to_string());
+    }
+
+    let valid_labels = domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+
+    if !valid_labels {
+        return Err("Invalid domain".to_string());
+    }
+
+    let base_domain = relay_domain();
+    if domain == base_domain || domain.ends_with(&format!(".{}", base_domain)) {
+        return Err(format!("Subdomains of {} cannot be claimed", base_domain));
+    }
+
+    Ok(domain)
+}
+
+/// The relay's custom domain, as long as it is verified for this relay.
+pub async fn verified_custom_domain(pool: &PgPool, relay: &Relay) -> Option<String> {
+    if relay.custom_domain.is_empty() {
+        return None;
+    }
+
+    CustomDomainRepository::new(pool.clone())
+        .get_verified(&relay.custom_domain)
+        .await
+        .filter(|domain| domain.relay_uuid == relay.uuid)
+        .map(|domain| domain.domain)
+}
+
+pub async fn register_custom_domain(
+    pool: &PgPool,
+    relay_uuid: &str,
+    domain: &str,
+    method: CustomDomainMethod,
+) -> Result<CustomDomain, String> {
+    let domain = validate_domain(domain)?;
+    let repo = CustomDomainRepository::new(pool.clone());
+
+    if let Some(existing) = repo.get_verified(&domain).await {
+        if existing.relay_uuid != relay_uuid {
+            return Err("Domain is already in use by another relay".to_string());
+        }
+    }
+
+    repo.fail_pending_for_relay(relay_uuid)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let token = generate_random_string(32).await;
+    repo.create(relay_uuid, &domain, method, &token)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Looks up the challenge records for a pending domain. Verified domains are
+/// routed to the relay by setting `relays.custom_domain`; claims that are not
+/// verified within `DOMAIN_VERIFICATION_TIMEOUT_HOURS` are marked failed.
+pub async fn check_custom_domain(
+    pool: &PgPool,
+    resolver: &dyn DomainResolver,
+    domain: CustomDomain,
+) -> Result<CustomDomain, String> {
+    let relay_repo = RelayRepository::new(pool.clone());
+    let relay = relay_repo
+        .get_one(&domain.relay_uuid)
+        .await
+        .ok_or("Relay not found")?;
+
+    let verified = match domain.method {
+        CustomDomainMethod::Txt => resolver
+            .txt_records(&domain.challenge_name())
+            .await?
+            .iter()
+            .any(|record| record == &domain.token),
+        CustomDomainMethod::Cname => {
+            let expected = relay_hostname(&relay.subdomain);
+            resolver
+                .cname_target(&domain.domain)
+                .await?
+                .is_some_and(|target| target.trim_end_matches('.') == expected)
+        }
+    };
+
+    let timeout_hours = dotenvy::var("DOMAIN_VERIFICATION_TIMEOUT_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(72);
+    let expired = chrono::Local::now().naive_utc()
+        > domain.created_at + chrono::Duration::hours(timeout_hours);
+
+    let status = if relay.deleted_at.is_some() {
+        CustomDomainStatus::Failed
+    } else if verified {
+        CustomDomainStatus::Verified
+    } else if expired {
+        CustomDomainStatus::Failed
+    } else {
+        CustomDomainStatus::Pending
+    };
+
+    let domain = CustomDomainRepository::new(pool.clone())
+        .record_check(&domain.uuid, status)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if domain.status == CustomDomainStatus::Verified {
+        relay_repo
+            .update_custom_domain(&relay.uuid, &domain.domain)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(domain)
+}
+
+pub async fn run_domain_verifier(pool: PgPool, resolver: Arc<dyn DomainResolver>) {
+    let seconds = dotenvy::var("DOMAIN_VERIFICATION_INTERVAL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(60);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds));
+    let repo = CustomDomainRepository::new(pool.clone());
+
+    loop {
+        interval.tick().await;
+
+        for domain in repo.get_pending().await {
+            let name = domain.domain.clone();
+            if let Err(err) = check_custom_domain(&pool, resolver.as_ref(), domain).await {
+                eprintln!("Failed to check custom domain {}: {}", name, err);
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn register_custom_domain_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+    data: web::Json<RegisterCustomDomainDto>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Manager,
+        )
+        .await;
+
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let method = data.method.unwrap_or(CustomDomainMethod::Txt);
+    match register_custom_domain(&relay_repo.pool, &relay.uuid, &data.domain, method).await {
+        Ok(domain) => HttpResponse::Created().json(DataResponse::new(CustomDomainChallenge::new(
+            &domain,
+            &relay.subdomain,
+        ))),
+        Err(err) => HttpResponse::BadRequest().json(ErrorResponse::new(err)),
+    }
+}
+
+async fn get_custom_domain_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    match CustomDomainRepository::new(relay_repo.pool.clone())
+        .get_for_relay(&relay.uuid)
+        .await
+    {
+        Some(domain) => HttpResponse::Ok().json(DataResponse::new(CustomDomainChallenge::new(
+            &domain,
+            &relay.subdomain,
+        ))),
+        None => HttpResponse::NotFound().json(ErrorResponse::new(
+            "No custom domain registered".to_string(),
+        )),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/custom_domain")
+            .route(web::get().to(get_custom_domain_handler))
+            .route(web::post().to(register_custom_domain_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_provider::LocalInstanceProvider;
+    use crate::dns::LocalDnsProvider;
+    use crate::relay::terminate_relay_service;
+    use crate::util::TestUtils;
+
+    #[test]
+    fn test_validate_domain() {
+        assert_eq!(
+            validate_domain("Relay.Example.com.").unwrap(),
+            "relay.example.com"
+        );
+        assert!(validate_domain("localhost").is_err());
+        assert!(validate_domain("-bad.example.com").is_err());
+        assert!(validate_domain("bad_label.example.com").is_err());
+        assert!(validate_domain(&format!("mine.{}", relay_domain())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_custom_domain_with_txt_and_cname() {
+        let test_utils = TestUtils::new().await;
+        let resolver = StaticResolver::new();
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+
+        let domain = register_custom_domain(
+            &test_utils.pool,
+            &relay.uuid,
+            "relay.example.com",
+            CustomDomainMethod::Txt,
+        )
+        .await
+        .unwrap();
+
+        let domain = check_custom_domain(&test_utils.pool, &resolver, domain)
+            .await
+            .unwrap();
+        assert_eq!(domain.status, CustomDomainStatus::Pending);
+        assert_eq!(domain.attempts, 1);
+
+        resolver.set_txt(&domain.challenge_name(), &domain.token);
+        let domain = check_custom_domain(&test_utils.pool, &resolver, domain)
+            .await
+            .unwrap();
+        assert_eq!(domain.status, CustomDomainStatus::Verified);
+        assert!(domain.verified_at.is_some());
+
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.custom_domain, "relay.example.com");
+
+        let other_order = test_utils.create_relay_order(&user.npub).await;
+        let other_relay = test_utils.create_relay(other_order).await;
+        assert!(register_custom_domain(
+            &test_utils.pool,
+            &other_relay.uuid,
+            "relay.example.com",
+            CustomDomainMethod::Txt,
+        )
+        .await
+        .is_err());
+
+        let domain = register_custom_domain(
+            &test_utils.pool,
+            &other_relay.uuid,
+            "other.example.com",
+            CustomDomainMethod::Cname,
+        )
+        .await
+        .unwrap();
+        resolver.set_cname(
+            "other.example.com",
+            &format!("{}.", relay_hostname(&other_relay.subdomain)),
+        );
+        let domain = check_custom_domain(&test_utils.pool, &resolver, domain)
+            .await
+            .unwrap();
+        assert_eq!(domain.status, CustomDomainStatus::Verified);
+    }
+
+    #[tokio::test]
+    async fn test_reregister_domain_after_relay_terminated() {
+        let test_utils = TestUtils::new().await;
+        let resolver = StaticResolver::new();
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+
+        let domain = register_custom_domain(
+            &test_utils.pool,
+            &relay.uuid,
+            "relay.example.com",
+            CustomDomainMethod::Txt,
+        )
+        .await
+        .unwrap();
+        resolver.set_txt(&domain.challenge_name(), &domain.token);
+        let domain = check_custom_domain(&test_utils.pool, &resolver, domain)
+            .await
+            .unwrap();
+        assert_eq!(domain.status, CustomDomainStatus::Verified);
+
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        terminate_relay_service(
+            &test_utils.pool,
+            &LocalDnsProvider::new(),
+            &LocalInstanceProvider::default(),
+            relay,
+        )
+        .await
+        .unwrap();
+
+        // The replacement relay can claim the domain again.
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let replacement = test_utils.create_relay(order).await;
+        let domain = register_custom_domain(
+            &test_utils.pool,
+            &replacement.uuid,
+            "relay.example.com",
+            CustomDomainMethod::Txt,
+        )
+        .await
+        .unwrap();
+        resolver.set_txt(&domain.challenge_name(), &domain.token);
+        let domain = check_custom_domain(&test_utils.pool, &resolver, domain)
+            .await
+            .unwrap();
+        assert_eq!(domain.status, CustomDomainStatus::Verified);
+        assert_eq!(domain.relay_uuid, replacement.uuid);
+
+        let replacement = test_utils
+            .relay_repo
+            .get_one(&replacement.uuid)
+            .await
+            .unwrap();
+        assert_eq!(
+            verified_custom_domain(&test_utils.pool, &replacement).await,
+            Some("relay.example.com".to_string())
+        );
+    }
+}
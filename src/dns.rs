@@ -0,0 +1,563 @@
+use async_trait::async_trait;
+use rusoto_core::HttpClient;
+use rusoto_route53::{
+    Change, ChangeBatch, ChangeResourceRecordSetsRequest, ListResourceRecordSetsRequest,
+    ResourceRecord, ResourceRecordSet, Route53, Route53Client,
+};
+use rusoto_signature::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::cloud_provider::InstanceProvider;
+use crate::relay::{Relay, RelayRepository, RelayState};
+
+// -----------------------------------------------------------------------------
+// Models
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DnsRecordType {
+    A,
+    Aaaa,
+    Txt,
+}
+
+impl DnsRecordType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsRecordType::A => "A",
+            DnsRecordType::Aaaa => "AAAA",
+            DnsRecordType::Txt => "TXT",
+        }
+    }
+
+    /// A records for IPv4 addresses, AAAA records for IPv6 addresses.
+    pub fn for_ip(ip: &str) -> Result<Self, String> {
+        match ip.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => Ok(DnsRecordType::A),
+            Ok(IpAddr::V6(_)) => Ok(DnsRecordType::Aaaa),
+            Err(_) => Err(format!("Invalid IP address: {}", ip)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DnsRecord {
+    pub name: String,
+    pub record_type: DnsRecordType,
+    pub value: String,
+    pub ttl: i64,
+}
+
+impl DnsRecord {
+    pub fn for_relay(relay: &Relay) -> Result<Option<Self>, String> {
+        if relay.subdomain.is_empty() || relay.instance_ip.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(DnsRecord {
+            name: relay_hostname(&relay.subdomain),
+            record_type: DnsRecordType::for_ip(&relay.instance_ip)?,
+            value: relay.instance_ip.clone(),
+            ttl: 300,
+        }))
+    }
+}
+
+pub fn relay_domain() -> String {
+    dotenvy::var("RELAY_DOMAIN").unwrap_or_else(|_| "relaying.io".to_string())
+}
+
+pub fn relay_hostname(subdomain: &str) -> String {
+    format!("{}.{}", subdomain, relay_domain())
+}
+
+// -----------------------------------------------------------------------------
+// Providers
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Creates the record or replaces the value of an existing record with the
+    /// same name and type.
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), String>;
+
+    async fn delete_record(&self, record: &DnsRecord) -> Result<(), String>;
+}
+
+/// Picks the provider from `DNS_PROVIDER` (`route53`, `cloudflare` or `local`).
+pub fn provider_from_env() -> Arc<dyn DnsProvider> {
+    match dotenvy::var("DNS_PROVIDER").unwrap_or_default().as_str() {
+        "route53" => Arc::new(Route53DnsProvider::from_env()),
+        "cloudflare" => Arc::new(CloudflareDnsProvider::from_env()),
+        _ => Arc::new(LocalDnsProvider::new()),
+    }
+}
+
+pub struct Route53DnsProvider {
+    client: Route53Client,
+    hosted_zone_id: String,
+}
+
+impl Route53DnsProvider {
+    pub fn from_env() -> Self {
+        let env_provider = rusoto_credential::EnvironmentProvider::default();
+        let client =
+            Route53Client::new_with(HttpClient::new().unwrap(), env_provider, Region::UsEast1);
+
+        Self {
+            client,
+            hosted_zone_id: dotenvy::var("ROUTE53_HOSTED_ZONE_ID").unwrap_or_default(),
+        }
+    }
+
+    fn record_value(record: &DnsRecord) -> String {
+        match record.record_type {
+            DnsRecordType::Txt => format!("\"{}\"", record.value),
+            _ => record.value.clone(),
+        }
+    }
+
+    /// The record set with the record's name and type, if there is one.
+    async fn find_record_set(
+        &self,
+        record: &DnsRecord,
+    ) -> Result<Option<ResourceRecordSet>, String> {
+        let request = ListResourceRecordSetsRequest {
+            hosted_zone_id: self.hosted_zone_id.clone(),
+            start_record_name: Some(record.name.clone()),
+            start_record_type: Some(record.record_type.as_str().to_string()),
+            max_items: Some("1".to_string()),
+            ..Default::default()
+        };
+
+        let response = self
+            .client
+            .list_resource_record_sets(request)
+            .await
+            .map_err(|err| format!("Error listing Route53 records: {:?}", err))?;
+
+        // Listing starts at the name, so the first set may be a later one.
+        Ok(response.resource_record_sets.into_iter().find(|set| {
+            set.name
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(&record.name)
+                && set.type_ == record.record_type.as_str()
+        }))
+    }
+
+    async fn change(&self, action: &str, record_set: ResourceRecordSet) -> Result<(), String> {
+        let request = ChangeResourceRecordSetsRequest {
+            hosted_zone_id: self.hosted_zone_id.clone(),
+            change_batch: ChangeBatch {
+                comment: None,
+                changes: vec![Change {
+                    action: action.to_string(),
+                    resource_record_set: record_set,
+                }],
+            },
+        };
+
+        self.client
+            .change_resource_record_sets(request)
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("Error changing Route53 record: {:?}", err))
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Route53DnsProvider {
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), String> {
+        let record_set = ResourceRecordSet {
+            name: record.name.clone(),
+            type_: record.record_type.as_str().to_string(),
+            ttl: Some(record.ttl),
+            resource_records: Some(vec![ResourceRecord {
+                value: Self::record_value(record),
+            }]),
+            ..Default::default()
+        };
+
+        self.change("UPSERT", record_set).await
+    }
+
+    /// Route53 rejects deleting a record that doesn't exist or whose value or
+    /// TTL differs, so the current set is looked up first. A missing record,
+    /// or one that now points elsewhere, is left alone.
+    async fn delete_record(&self, record: &DnsRecord) -> Result<(), String> {
+        let record_set = match self.find_record_set(record).await? {
+            Some(record_set) => record_set,
+            None => return Ok(()),
+        };
+
+        let value = Self::record_value(record);
+        let matches = record_set
+            .resource_records
+            .as_ref()
+            .is_some_and(|records| records.iter().any(|r| r.value == value));
+        if !matches {
+            return Ok(());
+        }
+
+        self.change("DELETE", record_set).await
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudflareListResponse {
+    result: Vec<CloudflareRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudflareRecord {
+    id: String,
+}
+
+pub struct CloudflareDnsProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_token: String,
+    zone_id: String,
+}
+
+impl CloudflareDnsProvider {
+    pub fn from_env() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: "https://api.cloudflare.com/client/v4".to_string(),
+            api_token: dotenvy::var("CLOUDFLARE_API_TOKEN").unwrap_or_default(),
+            zone_id: dotenvy::var("CLOUDFLARE_ZONE_ID").unwrap_or_default(),
+        }
+    }
+
+    fn records_url(&self) -> String {
+        format!("{}/zones/{}/dns_records", self.base_url, self.zone_id)
+    }
+
+    async fn find_record_id(&self, record: &DnsRecord) -> Result<Option<String>, String> {
+        let response = self
+            .client
+            .get(self.records_url())
+            .bearer_auth(&self.api_token)
+            .query(&[
+                ("type", record.record_type.as_str()),
+                ("name", record.name.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|err| format!("Error listing Cloudflare records: {}", err))?
+            .error_for_status()
+            .map_err(|err| format!("Error listing Cloudflare records: {}", err))?;
+
+        let list: CloudflareListResponse = response
+            .json()
+            .await
+            .map_err(|err| format!("Invalid Cloudflare response: {}", err))?;
+
+        Ok(list.result.into_iter().next().map(|record| record.id))
+    }
+}
+
+#[async_trait]
+impl DnsProvider for CloudflareDnsProvider {
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), String> {
+        let payload = json!({
+            "type": record.record_type.as_str(),
+            "name": record.name,
+            "content": record.value,
+            "ttl": record.ttl,
+            "proxied": false,
+        });
+
+        let request = match self.find_record_id(record).await? {
+            Some(id) => self.client.put(format!("{}/{}", self.records_url(), id)),
+            None => self.client.post(self.records_url()),
+        };
+
+        request
+            .bearer_auth(&self.api_token)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| format!("Error upserting Cloudflare record: {}", err))
+    }
+
+    async fn delete_record(&self, record: &DnsRecord) -> Result<(), String> {
+        let id = match self.find_record_id(record).await? {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        self.client
+            .delete(format!("{}/{}", self.records_url(), id))
+            .bearer_auth(&self.api_token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| format!("Error deleting Cloudflare record: {}", err))
+    }
+}
+
+/// In-memory provider for local development and tests.
+#[derive(Clone, Default)]
+pub struct LocalDnsProvider {
+    records: Arc<Mutex<HashMap<(String, DnsRecordType), DnsRecord>>>,
+}
+
+impl LocalDnsProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, name: &str, record_type: DnsRecordType) -> Option<DnsRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .get(&(name.to_string(), record_type))
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl DnsProvider for LocalDnsProvider {
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), String> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert((record.name.clone(), record.record_type), record.clone());
+        Ok(())
+    }
+
+    async fn delete_record(&self, record: &DnsRecord) -> Result<(), String> {
+        self.records
+            .lock()
+            .unwrap()
+            .remove(&(record.name.clone(), record.record_type));
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+pub async fn sync_relay_dns(dns: &dyn DnsProvider, relay: &Relay) -> Result<(), String> {
+    match DnsRecord::for_relay(relay)? {
+        Some(record) => dns.upsert_record(&record).await,
+        None => Ok(()),
+    }
+}
+
+pub async fn delete_relay_dns(dns: &dyn DnsProvider, relay: &Relay) -> Result<(), String> {
+    match DnsRecord::for_relay(relay)? {
+        Some(record) => dns.delete_record(&record).await,
+        None => Ok(()),
+    }
+}
+
+/// Points the relay's hostname at `new_ip`, removing the old record when the
+/// address family changes.
+pub async fn replace_relay_ip(
+    pool: &PgPool,
+    dns: &dyn DnsProvider,
+    relay: Relay,
+    new_ip: &str,
+) -> Result<Relay, String> {
+    if relay.instance_ip == new_ip {
+        return Ok(relay);
+    }
+
+    let old_record = DnsRecord::for_relay(&relay)?;
+    let relay = RelayRepository::new(pool.clone())
+        .update_instance_ip(&relay.uuid, new_ip)
+        .await
+        .map_err(|err| err.to_string())?;
+    let new_record = DnsRecord::for_relay(&relay)?;
+
+    if let (Some(old), Some(new)) = (&old_record, &new_record) {
+        if old.record_type != new.record_type {
+            dns.delete_record(old).await?;
+        }
+    }
+
+    sync_relay_dns(dns, &relay).await?;
+
+    Ok(relay)
+}
+
+/// Compares every live relay's stored IP against its cloud provider and
+/// updates the database and DNS for instances that came back with a new
+/// address after a stop/start. Relays on providers `instances` doesn't manage
+/// are skipped, and relays that never came online only get their address
+/// updated since the health prober publishes their record once they do.
+pub async fn reconcile_relay_ips(
+    pool: &PgPool,
+    dns: &dyn DnsProvider,
+    instances: &dyn InstanceProvider,
+) {
+    let relays = RelayRepository::new(pool.clone()).get_active().await;
+
+    for relay in relays {
+        if !instances.supports(relay.cloud_provider) {
+            continue;
+        }
+
+        let current_ip = match instances.describe_ip(&relay.instance_id).await {
+            Ok(Some(ip)) => ip,
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("Failed to describe instance {}: {}", relay.instance_id, err);
+                continue;
+            }
+        };
+
+        if relay.instance_ip == current_ip {
+            continue;
+        }
+
+        let uuid = relay.uuid.clone();
+        let result = if relay.state == RelayState::Initializing {
+            RelayRepository::new(pool.clone())
+                .update_instance_ip(&uuid, &current_ip)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        } else {
+            replace_relay_ip(pool, dns, relay, &current_ip)
+                .await
+                .map(|_| ())
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to update DNS for relay {}: {}", uuid, err);
+        }
+    }
+}
+
+pub async fn run_dns_reconciler(
+    pool: PgPool,
+    dns: Arc<dyn DnsProvider>,
+    instances: Arc<dyn InstanceProvider>,
+) {
+    let seconds = dotenvy::var("DNS_RECONCILE_INTERVAL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(300);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds));
+
+    loop {
+        interval.tick().await;
+        reconcile_relay_ips(&pool, dns.as_ref(), instances.as_ref()).await;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_provider::LocalInstanceProvider;
+    use crate::util::TestUtils;
+
+    #[test]
+    fn test_record_type_for_ip() {
+        assert_eq!(DnsRecordType::for_ip("10.0.0.1").unwrap(), DnsRecordType::A);
+        assert_eq!(
+            DnsRecordType::for_ip("2001:db8::1").unwrap(),
+            DnsRecordType::Aaaa
+        );
+        assert!(DnsRecordType::for_ip("not-an-ip").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_replace_and_delete_relay_dns() {
+        let test_utils = TestUtils::new().await;
+        let dns = LocalDnsProvider::new();
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "10.0.0.1")
+            .await
+            .unwrap();
+        let hostname = relay_hostname(&relay.subdomain);
+
+        sync_relay_dns(&dns, &relay).await.unwrap();
+        assert_eq!(
+            dns.get(&hostname, DnsRecordType::A).unwrap().value,
+            "10.0.0.1"
+        );
+
+        let relay = replace_relay_ip(&test_utils.pool, &dns, relay, "2001:db8::1")
+            .await
+            .unwrap();
+        assert_eq!(relay.instance_ip, "2001:db8::1");
+        assert!(dns.get(&hostname, DnsRecordType::A).is_none());
+        assert_eq!(
+            dns.get(&hostname, DnsRecordType::Aaaa).unwrap().value,
+            "2001:db8::1"
+        );
+
+        delete_relay_dns(&dns, &relay).await.unwrap();
+        assert!(dns.get(&hostname, DnsRecordType::Aaaa).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_relay_ips_through_instance_provider() {
+        let test_utils = TestUtils::new().await;
+        let dns = LocalDnsProvider::new();
+        let instances = LocalInstanceProvider::default();
+        let user = test_utils.create_user().await;
+        let booting = test_utils
+            .create_relay(test_utils.create_relay_order(&user.npub).await)
+            .await;
+        let online = test_utils
+            .create_relay(test_utils.create_relay_order(&user.npub).await)
+            .await;
+        test_utils
+            .relay_repo
+            .update_state(&online.uuid, RelayState::Online)
+            .await
+            .unwrap();
+        for relay in [&booting, &online] {
+            test_utils
+                .relay_repo
+                .update_instance_ip(&relay.uuid, "10.0.0.1")
+                .await
+                .unwrap();
+            instances
+                .ips
+                .lock()
+                .unwrap()
+                .insert(relay.instance_id.clone(), "10.0.0.2".to_string());
+        }
+
+        reconcile_relay_ips(&test_utils.pool, &dns, &instances).await;
+
+        // Both addresses are recorded, but only the online relay is published.
+        for relay in [&booting, &online] {
+            let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+            assert_eq!(relay.instance_ip, "10.0.0.2");
+        }
+        assert!(dns
+            .get(&relay_hostname(&booting.subdomain), DnsRecordType::A)
+            .is_none());
+        assert_eq!(
+            dns.get(&relay_hostname(&online.subdomain), DnsRecordType::A)
+                .unwrap()
+                .value,
+            "10.0.0.2"
+        );
+    }
+}
@@ -0,0 +1,1118 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use nostr::prelude::FromBech32;
+use nostr::{Event, EventId, Filter, Kind, Timestamp};
+use secp256k1::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use validator::Validate;
+
+use crate::{
+    backup::restore_events,
+    health::relay_url,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    outbound::resolve_public,
+    relay::{Relay, RelayRepository},
+    relay_client::RelayClient,
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Events requested per REQ from a source relay.
+const IMPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "event_import_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Running,
+    Completed,
+    /// At least one source failed; the import can be resumed.
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct EventImport {
+    pub uuid: String,
+    /// Relay the events are imported into.
+    pub relay_uuid: String,
+    pub npub: String,
+    pub status: ImportStatus,
+    /// Hex pubkeys whose events are imported.
+    pub authors: Vec<String>,
+    /// Empty for every kind.
+    pub kinds: Vec<i64>,
+    /// Unix timestamp of the oldest events to import.
+    pub since: Option<i64>,
+    pub fetched_count: i64,
+    pub imported_count: i64,
+    /// Events already imported, from another source or before a resume.
+    pub duplicate_count: i64,
+    /// Events with a bad signature or outside the filters.
+    pub invalid_count: i64,
+    /// Events the relay refused, e.g. because of its whitelist.
+    pub rejected_count: i64,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct EventImportSource {
+    pub import_uuid: String,
+    pub url: String,
+    /// `until` of the next page. Sources are read newest first, so everything
+    /// after the cursor has been imported.
+    pub cursor: Option<i64>,
+    pub fetched_count: i64,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventImportResponse {
+    #[serde(flatten)]
+    pub import: EventImport,
+    pub sources: Vec<EventImportSource>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateImportDto {
+    #[validate(length(min = 1, max = 20), custom = "validate_source_urls")]
+    pub source_urls: Vec<String>,
+    /// Hex pubkeys or npubs. Defaults to the relay's write whitelist, or its
+    /// owner if the whitelist names nobody.
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<u16>,
+    #[validate(range(min = 0))]
+    pub since: Option<i64>,
+}
+
+fn validate_source_urls(urls: &[String]) -> Result<(), validator::ValidationError> {
+    let valid = urls.iter().all(|url| {
+        url::Url::parse(url)
+            .is_ok_and(|url| matches!(url.scheme(), "ws" | "wss") && url.host_str().is_some())
+    });
+
+    match valid {
+        true => Ok(()),
+        false => Err(validator::ValidationError::new(
+            "source urls must be ws:// or wss://",
+        )),
+    }
+}
+
+/// The source's addresses, failing unless they are all public so imports
+/// can't be pointed at internal services. Sources are fetched from these
+/// addresses only.
+async fn resolve_source(url: &str) -> Result<Vec<SocketAddr>, String> {
+    let parsed = url::Url::parse(url).map_err(|err| err.to_string())?;
+    resolve_public(&parsed).await
+}
+
+/// Hex form of a pubkey given as hex or npub.
+fn parse_pubkey(pubkey: &str) -> Option<String> {
+    XOnlyPublicKey::from_bech32(pubkey)
+        .or_else(|_| XOnlyPublicKey::from_str(pubkey))
+        .ok()
+        .map(|pubkey| pubkey.to_string())
+}
+
+/// Pubkeys named by a write whitelist, which is either a list of keys or an
+/// object with a `pubkeys` list. Entries that aren't keys are skipped.
+pub fn whitelist_pubkeys(whitelist: &Value) -> Vec<String> {
+    let entries = match whitelist {
+        Value::Array(entries) => entries,
+        Value::Object(object) => match object.get("pubkeys") {
+            Some(Value::Array(entries)) => entries,
+            _ => return vec![],
+        },
+        _ => return vec![],
+    };
+
+    entries
+        .iter()
+        .filter_map(Value::as_str)
+        .filter_map(parse_pubkey)
+        .collect()
+}
+
+/// The authors to import, falling back to the whitelist and then the owner.
+pub fn resolve_authors(authors: &[String], relay: &Relay) -> Result<Vec<String>, String> {
+    let mut resolved = if authors.is_empty() {
+        whitelist_pubkeys(&relay.write_whitelist)
+    } else {
+        authors
+            .iter()
+            .map(|author| parse_pubkey(author).ok_or(format!("Invalid author: {}", author)))
+            .collect::<Result<_, _>>()?
+    };
+    if resolved.is_empty() {
+        resolved.extend(parse_pubkey(&relay.user_npub));
+    }
+    resolved.sort();
+    resolved.dedup();
+
+    match resolved.is_empty() {
+        true => Err("No authors to import".to_string()),
+        false => Ok(resolved),
+    }
+}
+
+impl EventImport {
+    pub fn filter(&self) -> Filter {
+        let mut filter = Filter::new()
+            .authors(self.authors.clone())
+            .limit(IMPORT_PAGE_SIZE);
+        if !self.kinds.is_empty() {
+            filter = filter.kinds(
+                self.kinds
+                    .iter()
+                    .map(|kind| Kind::from(*kind as u64))
+                    .collect(),
+            );
+        }
+        if let Some(since) = self.since {
+            filter = filter.since(Timestamp::from(since as u64));
+        }
+        filter
+    }
+
+    /// Source relays aren't trusted to apply the filter.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.authors.contains(&event.pubkey.to_string())
+            && (self.kinds.is_empty() || self.kinds.contains(&(event.kind.as_u64() as i64)))
+            && self
+                .since
+                .is_none_or(|since| event.created_at.as_i64() >= since)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+/// What importing one page of a source did.
+#[derive(Debug, Default)]
+pub struct ImportPage {
+    pub cursor: Option<i64>,
+    pub done: bool,
+    pub fetched: i64,
+    pub imported: i64,
+    pub duplicates: i64,
+    pub invalid: i64,
+    pub rejected: i64,
+    /// IDs published to the relay, accepted or not.
+    pub published: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct EventImportRepository {
+    pub pool: PgPool,
+}
+
+impl EventImportRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        relay_uuid: &str,
+        npub: &str,
+        authors: &[String],
+        dto: &CreateImportDto,
+    ) -> Result<EventImport, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let kinds: Vec<i64> = dto.kinds.iter().map(|kind| *kind as i64).collect();
+
+        let import = sqlx::query_as::<_, EventImport>(
+            "INSERT INTO event_imports (uuid, relay_uuid, npub, status, authors, kinds, since)
+            VALUES ($1, $2, $3, 'running', $4, $5, $6)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(relay_uuid)
+        .bind(npub)
+        .bind(authors)
+        .bind(kinds)
+        .bind(dto.since)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO event_import_sources (import_uuid, url)
+            SELECT $1, url FROM UNNEST($2::TEXT[]) AS url
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(&import.uuid)
+        .bind(&dto.source_urls)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(import)
+    }
+
+    pub async fn get_one(&self, uuid: &str, relay_uuid: &str) -> Option<EventImport> {
+        sqlx::query_as::<_, EventImport>(
+            "SELECT * FROM event_imports WHERE uuid = $1 AND relay_uuid = $2",
+        )
+        .bind(uuid)
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    /// The relay's imports, newest first.
+    pub async fn get_for_relay(&self, relay_uuid: &str) -> Vec<EventImport> {
+        sqlx::query_as::<_, EventImport>(
+            "SELECT * FROM event_imports WHERE relay_uuid = $1 ORDER BY created_at DESC",
+        )
+        .bind(relay_uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn get_running(&self) -> Vec<EventImport> {
+        sqlx::query_as::<_, EventImport>("SELECT * FROM event_imports WHERE status = 'running'")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn get_running_for_relay(&self, relay_uuid: &str) -> Option<EventImport> {
+        sqlx::query_as::<_, EventImport>(
+            "SELECT * FROM event_imports WHERE relay_uuid = $1 AND status = 'running'",
+        )
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn get_sources(&self, import_uuid: &str) -> Vec<EventImportSource> {
+        sqlx::query_as::<_, EventImportSource>(
+            "SELECT * FROM event_import_sources WHERE import_uuid = $1 ORDER BY url",
+        )
+        .bind(import_uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// The IDs among `ids` that were already published by the import.
+    pub async fn get_seen(
+        &self,
+        import_uuid: &str,
+        ids: &[String],
+    ) -> Result<HashSet<String>, sqlx::Error> {
+        let seen: Vec<(String,)> = sqlx::query_as(
+            "SELECT event_id FROM event_import_seen WHERE import_uuid = $1 AND event_id = ANY($2)",
+        )
+        .bind(import_uuid)
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(seen.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Moves the source's cursor and adds up the page, in one transaction so
+    /// a resumed import neither skips nor double counts the page.
+    pub async fn record_page(
+        &self,
+        import_uuid: &str,
+        url: &str,
+        page: &ImportPage,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO event_import_seen (import_uuid, event_id)
+            SELECT $1, event_id FROM UNNEST($2::VARCHAR[]) AS event_id
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(import_uuid)
+        .bind(&page.published)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "UPDATE event_import_sources
+            SET cursor = $3, done = $4, fetched_count = fetched_count + $5, error = NULL
+            WHERE import_uuid = $1 AND url = $2",
+        )
+        .bind(import_uuid)
+        .bind(url)
+        .bind(page.cursor)
+        .bind(page.done)
+        .bind(page.fetched)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "UPDATE event_imports
+            SET fetched_count = fetched_count + $2, imported_count = imported_count + $3,
+                duplicate_count = duplicate_count + $4, invalid_count = invalid_count + $5,
+                rejected_count = rejected_count + $6, updated_at = $7
+            WHERE uuid = $1",
+        )
+        .bind(import_uuid)
+        .bind(page.fetched)
+        .bind(page.imported)
+        .bind(page.duplicates)
+        .bind(page.invalid)
+        .bind(page.rejected)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn fail_source(
+        &self,
+        import_uuid: &str,
+        url: &str,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE event_import_sources SET error = $3 WHERE import_uuid = $1 AND url = $2",
+        )
+        .bind(import_uuid)
+        .bind(url)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sets a failed import running again. Returns it, or `None` if it wasn't
+    /// failed.
+    pub async fn restart(&self, uuid: &str) -> Result<Option<EventImport>, sqlx::Error> {
+        sqlx::query_as::<_, EventImport>(
+            "UPDATE event_imports
+            SET status = 'running', error = NULL, updated_at = $2, completed_at = NULL
+            WHERE uuid = $1 AND status = 'failed'
+            RETURNING *",
+        )
+        .bind(uuid)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// The seen IDs of a completed import are dropped; they only matter for
+    /// resuming.
+    pub async fn finish(&self, uuid: &str, result: Result<(), String>) -> Result<(), sqlx::Error> {
+        let (status, error) = match result {
+            Ok(()) => (ImportStatus::Completed, None),
+            Err(err) => (ImportStatus::Failed, Some(err)),
+        };
+        let now = Utc::now().naive_utc();
+
+        sqlx::query(
+            "UPDATE event_imports
+            SET status = $2, error = $3, updated_at = $4, completed_at = $4
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(status)
+        .bind(error)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        if status == ImportStatus::Completed {
+            sqlx::query("DELETE FROM event_import_seen WHERE import_uuid = $1")
+                .bind(uuid)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Pages backwards through one source by `until`, publishing what is new into
+/// the relay and recording every page. `until` is inclusive, so the events of
+/// the previous page's oldest second come back and are skipped; a source is
+/// done once a page brings nothing else.
+pub async fn import_source(
+    repo: &EventImportRepository,
+    relay_client: &dyn RelayClient,
+    import: &EventImport,
+    mut source: EventImportSource,
+    url: &str,
+) -> Result<(), String> {
+    let addrs = resolve_source(&source.url).await?;
+    let mut overlap: HashSet<EventId> = HashSet::new();
+
+    while !source.done {
+        let mut filter = import.filter();
+        if let Some(cursor) = source.cursor {
+            filter = filter.until(Timestamp::from(cursor as u64));
+        }
+        let events = relay_client
+            .fetch_from(&source.url, &addrs, vec![filter])
+            .await?;
+
+        let mut ids = HashSet::new();
+        let events: Vec<Event> = events
+            .into_iter()
+            .filter(|event| !overlap.contains(&event.id) && ids.insert(event.id))
+            .collect();
+        let oldest = events.iter().map(|event| event.created_at.as_i64()).min();
+        let mut page = ImportPage {
+            cursor: oldest.or(source.cursor),
+            done: events.is_empty(),
+            fetched: events.len() as i64,
+            ..Default::default()
+        };
+
+        if let Some(oldest) = oldest {
+            if source.cursor != Some(oldest) {
+                overlap.clear();
+            }
+            overlap.extend(
+                events
+                    .iter()
+                    .filter(|event| event.created_at.as_i64() == oldest)
+                    .map(|event| event.id),
+            );
+        }
+
+        let (candidates, invalid): (Vec<Event>, Vec<Event>) = events
+            .into_iter()
+            .partition(|event| event.verify().is_ok() && import.matches(event));
+        page.invalid = invalid.len() as i64;
+
+        let candidate_ids: Vec<String> = candidates.iter().map(|event| event.id.to_hex()).collect();
+        let seen = repo
+            .get_seen(&import.uuid, &candidate_ids)
+            .await
+            .map_err(|err| err.to_string())?;
+        let fresh: Vec<Event> = candidates
+            .into_iter()
+            .filter(|event| !seen.contains(&event.id.to_hex()))
+            .collect();
+        page.duplicates = candidate_ids.len() as i64 - fresh.len() as i64;
+        page.published = fresh.iter().map(|event| event.id.to_hex()).collect();
+
+        if !fresh.is_empty() {
+            let (imported, rejected) = restore_events(relay_client, url, fresh).await;
+            // Nothing accepted means the relay is either down, which leaves
+            // the page to a resume, or refusing these events, e.g. through
+            // its whitelist, which counts as rejections like any other.
+            if imported == 0 {
+                relay_client
+                    .fetch(url, vec![Filter::new().limit(1)])
+                    .await
+                    .map_err(|err| format!("{} is unreachable: {}", url, err))?;
+            }
+            page.imported = imported;
+            page.rejected = rejected;
+        }
+
+        repo.record_page(&import.uuid, &source.url, &page)
+            .await
+            .map_err(|err| err.to_string())?;
+        source.cursor = page.cursor;
+        source.done = page.done;
+    }
+
+    Ok(())
+}
+
+/// Imports from every source that isn't done yet. A failing source doesn't
+/// stop the others, but fails the import so it can be resumed.
+pub async fn run_import(
+    repo: EventImportRepository,
+    relay_client: Arc<dyn RelayClient>,
+    import: EventImport,
+    url: String,
+) {
+    let mut errors = vec![];
+    for source in repo.get_sources(&import.uuid).await {
+        if source.done {
+            continue;
+        }
+        let source_url = source.url.clone();
+        if let Err(err) = import_source(&repo, relay_client.as_ref(), &import, source, &url).await {
+            eprintln!("Import {} from {} failed: {}", import.uuid, source_url, err);
+            if let Err(err) = repo.fail_source(&import.uuid, &source_url, &err).await {
+                eprintln!("Failed to record import source {}: {}", source_url, err);
+            }
+            errors.push(format!("{}: {}", source_url, err));
+        }
+    }
+
+    let result = match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("; ")),
+    };
+    if let Err(err) = repo.finish(&import.uuid, result).await {
+        eprintln!("Failed to record import {}: {}", import.uuid, err);
+    }
+}
+
+/// Picks up imports that were running when the server stopped.
+pub async fn resume_running_imports(pool: PgPool, relay_client: Arc<dyn RelayClient>) {
+    let repo = EventImportRepository::new(pool.clone());
+    let relay_repo = RelayRepository::new(pool);
+
+    for import in repo.get_running().await {
+        match relay_repo.get_one(&import.relay_uuid).await {
+            Some(relay) if relay.deleted_at.is_none() => {
+                tokio::spawn(run_import(
+                    repo.clone(),
+                    relay_client.clone(),
+                    import,
+                    relay_url(&relay),
+                ));
+            }
+            _ => {
+                let _ = repo
+                    .finish(&import.uuid, Err("Relay not found".to_string()))
+                    .await;
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_imports_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let imports = EventImportRepository::new(relay_repo.pool.clone())
+        .get_for_relay(&relay.uuid)
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(imports))
+}
+
+async fn create_import_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    relay_client: web::Data<dyn RelayClient>,
+    path: web::Path<String>,
+    body: web::Json<CreateImportDto>,
+) -> impl Responder {
+    if let Err(err) = body.validate() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(err.to_string()));
+    }
+    for url in &body.source_urls {
+        if let Err(err) = resolve_source(url).await {
+            return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+                "Invalid source {}: {}",
+                url, err
+            )));
+        }
+    }
+    let npub = auth.npub().unwrap().to_string();
+
+    let relay = relay_repo
+        .get_one_for_member(path.into_inner(), npub.clone(), OrganizationRole::Manager)
+        .await;
+    let relay = match relay {
+        Some(relay) if relay.deleted_at.is_none() => relay,
+        _ => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let authors = match resolve_authors(&body.authors, &relay) {
+        Ok(authors) => authors,
+        Err(err) => return HttpResponse::BadRequest().json(ErrorResponse::new(err)),
+    };
+
+    let repo = EventImportRepository::new(relay_repo.pool.clone());
+    if repo.get_running_for_relay(&relay.uuid).await.is_some() {
+        return HttpResponse::Conflict().json(ErrorResponse::new(
+            "An import into the relay is already running".to_string(),
+        ));
+    }
+
+    let import = match repo.create(&relay.uuid, &npub, &authors, &body).await {
+        Ok(import) => import,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    let sources = repo.get_sources(&import.uuid).await;
+    tokio::spawn(run_import(
+        repo,
+        relay_client.into_inner(),
+        import.clone(),
+        relay_url(&relay),
+    ));
+
+    HttpResponse::Accepted().json(DataResponse::new(EventImportResponse { import, sources }))
+}
+
+async fn get_import_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (relay_uuid, import_uuid) = path.into_inner();
+    let relay = relay_repo
+        .get_one_for_member(
+            relay_uuid,
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let repo = EventImportRepository::new(relay_repo.pool.clone());
+    match repo.get_one(&import_uuid, &relay.uuid).await {
+        Some(import) => {
+            let sources = repo.get_sources(&import.uuid).await;
+            HttpResponse::Ok().json(DataResponse::new(EventImportResponse { import, sources }))
+        }
+        None => HttpResponse::NotFound().json(ErrorResponse::new("Import not found".to_string())),
+    }
+}
+
+/// Continues a failed import from the cursors of its unfinished sources.
+async fn resume_import_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    relay_client: web::Data<dyn RelayClient>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (relay_uuid, import_uuid) = path.into_inner();
+    let relay = relay_repo
+        .get_one_for_member(
+            relay_uuid,
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Manager,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) if relay.deleted_at.is_none() => relay,
+        _ => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let repo = EventImportRepository::new(relay_repo.pool.clone());
+    if repo.get_one(&import_uuid, &relay.uuid).await.is_none() {
+        return HttpResponse::NotFound().json(ErrorResponse::new("Import not found".to_string()));
+    }
+    if repo.get_running_for_relay(&relay.uuid).await.is_some() {
+        return HttpResponse::Conflict().json(ErrorResponse::new(
+            "An import into the relay is already running".to_string(),
+        ));
+    }
+
+    let import = match repo.restart(&import_uuid).await {
+        Ok(Some(import)) => import,
+        Ok(None) => {
+            return HttpResponse::Conflict()
+                .json(ErrorResponse::new("Import has not failed".to_string()))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    let sources = repo.get_sources(&import.uuid).await;
+    tokio::spawn(run_import(
+        repo,
+        relay_client.into_inner(),
+        import.clone(),
+        relay_url(&relay),
+    ));
+
+    HttpResponse::Accepted().json(DataResponse::new(EventImportResponse { import, sources }))
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/imports")
+            .route(web::get().to(get_imports_handler))
+            .route(web::post().to(create_import_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/imports/{import_uuid}")
+            .route(web::get().to(get_import_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/imports/{import_uuid}/resume")
+            .route(web::post().to(resume_import_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::relay_client::LocalRelayClient;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use async_trait::async_trait;
+    use nostr::prelude::ToBech32;
+    use nostr::{EventBuilder, Keys};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    // Public addresses, so they pass the source check without DNS.
+    const SOURCE_A: &str = "wss://93.184.216.34";
+    const SOURCE_B: &str = "wss://93.184.216.35";
+
+    /// A `LocalRelayClient` whose relays can be taken down or made to reject
+    /// every event.
+    #[derive(Clone)]
+    struct FlakyRelayClient {
+        inner: LocalRelayClient,
+        down: Arc<Mutex<HashSet<String>>>,
+        rejecting: Arc<Mutex<HashSet<String>>>,
+        /// Addresses each source was fetched from.
+        pinned: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+    }
+
+    impl Default for FlakyRelayClient {
+        fn default() -> Self {
+            Self {
+                inner: LocalRelayClient::new(),
+                down: Default::default(),
+                rejecting: Default::default(),
+                pinned: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RelayClient for FlakyRelayClient {
+        async fn publish(&self, relay_url: &str, event: &Event) -> Result<(), String> {
+            if self.down.lock().unwrap().contains(relay_url) {
+                return Err("Connection refused".to_string());
+            }
+            if self.rejecting.lock().unwrap().contains(relay_url) {
+                return Err("blocked: not on the whitelist".to_string());
+            }
+            self.inner.publish(relay_url, event).await
+        }
+
+        async fn fetch(&self, relay_url: &str, filters: Vec<Filter>) -> Result<Vec<Event>, String> {
+            if self.down.lock().unwrap().contains(relay_url) {
+                return Err("Connection refused".to_string());
+            }
+            self.inner.fetch(relay_url, filters).await
+        }
+
+        async fn fetch_from(
+            &self,
+            relay_url: &str,
+            addrs: &[SocketAddr],
+            filters: Vec<Filter>,
+        ) -> Result<Vec<Event>, String> {
+            self.pinned
+                .lock()
+                .unwrap()
+                .insert(relay_url.to_string(), addrs.to_vec());
+            self.fetch(relay_url, filters).await
+        }
+
+        async fn subscribe(
+            &self,
+            relay_url: &str,
+            filters: Vec<Filter>,
+        ) -> Result<mpsc::Receiver<Event>, String> {
+            self.inner.subscribe(relay_url, filters).await
+        }
+    }
+
+    async fn wait_for_import(repo: &EventImportRepository, uuid: &str) -> EventImport {
+        for _ in 0..50 {
+            let import =
+                sqlx::query_as::<_, EventImport>("SELECT * FROM event_imports WHERE uuid = $1")
+                    .bind(uuid)
+                    .fetch_one(&repo.pool)
+                    .await
+                    .unwrap();
+            if import.status != ImportStatus::Running {
+                return import;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Timed out waiting for the import");
+    }
+
+    #[test]
+    fn test_whitelist_pubkeys() {
+        let keys = Keys::generate();
+        let hex = keys.public_key().to_string();
+        let npub = keys.public_key().to_bech32().unwrap();
+
+        assert_eq!(
+            whitelist_pubkeys(&serde_json::json!([npub, "not a key", 7])),
+            vec![hex.clone()]
+        );
+        assert_eq!(
+            whitelist_pubkeys(&serde_json::json!({ "pubkeys": [hex] })),
+            vec![hex]
+        );
+        assert!(whitelist_pubkeys(&serde_json::json!({ "key": "value" })).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_import_and_resume() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+
+        let author = Keys::generate();
+        let stranger = Keys::generate();
+        let client = FlakyRelayClient::default();
+        let mut notes = vec![];
+        for index in 0..3 {
+            let note = EventBuilder::new_text_note(format!("note {}", index), &[])
+                .to_event(&author)
+                .unwrap();
+            client.publish(SOURCE_A, &note).await.unwrap();
+            notes.push(note);
+        }
+        // Source B has two of the same notes, a new one and another kind.
+        client.publish(SOURCE_B, &notes[0]).await.unwrap();
+        client.publish(SOURCE_B, &notes[1]).await.unwrap();
+        let only_on_b = EventBuilder::new_text_note("note 3", &[])
+            .to_event(&author)
+            .unwrap();
+        client.publish(SOURCE_B, &only_on_b).await.unwrap();
+        let reaction = EventBuilder::new_reaction(notes[0].id, author.public_key(), "+")
+            .to_event(&author)
+            .unwrap();
+        client.publish(SOURCE_A, &reaction).await.unwrap();
+        let other = EventBuilder::new_text_note("not mine", &[])
+            .to_event(&stranger)
+            .unwrap();
+        client.publish(SOURCE_A, &other).await.unwrap();
+        client.down.lock().unwrap().insert(SOURCE_B.to_string());
+
+        let relay_client: Arc<dyn RelayClient> = Arc::new(client.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::from(relay_client))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/relays/{}/imports", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "source_urls": ["https://93.184.216.34"],
+                "authors": [author.public_key().to_bech32().unwrap()],
+            }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        for source in [
+            "ws://127.0.0.1:7777",
+            "wss://169.254.169.254",
+            "ws://localhost",
+        ] {
+            let req = actix_web::test::TestRequest::post()
+                .uri(&format!("/relays/{}/imports", relay.uuid))
+                .insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .set_json(serde_json::json!({
+                    "source_urls": [source],
+                    "authors": [author.public_key().to_bech32().unwrap()],
+                }))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        }
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/relays/{}/imports", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "source_urls": [SOURCE_A, SOURCE_B],
+                "authors": [author.public_key().to_bech32().unwrap()],
+                "kinds": [1],
+            }))
+            .to_request();
+        let import: DataResponse<EventImportResponse> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(import.data.import.status, ImportStatus::Running);
+        assert_eq!(import.data.sources.len(), 2);
+
+        let repo = EventImportRepository::new(test_utils.pool.clone());
+        let import = wait_for_import(&repo, &import.data.import.uuid).await;
+        assert_eq!(import.status, ImportStatus::Failed);
+        assert!(import.error.unwrap().contains(SOURCE_B));
+        assert_eq!(import.imported_count, 3);
+        // Fetched from the address that passed the check, not a fresh lookup.
+        assert_eq!(
+            client.pinned.lock().unwrap()[SOURCE_A],
+            vec!["93.184.216.34:443".parse::<SocketAddr>().unwrap()]
+        );
+        let sources = repo.get_sources(&import.uuid).await;
+        assert!(sources[0].done);
+        assert!(!sources[1].done && sources[1].error.is_some());
+
+        client.down.lock().unwrap().clear();
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!(
+                "/relays/{}/imports/{}/resume",
+                relay.uuid, import.uuid
+            ))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::ACCEPTED);
+
+        let import = wait_for_import(&repo, &import.uuid).await;
+        assert_eq!(import.status, ImportStatus::Completed);
+        assert_eq!(import.fetched_count, 6);
+        assert_eq!(import.imported_count, 4);
+        assert_eq!(import.duplicate_count, 2);
+        assert_eq!(import.rejected_count, 0);
+
+        let imported = client
+            .fetch(&relay_url(&relay), vec![Filter::new()])
+            .await
+            .unwrap();
+        assert_eq!(imported.len(), 4);
+        assert!(imported
+            .iter()
+            .all(|event| event.pubkey == author.public_key()));
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/relays/{}/imports/{}", relay.uuid, import.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let progress: DataResponse<EventImportResponse> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert!(progress.data.sources.iter().all(|source| source.done));
+        assert_eq!(progress.data.sources[1].fetched_count, 3);
+
+        // A completed import can't be resumed.
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!(
+                "/relays/{}/imports/{}/resume",
+                relay.uuid, import.uuid
+            ))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_import_into_rejecting_or_down_relay() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let url = relay_url(&relay);
+
+        let author = Keys::generate();
+        let client = FlakyRelayClient::default();
+        for index in 0..2 {
+            let note = EventBuilder::new_text_note(format!("note {}", index), &[])
+                .to_event(&author)
+                .unwrap();
+            client.publish(SOURCE_A, &note).await.unwrap();
+        }
+
+        let repo = EventImportRepository::new(test_utils.pool.clone());
+        let dto = CreateImportDto {
+            source_urls: vec![SOURCE_A.to_string()],
+            authors: vec![],
+            kinds: vec![],
+            since: None,
+        };
+        let authors = vec![author.public_key().to_string()];
+
+        // A relay that refuses every event completes the import.
+        client.rejecting.lock().unwrap().insert(url.clone());
+        let import = repo
+            .create(&relay.uuid, &user.npub, &authors, &dto)
+            .await
+            .unwrap();
+        run_import(
+            repo.clone(),
+            Arc::new(client.clone()),
+            import.clone(),
+            url.clone(),
+        )
+        .await;
+        let import = wait_for_import(&repo, &import.uuid).await;
+        assert_eq!(import.status, ImportStatus::Completed);
+        assert_eq!(import.imported_count, 0);
+        assert_eq!(import.rejected_count, 2);
+
+        // A relay that is down fails it, so it can be resumed.
+        client.rejecting.lock().unwrap().clear();
+        client.down.lock().unwrap().insert(url.clone());
+        let import = repo
+            .create(&relay.uuid, &user.npub, &authors, &dto)
+            .await
+            .unwrap();
+        run_import(repo.clone(), Arc::new(client.clone()), import.clone(), url).await;
+        let import = wait_for_import(&repo, &import.uuid).await;
+        assert_eq!(import.status, ImportStatus::Failed);
+        assert!(import.error.unwrap().contains("unreachable"));
+    }
+}
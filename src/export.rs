@@ -0,0 +1,737 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    api_key::ApiKey,
+    audit::{audit, AuditAction, AuditEvent, AuditRepository},
+    backup::ObjectStore,
+    middleware::AuthorizationService,
+    profile::{Profile, ProfileRepository},
+    relay::Relay,
+    relay_order::RelayOrder,
+    user::{User, UserRepository},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Default)]
+#[sqlx(type_name = "export_format", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    /// One JSON file per section.
+    Zip,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Zip => "zip",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "export_job_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ExportJobStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportJob {
+    pub uuid: String,
+    pub npub: String,
+    pub format: ExportFormat,
+    pub status: ExportJobStatus,
+    /// Where the finished export is kept in the object store.
+    #[serde(skip)]
+    pub object_key: Option<String>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportJobResponse {
+    #[serde(flatten)]
+    pub job: ExportJob,
+    pub download_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Generate the export in the background even if it is small.
+    #[serde(default, rename = "async")]
+    pub background: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Transaction {
+    pub uuid: String,
+    pub relay_order_uuid: String,
+    pub amount: i32,
+    #[sqlx(rename = "type")]
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A login session, without the token itself.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// Everything stored about a user. Secrets such as API key and refresh token
+/// hashes are left out.
+#[derive(Serialize, Deserialize)]
+pub struct UserExport {
+    pub exported_at: NaiveDateTime,
+    pub user: User,
+    pub profile: Option<Profile>,
+    pub relays: Vec<Relay>,
+    pub orders: Vec<RelayOrder>,
+    pub transactions: Vec<Transaction>,
+    pub api_keys: Vec<ApiKey>,
+    pub sessions: Vec<Session>,
+    pub audit_events: Vec<AuditEvent>,
+}
+
+pub struct ExportConfig {
+    /// Exports with more records than this are generated in the background.
+    pub sync_max_records: i64,
+    pub ttl: chrono::Duration,
+    /// Background exports a user may have running at once.
+    pub max_pending_jobs: i64,
+}
+
+impl ExportConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: i64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        ExportConfig {
+            sync_max_records: var("EXPORT_SYNC_MAX_RECORDS", 1000),
+            ttl: chrono::Duration::hours(var("EXPORT_TTL_HOURS", 24)),
+            max_pending_jobs: var("EXPORT_MAX_PENDING_JOBS", 1),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct ExportRepository {
+    pub pool: PgPool,
+}
+
+impl ExportRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Rows an export of the user would contain.
+    pub async fn record_count(&self, user: &User) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT
+                (SELECT COUNT(*) FROM relays WHERE user_npub = $1)
+                + (SELECT COUNT(*) FROM relay_orders WHERE user_npub = $1)
+                + (SELECT COUNT(*) FROM transactions WHERE user_npub = $1)
+                + (SELECT COUNT(*) FROM api_keys WHERE npub = $1)
+                + (SELECT COUNT(*) FROM refresh_tokens WHERE hexpub = $2)
+                + (SELECT COUNT(*) FROM audit_events WHERE npub = $1)",
+        )
+        .bind(&user.npub)
+        .bind(&user.hexpub)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn collect(&self, user: User) -> Result<UserExport, sqlx::Error> {
+        let relays = sqlx::query_as::<_, Relay>(
+            "SELECT * FROM relays WHERE user_npub = $1 ORDER BY created_at",
+        )
+        .bind(&user.npub)
+        .fetch_all(&self.pool)
+        .await?;
+        let orders = sqlx::query_as::<_, RelayOrder>(
+            "SELECT * FROM relay_orders WHERE user_npub = $1 ORDER BY created_at",
+        )
+        .bind(&user.npub)
+        .fetch_all(&self.pool)
+        .await?;
+        let transactions = sqlx::query_as::<_, Transaction>(
+            "SELECT * FROM transactions WHERE user_npub = $1 ORDER BY created_at",
+        )
+        .bind(&user.npub)
+        .fetch_all(&self.pool)
+        .await?;
+        let api_keys = sqlx::query_as::<_, ApiKey>(
+            "SELECT * FROM api_keys WHERE npub = $1 ORDER BY created_at",
+        )
+        .bind(&user.npub)
+        .fetch_all(&self.pool)
+        .await?;
+        let sessions = sqlx::query_as::<_, Session>(
+            "SELECT created_at, expires_at, revoked_at FROM refresh_tokens
+            WHERE hexpub = $1 ORDER BY created_at",
+        )
+        .bind(&user.hexpub)
+        .fetch_all(&self.pool)
+        .await?;
+        let audit_events = AuditRepository::new(self.pool.clone())
+            .get_user_events(&user.npub)
+            .await?;
+        let profile = ProfileRepository::new(self.pool.clone())
+            .get_one(&user.hexpub)
+            .await;
+
+        Ok(UserExport {
+            exported_at: Utc::now().naive_utc(),
+            user,
+            profile,
+            relays,
+            orders,
+            transactions,
+            api_keys,
+            sessions,
+            audit_events,
+        })
+    }
+
+    /// Creates a pending job, or returns `None` if the user already has
+    /// `max_pending` jobs running. Pending jobs past their expiry, left over
+    /// from a restart, no longer count.
+    pub async fn create_job(
+        &self,
+        npub: &str,
+        format: ExportFormat,
+        ttl: chrono::Duration,
+        max_pending: i64,
+    ) -> Result<Option<ExportJob>, sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let mut tx = self.pool.begin().await?;
+
+        // Serializes concurrent requests from the same user.
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(npub)
+            .execute(&mut *tx)
+            .await?;
+
+        let pending = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM export_jobs
+            WHERE npub = $1 AND status = 'pending' AND expires_at > $2",
+        )
+        .bind(npub)
+        .bind(now)
+        .fetch_one(&mut *tx)
+        .await?;
+        if pending >= max_pending {
+            return Ok(None);
+        }
+
+        let job = sqlx::query_as::<_, ExportJob>(
+            "INSERT INTO export_jobs (uuid, npub, format, status, expires_at)
+            VALUES ($1, $2, $3, 'pending', $4)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(npub)
+        .bind(format)
+        .bind(now + ttl)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some(job))
+    }
+
+    pub async fn get_job(&self, uuid: &str, npub: &str) -> Option<ExportJob> {
+        sqlx::query_as::<_, ExportJob>("SELECT * FROM export_jobs WHERE uuid = $1 AND npub = $2")
+            .bind(uuid)
+            .bind(npub)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn finish_job(
+        &self,
+        uuid: &str,
+        result: Result<String, String>,
+    ) -> Result<(), sqlx::Error> {
+        let (status, object_key, error) = match result {
+            Ok(object_key) => (ExportJobStatus::Completed, Some(object_key), None),
+            Err(err) => (ExportJobStatus::Failed, None, Some(err)),
+        };
+
+        sqlx::query(
+            "UPDATE export_jobs SET status = $2, object_key = $3, error = $4 WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(status)
+        .bind(object_key)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_expired_jobs(&self) -> Result<Vec<ExportJob>, sqlx::Error> {
+        sqlx::query_as::<_, ExportJob>("SELECT * FROM export_jobs WHERE expires_at < $1")
+            .bind(Utc::now().naive_utc())
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn delete_job(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM export_jobs WHERE uuid = $1")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+pub fn render_export(export: &UserExport, format: ExportFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ExportFormat::Json => serde_json::to_vec_pretty(export).map_err(|err| err.to_string()),
+        ExportFormat::Zip => {
+            let sections = match serde_json::to_value(export).map_err(|err| err.to_string())? {
+                Value::Object(sections) => sections,
+                _ => return Err("Export is not an object".to_string()),
+            };
+
+            let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+
+            for (name, section) in sections {
+                let json = serde_json::to_vec_pretty(&section).map_err(|err| err.to_string())?;
+                zip.start_file(format!("{}.json", name), options)
+                    .map_err(|err| err.to_string())?;
+                zip.write_all(&json).map_err(|err| err.to_string())?;
+            }
+
+            zip.finish()
+                .map(Cursor::into_inner)
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+pub async fn generate_export(
+    export_repo: &ExportRepository,
+    user: User,
+    format: ExportFormat,
+) -> Result<Vec<u8>, String> {
+    let export = export_repo
+        .collect(user)
+        .await
+        .map_err(|err| format!("Failed to collect export: {}", err))?;
+
+    render_export(&export, format)
+}
+
+pub fn export_object_key(job: &ExportJob) -> String {
+    format!(
+        "exports/{}/{}.{}",
+        job.npub,
+        job.uuid,
+        job.format.extension()
+    )
+}
+
+pub async fn run_export_job(
+    export_repo: ExportRepository,
+    store: Arc<dyn ObjectStore>,
+    job: ExportJob,
+    user: User,
+) {
+    let key = export_object_key(&job);
+    let result = match generate_export(&export_repo, user, job.format).await {
+        Ok(data) => store
+            .put(&key, data)
+            .await
+            .map(|_| key)
+            .map_err(|err| format!("Failed to store export: {}", err)),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = &result {
+        eprintln!("Export {} failed: {}", job.uuid, err);
+    }
+
+    if let Err(err) = export_repo.finish_job(&job.uuid, result).await {
+        eprintln!("Failed to finish export {}: {}", job.uuid, err);
+    }
+}
+
+/// Deletes expired jobs along with their files. A job whose file can't be
+/// deleted is kept for the next run.
+pub async fn prune_expired_exports(
+    export_repo: &ExportRepository,
+    store: &dyn ObjectStore,
+) -> Result<(), String> {
+    let jobs = export_repo
+        .get_expired_jobs()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    for job in jobs {
+        if let Some(key) = &job.object_key {
+            if let Err(err) = store.delete(key).await {
+                eprintln!("Failed to delete export {}: {}", job.uuid, err);
+                continue;
+            }
+        }
+        export_repo
+            .delete_job(&job.uuid)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+pub async fn run_export_pruner(pool: PgPool, store: Arc<dyn ObjectStore>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    let export_repo = ExportRepository::new(pool);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = prune_expired_exports(&export_repo, store.as_ref()).await {
+            eprintln!("Failed to prune expired exports: {}", err);
+        }
+    }
+}
+
+fn download(data: Vec<u8>, format: ExportFormat) -> HttpResponse {
+    let filename = format!(
+        "relaying-export-{}.{}",
+        Utc::now().format("%Y-%m-%d"),
+        format.extension()
+    );
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .body(data)
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+/// Returns the export right away, or a job to poll if it is large.
+async fn export_handler(
+    auth: AuthorizationService,
+    user_repo: web::Data<UserRepository>,
+    store: web::Data<dyn ObjectStore>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    let user = match user_repo.get_one(auth.npub().unwrap()).await {
+        Some(user) => user,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("User not found".to_string()))
+        }
+    };
+    let export_repo = ExportRepository::new(user_repo.pool.clone());
+    let config = ExportConfig::from_env();
+    audit(
+        &user_repo.pool,
+        &user.npub,
+        AuditAction::ExportRequested,
+        None,
+    )
+    .await;
+
+    let records = match export_repo.record_count(&user).await {
+        Ok(records) => records,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+
+    if !query.background && records <= config.sync_max_records {
+        return match generate_export(&export_repo, user, query.format).await {
+            Ok(data) => download(data, query.format),
+            Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err)),
+        };
+    }
+
+    let job = match export_repo
+        .create_job(
+            &user.npub,
+            query.format,
+            config.ttl,
+            config.max_pending_jobs,
+        )
+        .await
+    {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return HttpResponse::TooManyRequests().json(ErrorResponse::new(
+                "An export is already being generated".to_string(),
+            ))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    tokio::spawn(run_export_job(
+        export_repo,
+        store.into_inner(),
+        job.clone(),
+        user,
+    ));
+
+    HttpResponse::Accepted().json(DataResponse::new(ExportJobResponse {
+        download_url: format!("/me/export/{}", job.uuid),
+        job,
+    }))
+}
+
+async fn download_export_handler(
+    auth: AuthorizationService,
+    user_repo: web::Data<UserRepository>,
+    store: web::Data<dyn ObjectStore>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let export_repo = ExportRepository::new(user_repo.pool.clone());
+    let job = match export_repo.get_job(&path, auth.npub().unwrap()).await {
+        Some(job) => job,
+        None => {
+            return HttpResponse::NotFound()
+                .json(ErrorResponse::new("Export not found".to_string()))
+        }
+    };
+
+    if job.expires_at < Utc::now().naive_utc() {
+        return HttpResponse::Gone().json(ErrorResponse::new("Export expired".to_string()));
+    }
+
+    match (job.status, &job.object_key) {
+        (ExportJobStatus::Completed, Some(key)) => match store.get(key).await {
+            Ok(data) => download(data, job.format),
+            Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err)),
+        },
+        (ExportJobStatus::Failed, _) => HttpResponse::InternalServerError().json(
+            ErrorResponse::new(job.error.unwrap_or("Export failed".to_string())),
+        ),
+        (status, _) => HttpResponse::Accepted().json(DataResponse::new(status)),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/me/export", web::get().to(export_handler))
+        .route("/me/export/{uuid}", web::get().to(download_export_handler));
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_key::{ApiKeyRepository, CreateApiKeyDto};
+    use crate::auth::generate_jwt_by_hex;
+    use crate::backup::FilesystemObjectStore;
+    use crate::token::{issue_tokens, TokenRepository};
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use std::io::Read;
+
+    #[actix_web::test]
+    async fn test_export() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let token_repo = TokenRepository::new(test_utils.pool.clone());
+        let tokens = issue_tokens(&token_repo, &user.hexpub).await.unwrap();
+        let api_key = ApiKeyRepository::new(test_utils.pool.clone())
+            .create(
+                &user.hexpub,
+                &user.npub,
+                &CreateApiKeyDto {
+                    name: "ci".to_string(),
+                    scopes: vec!["relays:read".to_string()],
+                    expires_in_days: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store: Arc<dyn ObjectStore> = Arc::new(FilesystemObjectStore::new(root.clone()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.user_repo.clone()))
+                .app_data(web::Data::new(token_repo))
+                .app_data(web::Data::from(store.clone()))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+        let get = |uri: &str| {
+            actix_web::test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .to_request()
+        };
+
+        let resp = actix_web::test::call_service(&app, get("/me/export")).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp
+            .headers()
+            .get("Content-Disposition")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(".json\""));
+        let body = actix_web::test::read_body(resp).await;
+        let export: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(export["user"]["npub"], user.npub.as_str());
+        assert_eq!(export["relays"][0]["uuid"], relay.uuid.as_str());
+        assert_eq!(export["relays"][0]["write_whitelist"]["key"], "value");
+        assert_eq!(export["orders"].as_array().unwrap().len(), 1);
+        assert_eq!(export["api_keys"][0]["uuid"], api_key.api_key.uuid.as_str());
+        assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+        assert_eq!(export["audit_events"][0]["action"], "export_requested");
+
+        // Secrets never leave the database.
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains(&api_key.key));
+        assert!(!body.contains("key_hash"));
+        assert!(!body.contains(&tokens.refresh_token));
+        assert!(!body.contains("token_hash"));
+
+        let resp =
+            actix_web::test::call_service(&app, get("/me/export?format=zip&async=true")).await;
+        assert_eq!(resp.status(), 202);
+        let job: DataResponse<ExportJobResponse> = actix_web::test::read_body_json(resp).await;
+        assert_eq!(job.data.job.status, ExportJobStatus::Pending);
+
+        let mut resp = actix_web::test::call_service(&app, get(&job.data.download_url)).await;
+        for _ in 0..50 {
+            if resp.status() != 202 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            resp = actix_web::test::call_service(&app, get(&job.data.download_url)).await;
+        }
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/zip"
+        );
+
+        let body = actix_web::test::read_body(resp).await;
+        let mut archive = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        let mut relays = String::new();
+        archive
+            .by_name("relays.json")
+            .unwrap()
+            .read_to_string(&mut relays)
+            .unwrap();
+        assert!(relays.contains(&relay.uuid));
+        assert!(archive.by_name("user.json").is_ok());
+
+        // Jobs belong to the user who started them.
+        let other = test_utils.create_user().await;
+        let jwt = generate_jwt_by_hex(&other.hexpub).unwrap();
+        let req = actix_web::test::TestRequest::get()
+            .uri(&job.data.download_url)
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+
+        // The file lives in the object store until the job expires.
+        let export_repo = ExportRepository::new(test_utils.pool.clone());
+        let finished = export_repo
+            .get_job(&job.data.job.uuid, &user.npub)
+            .await
+            .unwrap();
+        let key = finished.object_key.unwrap();
+        assert!(store.get(&key).await.is_ok());
+
+        prune_expired_exports(&export_repo, store.as_ref())
+            .await
+            .unwrap();
+        assert!(store.get(&key).await.is_ok());
+
+        sqlx::query("UPDATE export_jobs SET expires_at = $1 WHERE uuid = $2")
+            .bind(Utc::now().naive_utc() - chrono::Duration::minutes(1))
+            .bind(&job.data.job.uuid)
+            .execute(&test_utils.pool)
+            .await
+            .unwrap();
+        prune_expired_exports(&export_repo, store.as_ref())
+            .await
+            .unwrap();
+        assert!(store.get(&key).await.is_err());
+        assert!(export_repo
+            .get_job(&job.data.job.uuid, &user.npub)
+            .await
+            .is_none());
+
+        // Only one background export at a time.
+        let pending = export_repo
+            .create_job(
+                &user.npub,
+                ExportFormat::Json,
+                chrono::Duration::hours(1),
+                1,
+            )
+            .await
+            .unwrap();
+        assert!(pending.is_some());
+        let resp = actix_web::test::call_service(&app, get("/me/export?async=true")).await;
+        assert_eq!(resp.status(), 429);
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,637 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use futures::{SinkExt, StreamExt};
+use nostr::{ClientMessage, Filter, RelayMessage, SubscriptionId};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    announcement::announcement_url,
+    dns::{relay_hostname, sync_relay_dns, DnsProvider},
+    middleware::AuthorizationService,
+    notification::{notify_relay_team, Notification},
+    organization::OrganizationRole,
+    relay::{Relay, RelayRepository, RelayState},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct HealthCheck {
+    pub id: i64,
+    pub relay_uuid: String,
+    pub available: bool,
+    pub latency_ms: Option<i32>,
+    pub nip11_available: bool,
+    pub error: Option<String>,
+    pub checked_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProbeResult {
+    pub available: bool,
+    pub latency_ms: Option<i32>,
+    pub nip11: Option<Value>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayHealth {
+    pub state: RelayState,
+    pub uptime_24h: Option<f64>,
+    pub checks: Vec<HealthCheck>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failed checks before an online relay is marked offline.
+    pub failure_threshold: usize,
+    /// How long a relay may stay initializing before provisioning counts as
+    /// failed.
+    pub provisioning_timeout: Duration,
+    /// How long checks are kept.
+    pub retention: chrono::Duration,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        HealthConfig {
+            interval: Duration::from_secs(var("HEALTH_CHECK_INTERVAL_SECS", 60)),
+            timeout: Duration::from_secs(var("HEALTH_CHECK_TIMEOUT_SECS", 10)),
+            failure_threshold: var("HEALTH_FAILURE_THRESHOLD", 3) as usize,
+            provisioning_timeout: Duration::from_secs(
+                var("HEALTH_PROVISIONING_TIMEOUT_MINS", 30) * 60,
+            ),
+            retention: chrono::Duration::days(var("HEALTH_CHECK_RETENTION_DAYS", 30) as i64),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct HealthCheckRepository {
+    pub pool: PgPool,
+}
+
+impl HealthCheckRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        relay_uuid: &str,
+        result: &ProbeResult,
+    ) -> Result<HealthCheck, sqlx::Error> {
+        sqlx::query_as::<_, HealthCheck>(
+            "INSERT INTO relay_health_checks (relay_uuid, available, latency_ms, nip11_available, error)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *",
+        )
+        .bind(relay_uuid)
+        .bind(result.available)
+        .bind(result.latency_ms)
+        .bind(result.nip11.is_some())
+        .bind(&result.error)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// The most recent checks, newest first.
+    pub async fn get_recent(&self, relay_uuid: &str, limit: i64) -> Vec<HealthCheck> {
+        sqlx::query_as::<_, HealthCheck>(
+            "SELECT * FROM relay_health_checks WHERE relay_uuid = $1 ORDER BY checked_at DESC, id DESC LIMIT $2",
+        )
+        .bind(relay_uuid)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Share of successful checks since `since`, or `None` without checks.
+    pub async fn uptime_since(&self, relay_uuid: &str, since: NaiveDateTime) -> Option<f64> {
+        sqlx::query_scalar::<_, Option<f64>>(
+            "SELECT AVG(CASE WHEN available THEN 1.0 ELSE 0.0 END)::FLOAT8
+            FROM relay_health_checks WHERE relay_uuid = $1 AND checked_at >= $2",
+        )
+        .bind(relay_uuid)
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn delete_before(&self, cutoff: NaiveDateTime) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM relay_health_checks WHERE checked_at < $1")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Prober
+// -----------------------------------------------------------------------------
+
+pub fn relay_url(relay: &Relay) -> String {
+    format!("wss://{}", relay_hostname(&relay.subdomain))
+}
+
+/// Opens a websocket, runs a REQ/EOSE/CLOSE round trip and fetches the NIP-11
+/// document. The latency covers connecting and the REQ round trip.
+pub async fn probe_relay(url: &str, timeout: Duration) -> ProbeResult {
+    probe_relay_at(url, None, timeout).await
+}
+
+/// Like `probe_relay`, but connects to `ip` instead of resolving the URL's
+/// host, so relays can be checked before their DNS record exists.
+pub async fn probe_relay_at(url: &str, ip: Option<IpAddr>, timeout: Duration) -> ProbeResult {
+    let started = Instant::now();
+    let round_trip = tokio::time::timeout(timeout, req_round_trip(url, ip)).await;
+
+    let mut result = match round_trip {
+        Ok(Ok(())) => ProbeResult {
+            available: true,
+            latency_ms: Some(started.elapsed().as_millis() as i32),
+            ..Default::default()
+        },
+        Ok(Err(err)) => ProbeResult {
+            error: Some(err),
+            ..Default::default()
+        },
+        Err(_) => ProbeResult {
+            error: Some("Timed out".to_string()),
+            ..Default::default()
+        },
+    };
+
+    result.nip11 = fetch_nip11(url, ip, timeout).await.ok();
+    result
+}
+
+/// The address to connect to for `url`, or `None` to resolve its host.
+fn pinned_addr(url: &str, ip: Option<IpAddr>) -> Result<Option<SocketAddr>, String> {
+    let Some(ip) = ip else {
+        return Ok(None);
+    };
+    let port = Url::parse(url)
+        .map_err(|err| err.to_string())?
+        .port_or_known_default()
+        .ok_or("URL has no port")?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn req_round_trip(url: &str, ip: Option<IpAddr>) -> Result<(), String> {
+    let connected = match pinned_addr(url, ip)? {
+        Some(addr) => {
+            let stream = TcpStream::connect(addr)
+                .await
+                .map_err(|err| format!("Failed to connect: {}", err))?;
+            tokio_tungstenite::client_async_tls(url, stream).await
+        }
+        None => tokio_tungstenite::connect_async(url).await,
+    };
+    let (mut socket, _) = connected.map_err(|err| format!("Failed to connect: {}", err))?;
+
+    let subscription_id = SubscriptionId::generate();
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![Filter::new().limit(1)]);
+    socket
+        .send(Message::Text(req.as_json()))
+        .await
+        .map_err(|err| format!("Failed to send REQ: {}", err))?;
+
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                if let Ok(RelayMessage::EndOfStoredEvents(id)) = RelayMessage::from_json(text) {
+                    if id == subscription_id {
+                        break;
+                    }
+                }
+            }
+            Some(Ok(Message::Close(_))) | None => {
+                return Err("Connection closed before EOSE".to_string())
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(format!("Websocket error: {}", err)),
+        }
+    }
+
+    socket
+        .send(Message::Text(
+            ClientMessage::close(subscription_id).as_json(),
+        ))
+        .await
+        .map_err(|err| format!("Failed to send CLOSE: {}", err))?;
+    let _ = socket.close(None).await;
+
+    Ok(())
+}
+
+pub async fn fetch_nip11(
+    url: &str,
+    ip: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<Value, String> {
+    let http_url = url
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+
+    let mut client = reqwest::Client::builder();
+    if let Some(addr) = pinned_addr(url, ip)? {
+        let host = Url::parse(url).map_err(|err| err.to_string())?;
+        client = client.resolve(host.host_str().unwrap_or_default(), addr);
+    }
+
+    client
+        .build()
+        .map_err(|err| err.to_string())?
+        .get(http_url)
+        .header("Accept", "application/nostr+json")
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|err| format!("Failed to fetch NIP-11 document: {}", err))?
+        .json()
+        .await
+        .map_err(|err| format!("Invalid NIP-11 document: {}", err))
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Decides the relay's state from its latest checks (newest first). Relays
+/// that are still booting stay in their state until the first success.
+pub fn next_state(current: RelayState, recent: &[bool], failure_threshold: usize) -> RelayState {
+    match (current, recent.first()) {
+        (RelayState::Deleted, _) | (_, None) => current,
+        (_, Some(true)) => RelayState::Online,
+        (RelayState::Online, Some(false)) => {
+            let failures = recent.iter().take_while(|available| !**available).count();
+            if failures >= failure_threshold {
+                RelayState::Offline
+            } else {
+                RelayState::Online
+            }
+        }
+        _ => current,
+    }
+}
+
+/// Whether a relay that never came online has been booting for too long.
+fn provisioning_timed_out(relay: &Relay, timeout: Duration) -> bool {
+    let timeout = chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::max_value());
+    chrono::Utc::now().naive_utc() - relay.created_at > timeout
+}
+
+/// Probes `url` at the relay's instance IP and moves the relay to its next
+/// state. The relay's DNS record is published when it comes online.
+pub async fn check_relay(
+    pool: &PgPool,
+    config: &HealthConfig,
+    dns: &dyn DnsProvider,
+    relay: &Relay,
+    url: &str,
+) -> Result<HealthCheck, String> {
+    let ip = relay.instance_ip.parse().ok();
+    let result = probe_relay_at(url, ip, config.timeout).await;
+    let repo = HealthCheckRepository::new(pool.clone());
+    let check = repo
+        .create(&relay.uuid, &result)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let recent: Vec<bool> = repo
+        .get_recent(&relay.uuid, config.failure_threshold.max(1) as i64)
+        .await
+        .iter()
+        .map(|check| check.available)
+        .collect();
+
+    let mut state = next_state(relay.state, &recent, config.failure_threshold);
+    let provisioning_failed = state == RelayState::Initializing
+        && provisioning_timed_out(relay, config.provisioning_timeout);
+    if provisioning_failed {
+        state = RelayState::Offline;
+    }
+
+    if state != relay.state {
+        let relay = RelayRepository::new(pool.clone())
+            .update_state(&relay.uuid, state)
+            .await
+            .map_err(|err| err.to_string())?;
+        let organization_uuid = relay.organization_uuid.as_deref();
+        if state == RelayState::Online {
+            if let Err(err) = sync_relay_dns(dns, &relay).await {
+                eprintln!(
+                    "Failed to create DNS record for relay {}: {}",
+                    relay.uuid, err
+                );
+            }
+            let url = announcement_url(pool, &relay).await;
+            notify_relay_team(
+                pool,
+                organization_uuid,
+                Notification::relay_online(&relay, &url),
+            )
+            .await;
+        } else if provisioning_failed {
+            let notification = Notification::provisioning_failed(
+                &relay.user_npub,
+                &relay.relay_order_uuid,
+                &relay.name,
+            );
+            notify_relay_team(pool, organization_uuid, notification).await;
+        } else if state == RelayState::Offline {
+            notify_relay_team(pool, organization_uuid, Notification::relay_offline(&relay)).await;
+        }
+    }
+
+    Ok(check)
+}
+
+pub async fn run_health_prober(pool: PgPool, config: HealthConfig, dns: Arc<dyn DnsProvider>) {
+    let mut interval = tokio::time::interval(config.interval);
+    let relay_repo = RelayRepository::new(pool.clone());
+    let check_repo = HealthCheckRepository::new(pool.clone());
+
+    loop {
+        interval.tick().await;
+
+        let relays = relay_repo
+            .get_active()
+            .await
+            .into_iter()
+            .filter(|relay| !relay.subdomain.is_empty());
+
+        futures::stream::iter(relays)
+            .for_each_concurrent(10, |relay| {
+                let pool = pool.clone();
+                let dns = dns.clone();
+                async move {
+                    let url = relay_url(&relay);
+                    let result = check_relay(&pool, &config, dns.as_ref(), &relay, &url).await;
+                    if let Err(err) = result {
+                        eprintln!("Failed to record health of relay {}: {}", relay.uuid, err);
+                    }
+                }
+            })
+            .await;
+
+        if let Err(err) = check_repo
+            .delete_before(chrono::Local::now().naive_utc() - config.retention)
+            .await
+        {
+            eprintln!("Failed to prune health checks: {}", err);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_relay_health_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let repo = HealthCheckRepository::new(relay_repo.pool.clone());
+    let since = chrono::Local::now().naive_utc() - chrono::Duration::hours(24);
+
+    HttpResponse::Ok().json(DataResponse::new(RelayHealth {
+        state: relay.state,
+        uptime_24h: repo.uptime_since(&relay.uuid, since).await,
+        checks: repo.get_recent(&relay.uuid, 50).await,
+    }))
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/health").route(web::get().to(get_relay_health_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsRecordType, LocalDnsProvider};
+    use crate::notification::{NotificationKind, NotificationRepository};
+    use crate::organization::OrganizationRepository;
+    use crate::test_relay::TestRelay;
+    use crate::util::TestUtils;
+    use serde_json::json;
+
+    #[test]
+    fn test_next_state() {
+        use RelayState::*;
+
+        assert_eq!(
+            next_state(Initializing, &[false, false, false], 3),
+            Initializing
+        );
+        assert_eq!(next_state(Initializing, &[true], 3), Online);
+        assert_eq!(next_state(Online, &[false, false, true], 3), Online);
+        assert_eq!(next_state(Online, &[false, false, false], 3), Offline);
+        assert_eq!(next_state(Offline, &[true, false], 3), Online);
+        assert_eq!(next_state(Deleted, &[true], 3), Deleted);
+    }
+
+    #[tokio::test]
+    async fn test_check_relay_against_local_relay() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        // Probes are pinned to the instance IP.
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "127.0.0.1")
+            .await
+            .unwrap();
+        let dns = LocalDnsProvider::new();
+        let config = HealthConfig {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(2),
+            failure_threshold: 2,
+            provisioning_timeout: Duration::from_secs(1800),
+            retention: chrono::Duration::days(30),
+        };
+
+        let test_relay = TestRelay::start().await;
+        test_relay.set_nip11(json!({ "name": "probe me" }));
+
+        let check = check_relay(&test_utils.pool, &config, &dns, &relay, &test_relay.url)
+            .await
+            .unwrap();
+        assert!(check.available);
+        assert!(check.nip11_available);
+        assert!(check.latency_ms.is_some());
+
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.state, RelayState::Online);
+        let record = dns.get(&relay_hostname(&relay.subdomain), DnsRecordType::A);
+        assert_eq!(record.unwrap().value, "127.0.0.1");
+
+        test_relay.stop();
+
+        check_relay(&test_utils.pool, &config, &dns, &relay, &test_relay.url)
+            .await
+            .unwrap();
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.state, RelayState::Online);
+
+        let check = check_relay(&test_utils.pool, &config, &dns, &relay, &test_relay.url)
+            .await
+            .unwrap();
+        assert!(!check.available);
+        assert!(check.error.is_some());
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.state, RelayState::Offline);
+
+        let kinds: Vec<NotificationKind> = NotificationRepository::new(test_utils.pool.clone())
+            .get_user_deliveries(&user.npub, 10)
+            .await
+            .iter()
+            .map(|delivery| delivery.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![NotificationKind::RelayOffline, NotificationKind::RelayOnline]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune_health_checks() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let repo = HealthCheckRepository::new(test_utils.pool.clone());
+        let probe = ProbeResult {
+            available: true,
+            ..Default::default()
+        };
+
+        let old = repo.create(&relay.uuid, &probe).await.unwrap();
+        let recent = repo.create(&relay.uuid, &probe).await.unwrap();
+        let now = chrono::Local::now().naive_utc();
+        sqlx::query("UPDATE relay_health_checks SET checked_at = $1 WHERE id = $2")
+            .bind(now - chrono::Duration::days(31))
+            .bind(old.id)
+            .execute(&test_utils.pool)
+            .await
+            .unwrap();
+
+        let deleted = repo
+            .delete_before(now - chrono::Duration::days(30))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+        let checks = repo.get_recent(&relay.uuid, 10).await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].id, recent.id);
+    }
+
+    #[tokio::test]
+    async fn test_provisioning_timeout_notifies_organization() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let owner = test_utils.create_user().await;
+        let viewer = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let organization = OrganizationRepository::new(test_utils.pool.clone())
+            .create("Acme", &owner.npub)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO organization_members (organization_uuid, npub, role) VALUES ($1, $2, 'viewer')",
+        )
+        .bind(&organization.uuid)
+        .bind(&viewer.npub)
+        .execute(&test_utils.pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "UPDATE relays SET organization_uuid = $2, created_at = NOW() - INTERVAL '2 hours' WHERE uuid = $1",
+        )
+        .bind(&relay.uuid)
+        .bind(&organization.uuid)
+        .execute(&test_utils.pool)
+        .await
+        .unwrap();
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        let dns = LocalDnsProvider::new();
+        let config = HealthConfig {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(1),
+            failure_threshold: 2,
+            provisioning_timeout: Duration::from_secs(1800),
+            retention: chrono::Duration::days(30),
+        };
+
+        check_relay(&test_utils.pool, &config, &dns, &relay, "ws://127.0.0.1:1")
+            .await
+            .unwrap();
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.state, RelayState::Offline);
+
+        let notifications = NotificationRepository::new(test_utils.pool.clone());
+        for npub in [&user.npub, &owner.npub] {
+            let deliveries = notifications.get_user_deliveries(npub, 10).await;
+            assert_eq!(deliveries.len(), 1);
+            assert_eq!(deliveries[0].kind, NotificationKind::ProvisioningFailed);
+        }
+        assert!(notifications
+            .get_user_deliveries(&viewer.npub, 10)
+            .await
+            .is_empty());
+    }
+}
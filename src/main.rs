@@ -1,14 +1,38 @@
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web::Data, App, HttpServer};
+use actix_web::{middleware::Logger, web::Data, web::JsonConfig, App, HttpServer};
 use sqlx::postgres::PgPool;
 use std::{env, sync::Arc};
 
+mod announcement;
+mod api_key;
+mod audit;
 mod auth;
 mod aws;
+mod backup;
+mod certificate;
 mod cloud_provider;
+mod custom_domain;
+mod dns;
+mod event_import;
+mod export;
+mod health;
+mod metrics;
 mod middleware;
+mod nip46;
+mod notification;
+mod organization;
+mod outbound;
+mod profile;
+mod rate_limit;
 mod relay;
+mod relay_client;
+mod relay_info;
+mod relay_migration;
 mod relay_order;
+mod replay_guard;
+#[cfg(test)]
+mod test_relay;
+mod token;
 mod user;
 mod util;
 
@@ -24,21 +48,145 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to create PostgreSQL pool.");
 
+    certificate::seal_plaintext_keys(&pool).await;
+
     let user_repo = user::UserRepository::new(pool.clone());
+    user::seed_admins(&user_repo, &dotenvy::var("ADMIN_NPUBS").unwrap_or_default()).await;
     let relay_order_repo = relay_order::RelayOrderRepository::new(pool.clone());
     let relay_repo = relay::RelayRepository::new(pool.clone());
+    let token_repo = token::TokenRepository::new(pool.clone());
+    let api_key_repo = api_key::ApiKeyRepository::new(pool.clone());
+    let nip46_session_repo = nip46::Nip46SessionRepository::new(pool.clone());
+    let organization_repo = organization::OrganizationRepository::new(pool.clone());
+    let notification_repo = notification::NotificationRepository::new(pool.clone());
+    let dns_provider = dns::provider_from_env();
+    let replay_guard = replay_guard::guard_from_env(pool.clone());
+    let rate_limit_store = rate_limit::store_from_env(pool.clone());
+    let relay_client: Arc<dyn relay_client::RelayClient> =
+        Arc::new(relay_client::WebsocketRelayClient::from_env());
+    let instance_provider: Arc<dyn cloud_provider::InstanceProvider> =
+        Arc::new(cloud_provider::Ec2InstanceProvider);
+    let object_store = backup::store_from_env();
+    let migration_config = Arc::new(relay_migration::MigrationConfig::from_env());
+    let metrics_source: Arc<dyn metrics::MetricsSource> =
+        Arc::new(metrics::AgentMetricsSource::from_env());
+
+    let domain_resolver: Arc<dyn custom_domain::DomainResolver> =
+        Arc::new(custom_domain::DohResolver::from_env());
+    let acme_client: Arc<dyn certificate::AcmeClient> =
+        Arc::new(certificate::InstantAcmeClient::from_env());
+
+    tokio::spawn(replay_guard::run_seen_event_pruner(pool.clone()));
+    tokio::spawn(dns::run_dns_reconciler(
+        pool.clone(),
+        dns_provider.clone(),
+        instance_provider.clone(),
+    ));
+    tokio::spawn(custom_domain::run_domain_verifier(pool.clone(), domain_resolver));
+    tokio::spawn(health::run_health_prober(
+        pool.clone(),
+        health::HealthConfig::from_env(),
+        dns_provider.clone(),
+    ));
+    tokio::spawn(profile::run_profile_refresher(
+        pool.clone(),
+        relay_client.clone(),
+        profile::ProfileConfig::from_env(),
+    ));
+    if let Some(config) = announcement::AnnouncementConfig::from_env() {
+        tokio::spawn(announcement::run_announcer(
+            pool.clone(),
+            relay_client.clone(),
+            config,
+        ));
+    }
+    tokio::spawn(notification::run_notifier(
+        pool.clone(),
+        relay_client.clone(),
+        notification::NotificationConfig::from_env(),
+    ));
+    tokio::spawn(backup::run_backup_scheduler(
+        pool.clone(),
+        relay_client.clone(),
+        object_store.clone(),
+        backup::BackupConfig::from_env(),
+    ));
+    tokio::spawn(backup::run_restore_queue(
+        pool.clone(),
+        relay_client.clone(),
+        object_store.clone(),
+    ));
+    tokio::spawn(metrics::run_metrics_collector(
+        pool.clone(),
+        metrics_source,
+        metrics::MetricsConfig::from_env(),
+    ));
+    tokio::spawn(event_import::resume_running_imports(
+        pool.clone(),
+        relay_client.clone(),
+    ));
+    tokio::spawn(relay_migration::recover_running_migrations(
+        pool.clone(),
+        dns_provider.clone(),
+        instance_provider.clone(),
+        relay_client.clone(),
+        migration_config.clone(),
+    ));
+    tokio::spawn(export::run_export_pruner(pool.clone(), object_store.clone()));
+    tokio::spawn(certificate::run_certificate_renewer(
+        pool.clone(),
+        acme_client.clone(),
+        dns_provider.clone(),
+    ));
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .wrap(Cors::permissive())
+            .wrap(middleware::Nip98Body)
+            // Wrapped last so it runs first, before any body is buffered.
+            .wrap(rate_limit::RateLimiter::new(
+                rate_limit_store.clone(),
+                rate_limit::RateLimitConfig::from_env(),
+            ))
+            .app_data(JsonConfig::default().limit(middleware::MAX_BODY_BYTES))
             .app_data(Data::new(pool.clone()))
             .app_data(Data::new(user_repo.clone()))
             .app_data(Data::new(relay_order_repo.clone()))
             .app_data(Data::new(relay_repo.clone()))
+            .app_data(Data::new(token_repo.clone()))
+            .app_data(Data::new(api_key_repo.clone()))
+            .app_data(Data::new(nip46_session_repo.clone()))
+            .app_data(Data::new(organization_repo.clone()))
+            .app_data(Data::new(notification_repo.clone()))
+            .app_data(Data::from(dns_provider.clone()))
+            .app_data(Data::from(acme_client.clone()))
+            .app_data(Data::from(replay_guard.clone()))
+            .app_data(Data::new(auth::Nip98Config::from_env()))
+            .app_data(Data::from(relay_client.clone()))
+            .app_data(Data::from(instance_provider.clone()))
+            .app_data(Data::from(object_store.clone()))
+            .app_data(Data::from(migration_config.clone()))
             .configure(user::configure_routes)
+            .configure(export::configure_routes)
+            .configure(notification::configure_routes)
             .configure(auth::configure_routes)
+            .configure(token::configure_routes)
+            .configure(nip46::configure_routes)
+            .configure(api_key::configure_routes)
+            .configure(organization::configure_routes)
             .configure(relay_order::configure_routes)
+            // Routes under /relays/{uuid}/ must come before
+            // relay::configure_routes, whose /relays scope would swallow them.
+            .configure(custom_domain::configure_routes)
+            .configure(certificate::configure_routes)
+            .configure(relay_info::configure_routes)
+            .configure(health::configure_routes)
+            .configure(announcement::configure_routes)
+            .configure(backup::configure_routes)
+            .configure(relay_migration::configure_routes)
+            .configure(event_import::configure_routes)
+            .configure(metrics::configure_routes)
             .configure(relay::configure_routes)
     })
     .bind("127.0.0.1:8888")?
@@ -0,0 +1,877 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::{
+    certificate::{bearer_token, CertificateRepository},
+    dns::relay_hostname,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayRepository, RelayState},
+    relay_order::calculate_hmac_sha256,
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Most points a metrics query may return.
+const MAX_POINTS: i64 = 1000;
+/// Relays sampled concurrently.
+const COLLECT_CONCURRENCY: usize = 10;
+/// Samples further apart than this many intervals get no rates, since
+/// counters may have been reset and caught up again in between.
+const STALE_AFTER_INTERVALS: u32 = 3;
+
+/// What a relay reports about itself. The counters behind `events_received`,
+/// `bytes_in` and `bytes_out` start at zero when the relay starts.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RelaySample {
+    pub event_count: i64,
+    pub storage_bytes: i64,
+    pub connections: i64,
+    pub events_received: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct MetricSample {
+    pub id: i64,
+    pub relay_uuid: String,
+    pub sampled_at: NaiveDateTime,
+    pub event_count: i64,
+    pub storage_bytes: i64,
+    pub connections: i64,
+    pub events_received: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    /// Rates since the previous sample; `None` for the relay's first.
+    pub events_per_sec: Option<f64>,
+    pub bytes_in_per_sec: Option<f64>,
+    pub bytes_out_per_sec: Option<f64>,
+}
+
+/// Samples averaged over one bucket of the requested resolution.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct MetricPoint {
+    /// Start of the bucket.
+    pub time: NaiveDateTime,
+    pub samples: i64,
+    pub event_count: i64,
+    pub storage_bytes: i64,
+    pub connections: f64,
+    pub max_connections: i64,
+    pub events_per_sec: Option<f64>,
+    pub bytes_in_per_sec: Option<f64>,
+    pub bytes_out_per_sec: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 3600,
+            Resolution::Day => 86400,
+        }
+    }
+}
+
+/// Defaults to the last day by hour.
+#[derive(Debug, Deserialize)]
+pub struct MetricsQuery {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub resolution: Resolution,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayMetrics {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub resolution: Resolution,
+    pub points: Vec<MetricPoint>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MetricsConfig {
+    pub interval: Duration,
+    pub retention: chrono::Duration,
+}
+
+impl MetricsConfig {
+    /// The longest gap between two samples that rates are computed across.
+    pub fn max_gap(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.interval * STALE_AFTER_INTERVALS)
+            .unwrap_or(chrono::Duration::max_value())
+    }
+
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        MetricsConfig {
+            interval: Duration::from_secs(var("METRICS_INTERVAL_SECS", 60)),
+            retention: chrono::Duration::days(var("METRICS_RETENTION_DAYS", 30) as i64),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Sources
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait MetricsSource: Send + Sync {
+    async fn collect(&self, relay: &Relay) -> Result<RelaySample, String>;
+}
+
+fn agent_port() -> u16 {
+    dotenvy::var("RELAY_AGENT_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(7780)
+}
+
+/// `RELAY_AGENT_TOKEN`, which every agent token is derived from, loaded once.
+/// Panics when it is not set, which `AgentMetricsSource::from_env` surfaces at
+/// startup. Tests fall back to a fixed secret.
+fn agent_secret() -> &'static str {
+    static SECRET: OnceLock<String> = OnceLock::new();
+    SECRET.get_or_init(|| {
+        match dotenvy::var("RELAY_AGENT_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+        {
+            Some(secret) => secret,
+            #[cfg(test)]
+            None => "test-agent-secret".to_string(),
+            #[cfg(not(test))]
+            None => panic!("RELAY_AGENT_TOKEN is not set"),
+        }
+    })
+}
+
+/// The token the collector presents to the stats agent of one relay. Each
+/// instance only learns its own, see `get_agent_metrics_token_handler`.
+pub fn agent_metrics_token(relay_uuid: &str) -> String {
+    calculate_hmac_sha256(relay_uuid.as_bytes(), agent_secret())
+}
+
+/// Reads the stats agent running next to the relay on every instance, which
+/// reports the host's counters plus what the implementation's own stats hook
+/// provides. The agent serves HTTPS with the relay's certificate, so it is
+/// reached by hostname at the instance's address.
+pub struct AgentMetricsSource {
+    port: u16,
+    timeout: Duration,
+}
+
+impl AgentMetricsSource {
+    pub fn from_env() -> Self {
+        agent_secret();
+
+        Self {
+            port: agent_port(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsSource for AgentMetricsSource {
+    async fn collect(&self, relay: &Relay) -> Result<RelaySample, String> {
+        if relay.subdomain.is_empty() {
+            return Err("Relay has no hostname for the agent's certificate".to_string());
+        }
+        let hostname = relay_hostname(&relay.subdomain);
+        let ip: IpAddr = relay
+            .instance_ip
+            .parse()
+            .map_err(|_| format!("Invalid instance IP {}", relay.instance_ip))?;
+
+        reqwest::Client::builder()
+            .timeout(self.timeout)
+            .resolve(&hostname, SocketAddr::new(ip, self.port))
+            .build()
+            .map_err(|err| err.to_string())?
+            .get(format!("https://{}:{}/metrics", hostname, self.port))
+            .bearer_auth(agent_metrics_token(&relay.uuid))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Failed to reach the stats agent: {}", err))?
+            .json()
+            .await
+            .map_err(|err| format!("Invalid stats from the agent: {}", err))
+    }
+}
+
+/// The stats agent, installed as `/usr/local/bin/relaying-stats`. It reports
+/// connections on the relay's port and the host's network counters, and the
+/// image may add implementation stats such as `event_count` by providing an
+/// executable `/etc/relaying/relay-stats` that prints them as a JSON object.
+const STATS_AGENT: &str = r##"#!/usr/bin/env python3
+import hmac, json, os, ssl, subprocess, urllib.request
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+API = 'RELAYING_API_URL'
+PORT = RELAYING_AGENT_PORT
+TLS = '/etc/relaying/tls'
+STATS_HOOK = '/etc/relaying/relay-stats'
+
+
+def metrics_token():
+    with open('/etc/relaying/agent-token') as file:
+        agent_token = file.read().strip()
+    request = urllib.request.Request(
+        API + '/agent/metrics-token', headers={'Authorization': 'Bearer ' + agent_token})
+    with urllib.request.urlopen(request, timeout=10) as response:
+        return response.read().decode().strip()
+
+
+def read_counter(path):
+    try:
+        with open(path) as file:
+            return int(file.read())
+    except (OSError, ValueError):
+        return 0
+
+
+def default_interface():
+    with open('/proc/net/route') as file:
+        for line in file.readlines()[1:]:
+            fields = line.split()
+            if fields[1] == '00000000':
+                return fields[0]
+    return 'lo'
+
+
+def sample():
+    interface = '/sys/class/net/' + default_interface() + '/statistics/'
+    connections = subprocess.run(
+        ['ss', '-Htn', 'state', 'established', '( sport = :443 )'],
+        capture_output=True, text=True).stdout
+    stats = {
+        'event_count': 0,
+        'storage_bytes': 0,
+        'connections': len(connections.splitlines()),
+        'events_received': 0,
+        'bytes_in': read_counter(interface + 'rx_bytes'),
+        'bytes_out': read_counter(interface + 'tx_bytes'),
+    }
+    if os.access(STATS_HOOK, os.X_OK):
+        output = subprocess.run([STATS_HOOK], capture_output=True, timeout=20).stdout
+        stats.update({key: int(value) for key, value in json.loads(output).items() if key in stats})
+    return stats
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        if not hmac.compare_digest(self.headers.get('Authorization', ''), 'Bearer ' + TOKEN):
+            return self.send_error(401)
+        if self.path != '/metrics':
+            return self.send_error(404)
+        body = json.dumps(sample()).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+TOKEN = metrics_token()
+context = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+context.load_cert_chain(TLS + '/fullchain.pem', TLS + '/privkey.pem')
+server = ThreadingHTTPServer(('', PORT), Handler)
+server.socket = context.wrap_socket(server.socket, server_side=True)
+server.serve_forever()
+"##;
+
+/// Part of the instance bootstrap that installs the stats agent as a service.
+/// It keeps restarting until the certificate agent has enrolled the instance
+/// and fetched the certificate, and restarts again after each renewal.
+pub fn stats_agent_bootstrap(api_url: &str) -> String {
+    let agent = STATS_AGENT
+        .replace("RELAYING_API_URL", api_url)
+        .replace("RELAYING_AGENT_PORT", &agent_port().to_string());
+
+    format!(
+        r#"cat > /usr/local/bin/relaying-stats <<'RELAYING_STATS'
+{agent}RELAYING_STATS
+chmod 700 /usr/local/bin/relaying-stats
+cat > /etc/systemd/system/relaying-stats.service <<'RELAYING_UNIT'
+[Unit]
+Description=Report the relay's stats to the backend
+Wants=network-online.target
+After=network-online.target relaying-certificate.service
+
+[Service]
+ExecStart=/usr/local/bin/relaying-stats
+Restart=always
+RestartSec=30
+
+[Install]
+WantedBy=multi-user.target
+RELAYING_UNIT
+systemctl daemon-reload
+systemctl enable --now relaying-stats.service
+"#
+    )
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct LocalMetricsSource {
+    samples: std::sync::Mutex<std::collections::HashMap<String, RelaySample>>,
+}
+
+#[cfg(test)]
+impl LocalMetricsSource {
+    pub fn set(&self, relay_uuid: &str, sample: RelaySample) {
+        self.samples
+            .lock()
+            .unwrap()
+            .insert(relay_uuid.to_string(), sample);
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl MetricsSource for LocalMetricsSource {
+    async fn collect(&self, relay: &Relay) -> Result<RelaySample, String> {
+        self.samples
+            .lock()
+            .unwrap()
+            .get(&relay.uuid)
+            .cloned()
+            .ok_or("Stats agent unreachable".to_string())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct MetricsRepository {
+    pub pool: PgPool,
+}
+
+impl MetricsRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        relay_uuid: &str,
+        sampled_at: NaiveDateTime,
+        sample: &RelaySample,
+        rates: Option<(f64, f64, f64)>,
+    ) -> Result<MetricSample, sqlx::Error> {
+        sqlx::query_as::<_, MetricSample>(
+            "INSERT INTO relay_metrics
+            (relay_uuid, sampled_at, event_count, storage_bytes, connections, events_received,
+             bytes_in, bytes_out, events_per_sec, bytes_in_per_sec, bytes_out_per_sec)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *",
+        )
+        .bind(relay_uuid)
+        .bind(sampled_at)
+        .bind(sample.event_count)
+        .bind(sample.storage_bytes)
+        .bind(sample.connections)
+        .bind(sample.events_received)
+        .bind(sample.bytes_in)
+        .bind(sample.bytes_out)
+        .bind(rates.map(|rates| rates.0))
+        .bind(rates.map(|rates| rates.1))
+        .bind(rates.map(|rates| rates.2))
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_latest(&self, relay_uuid: &str) -> Option<MetricSample> {
+        sqlx::query_as::<_, MetricSample>(
+            "SELECT * FROM relay_metrics WHERE relay_uuid = $1 ORDER BY sampled_at DESC, id DESC LIMIT 1",
+        )
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Samples in `[from, to)` grouped into buckets of `bucket_secs`, aligned
+    /// to the Unix epoch.
+    pub async fn get_points(
+        &self,
+        relay_uuid: &str,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        bucket_secs: i64,
+    ) -> Vec<MetricPoint> {
+        sqlx::query_as::<_, MetricPoint>(
+            "SELECT
+                TO_TIMESTAMP(FLOOR(EXTRACT(EPOCH FROM sampled_at)::FLOAT8 / $4) * $4)
+                    AT TIME ZONE 'UTC' AS time,
+                COUNT(*) AS samples,
+                MAX(event_count) AS event_count,
+                MAX(storage_bytes) AS storage_bytes,
+                AVG(connections)::FLOAT8 AS connections,
+                MAX(connections) AS max_connections,
+                AVG(events_per_sec) AS events_per_sec,
+                AVG(bytes_in_per_sec) AS bytes_in_per_sec,
+                AVG(bytes_out_per_sec) AS bytes_out_per_sec
+            FROM relay_metrics
+            WHERE relay_uuid = $1 AND sampled_at >= $2 AND sampled_at < $3
+            GROUP BY 1 ORDER BY 1",
+        )
+        .bind(relay_uuid)
+        .bind(from)
+        .bind(to)
+        .bind(bucket_secs as f64)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn delete_before(&self, cutoff: NaiveDateTime) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM relay_metrics WHERE sampled_at < $1")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Collector
+// -----------------------------------------------------------------------------
+
+/// Growth per second of a counter. A counter that went down was reset by a
+/// restart, so everything it counts is new.
+fn per_sec(previous: i64, current: i64, secs: f64) -> f64 {
+    let delta = if current >= previous {
+        current - previous
+    } else {
+        current
+    };
+    delta as f64 / secs
+}
+
+/// Events, bytes in and bytes out per second between two samples, unless
+/// they are more than `max_gap` apart.
+pub fn rates(
+    previous: &MetricSample,
+    sample: &RelaySample,
+    now: NaiveDateTime,
+    max_gap: chrono::Duration,
+) -> Option<(f64, f64, f64)> {
+    let gap = now - previous.sampled_at;
+    let secs = gap.num_milliseconds() as f64 / 1000.0;
+    if secs <= 0.0 || gap > max_gap {
+        return None;
+    }
+
+    Some((
+        per_sec(previous.events_received, sample.events_received, secs),
+        per_sec(previous.bytes_in, sample.bytes_in, secs),
+        per_sec(previous.bytes_out, sample.bytes_out, secs),
+    ))
+}
+
+pub async fn collect_relay(
+    repo: &MetricsRepository,
+    source: &dyn MetricsSource,
+    config: &MetricsConfig,
+    relay: &Relay,
+) -> Result<MetricSample, String> {
+    let sample = source.collect(relay).await?;
+    let now = Utc::now().naive_utc();
+    let rates = repo
+        .get_latest(&relay.uuid)
+        .await
+        .and_then(|previous| rates(&previous, &sample, now, config.max_gap()));
+
+    repo.create(&relay.uuid, now, &sample, rates)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+pub async fn run_metrics_collector(
+    pool: PgPool,
+    source: Arc<dyn MetricsSource>,
+    config: MetricsConfig,
+) {
+    let repo = MetricsRepository::new(pool.clone());
+    let relay_repo = RelayRepository::new(pool);
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        let relays = relay_repo.get_active().await.into_iter().filter(|relay| {
+            relay.state == RelayState::Online
+                && !relay.instance_ip.is_empty()
+                && !relay.subdomain.is_empty()
+        });
+
+        futures::stream::iter(relays)
+            .for_each_concurrent(COLLECT_CONCURRENCY, |relay| {
+                let repo = repo.clone();
+                let source = source.clone();
+                async move {
+                    if let Err(err) = collect_relay(&repo, source.as_ref(), &config, &relay).await {
+                        eprintln!("Failed to collect metrics of relay {}: {}", relay.uuid, err);
+                    }
+                }
+            })
+            .await;
+
+        if let Err(err) = repo
+            .delete_before(Utc::now().naive_utc() - config.retention)
+            .await
+        {
+            eprintln!("Failed to prune relay metrics: {}", err);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_metrics_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+    query: web::Query<MetricsQuery>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let to = query.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let from = query.from.unwrap_or(to - chrono::Duration::days(1));
+    if from >= to {
+        return HttpResponse::BadRequest()
+            .json(ErrorResponse::new("from must be before to".to_string()));
+    }
+    if (to - from).num_seconds() / query.resolution.seconds() > MAX_POINTS {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "Too many points, use a coarser resolution".to_string(),
+        ));
+    }
+
+    let points = MetricsRepository::new(relay_repo.pool.clone())
+        .get_points(&relay.uuid, from, to, query.resolution.seconds())
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(RelayMetrics {
+        from,
+        to,
+        resolution: query.resolution,
+        points,
+    }))
+}
+
+/// Hands an instance's stats agent the token the collector will present,
+/// authenticated with the agent token from enrollment.
+async fn get_agent_metrics_token_handler(
+    req: HttpRequest,
+    relay_repo: web::Data<RelayRepository>,
+) -> impl Responder {
+    let relay_uuid = match bearer_token(&req) {
+        Some(token) => {
+            CertificateRepository::new(relay_repo.pool.clone())
+                .agent_relay(token)
+                .await
+        }
+        None => None,
+    };
+
+    match relay_uuid {
+        Some(relay_uuid) => HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(agent_metrics_token(&relay_uuid)),
+        None => {
+            HttpResponse::Unauthorized().json(ErrorResponse::new("Invalid agent token".to_string()))
+        }
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/relays/{uuid}/metrics").route(web::get().to(get_metrics_handler)))
+        .service(
+            web::resource("/agent/metrics-token")
+                .route(web::get().to(get_agent_metrics_token_handler)),
+        );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_rates_handle_counter_resets() {
+        let now = NaiveDate::from_ymd_opt(2023, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 1, 0)
+            .unwrap();
+        let previous = MetricSample {
+            id: 1,
+            relay_uuid: "relay".to_string(),
+            sampled_at: now - chrono::Duration::seconds(60),
+            event_count: 0,
+            storage_bytes: 0,
+            connections: 0,
+            events_received: 600,
+            bytes_in: 6000,
+            bytes_out: 60_000,
+            events_per_sec: None,
+            bytes_in_per_sec: None,
+            bytes_out_per_sec: None,
+        };
+        let sample = RelaySample {
+            events_received: 720,
+            bytes_in: 1200,
+            bytes_out: 66_000,
+            ..Default::default()
+        };
+
+        let max_gap = chrono::Duration::minutes(3);
+        assert_eq!(
+            rates(&previous, &sample, now, max_gap),
+            Some((2.0, 20.0, 100.0))
+        );
+        assert_eq!(
+            rates(&previous, &sample, previous.sampled_at, max_gap),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rates_skip_stale_gaps() {
+        let config = MetricsConfig {
+            interval: Duration::from_secs(60),
+            retention: chrono::Duration::days(30),
+        };
+        let now = NaiveDate::from_ymd_opt(2023, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let previous = MetricSample {
+            id: 1,
+            relay_uuid: "relay".to_string(),
+            sampled_at: now - chrono::Duration::hours(2),
+            event_count: 0,
+            storage_bytes: 0,
+            connections: 0,
+            events_received: 600,
+            bytes_in: 6000,
+            bytes_out: 60_000,
+            events_per_sec: None,
+            bytes_in_per_sec: None,
+            bytes_out_per_sec: None,
+        };
+        // The relay restarted during the gap and counted past its old totals,
+        // which looks like slow growth over two hours.
+        let sample = RelaySample {
+            events_received: 7800,
+            bytes_in: 78_000,
+            bytes_out: 780_000,
+            ..Default::default()
+        };
+        assert_eq!(per_sec(600, 7800, 7200.0), 1.0);
+
+        assert_eq!(rates(&previous, &sample, now, config.max_gap()), None);
+
+        // Gaps of up to three intervals still get rates.
+        let within = previous.sampled_at + chrono::Duration::seconds(180);
+        assert!(rates(&previous, &sample, within, config.max_gap()).is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_collect_and_query_metrics() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let repo = MetricsRepository::new(test_utils.pool.clone());
+        let source = LocalMetricsSource::default();
+        let config = MetricsConfig {
+            interval: Duration::from_secs(60),
+            retention: chrono::Duration::days(30),
+        };
+
+        assert!(collect_relay(&repo, &source, &config, &relay)
+            .await
+            .is_err());
+
+        source.set(
+            &relay.uuid,
+            RelaySample {
+                event_count: 100,
+                storage_bytes: 4096,
+                connections: 3,
+                events_received: 100,
+                bytes_in: 1000,
+                bytes_out: 5000,
+            },
+        );
+        let first = collect_relay(&repo, &source, &config, &relay)
+            .await
+            .unwrap();
+        assert_eq!(first.events_per_sec, None);
+        sqlx::query("UPDATE relay_metrics SET sampled_at = sampled_at - INTERVAL '10 seconds'")
+            .execute(&test_utils.pool)
+            .await
+            .unwrap();
+
+        source.set(
+            &relay.uuid,
+            RelaySample {
+                event_count: 150,
+                storage_bytes: 8192,
+                connections: 5,
+                events_received: 150,
+                bytes_in: 2000,
+                bytes_out: 9000,
+            },
+        );
+        let second = collect_relay(&repo, &source, &config, &relay)
+            .await
+            .unwrap();
+        let events_per_sec = second.events_per_sec.unwrap();
+        assert!(events_per_sec > 4.5 && events_per_sec <= 5.0);
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/relays/{}/metrics?resolution=day", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let metrics: DataResponse<RelayMetrics> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(metrics.data.resolution, Resolution::Day);
+        let samples: i64 = metrics.data.points.iter().map(|point| point.samples).sum();
+        assert_eq!(samples, 2);
+        let latest = metrics.data.points.last().unwrap();
+        assert_eq!(latest.event_count, 150);
+        assert_eq!(latest.max_connections, 5);
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!(
+                "/relays/{}/metrics?from=2023-11-01T00:00:00&to=2023-12-01T00:00:00&resolution=minute",
+                relay.uuid
+            ))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let other = test_utils.create_user().await;
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/relays/{}/metrics", relay.uuid))
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", generate_jwt_by_hex(&other.hexpub).unwrap()),
+            ))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_agent_metrics_token() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let certificates = CertificateRepository::new(test_utils.pool.clone());
+        certificates
+            .create_enrollment(&relay.uuid, "enrollment")
+            .await
+            .unwrap();
+        let agent_token = certificates.enroll("enrollment").await.unwrap().unwrap();
+
+        // Nothing secret is baked into the instance's user data.
+        assert!(!stats_agent_bootstrap("https://api.example").contains(agent_secret()));
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .configure(configure_routes),
+        )
+        .await;
+        let fetch = |token: &str| {
+            actix_web::test::TestRequest::get()
+                .uri("/agent/metrics-token")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let body = actix_web::test::call_and_read_body(&app, fetch(&agent_token)).await;
+        assert_eq!(body, agent_metrics_token(&relay.uuid).as_bytes());
+        assert_ne!(
+            agent_metrics_token(&relay.uuid),
+            agent_metrics_token("another-relay")
+        );
+
+        let resp = actix_web::test::call_service(&app, fetch("enrollment")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,13 +1,28 @@
-use actix_web::{dev, Error, FromRequest, HttpRequest};
-use futures::future::{ready, Ready};
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use actix_web::dev::{
+    forward_ready, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{dev, web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Method};
+use futures::{stream, Stream, StreamExt};
 use serde::Deserialize;
+use std::pin::Pin;
+use std::rc::Rc;
 
-use crate::auth::Claims;
+use crate::api_key::{required_scope, ApiKeyRepository, API_KEY_PREFIX};
+use crate::auth::authenticate_nip98;
+use crate::token::{signing_keys, TokenRepository};
+use crate::user::{UserRepository, UserRole};
+use crate::util::bech32_encode;
 
 pub struct AuthorizationService {
     hexpub: Option<String>,
     npub: Option<String>,
+    jti: Option<String>,
+    exp: Option<usize>,
+    role: UserRole,
 }
 
 impl AuthorizationService {
@@ -18,32 +33,429 @@ impl AuthorizationService {
     pub fn npub(&self) -> Option<&String> {
         self.npub.as_ref()
     }
+
+    /// Id of the JWT used, if the request wasn't signed with NIP-98.
+    pub fn jti(&self) -> Option<&String> {
+        self.jti.as_ref()
+    }
+
+    pub fn exp(&self) -> Option<usize> {
+        self.exp
+    }
+
+    /// Whether the caller has `role` or a more privileged one.
+    pub fn has_role(&self, role: UserRole) -> bool {
+        self.role >= role
+    }
+
+    /// Whether the caller owns the resources of `npub`, or holds `role`.
+    pub fn is_self_or(&self, npub: &str, role: UserRole) -> bool {
+        self.npub.as_deref() == Some(npub) || self.has_role(role)
+    }
+}
+
+impl AuthorizationService {
+    async fn from_jwt(req: &HttpRequest, token: &str) -> Result<AuthorizationService, Error> {
+        let claims = signing_keys()
+            .decode(token)
+            .map_err(actix_web::error::ErrorUnauthorized)?;
+
+        let token_repo = req
+            .app_data::<web::Data<TokenRepository>>()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("Token store not configured"))?;
+        let revoked = token_repo.is_revoked(&claims.jti).await.map_err(|err| {
+            eprintln!("Failed to check token revocation: {}", err);
+            actix_web::error::ErrorInternalServerError("Failed to check token")
+        })?;
+        if revoked {
+            return Err(actix_web::error::ErrorUnauthorized("Token revoked"));
+        }
+
+        // The role in the token may be stale, e.g. for a demoted admin.
+        let role = match user_repo(req) {
+            Some(user_repo) => user_repo.get_role(&claims.hexpub).await,
+            None => UserRole::User,
+        };
+
+        Ok(AuthorizationService {
+            hexpub: Some(claims.hexpub),
+            npub: Some(claims.npub),
+            jti: Some(claims.jti).filter(|jti| !jti.is_empty()),
+            role,
+            exp: Some(claims.exp),
+        })
+    }
+
+    /// Identifies the caller from whichever credential they present.
+    async fn authenticate(req: &HttpRequest) -> Result<AuthorizationService, Error> {
+        let auth_header = req
+            .headers()
+            .get("Authorization")
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing token"))?
+            .to_str()
+            .unwrap_or("")
+            .trim()
+            .to_string();
+
+        if auth_header.starts_with("Nostr ") {
+            let body = req
+                .extensions()
+                .get::<BufferedBody>()
+                .map(|body| body.0.clone())
+                .unwrap_or_default();
+            let event = authenticate_nip98(req, &body).await?;
+            let hexpub = event.pubkey.to_string();
+            let npub = bech32_encode(&hexpub)
+                .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid pubkey"))?;
+            let role = match req.app_data::<web::Data<UserRepository>>() {
+                Some(user_repo) => user_repo.get_role(&hexpub).await,
+                None => UserRole::User,
+            };
+
+            return Ok(AuthorizationService {
+                hexpub: Some(hexpub),
+                npub: Some(npub),
+                jti: None,
+                exp: None,
+                role,
+            });
+        }
+
+        let token = auth_header
+            .strip_prefix("Bearer ")
+            .unwrap_or(&auth_header)
+            .trim();
+        if token.starts_with(API_KEY_PREFIX) {
+            return AuthorizationService::from_api_key(req, token).await;
+        }
+        AuthorizationService::from_jwt(req, token).await
+    }
+
+    /// API keys act with the `user` role and only on routes their scopes
+    /// cover.
+    async fn from_api_key(req: &HttpRequest, key: &str) -> Result<AuthorizationService, Error> {
+        let api_key_repo = req
+            .app_data::<web::Data<ApiKeyRepository>>()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("API keys not configured"))?;
+        let api_key = api_key_repo
+            .authenticate(key)
+            .await
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid API key"))?;
+
+        match required_scope(req.method(), req.path()) {
+            Some(scope) if api_key.scopes.iter().any(|granted| granted == scope) => {}
+            Some(scope) => {
+                return Err(actix_web::error::ErrorForbidden(format!(
+                    "API key is missing the {} scope",
+                    scope
+                )))
+            }
+            None => {
+                return Err(actix_web::error::ErrorForbidden(
+                    "API keys can't access this route",
+                ))
+            }
+        }
+
+        Ok(AuthorizationService {
+            hexpub: Some(api_key.hexpub),
+            npub: Some(api_key.npub),
+            jti: None,
+            exp: None,
+            role: UserRole::User,
+        })
+    }
+}
+
+/// The user repository, or one on the token store's pool for apps that only
+/// configure the latter.
+fn user_repo(req: &HttpRequest) -> Option<UserRepository> {
+    req.app_data::<web::Data<UserRepository>>()
+        .map(|user_repo| user_repo.get_ref().clone())
+        .or_else(|| {
+            req.app_data::<web::Data<TokenRepository>>()
+                .map(|token_repo| UserRepository::new(token_repo.pool.clone()))
+        })
 }
 
+/// Closed accounts lose access right away, whatever credential they present.
+async fn reject_closed(
+    req: &HttpRequest,
+    auth: AuthorizationService,
+) -> Result<AuthorizationService, Error> {
+    let (Some(user_repo), Some(hexpub)) = (user_repo(req), auth.hexpub()) else {
+        return Ok(auth);
+    };
+
+    match user_repo.get_by_hexpub(hexpub).await {
+        Some(user) if user.deleted_at.is_some() => {
+            Err(actix_web::error::ErrorUnauthorized("Account closed"))
+        }
+        _ => Ok(auth),
+    }
+}
+
+/// Accepts `Bearer <jwt>`, a bare JWT, `Bearer <api key>`, or a NIP-98 signed
+/// `Nostr <event>`.
 impl FromRequest for AuthorizationService {
     type Error = Error;
-    type Future = Ready<Result<AuthorizationService, Error>>;
+    type Future = LocalBoxFuture<'static, Result<AuthorizationService, Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
-        let auth_header = req.headers().get("Authorization");
-
-        if let Some(auth_header) = auth_header {
-            let secret = dotenvy::var("JWT_SECRET").unwrap();
-            let token = auth_header.to_str().unwrap_or("");
-            let decoding_key = DecodingKey::from_secret(secret.as_ref());
-
-            match decode::<Claims>(token, &decoding_key, &Validation::default()) {
-                Ok(token_data) => {
-                    // Extract the 'sub' field from the token and store it in AuthorizationService
-                    let hexpub = token_data.claims.hexpub;
-                    let npub = token_data.claims.npub;
-                    let auth_service = AuthorizationService { hexpub: Some(hexpub), npub: Some(npub) };
-                    ready(Ok(auth_service))
+        let req = req.clone();
+
+        Box::pin(async move {
+            let auth = AuthorizationService::authenticate(&req).await?;
+            reject_closed(&req, auth).await
+        })
+    }
+}
+
+/// Requires the caller to have at least the `support` role.
+pub struct Staff(pub AuthorizationService);
+
+/// Requires the caller to have the `admin` role.
+pub struct Admin(pub AuthorizationService);
+
+fn require_role(
+    req: &HttpRequest,
+    payload: &mut dev::Payload,
+    role: UserRole,
+) -> LocalBoxFuture<'static, Result<AuthorizationService, Error>> {
+    let auth = AuthorizationService::from_request(req, payload);
+
+    Box::pin(async move {
+        let auth = auth.await?;
+        if !auth.has_role(role) {
+            return Err(actix_web::error::ErrorForbidden("Insufficient role"));
+        }
+        Ok(auth)
+    })
+}
+
+impl std::ops::Deref for Staff {
+    type Target = AuthorizationService;
+
+    fn deref(&self) -> &AuthorizationService {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for Admin {
+    type Target = AuthorizationService;
+
+    fn deref(&self) -> &AuthorizationService {
+        &self.0
+    }
+}
+
+impl FromRequest for Staff {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Staff, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let auth = require_role(req, payload, UserRole::Support);
+        Box::pin(async move { auth.await.map(Staff) })
+    }
+}
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Admin, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let auth = require_role(req, payload, UserRole::Admin);
+        Box::pin(async move { auth.await.map(Admin) })
+    }
+}
+
+/// The body of a NIP-98 signed request, kept so `AuthorizationService` can
+/// check its `payload` tag.
+#[derive(Clone)]
+pub struct BufferedBody(pub Bytes);
+
+/// Largest request body the app accepts, buffered or extracted as JSON.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Routes that take a body but never a NIP-98 signature.
+const UNSIGNED_ROUTES: [&str; 3] = ["/login/nip46", "/nodeless_webhook", "/token/refresh"];
+
+/// Whether the request is NIP-98 signed and headed for a route that checks
+/// the signature against its body.
+fn accepts_signed_body(req: &ServiceRequest) -> bool {
+    let signed = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().starts_with("Nostr "));
+    let has_body = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+
+    signed
+        && has_body
+        && req
+            .match_pattern()
+            .is_some_and(|pattern| !UNSIGNED_ROUTES.contains(&pattern.as_str()))
+}
+
+/// Buffers the body of NIP-98 signed requests, up to `MAX_BODY_BYTES`, and
+/// hands a copy back to the handler, since the payload stream can only be
+/// read once. Runs before authentication, so larger bodies get a 413 before
+/// anything is kept.
+pub struct Nip98Body;
+
+impl<S, B> Transform<S, ServiceRequest> for Nip98Body
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = Nip98BodyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(Nip98BodyMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct Nip98BodyMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for Nip98BodyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            if accepts_signed_body(&req) {
+                let too_large =
+                    || actix_web::error::ErrorPayloadTooLarge("Request body is too large");
+                let declared = req
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<usize>().ok());
+                if declared.is_some_and(|length| length > MAX_BODY_BYTES) {
+                    return Err(too_large());
+                }
+
+                let mut payload = req.take_payload();
+                let mut body = BytesMut::new();
+                while let Some(chunk) = payload.next().await {
+                    let chunk = chunk?;
+                    if body.len() + chunk.len() > MAX_BODY_BYTES {
+                        return Err(too_large());
+                    }
+                    body.extend_from_slice(&chunk);
                 }
-                Err(_) => ready(Err(actix_web::error::ErrorUnauthorized("Invalid token"))),
+                let body = body.freeze();
+
+                req.extensions_mut().insert(BufferedBody(body.clone()));
+                let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+                    Box::pin(stream::once(ready(Ok(body))));
+                req.set_payload(dev::Payload::from(stream));
             }
-        } else {
-            ready(Err(actix_web::error::ErrorUnauthorized("Missing token")))
+
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{generate_jwt_by_hex, nip98_header};
+    use crate::replay_guard::{MemoryReplayGuard, ReplayGuard};
+    use crate::util::TestUtils;
+    use actix_web::{test, web, App, HttpResponse};
+    use nostr::Keys;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    async fn whoami(auth: AuthorizationService, body: web::Json<Value>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "hexpub": auth.hexpub(),
+            "body": body.into_inner(),
+        }))
+    }
+
+    #[actix_web::test]
+    async fn test_authorization_schemes() {
+        let test_utils = TestUtils::new().await;
+        let guard: Arc<dyn ReplayGuard> = Arc::new(MemoryReplayGuard::new(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(Nip98Body)
+                .app_data(web::Data::from(guard))
+                .app_data(web::Data::new(TokenRepository::new(test_utils.pool.clone())))
+                .route("/whoami", web::post().to(whoami)),
+        )
+        .await;
+
+        let keys = Keys::generate();
+        let hexpub = keys.public_key().to_string();
+        let body = json!({ "name": "relay" });
+        let body_bytes = serde_json::to_vec(&body).unwrap();
+        let jwt = generate_jwt_by_hex(&hexpub).unwrap();
+
+        let authorizations = [
+            format!("Bearer {}", jwt),
+            jwt,
+            nip98_header(&keys, "POST", "http://localhost:8080/whoami", Some(&body_bytes)),
+        ];
+        for authorization in authorizations {
+            let req = test::TestRequest::post()
+                .uri("/whoami")
+                .insert_header(("Authorization", authorization.clone()))
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(body_bytes.clone())
+                .to_request();
+            let resp: Value = test::call_and_read_body_json(&app, req).await;
+
+            assert_eq!(resp["hexpub"], json!(hexpub), "{}", authorization);
+            assert_eq!(resp["body"], body);
+        }
+
+        let rejected = [
+            "Bearer not-a-jwt".to_string(),
+            nip98_header(&keys, "POST", "http://localhost:8080/whoami", Some(b"{}")),
+            nip98_header(&keys, "GET", "http://localhost:8080/whoami", None),
+        ];
+        for authorization in rejected {
+            let req = test::TestRequest::post()
+                .uri("/whoami")
+                .insert_header(("Authorization", authorization.clone()))
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+
+            assert_eq!(resp.status(), 401, "{}", authorization);
         }
+
+        let oversized = vec![b' '; MAX_BODY_BYTES + 1];
+        let req = test::TestRequest::post()
+            .uri("/whoami")
+            .insert_header((
+                "Authorization",
+                nip98_header(&keys, "POST", "http://localhost:8080/whoami", Some(&oversized)),
+            ))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(oversized)
+            .to_request();
+        let resp = test::try_call_service(&app, req).await;
+        assert_eq!(resp.err().unwrap().as_response_error().status_code(), 413);
     }
 }
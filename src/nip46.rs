@@ -0,0 +1,472 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use nostr::nips::nip04;
+use nostr::nips::nip46::{Message, NostrConnectURI, Request};
+use nostr::secp256k1::schnorr::Signature;
+use nostr::{Event, EventBuilder, Filter, Keys, Kind, Tag, Timestamp, UnsignedEvent, Url};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::{
+    audit::{audit, AuditAction},
+    auth::account_closed,
+    relay_client::RelayClient,
+    token::{issue_tokens, TokenRepository},
+    user::{register_user, UserRepository},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "nip46_session_status", rename_all = "lowercase")]
+pub enum Nip46SessionStatus {
+    Pending,
+    Completed,
+    Consumed,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Nip46Session {
+    pub uuid: String,
+    pub session_pubkey: String,
+    pub challenge: String,
+    pub status: Nip46SessionStatus,
+    pub hexpub: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Nip46LoginResponse {
+    pub session_uuid: String,
+    /// `nostrconnect://` URI for the user to open in their signer.
+    pub uri: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Nip46Config {
+    pub relay_url: String,
+    pub app_name: String,
+    pub timeout: Duration,
+}
+
+impl Nip46Config {
+    pub fn from_env() -> Self {
+        let timeout = dotenvy::var("NIP46_LOGIN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(300);
+
+        Nip46Config {
+            relay_url: dotenvy::var("NIP46_RELAY_URL")
+                .unwrap_or_else(|_| "wss://relay.nsecbunker.com".to_string()),
+            app_name: dotenvy::var("NIP46_APP_NAME").unwrap_or_else(|_| "relaying.io".to_string()),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Nip46SessionRepository {
+    pub pool: PgPool,
+}
+
+impl Nip46SessionRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        session_pubkey: &str,
+        challenge: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<Nip46Session, sqlx::Error> {
+        sqlx::query_as::<_, Nip46Session>(
+            "INSERT INTO nip46_sessions (uuid, session_pubkey, challenge, expires_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(session_pubkey)
+        .bind(challenge)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_one(&self, uuid: &str) -> Option<Nip46Session> {
+        sqlx::query_as::<_, Nip46Session>("SELECT * FROM nip46_sessions WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn complete(&self, uuid: &str, hexpub: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE nip46_sessions
+            SET status = 'completed', hexpub = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE uuid = $1 AND status = 'pending'",
+        )
+        .bind(uuid)
+        .bind(hexpub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks a completed session as used and returns it, so its tokens can
+    /// only be collected once.
+    pub async fn consume(&self, uuid: &str) -> Option<Nip46Session> {
+        sqlx::query_as::<_, Nip46Session>(
+            "UPDATE nip46_sessions
+            SET status = 'consumed', updated_at = CURRENT_TIMESTAMP
+            WHERE uuid = $1 AND status = 'completed'
+            RETURNING *",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Creates a session keypair, starts listening for the signer on the
+/// configured relay and returns the session with its `nostrconnect://` URI.
+pub async fn start_login(
+    session_repo: &Nip46SessionRepository,
+    relay_client: Arc<dyn RelayClient>,
+    config: &Nip46Config,
+) -> Result<(Nip46Session, String), String> {
+    let keys = Keys::generate();
+    let relay_url =
+        Url::parse(&config.relay_url).map_err(|err| format!("Invalid NIP-46 relay: {}", err))?;
+
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    let challenge = hex::encode(challenge);
+
+    // Subscribe before handing out the URI so the signer's connect request
+    // can't be missed.
+    let filter = Filter::new()
+        .kind(Kind::NostrConnect)
+        .pubkey(keys.public_key())
+        .since(Timestamp::now());
+    let events = relay_client
+        .subscribe(&config.relay_url, vec![filter])
+        .await?;
+
+    let expires_at = chrono::Utc::now().naive_utc()
+        + chrono::Duration::from_std(config.timeout).unwrap_or(chrono::Duration::minutes(5));
+    let session = session_repo
+        .create(&keys.public_key().to_string(), &challenge, expires_at)
+        .await
+        .map_err(|err| format!("Failed to create NIP-46 session: {}", err))?;
+
+    let uri = NostrConnectURI::new(keys.public_key(), relay_url, &config.app_name).to_string();
+
+    tokio::spawn({
+        let session_repo = session_repo.clone();
+        let session = session.clone();
+        let relay_url = config.relay_url.clone();
+        let timeout = config.timeout;
+
+        async move {
+            let signed_in = tokio::time::timeout(
+                timeout,
+                wait_for_signer(relay_client.as_ref(), &relay_url, &keys, &session, events),
+            )
+            .await;
+
+            match signed_in {
+                Ok(Ok(hexpub)) => {
+                    if let Err(err) = session_repo.complete(&session.uuid, &hexpub).await {
+                        eprintln!(
+                            "Failed to complete NIP-46 session {}: {}",
+                            session.uuid, err
+                        );
+                    }
+                }
+                Ok(Err(err)) => eprintln!("NIP-46 session {} failed: {}", session.uuid, err),
+                Err(_) => {}
+            }
+        }
+    });
+
+    Ok((session, uri))
+}
+
+/// Runs our side of the handshake: waits for the signer's `connect`, asks it
+/// to sign the session challenge and returns its pubkey once the signature
+/// checks out.
+pub async fn wait_for_signer(
+    relay_client: &dyn RelayClient,
+    relay_url: &str,
+    keys: &Keys,
+    session: &Nip46Session,
+    mut events: mpsc::Receiver<Event>,
+) -> Result<String, String> {
+    let secret_key = keys.secret_key().map_err(|err| err.to_string())?;
+    let mut pending: Option<(String, UnsignedEvent)> = None;
+
+    while let Some(event) = events.recv().await {
+        let message = nip04::decrypt(&secret_key, &event.pubkey, &event.content)
+            .ok()
+            .and_then(|content| Message::from_json(content).ok());
+
+        match message {
+            Some(message @ Message::Request { .. }) => {
+                let signer = match message.to_request() {
+                    Ok(Request::Connect(signer)) => signer,
+                    _ => continue,
+                };
+
+                let challenge = EventBuilder::new(
+                    Kind::Authentication,
+                    "",
+                    &[Tag::Challenge(session.challenge.clone())],
+                )
+                .to_unsigned_event(signer);
+                let request = Message::request(Request::SignEvent(challenge.clone()));
+                let request_id = request.id();
+
+                let reply = EventBuilder::nostr_connect(keys, event.pubkey, request)
+                    .and_then(|builder| builder.to_event(keys))
+                    .map_err(|err| format!("Failed to build sign request: {}", err))?;
+                relay_client.publish(relay_url, &reply).await?;
+
+                pending = Some((request_id, challenge));
+            }
+            Some(Message::Response { id, result, error }) => {
+                let challenge = match &pending {
+                    Some((request_id, challenge)) if *request_id == id => challenge.clone(),
+                    _ => continue,
+                };
+
+                if let Some(error) = error {
+                    return Err(format!("Signer refused to sign: {}", error));
+                }
+
+                let signature: Signature = result
+                    .and_then(|result| serde_json::from_value(result).ok())
+                    .ok_or("Signer returned no signature")?;
+                // The challenge names the signer's pubkey, so this only
+                // succeeds if that key signed it.
+                let signed = challenge
+                    .add_signature(signature)
+                    .map_err(|_| "Invalid challenge signature".to_string())?;
+
+                return Ok(signed.pubkey.to_string());
+            }
+            None => continue,
+        }
+    }
+
+    Err("Relay subscription closed".to_string())
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn start_login_handler(
+    session_repo: web::Data<Nip46SessionRepository>,
+    relay_client: web::Data<dyn RelayClient>,
+) -> impl Responder {
+    let config = Nip46Config::from_env();
+
+    match start_login(&session_repo, relay_client.into_inner(), &config).await {
+        Ok((session, uri)) => HttpResponse::Created().json(DataResponse::new(Nip46LoginResponse {
+            session_uuid: session.uuid,
+            uri,
+            expires_at: session.expires_at,
+        })),
+        Err(err) => {
+            eprintln!("{}", err);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(err))
+        }
+    }
+}
+
+/// Polled by the client until the signer has answered. Returns the same
+/// tokens as `/login` exactly once.
+async fn poll_login_handler(
+    session_repo: web::Data<Nip46SessionRepository>,
+    user_repo: web::Data<UserRepository>,
+    token_repo: web::Data<TokenRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let uuid = path.into_inner();
+
+    if let Some(session) = session_repo.consume(&uuid).await {
+        let hexpub = session.hexpub.unwrap_or_default();
+        let tokens = match register_user(&user_repo, &hexpub).await {
+            Ok(user) if user.deleted_at.is_some() => return account_closed(&user),
+            Ok(user) => {
+                audit(&user_repo.pool, &user.npub, AuditAction::Login, None).await;
+                issue_tokens(&token_repo, &hexpub).await
+            }
+            Err(err) => Err(err),
+        };
+
+        return match tokens {
+            Ok(tokens) => HttpResponse::Ok().json(tokens),
+            Err(err) => {
+                eprintln!("{}", err);
+                HttpResponse::InternalServerError().json(ErrorResponse::new(err))
+            }
+        };
+    }
+
+    match session_repo.get_one(&uuid).await {
+        Some(session)
+            if session.status == Nip46SessionStatus::Pending
+                && session.expires_at > chrono::Utc::now().naive_utc() =>
+        {
+            HttpResponse::Accepted().json(DataResponse::new(session.status))
+        }
+        Some(_) => {
+            HttpResponse::Gone().json(ErrorResponse::new("Login session expired".to_string()))
+        }
+        None => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Login session not found".to_string()))
+        }
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/login/nip46").route(web::post().to(start_login_handler)))
+        .service(web::resource("/login/nip46/{uuid}").route(web::get().to(poll_login_handler)));
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::LoginResponse;
+    use crate::relay_client::LocalRelayClient;
+    use crate::util::TestUtils;
+    use actix_web::{web::Data, App};
+    use std::str::FromStr;
+
+    const RELAY: &str = "wss://signer.example.com";
+
+    /// Answers the backend like a remote signer holding `keys` would.
+    async fn run_signer(
+        client: LocalRelayClient,
+        keys: Keys,
+        app_pubkey: nostr::secp256k1::XOnlyPublicKey,
+    ) {
+        let mut requests = client
+            .subscribe(
+                RELAY,
+                vec![Filter::new()
+                    .kind(Kind::NostrConnect)
+                    .pubkey(keys.public_key())],
+            )
+            .await
+            .unwrap();
+
+        let connect = Message::request(Request::Connect(keys.public_key()));
+        let event = EventBuilder::nostr_connect(&keys, app_pubkey, connect)
+            .unwrap()
+            .to_event(&keys)
+            .unwrap();
+        client.publish(RELAY, &event).await.unwrap();
+
+        while let Some(event) = requests.recv().await {
+            let content =
+                nip04::decrypt(&keys.secret_key().unwrap(), &event.pubkey, &event.content).unwrap();
+            let request = Message::from_json(content).unwrap();
+            if let Some(response) = request.generate_response(&keys).unwrap() {
+                let event = EventBuilder::nostr_connect(&keys, event.pubkey, response)
+                    .unwrap()
+                    .to_event(&keys)
+                    .unwrap();
+                client.publish(RELAY, &event).await.unwrap();
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_nip46_login() {
+        let test_utils = TestUtils::new().await;
+        let session_repo = Nip46SessionRepository::new(test_utils.pool.clone());
+        let token_repo = TokenRepository::new(test_utils.pool.clone());
+        let client = LocalRelayClient::new();
+        let relay_client: Arc<dyn RelayClient> = Arc::new(client.clone());
+        let config = Nip46Config {
+            relay_url: RELAY.to_string(),
+            app_name: "relaying.io".to_string(),
+            timeout: Duration::from_secs(5),
+        };
+
+        let (session, uri) = start_login(&session_repo, relay_client, &config)
+            .await
+            .unwrap();
+        let uri = NostrConnectURI::from_str(&uri).unwrap();
+        assert_eq!(uri.public_key.to_string(), session.session_pubkey);
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(session_repo.clone()))
+                .app_data(Data::new(token_repo))
+                .app_data(Data::new(test_utils.user_repo.clone()))
+                .configure(configure_routes),
+        )
+        .await;
+        let poll = || {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("/login/nip46/{}", session.uuid))
+                .to_request()
+        };
+
+        let resp = actix_web::test::call_service(&app, poll()).await;
+        assert_eq!(resp.status(), 202);
+
+        let signer_keys = Keys::generate();
+        tokio::spawn(run_signer(client, signer_keys.clone(), uri.public_key));
+
+        for _ in 0..50 {
+            let session = session_repo.get_one(&session.uuid).await.unwrap();
+            if session.status == Nip46SessionStatus::Completed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let resp = actix_web::test::call_service(&app, poll()).await;
+        assert_eq!(resp.status(), 200);
+        let tokens: LoginResponse = actix_web::test::read_body_json(resp).await;
+        let claims = crate::token::signing_keys().decode(&tokens.token).unwrap();
+        assert_eq!(claims.hexpub, signer_keys.public_key().to_string());
+        let npub = crate::util::bech32_encode(&claims.hexpub).unwrap();
+        assert!(test_utils.user_repo.user_exists(&npub).await);
+
+        // Tokens are handed out once.
+        let resp = actix_web::test::call_service(&app, poll()).await;
+        assert_eq!(resp.status(), 410);
+    }
+}
@@ -0,0 +1,1274 @@
+use actix_web::{web, HttpResponse, Responder};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use nostr::prelude::FromBech32;
+use nostr::{Event, EventBuilder, Keys};
+use rand::RngCore;
+use secp256k1::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    middleware::AuthorizationService,
+    organization::{OrganizationRepository, OrganizationRole},
+    outbound::{public_client, resolve_public},
+    relay::{Relay, RelayRepository},
+    relay_client::{publish_to_any, service_keys, RelayClient},
+    relay_order::{calculate_hmac_sha256, RelayOrder},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Days before `expires_at` at which the operator is reminded.
+const EXPIRY_NOTICE_DAYS: [i64; 3] = [7, 3, 1];
+/// Sends attempted before a delivery is marked failed.
+const MAX_ATTEMPTS: i32 = 6;
+/// Delay before the first retry, doubled after every failed attempt.
+const RETRY_BASE_SECS: i64 = 60;
+/// Header carrying the hex HMAC-SHA256 of a webhook body, keyed with the
+/// user's webhook secret.
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "relaying-signature";
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "notification_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    OrderPaid,
+    RelayOnline,
+    RelayOffline,
+    ProvisioningFailed,
+    RelayExpiring,
+    RelayTerminated,
+}
+
+impl NotificationKind {
+    /// Email subject line.
+    pub fn subject(&self) -> &'static str {
+        match self {
+            NotificationKind::OrderPaid => "Payment received",
+            NotificationKind::RelayOnline => "Your relay is online",
+            NotificationKind::RelayOffline => "Your relay is offline",
+            NotificationKind::ProvisioningFailed => "Relay setup failed",
+            NotificationKind::RelayExpiring => "Your relay expires soon",
+            NotificationKind::RelayTerminated => "Your relay was terminated",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "notification_channel", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationChannel {
+    /// Encrypted DM from the service key.
+    Nostr,
+    Email,
+    /// Signed HTTP POST to the user's endpoint.
+    Webhook,
+}
+
+impl NotificationChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationChannel::Nostr => "nostr",
+            NotificationChannel::Email => "email",
+            NotificationChannel::Webhook => "webhook",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "notification_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationStatus {
+    Pending,
+    Sent,
+    Failed,
+    /// The user turned this kind of notification off.
+    Skipped,
+}
+
+/// A message to send, keyed so the same event never notifies twice.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub npub: String,
+    pub kind: NotificationKind,
+    pub dedupe_key: String,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn order_paid(order: &RelayOrder) -> Self {
+        Notification {
+            npub: order.user_npub.clone(),
+            kind: NotificationKind::OrderPaid,
+            dedupe_key: format!("order_paid:{}", order.uuid),
+            message: format!(
+                "Payment of {} sats received for order {}. Your relay is being set up.",
+                order.amount, order.uuid
+            ),
+        }
+    }
+
+    /// `url` is where the relay is reachable, see `announcement_url`.
+    pub fn relay_online(relay: &Relay, url: &str) -> Self {
+        Notification {
+            npub: relay.user_npub.clone(),
+            kind: NotificationKind::RelayOnline,
+            dedupe_key: format!("relay_online:{}", relay.uuid),
+            message: format!(
+                "Your relay \"{}\" is online at {}.",
+                relay.name, url
+            ),
+        }
+    }
+
+    /// Keyed by when the relay went offline, so every outage is reported.
+    pub fn relay_offline(relay: &Relay) -> Self {
+        Notification {
+            npub: relay.user_npub.clone(),
+            kind: NotificationKind::RelayOffline,
+            dedupe_key: format!(
+                "relay_offline:{}:{}",
+                relay.uuid,
+                relay.updated_at.timestamp()
+            ),
+            message: format!(
+                "Your relay \"{}\" stopped responding at {} UTC.",
+                relay.name,
+                relay.updated_at.format("%Y-%m-%d %H:%M")
+            ),
+        }
+    }
+
+    pub fn provisioning_failed(npub: &str, order_uuid: &str, name: &str) -> Self {
+        Notification {
+            npub: npub.to_string(),
+            kind: NotificationKind::ProvisioningFailed,
+            dedupe_key: format!("provisioning_failed:{}", order_uuid),
+            message: format!(
+                "We couldn't set up your relay \"{}\" for order {}. Please contact support.",
+                name, order_uuid
+            ),
+        }
+    }
+
+    /// `notice_days` is the reminder being sent; renewals reset the reminders
+    /// since the key includes `expires_at`.
+    pub fn relay_expiring(relay: &Relay, notice_days: i64, days_left: i64) -> Self {
+        Notification {
+            npub: relay.user_npub.clone(),
+            kind: NotificationKind::RelayExpiring,
+            dedupe_key: format!(
+                "relay_expiring:{}:{}:{}",
+                relay.uuid,
+                relay.expires_at.timestamp(),
+                notice_days
+            ),
+            message: format!(
+                "Your relay \"{}\" expires in {} day{}, on {} UTC. Renew it to keep it running.",
+                relay.name,
+                days_left,
+                if days_left == 1 { "" } else { "s" },
+                relay.expires_at.format("%Y-%m-%d %H:%M")
+            ),
+        }
+    }
+
+    pub fn relay_terminated(relay: &Relay) -> Self {
+        Notification {
+            npub: relay.user_npub.clone(),
+            kind: NotificationKind::RelayTerminated,
+            dedupe_key: format!("relay_terminated:{}", relay.uuid),
+            message: format!("Your relay \"{}\" was terminated.", relay.name),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct NotificationDelivery {
+    pub id: i64,
+    pub npub: String,
+    pub kind: NotificationKind,
+    pub channel: NotificationChannel,
+    /// Email address or webhook URL at the time the notification was queued.
+    pub destination: Option<String>,
+    pub dedupe_key: String,
+    pub message: String,
+    pub status: NotificationStatus,
+    pub attempts: i32,
+    /// Failed deliveries aren't retried before this.
+    pub next_attempt_at: Option<NaiveDateTime>,
+    pub event_id: Option<String>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+/// Which notifications the user wants and where. Everything is on by
+/// default, delivered as nostr DMs only.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct NotificationPreferences {
+    pub npub: String,
+    pub order_paid: bool,
+    pub relay_online: bool,
+    pub relay_offline: bool,
+    pub provisioning_failed: bool,
+    pub relay_expiring: bool,
+    pub relay_terminated: bool,
+    pub nostr_enabled: bool,
+    pub email: Option<String>,
+    pub email_enabled: bool,
+    pub webhook_url: Option<String>,
+    /// Generated the first time a webhook URL is set.
+    pub webhook_secret: Option<String>,
+    pub webhook_enabled: bool,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl NotificationPreferences {
+    pub fn default_for(npub: &str) -> Self {
+        NotificationPreferences {
+            npub: npub.to_string(),
+            order_paid: true,
+            relay_online: true,
+            relay_offline: true,
+            provisioning_failed: true,
+            relay_expiring: true,
+            relay_terminated: true,
+            nostr_enabled: true,
+            email: None,
+            email_enabled: false,
+            webhook_url: None,
+            webhook_secret: None,
+            webhook_enabled: false,
+            updated_at: None,
+        }
+    }
+
+    pub fn allows(&self, kind: NotificationKind) -> bool {
+        match kind {
+            NotificationKind::OrderPaid => self.order_paid,
+            NotificationKind::RelayOnline => self.relay_online,
+            NotificationKind::RelayOffline => self.relay_offline,
+            NotificationKind::ProvisioningFailed => self.provisioning_failed,
+            NotificationKind::RelayExpiring => self.relay_expiring,
+            NotificationKind::RelayTerminated => self.relay_terminated,
+        }
+    }
+
+    /// Enabled channels with their destination.
+    pub fn channels(&self) -> Vec<(NotificationChannel, Option<String>)> {
+        let mut channels = Vec::new();
+        if self.nostr_enabled {
+            channels.push((NotificationChannel::Nostr, None));
+        }
+        if self.email_enabled && self.email.is_some() {
+            channels.push((NotificationChannel::Email, self.email.clone()));
+        }
+        if self.webhook_enabled && self.webhook_url.is_some() {
+            channels.push((NotificationChannel::Webhook, self.webhook_url.clone()));
+        }
+        channels
+    }
+}
+
+/// Fields left out keep their current value.
+#[derive(Debug, Deserialize, Default)]
+pub struct UpdatePreferencesDto {
+    pub order_paid: Option<bool>,
+    pub relay_online: Option<bool>,
+    pub relay_offline: Option<bool>,
+    pub provisioning_failed: Option<bool>,
+    pub relay_expiring: Option<bool>,
+    pub relay_terminated: Option<bool>,
+    pub nostr_enabled: Option<bool>,
+    pub email: Option<String>,
+    pub email_enabled: Option<bool>,
+    pub webhook_url: Option<String>,
+    pub webhook_enabled: Option<bool>,
+}
+
+impl UpdatePreferencesDto {
+    /// Checks the addresses, and that an enabled channel has somewhere to go
+    /// once merged with `current`.
+    pub fn validate(&self, current: &NotificationPreferences) -> Result<(), String> {
+        if let Some(email) = &self.email {
+            email
+                .parse::<Address>()
+                .map_err(|err| format!("Invalid email address: {}", err))?;
+        }
+        if let Some(url) = &self.webhook_url {
+            let parsed =
+                reqwest::Url::parse(url).map_err(|err| format!("Invalid webhook URL: {}", err))?;
+            if parsed.scheme() != "https" {
+                return Err("Webhook URL must be https".to_string());
+            }
+        }
+
+        let email_enabled = self.email_enabled.unwrap_or(current.email_enabled);
+        if email_enabled && self.email.is_none() && current.email.is_none() {
+            return Err("Set an email address to enable email notifications".to_string());
+        }
+        let webhook_enabled = self.webhook_enabled.unwrap_or(current.webhook_enabled);
+        if webhook_enabled && self.webhook_url.is_none() && current.webhook_url.is_none() {
+            return Err("Set a webhook URL to enable webhook notifications".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Channels
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+/// Sends mail through an SMTP server.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// `None` unless `SMTP_HOST` and `SMTP_FROM` are set. `SMTP_TLS` is
+    /// `starttls` (default), `tls`, or `none` for a local stand-in such as
+    /// Mailpit.
+    pub fn from_env() -> Option<Self> {
+        let host = dotenvy::var("SMTP_HOST").ok()?;
+        let from = match dotenvy::var("SMTP_FROM").ok()?.parse::<Mailbox>() {
+            Ok(from) => from,
+            Err(err) => {
+                eprintln!("Invalid SMTP_FROM, email notifications disabled: {}", err);
+                return None;
+            }
+        };
+
+        let builder = match dotenvy::var("SMTP_TLS").as_deref() {
+            Ok("none") => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &host,
+            )),
+            Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host),
+            _ => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host),
+        };
+        let mut builder = match builder {
+            Ok(builder) => builder,
+            Err(err) => {
+                eprintln!("Invalid SMTP_HOST, email notifications disabled: {}", err);
+                return None;
+            }
+        };
+
+        if let Some(port) = dotenvy::var("SMTP_PORT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+        {
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) =
+            (dotenvy::var("SMTP_USERNAME"), dotenvy::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Some(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let to = to.parse::<Mailbox>().map_err(|err| err.to_string())?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())
+            .map_err(|err| err.to_string())?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Mailer that keeps messages in memory, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    pub sent: Arc<std::sync::Mutex<Vec<(String, String, String)>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((to.to_string(), subject.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait WebhookSender: Send + Sync {
+    /// Posts `body` as JSON with the signature header. Non-2xx responses are
+    /// errors.
+    async fn post(&self, url: &str, body: Vec<u8>, signature: &str) -> Result<(), String>;
+}
+
+/// Re-checks the URL on every delivery, since its host may have started
+/// resolving to an internal address after it was saved.
+pub struct HttpWebhookSender {
+    timeout: Duration,
+}
+
+impl HttpWebhookSender {
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[async_trait]
+impl WebhookSender for HttpWebhookSender {
+    async fn post(&self, url: &str, body: Vec<u8>, signature: &str) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+        if parsed.scheme() != "https" {
+            return Err(format!("Webhook {} is not https", url));
+        }
+        let client = public_client(&parsed, self.timeout).await?;
+
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(WEBHOOK_SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| format!("Webhook request to {} failed: {}", url, err))?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Webhook {} responded {}", url, response.status()))
+        }
+    }
+}
+
+/// Webhook body; the signature covers these exact bytes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub id: i64,
+    pub kind: NotificationKind,
+    pub npub: String,
+    pub message: String,
+    pub created_at: NaiveDateTime,
+}
+
+pub struct NotificationConfig {
+    /// Service keypair the DMs are sent from. DMs stay queued without it.
+    pub keys: Option<Keys>,
+    /// Relays the DMs are published to.
+    pub relay_urls: Vec<String>,
+    /// Email stays queued without it.
+    pub mailer: Option<Arc<dyn Mailer>>,
+    pub webhooks: Arc<dyn WebhookSender>,
+    pub interval: Duration,
+}
+
+impl NotificationConfig {
+    pub fn from_env() -> Self {
+        let keys = service_keys();
+        let relay_urls = dotenvy::var("NOTIFY_RELAY_URLS")
+            .unwrap_or_else(|_| "wss://relay.damus.io,wss://nos.lol".to_string());
+        let interval = dotenvy::var("NOTIFY_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(60);
+
+        NotificationConfig {
+            keys,
+            relay_urls: relay_urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect(),
+            mailer: SmtpMailer::from_env().map(|mailer| Arc::new(mailer) as Arc<dyn Mailer>),
+            webhooks: Arc::new(HttpWebhookSender::new()),
+            interval: Duration::from_secs(interval),
+        }
+    }
+
+    /// Channels that can currently be delivered.
+    pub fn channels(&self) -> Vec<NotificationChannel> {
+        let mut channels = vec![NotificationChannel::Webhook];
+        if self.keys.is_some() {
+            channels.push(NotificationChannel::Nostr);
+        }
+        if self.mailer.is_some() {
+            channels.push(NotificationChannel::Email);
+        }
+        channels
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct NotificationRepository {
+    pub pool: PgPool,
+}
+
+impl NotificationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get_preferences(&self, npub: &str) -> NotificationPreferences {
+        sqlx::query_as::<_, NotificationPreferences>(
+            "SELECT * FROM notification_preferences WHERE npub = $1",
+        )
+        .bind(npub)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+        .unwrap_or_else(|| NotificationPreferences::default_for(npub))
+    }
+
+    /// The webhook secret is generated the first time a URL is set and kept
+    /// when the URL changes.
+    pub async fn update_preferences(
+        &self,
+        npub: &str,
+        update: &UpdatePreferencesDto,
+    ) -> Result<NotificationPreferences, sqlx::Error> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        sqlx::query_as::<_, NotificationPreferences>(
+            "INSERT INTO notification_preferences
+            (npub, order_paid, relay_online, provisioning_failed, relay_expiring, relay_terminated,
+             nostr_enabled, email, email_enabled, webhook_url, webhook_enabled, webhook_secret, updated_at,
+             relay_offline)
+            VALUES ($1, COALESCE($2, TRUE), COALESCE($3, TRUE), COALESCE($4, TRUE), COALESCE($5, TRUE), COALESCE($6, TRUE),
+                COALESCE($7, TRUE), $8, COALESCE($9, FALSE), $10, COALESCE($11, FALSE),
+                CASE WHEN $10::TEXT IS NOT NULL THEN $12 END, $13, COALESCE($14, TRUE))
+            ON CONFLICT (npub) DO UPDATE SET
+                order_paid = COALESCE($2, notification_preferences.order_paid),
+                relay_online = COALESCE($3, notification_preferences.relay_online),
+                provisioning_failed = COALESCE($4, notification_preferences.provisioning_failed),
+                relay_expiring = COALESCE($5, notification_preferences.relay_expiring),
+                relay_terminated = COALESCE($6, notification_preferences.relay_terminated),
+                nostr_enabled = COALESCE($7, notification_preferences.nostr_enabled),
+                email = COALESCE($8, notification_preferences.email),
+                email_enabled = COALESCE($9, notification_preferences.email_enabled),
+                webhook_url = COALESCE($10, notification_preferences.webhook_url),
+                webhook_enabled = COALESCE($11, notification_preferences.webhook_enabled),
+                webhook_secret = COALESCE(
+                    notification_preferences.webhook_secret,
+                    CASE WHEN $10::TEXT IS NOT NULL THEN $12 END
+                ),
+                updated_at = $13,
+                relay_offline = COALESCE($14, notification_preferences.relay_offline)
+            RETURNING *",
+        )
+        .bind(npub)
+        .bind(update.order_paid)
+        .bind(update.relay_online)
+        .bind(update.provisioning_failed)
+        .bind(update.relay_expiring)
+        .bind(update.relay_terminated)
+        .bind(update.nostr_enabled)
+        .bind(&update.email)
+        .bind(update.email_enabled)
+        .bind(&update.webhook_url)
+        .bind(update.webhook_enabled)
+        .bind(hex::encode(secret))
+        .bind(Utc::now().naive_utc())
+        .bind(update.relay_offline)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Logs the notification for one channel. Returns `false` if it was
+    /// logged for that channel before.
+    pub async fn enqueue(
+        &self,
+        notification: &Notification,
+        channel: NotificationChannel,
+        destination: Option<&str>,
+        status: NotificationStatus,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO notification_deliveries
+            (npub, kind, channel, destination, dedupe_key, message, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (dedupe_key, channel) DO NOTHING",
+        )
+        .bind(&notification.npub)
+        .bind(notification.kind)
+        .bind(channel)
+        .bind(destination)
+        .bind(&notification.dedupe_key)
+        .bind(&notification.message)
+        .bind(status)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Pending deliveries on the given channels that are due, oldest first.
+    pub async fn get_pending(
+        &self,
+        channels: &[NotificationChannel],
+        limit: i64,
+    ) -> Vec<NotificationDelivery> {
+        let channels: Vec<&str> = channels.iter().map(NotificationChannel::as_str).collect();
+
+        sqlx::query_as::<_, NotificationDelivery>(
+            "SELECT * FROM notification_deliveries
+            WHERE status = 'pending' AND channel::TEXT = ANY($1)
+                AND (next_attempt_at IS NULL OR next_attempt_at <= $3)
+            ORDER BY id LIMIT $2",
+        )
+        .bind(channels)
+        .bind(limit)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// `event_id` is the DM's id for nostr deliveries.
+    pub async fn mark_sent(&self, id: i64, event_id: Option<&str>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE notification_deliveries
+            SET status = 'sent', attempts = attempts + 1, event_id = $2, error = NULL, sent_at = $3
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(event_id)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed attempt and schedules the retry with exponential
+    /// backoff, giving up after `MAX_ATTEMPTS`.
+    pub async fn mark_attempt_failed(&self, id: i64, error: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE notification_deliveries
+            SET attempts = attempts + 1, error = $2,
+                status = CASE WHEN attempts + 1 >= $3 THEN 'failed'::notification_status ELSE status END,
+                next_attempt_at = $4 + make_interval(secs => $5 * POWER(2, attempts))
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .bind(MAX_ATTEMPTS)
+        .bind(Utc::now().naive_utc())
+        .bind(RETRY_BASE_SECS as f64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The user's most recent notifications, newest first.
+    pub async fn get_user_deliveries(&self, npub: &str, limit: i64) -> Vec<NotificationDelivery> {
+        sqlx::query_as::<_, NotificationDelivery>(
+            "SELECT * FROM notification_deliveries WHERE npub = $1 ORDER BY id DESC LIMIT $2",
+        )
+        .bind(npub)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Queues the notification on every channel the user enabled, or logs it as
+/// skipped there if the user turned this kind off. Never fails the caller.
+pub async fn notify(pool: &PgPool, notification: Notification) {
+    let repo = NotificationRepository::new(pool.clone());
+    let preferences = repo.get_preferences(&notification.npub).await;
+    let status = if preferences.allows(notification.kind) {
+        NotificationStatus::Pending
+    } else {
+        NotificationStatus::Skipped
+    };
+
+    for (channel, destination) in preferences.channels() {
+        if let Err(err) = repo
+            .enqueue(&notification, channel, destination.as_deref(), status)
+            .await
+        {
+            eprintln!(
+                "Failed to queue {} notification {}: {}",
+                channel.as_str(),
+                notification.dedupe_key,
+                err
+            );
+        }
+    }
+}
+
+/// Notifies whoever ordered the relay and, for organization relays, the
+/// organization's owners and managers. Other recipients get the dedupe key
+/// suffixed with their npub so each of them is notified once.
+pub async fn notify_relay_team(
+    pool: &PgPool,
+    organization_uuid: Option<&str>,
+    notification: Notification,
+) {
+    let mut recipients = vec![notification.npub.clone()];
+    if let Some(organization_uuid) = organization_uuid {
+        let members = OrganizationRepository::new(pool.clone())
+            .get_members(organization_uuid)
+            .await;
+        for member in members {
+            if member.role >= OrganizationRole::Manager && !recipients.contains(&member.npub) {
+                recipients.push(member.npub);
+            }
+        }
+    }
+
+    for npub in recipients {
+        let dedupe_key = if npub == notification.npub {
+            notification.dedupe_key.clone()
+        } else {
+            format!("{}:{}", notification.dedupe_key, npub)
+        };
+        notify(
+            pool,
+            Notification {
+                npub,
+                dedupe_key,
+                ..notification.clone()
+            },
+        )
+        .await;
+    }
+}
+
+/// NIP-04 encrypted DM from the service key. NIP-17 needs NIP-44 and gift
+/// wraps, which our nostr version doesn't provide yet.
+pub fn direct_message(keys: &Keys, npub: &str, message: &str) -> Result<Event, String> {
+    let recipient = XOnlyPublicKey::from_bech32(npub).map_err(|err| err.to_string())?;
+
+    EventBuilder::new_encrypted_direct_msg(keys, recipient, message)
+        .and_then(|builder| builder.to_event(keys))
+        .map_err(|err| err.to_string())
+}
+
+/// Posts the delivery to its webhook URL, signed with the user's secret the
+/// same way Nodeless signs its webhooks to us.
+async fn send_webhook(
+    repo: &NotificationRepository,
+    webhooks: &dyn WebhookSender,
+    delivery: &NotificationDelivery,
+) -> Result<(), String> {
+    let url = delivery
+        .destination
+        .as_deref()
+        .ok_or("Missing webhook URL")?;
+    let secret = repo
+        .get_preferences(&delivery.npub)
+        .await
+        .webhook_secret
+        .ok_or("Missing webhook secret")?;
+
+    let body = serde_json::to_vec(&WebhookPayload {
+        id: delivery.id,
+        kind: delivery.kind,
+        npub: delivery.npub.clone(),
+        message: delivery.message.clone(),
+        created_at: delivery.created_at,
+    })
+    .map_err(|err| err.to_string())?;
+    let signature = calculate_hmac_sha256(&body, &secret);
+
+    webhooks.post(url, body, &signature).await
+}
+
+/// Sends one delivery. Returns the DM's event id for nostr deliveries.
+async fn send_delivery(
+    repo: &NotificationRepository,
+    relay_client: &dyn RelayClient,
+    config: &NotificationConfig,
+    delivery: &NotificationDelivery,
+) -> Result<Option<String>, String> {
+    match delivery.channel {
+        NotificationChannel::Nostr => {
+            let keys = config.keys.as_ref().ok_or("Nostr DMs are not configured")?;
+            let event = direct_message(keys, &delivery.npub, &delivery.message)?;
+            publish_to_any(relay_client, &config.relay_urls, &event).await?;
+            Ok(Some(event.id.to_hex()))
+        }
+        NotificationChannel::Email => {
+            let mailer = config.mailer.as_ref().ok_or("Email is not configured")?;
+            let to = delivery
+                .destination
+                .as_deref()
+                .ok_or("Missing email address")?;
+            mailer
+                .send(to, delivery.kind.subject(), &delivery.message)
+                .await?;
+            Ok(None)
+        }
+        NotificationChannel::Webhook => {
+            send_webhook(repo, config.webhooks.as_ref(), delivery).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Sends pending deliveries on the configured channels. Failed ones are
+/// retried on the next run until `MAX_ATTEMPTS`.
+pub async fn deliver_pending(
+    pool: &PgPool,
+    relay_client: &dyn RelayClient,
+    config: &NotificationConfig,
+) {
+    let repo = NotificationRepository::new(pool.clone());
+
+    for delivery in repo.get_pending(&config.channels(), 100).await {
+        let result = match send_delivery(&repo, relay_client, config, &delivery).await {
+            Ok(event_id) => repo.mark_sent(delivery.id, event_id.as_deref()).await,
+            Err(err) => repo.mark_attempt_failed(delivery.id, &err).await,
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to record notification {}: {}", delivery.id, err);
+        }
+    }
+}
+
+/// Queues the reminder for relays that crossed one of `EXPIRY_NOTICE_DAYS`.
+/// Only the closest reminder is sent, so a relay with two days left doesn't
+/// also get the seven day one.
+pub async fn notify_expiring_relays(pool: &PgPool) {
+    let now = Utc::now().naive_utc();
+
+    for relay in RelayRepository::new(pool.clone()).get_active().await {
+        let remaining = relay.expires_at - now;
+        if remaining <= chrono::Duration::zero() {
+            continue;
+        }
+
+        let notice_days = EXPIRY_NOTICE_DAYS
+            .iter()
+            .filter(|days| remaining <= chrono::Duration::days(**days))
+            .min();
+        if let Some(notice_days) = notice_days {
+            let days_left = (remaining.num_seconds() + 86_399) / 86_400;
+            notify_relay_team(
+                pool,
+                relay.organization_uuid.as_deref(),
+                Notification::relay_expiring(&relay, *notice_days, days_left),
+            )
+            .await;
+        }
+    }
+}
+
+pub async fn run_notifier(
+    pool: PgPool,
+    relay_client: Arc<dyn RelayClient>,
+    config: NotificationConfig,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        notify_expiring_relays(&pool).await;
+        deliver_pending(&pool, relay_client.as_ref(), &config).await;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn get_notifications_handler(
+    auth: AuthorizationService,
+    notification_repo: web::Data<NotificationRepository>,
+) -> impl Responder {
+    let deliveries = notification_repo
+        .get_user_deliveries(auth.npub().unwrap(), 100)
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(deliveries))
+}
+
+async fn get_preferences_handler(
+    auth: AuthorizationService,
+    notification_repo: web::Data<NotificationRepository>,
+) -> impl Responder {
+    let preferences = notification_repo
+        .get_preferences(auth.npub().unwrap())
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(preferences))
+}
+
+async fn update_preferences_handler(
+    auth: AuthorizationService,
+    notification_repo: web::Data<NotificationRepository>,
+    body: web::Json<UpdatePreferencesDto>,
+) -> impl Responder {
+    let npub = auth.npub().unwrap();
+    let current = notification_repo.get_preferences(npub).await;
+    if let Err(err) = body.validate(&current) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(err));
+    }
+    if let Some(url) = body.webhook_url.as_deref().and_then(|url| reqwest::Url::parse(url).ok()) {
+        if let Err(err) = resolve_public(&url).await {
+            return HttpResponse::BadRequest()
+                .json(ErrorResponse::new(format!("Invalid webhook URL: {}", err)));
+        }
+    }
+
+    match notification_repo.update_preferences(npub, &body).await {
+        Ok(preferences) => HttpResponse::Ok().json(DataResponse::new(preferences)),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route(
+        "/me/notifications",
+        web::get().to(get_notifications_handler),
+    )
+    .service(
+        web::resource("/me/notifications/preferences")
+            .route(web::get().to(get_preferences_handler))
+            .route(web::put().to(update_preferences_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::health::relay_url;
+    use crate::relay_client::LocalRelayClient;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use nostr::prelude::ToBech32;
+    use nostr::{Filter, Kind};
+
+    /// Records webhook posts, failing the first `failures` of them.
+    #[derive(Default)]
+    struct RecordingWebhooks {
+        failures: std::sync::Mutex<usize>,
+        posts: std::sync::Mutex<Vec<(String, Vec<u8>, String)>>,
+    }
+
+    #[async_trait]
+    impl WebhookSender for RecordingWebhooks {
+        async fn post(&self, url: &str, body: Vec<u8>, signature: &str) -> Result<(), String> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(format!("Webhook {} responded 503", url));
+            }
+            self.posts
+                .lock()
+                .unwrap()
+                .push((url.to_string(), body, signature.to_string()));
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_notifications() {
+        let test_utils = TestUtils::new().await;
+        let keys = Keys::generate();
+        let user = test_utils
+            .user_repo
+            .upsert(
+                &keys.public_key().to_bech32().unwrap(),
+                &keys.public_key().to_string(),
+            )
+            .await
+            .unwrap();
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let repo = NotificationRepository::new(test_utils.pool.clone());
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+        let req = actix_web::test::TestRequest::put()
+            .uri("/me/notifications/preferences")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({"relay_terminated": false}))
+            .to_request();
+        let preferences: DataResponse<NotificationPreferences> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert!(!preferences.data.relay_terminated);
+        assert!(preferences.data.relay_online);
+
+        notify(&test_utils.pool, Notification::relay_online(&relay, &relay_url(&relay))).await;
+        notify(&test_utils.pool, Notification::relay_online(&relay, &relay_url(&relay))).await;
+        notify(&test_utils.pool, Notification::relay_terminated(&relay)).await;
+
+        // With two days left only the three day reminder goes out.
+        let relay = sqlx::query_as::<_, Relay>(
+            "UPDATE relays SET expires_at = $1 WHERE uuid = $2 RETURNING *",
+        )
+        .bind(Utc::now().naive_utc() + chrono::Duration::hours(47))
+        .bind(&relay.uuid)
+        .fetch_one(&test_utils.pool)
+        .await
+        .unwrap();
+        notify_expiring_relays(&test_utils.pool).await;
+        notify_expiring_relays(&test_utils.pool).await;
+
+        let client = LocalRelayClient::new();
+        let config = NotificationConfig {
+            keys: Some(Keys::generate()),
+            relay_urls: vec!["wss://relay.example".to_string()],
+            mailer: None,
+            webhooks: Arc::new(RecordingWebhooks::default()),
+            interval: Duration::from_secs(60),
+        };
+        deliver_pending(&test_utils.pool, &client, &config).await;
+
+        let dms = client
+            .fetch(
+                "wss://relay.example",
+                vec![Filter::new()
+                    .kind(Kind::EncryptedDirectMessage)
+                    .pubkey(keys.public_key())],
+            )
+            .await
+            .unwrap();
+        let messages: Vec<String> = dms
+            .iter()
+            .map(|dm| {
+                nostr::nips::nip04::decrypt(&keys.secret_key().unwrap(), &dm.pubkey, &dm.content)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().any(|m| m.contains("is online at wss://")));
+        assert!(messages.iter().any(|m| m.contains("expires in 2 days")));
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/me/notifications")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let log: DataResponse<Vec<NotificationDelivery>> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        let status = |kind: NotificationKind| {
+            log.data
+                .iter()
+                .filter(|delivery| delivery.kind == kind)
+                .map(|delivery| delivery.status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            status(NotificationKind::RelayOnline),
+            vec![NotificationStatus::Sent]
+        );
+        assert_eq!(
+            status(NotificationKind::RelayTerminated),
+            vec![NotificationStatus::Skipped]
+        );
+        let expiring = log
+            .data
+            .iter()
+            .find(|delivery| delivery.kind == NotificationKind::RelayExpiring)
+            .unwrap();
+        assert!(expiring.dedupe_key.ends_with(":3"));
+        assert!(expiring
+            .dedupe_key
+            .contains(&relay.expires_at.timestamp().to_string()));
+        assert_eq!(log.data.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_email_and_webhook_channels() {
+        let test_utils = TestUtils::new().await;
+        let keys = Keys::generate();
+        let user = test_utils
+            .user_repo
+            .upsert(
+                &keys.public_key().to_bech32().unwrap(),
+                &keys.public_key().to_string(),
+            )
+            .await
+            .unwrap();
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let repo = NotificationRepository::new(test_utils.pool.clone());
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let req = actix_web::test::TestRequest::put()
+            .uri("/me/notifications/preferences")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({"webhook_enabled": true}))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        for url in [
+            "http://93.184.216.34/relaying",
+            "https://169.254.169.254/latest/meta-data",
+            "https://10.0.0.5/relaying",
+        ] {
+            let req = actix_web::test::TestRequest::put()
+                .uri("/me/notifications/preferences")
+                .insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .set_json(serde_json::json!({"webhook_url": url}))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        }
+
+        let req = actix_web::test::TestRequest::put()
+            .uri("/me/notifications/preferences")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "nostr_enabled": false,
+                "email": "operator@example.com",
+                "email_enabled": true,
+                "webhook_url": "https://93.184.216.34/relaying",
+                "webhook_enabled": true,
+            }))
+            .to_request();
+        let preferences: DataResponse<NotificationPreferences> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        let secret = preferences.data.webhook_secret.clone().unwrap();
+        assert_eq!(secret.len(), 64);
+
+        // Changing the URL keeps the secret.
+        let req = actix_web::test::TestRequest::put()
+            .uri("/me/notifications/preferences")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({"webhook_url": "https://93.184.216.34/v2"}))
+            .to_request();
+        let preferences: DataResponse<NotificationPreferences> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(preferences.data.webhook_secret, Some(secret.clone()));
+
+        notify(&test_utils.pool, Notification::relay_online(&relay, &relay_url(&relay))).await;
+
+        let mailer = MemoryMailer::default();
+        let webhooks = Arc::new(RecordingWebhooks::default());
+        *webhooks.failures.lock().unwrap() = 1;
+        let config = NotificationConfig {
+            keys: None,
+            relay_urls: vec![],
+            mailer: Some(Arc::new(mailer.clone())),
+            webhooks: webhooks.clone(),
+            interval: Duration::from_secs(60),
+        };
+        deliver_pending(&test_utils.pool, &LocalRelayClient::new(), &config).await;
+        // The failed webhook waits for its backoff.
+        deliver_pending(&test_utils.pool, &LocalRelayClient::new(), &config).await;
+        assert!(webhooks.posts.lock().unwrap().is_empty());
+
+        let retry = repo
+            .get_user_deliveries(&user.npub, 10)
+            .await
+            .into_iter()
+            .find(|delivery| delivery.channel == NotificationChannel::Webhook)
+            .unwrap();
+        let backoff = retry.next_attempt_at.unwrap() - Utc::now().naive_utc();
+        assert!(backoff > chrono::Duration::seconds(50));
+        assert!(backoff <= chrono::Duration::seconds(RETRY_BASE_SECS));
+        sqlx::query("UPDATE notification_deliveries SET next_attempt_at = $2 WHERE id = $1")
+            .bind(retry.id)
+            .bind(Utc::now().naive_utc())
+            .execute(&test_utils.pool)
+            .await
+            .unwrap();
+        deliver_pending(&test_utils.pool, &LocalRelayClient::new(), &config).await;
+
+        let sent = mailer.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "operator@example.com");
+        assert_eq!(sent[0].1, "Your relay is online");
+
+        let posts = webhooks.posts.lock().unwrap().clone();
+        assert_eq!(posts.len(), 1);
+        let (url, body, signature) = &posts[0];
+        assert_eq!(url, "https://93.184.216.34/v2");
+        assert_eq!(signature, &calculate_hmac_sha256(body, &secret));
+        let payload: WebhookPayload = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload.kind, NotificationKind::RelayOnline);
+        assert_eq!(payload.npub, user.npub);
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/me/notifications")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let log: DataResponse<Vec<NotificationDelivery>> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(log.data.len(), 2);
+        let webhook = log
+            .data
+            .iter()
+            .find(|delivery| delivery.channel == NotificationChannel::Webhook)
+            .unwrap();
+        assert_eq!(webhook.status, NotificationStatus::Sent);
+        assert_eq!(webhook.attempts, 2);
+        assert!(log
+            .data
+            .iter()
+            .all(|delivery| delivery.channel != NotificationChannel::Nostr));
+    }
+}
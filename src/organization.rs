@@ -0,0 +1,1142 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use nostr::{Event, Kind, Tag};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    middleware::AuthorizationService,
+    util::{bech32_encode, DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Kind of the signed event an existing member submits to invite someone.
+pub const INVITATION_KIND: u64 = 7_070;
+/// Invitations without an `expiration` tag are valid this long.
+const INVITATION_TTL_DAYS: i64 = 7;
+
+/// Roles are ordered by privilege: viewers can see the organization's relays,
+/// managers can also change them and invite people, and owners can also
+/// delete relays and manage members.
+#[derive(
+    Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[sqlx(type_name = "organization_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OrganizationRole {
+    Viewer,
+    Manager,
+    Owner,
+}
+
+impl OrganizationRole {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(OrganizationRole::Viewer),
+            "manager" => Some(OrganizationRole::Manager),
+            "owner" => Some(OrganizationRole::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Organization {
+    pub uuid: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// An organization along with the caller's role in it.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Membership {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub organization: Organization,
+    pub role: OrganizationRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct OrganizationMember {
+    pub organization_uuid: String,
+    pub npub: String,
+    pub role: OrganizationRole,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationWithMembers {
+    #[serde(flatten)]
+    pub organization: Organization,
+    pub members: Vec<OrganizationMember>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Invitation {
+    pub uuid: String,
+    pub organization_uuid: String,
+    pub npub: String,
+    pub role: OrganizationRole,
+    pub invited_by: String,
+    pub event_id: String,
+    /// The signed invitation, so the invitee can check who sent it.
+    pub event: serde_json::Value,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+}
+
+/// What a valid invitation event grants.
+#[derive(Debug, PartialEq)]
+pub struct InvitationClaim {
+    pub organization_uuid: String,
+    pub npub: String,
+    pub role: OrganizationRole,
+    pub invited_by: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrganizationDto {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInvitationDto {
+    pub event: Event,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMemberRoleDto {
+    pub role: OrganizationRole,
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct OrganizationRepository {
+    pub pool: PgPool,
+}
+
+impl OrganizationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Creates the organization with `owner_npub` as its only owner.
+    pub async fn create(&self, name: &str, owner_npub: &str) -> Result<Organization, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let organization = sqlx::query_as::<_, Organization>(
+            "INSERT INTO organizations (uuid, name) VALUES ($1, $2) RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO organization_members (organization_uuid, npub, role)
+            VALUES ($1, $2, 'owner')",
+        )
+        .bind(&organization.uuid)
+        .bind(owner_npub)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(organization)
+    }
+
+    pub async fn get_one(&self, uuid: &str) -> Option<Organization> {
+        sqlx::query_as::<_, Organization>("SELECT * FROM organizations WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn get_memberships(&self, npub: &str) -> Vec<Membership> {
+        sqlx::query_as::<_, Membership>(
+            "SELECT o.*, m.role FROM organizations o
+            JOIN organization_members m ON m.organization_uuid = o.uuid
+            WHERE m.npub = $1
+            ORDER BY o.created_at",
+        )
+        .bind(npub)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn delete(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM organizations WHERE uuid = $1")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Relays of the organization that haven't been terminated.
+    pub async fn live_relay_count(&self, uuid: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM relays WHERE organization_uuid = $1 AND deleted_at IS NULL",
+        )
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn role(&self, uuid: &str, npub: &str) -> Option<OrganizationRole> {
+        sqlx::query_scalar::<_, OrganizationRole>(
+            "SELECT role FROM organization_members WHERE organization_uuid = $1 AND npub = $2",
+        )
+        .bind(uuid)
+        .bind(npub)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn get_members(&self, uuid: &str) -> Vec<OrganizationMember> {
+        sqlx::query_as::<_, OrganizationMember>(
+            "SELECT * FROM organization_members WHERE organization_uuid = $1 ORDER BY created_at",
+        )
+        .bind(uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn owner_count(&self, uuid: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM organization_members
+            WHERE organization_uuid = $1 AND role = 'owner'",
+        )
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn set_role(
+        &self,
+        uuid: &str,
+        npub: &str,
+        role: OrganizationRole,
+    ) -> Result<Option<OrganizationMember>, sqlx::Error> {
+        sqlx::query_as::<_, OrganizationMember>(
+            "UPDATE organization_members SET role = $3
+            WHERE organization_uuid = $1 AND npub = $2
+            RETURNING *",
+        )
+        .bind(uuid)
+        .bind(npub)
+        .bind(role)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Returns whether the member existed.
+    pub async fn remove_member(&self, uuid: &str, npub: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM organization_members WHERE organization_uuid = $1 AND npub = $2",
+        )
+        .bind(uuid)
+        .bind(npub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn create_invitation(
+        &self,
+        claim: &InvitationClaim,
+        event: &Event,
+    ) -> Result<Invitation, sqlx::Error> {
+        sqlx::query_as::<_, Invitation>(
+            "INSERT INTO organization_invitations
+            (uuid, organization_uuid, npub, role, invited_by, event_id, event, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&claim.organization_uuid)
+        .bind(&claim.npub)
+        .bind(claim.role)
+        .bind(&claim.invited_by)
+        .bind(event.id.to_hex())
+        .bind(Json(event))
+        .bind(claim.expires_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_invitation(&self, uuid: &str) -> Option<Invitation> {
+        sqlx::query_as::<_, Invitation>("SELECT * FROM organization_invitations WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn get_pending_invitations(&self, npub: &str) -> Vec<Invitation> {
+        sqlx::query_as::<_, Invitation>(
+            "SELECT * FROM organization_invitations
+            WHERE npub = $1 AND accepted_at IS NULL AND expires_at > $2
+            ORDER BY created_at",
+        )
+        .bind(npub)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Marks the invitation accepted and adds the invitee. An invitation only
+    /// ever raises an existing member's role, so it can't demote anyone,
+    /// including the last owner. Returns `None` if it was already accepted.
+    pub async fn accept_invitation(
+        &self,
+        invitation: &Invitation,
+    ) -> Result<Option<OrganizationMember>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let accepted = sqlx::query(
+            "UPDATE organization_invitations SET accepted_at = $2
+            WHERE uuid = $1 AND accepted_at IS NULL",
+        )
+        .bind(&invitation.uuid)
+        .bind(Utc::now().naive_utc())
+        .execute(&mut *tx)
+        .await?;
+        if accepted.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        sqlx::query(
+            "INSERT INTO organization_members (organization_uuid, npub, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (organization_uuid, npub) DO UPDATE SET role = EXCLUDED.role
+            WHERE organization_members.role < EXCLUDED.role",
+        )
+        .bind(&invitation.organization_uuid)
+        .bind(&invitation.npub)
+        .bind(invitation.role)
+        .execute(&mut *tx)
+        .await?;
+        let member = sqlx::query_as::<_, OrganizationMember>(
+            "SELECT * FROM organization_members WHERE organization_uuid = $1 AND npub = $2",
+        )
+        .bind(&invitation.organization_uuid)
+        .bind(&invitation.npub)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(member))
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .map(Tag::as_vec)
+        .find(|values| values.first().map(String::as_str) == Some(name))
+        .and_then(|values| values.get(1).cloned())
+}
+
+/// Checks the signature and tags of an invitation event. The invitation
+/// names the invitee in a `p` tag, the organization in an `organization` tag
+/// and the role in a `role` tag, and may set a NIP-40 `expiration`.
+pub fn parse_invitation(event: &Event) -> Result<InvitationClaim, String> {
+    event
+        .verify()
+        .map_err(|_| "Invalid event signature".to_string())?;
+
+    if event.kind != Kind::from(INVITATION_KIND) {
+        return Err(format!("Invitations must be kind {}", INVITATION_KIND));
+    }
+
+    let organization_uuid =
+        tag_value(event, "organization").ok_or("Missing organization tag".to_string())?;
+    let npub = tag_value(event, "p")
+        .and_then(|hexpub| bech32_encode(&hexpub).ok())
+        .ok_or("Missing or invalid p tag".to_string())?;
+    let role = tag_value(event, "role")
+        .and_then(|role| OrganizationRole::parse(&role))
+        .ok_or("Missing or invalid role tag".to_string())?;
+    let invited_by = bech32_encode(&event.pubkey.to_string()).map_err(|err| err.to_string())?;
+
+    let expires_at = match tag_value(event, "expiration") {
+        Some(expiration) => expiration
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .ok_or("Invalid expiration tag".to_string())?,
+        None => {
+            Utc.timestamp_opt(event.created_at.as_i64(), 0)
+                .single()
+                .ok_or("Invalid timestamp".to_string())?
+                + chrono::Duration::days(INVITATION_TTL_DAYS)
+        }
+    }
+    .naive_utc();
+
+    if expires_at <= Utc::now().naive_utc() {
+        return Err("Invitation expired".to_string());
+    }
+    if npub == invited_by {
+        return Err("Can't invite yourself".to_string());
+    }
+
+    Ok(InvitationClaim {
+        organization_uuid,
+        npub,
+        role,
+        invited_by,
+        expires_at,
+    })
+}
+
+/// The caller's role in the organization, or the response to send if it
+/// isn't at least `role`. Non-members get a 404 so organizations can't be
+/// probed.
+async fn require_role(
+    organization_repo: &OrganizationRepository,
+    uuid: &str,
+    auth: &AuthorizationService,
+    role: OrganizationRole,
+) -> Result<OrganizationRole, HttpResponse> {
+    match organization_repo.role(uuid, auth.npub().unwrap()).await {
+        Some(granted) if granted >= role => Ok(granted),
+        Some(_) => Err(HttpResponse::Forbidden()
+            .json(ErrorResponse::new(format!("Requires the {:?} role", role)))),
+        None => {
+            Err(HttpResponse::NotFound()
+                .json(ErrorResponse::new("Organization not found".to_string())))
+        }
+    }
+}
+
+/// Keeps the organization from losing its last owner.
+async fn is_last_owner(
+    organization_repo: &OrganizationRepository,
+    uuid: &str,
+    npub: &str,
+) -> Result<bool, sqlx::Error> {
+    if organization_repo.role(uuid, npub).await != Some(OrganizationRole::Owner) {
+        return Ok(false);
+    }
+
+    Ok(organization_repo.owner_count(uuid).await? <= 1)
+}
+
+fn last_owner() -> HttpResponse {
+    HttpResponse::Conflict().json(ErrorResponse::new(
+        "Organizations need at least one owner".to_string(),
+    ))
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn create_organization_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    body: web::Json<CreateOrganizationDto>,
+) -> impl Responder {
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "Name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+
+    match organization_repo.create(name, auth.npub().unwrap()).await {
+        Ok(organization) => HttpResponse::Created().json(DataResponse::new(organization)),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+async fn get_organizations_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+) -> impl Responder {
+    let memberships = organization_repo
+        .get_memberships(auth.npub().unwrap())
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(memberships))
+}
+
+async fn get_organization_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) =
+        require_role(&organization_repo, &path, &auth, OrganizationRole::Viewer).await
+    {
+        return response;
+    }
+
+    match organization_repo.get_one(&path).await {
+        Some(organization) => HttpResponse::Ok().json(DataResponse::new(OrganizationWithMembers {
+            organization,
+            members: organization_repo.get_members(&path).await,
+        })),
+        None => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Organization not found".to_string()))
+        }
+    }
+}
+
+async fn delete_organization_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) =
+        require_role(&organization_repo, &path, &auth, OrganizationRole::Owner).await
+    {
+        return response;
+    }
+
+    match organization_repo.live_relay_count(&path).await {
+        Ok(0) => {}
+        Ok(_) => {
+            return HttpResponse::Conflict().json(ErrorResponse::new(
+                "Delete the organization's relays first".to_string(),
+            ))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    }
+
+    match organization_repo.delete(&path).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+/// Takes an invitation event signed by a manager or owner. Members can't
+/// grant a role above their own.
+async fn create_invitation_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<String>,
+    body: web::Json<CreateInvitationDto>,
+) -> impl Responder {
+    let granted =
+        match require_role(&organization_repo, &path, &auth, OrganizationRole::Manager).await {
+            Ok(granted) => granted,
+            Err(response) => return response,
+        };
+
+    let claim = match parse_invitation(&body.event) {
+        Ok(claim) => claim,
+        Err(err) => return HttpResponse::BadRequest().json(ErrorResponse::new(err)),
+    };
+    if claim.organization_uuid != *path || Some(&claim.invited_by) != auth.npub() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "Invitation must be signed by the caller for this organization".to_string(),
+        ));
+    }
+    if claim.role > granted {
+        return HttpResponse::Forbidden().json(ErrorResponse::new(
+            "Can't invite to a role above your own".to_string(),
+        ));
+    }
+    if organization_repo.role(&path, &claim.npub).await.is_some() {
+        return HttpResponse::Conflict().json(ErrorResponse::new("Already a member".to_string()));
+    }
+
+    match organization_repo
+        .create_invitation(&claim, &body.event)
+        .await
+    {
+        Ok(invitation) => HttpResponse::Created().json(DataResponse::new(invitation)),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => HttpResponse::Conflict()
+            .json(ErrorResponse::new(
+                "Invitation already submitted".to_string(),
+            )),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+async fn get_invitations_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+) -> impl Responder {
+    let invitations = organization_repo
+        .get_pending_invitations(auth.npub().unwrap())
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(invitations))
+}
+
+async fn accept_invitation_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let invitation = match organization_repo.get_invitation(&path).await {
+        Some(invitation) if Some(&invitation.npub) == auth.npub() => invitation,
+        _ => {
+            return HttpResponse::NotFound()
+                .json(ErrorResponse::new("Invitation not found".to_string()))
+        }
+    };
+
+    if invitation.expires_at <= Utc::now().naive_utc() {
+        return HttpResponse::Gone().json(ErrorResponse::new("Invitation expired".to_string()));
+    }
+
+    // The inviter may have been demoted or removed since signing.
+    let inviter_role = organization_repo
+        .role(&invitation.organization_uuid, &invitation.invited_by)
+        .await;
+    let can_grant = inviter_role
+        .is_some_and(|role| role >= OrganizationRole::Manager && role >= invitation.role);
+    if !can_grant {
+        return HttpResponse::Forbidden().json(ErrorResponse::new(
+            "The inviter can no longer grant this role".to_string(),
+        ));
+    }
+
+    match organization_repo.accept_invitation(&invitation).await {
+        Ok(Some(member)) => HttpResponse::Ok().json(DataResponse::new(member)),
+        Ok(None) => HttpResponse::Conflict().json(ErrorResponse::new(
+            "Invitation already accepted".to_string(),
+        )),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+async fn update_member_role_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<(String, String)>,
+    body: web::Json<UpdateMemberRoleDto>,
+) -> impl Responder {
+    let (uuid, npub) = path.into_inner();
+    if let Err(response) =
+        require_role(&organization_repo, &uuid, &auth, OrganizationRole::Owner).await
+    {
+        return response;
+    }
+
+    if body.role != OrganizationRole::Owner {
+        match is_last_owner(&organization_repo, &uuid, &npub).await {
+            Ok(false) => {}
+            Ok(true) => return last_owner(),
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .json(ErrorResponse::new(err.to_string()))
+            }
+        }
+    }
+
+    match organization_repo.set_role(&uuid, &npub, body.role).await {
+        Ok(Some(member)) => HttpResponse::Ok().json(DataResponse::new(member)),
+        Ok(None) => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Member not found".to_string()))
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+/// Owners can remove anyone; other members can only leave.
+async fn remove_member_handler(
+    auth: AuthorizationService,
+    organization_repo: web::Data<OrganizationRepository>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (uuid, npub) = path.into_inner();
+    let required = if auth.npub() == Some(&npub) {
+        OrganizationRole::Viewer
+    } else {
+        OrganizationRole::Owner
+    };
+    if let Err(response) = require_role(&organization_repo, &uuid, &auth, required).await {
+        return response;
+    }
+
+    match is_last_owner(&organization_repo, &uuid, &npub).await {
+        Ok(false) => {}
+        Ok(true) => return last_owner(),
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    }
+
+    match organization_repo.remove_member(&uuid, &npub).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Member not found".to_string()))
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/organizations")
+            .route("", web::get().to(get_organizations_handler))
+            .route("", web::post().to(create_organization_handler))
+            .route("/invitations", web::get().to(get_invitations_handler))
+            .route(
+                "/invitations/{uuid}/accept",
+                web::post().to(accept_invitation_handler),
+            )
+            .route("/{uuid}", web::get().to(get_organization_handler))
+            .route("/{uuid}", web::delete().to(delete_organization_handler))
+            .route(
+                "/{uuid}/invitations",
+                web::post().to(create_invitation_handler),
+            )
+            .route(
+                "/{uuid}/members/{npub}",
+                web::put().to(update_member_role_handler),
+            )
+            .route(
+                "/{uuid}/members/{npub}",
+                web::delete().to(remove_member_handler),
+            ),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::cloud_provider::{InstanceProvider, LocalInstanceProvider};
+    use crate::dns::{DnsProvider, LocalDnsProvider};
+    use crate::relay::Relay;
+    use crate::relay_order::{CreateRelayOrder, RelayOrderStatus};
+    use crate::token::TokenRepository;
+    use crate::user::User;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use nostr::prelude::ToBech32;
+    use nostr::{EventBuilder, Keys, TagKind, Timestamp};
+    use std::sync::Arc;
+
+    fn invitation(
+        keys: &Keys,
+        kind: u64,
+        organization_uuid: &str,
+        invitee: &Keys,
+        role: &str,
+        expiration: Option<i64>,
+    ) -> Event {
+        let tag = |name: &str, value: String| {
+            Tag::Generic(TagKind::Custom(name.to_string()), vec![value])
+        };
+        let mut tags = vec![
+            Tag::PubKey(invitee.public_key(), None),
+            tag("organization", organization_uuid.to_string()),
+            tag("role", role.to_string()),
+        ];
+        if let Some(expiration) = expiration {
+            tags.push(tag("expiration", expiration.to_string()));
+        }
+
+        EventBuilder::new(Kind::from(kind), "Join us", &tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    async fn register(test_utils: &TestUtils, keys: &Keys) -> User {
+        test_utils
+            .user_repo
+            .upsert(
+                &keys.public_key().to_bech32().unwrap(),
+                &keys.public_key().to_string(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_invitation() {
+        let owner = Keys::generate();
+        let invitee = Keys::generate();
+        let now = Timestamp::now().as_i64();
+
+        let claim = parse_invitation(&invitation(
+            &owner,
+            INVITATION_KIND,
+            "org",
+            &invitee,
+            "manager",
+            Some(now + 60),
+        ))
+        .unwrap();
+        assert_eq!(claim.organization_uuid, "org");
+        assert_eq!(claim.npub, invitee.public_key().to_bech32().unwrap());
+        assert_eq!(claim.role, OrganizationRole::Manager);
+        assert_eq!(claim.invited_by, owner.public_key().to_bech32().unwrap());
+
+        let cases = vec![
+            (
+                invitation(&owner, 1, "org", &invitee, "viewer", None),
+                "kind",
+            ),
+            (
+                invitation(&owner, INVITATION_KIND, "org", &invitee, "admin", None),
+                "role",
+            ),
+            (
+                invitation(
+                    &owner,
+                    INVITATION_KIND,
+                    "org",
+                    &invitee,
+                    "viewer",
+                    Some(now - 1),
+                ),
+                "expired",
+            ),
+            (
+                invitation(&owner, INVITATION_KIND, "org", &owner, "viewer", None),
+                "yourself",
+            ),
+        ];
+        for (event, message) in cases {
+            let err = parse_invitation(&event).unwrap_err();
+            assert!(err.contains(message), "expected {:?} in {:?}", message, err);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_organization_membership() {
+        let test_utils = TestUtils::new().await;
+        let (owner_keys, manager_keys, viewer_keys) =
+            (Keys::generate(), Keys::generate(), Keys::generate());
+        let owner = register(&test_utils, &owner_keys).await;
+        let manager = register(&test_utils, &manager_keys).await;
+        let viewer = register(&test_utils, &viewer_keys).await;
+        let outsider = test_utils.create_user().await;
+
+        let dns: Arc<dyn DnsProvider> = Arc::new(LocalDnsProvider::new());
+        let instances: Arc<dyn InstanceProvider> = Arc::new(LocalInstanceProvider::default());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(OrganizationRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::from(dns))
+                .app_data(web::Data::from(instances))
+                .configure(configure_routes)
+                .configure(crate::relay::configure_routes),
+        )
+        .await;
+        let as_user = |req: actix_web::test::TestRequest, user: &User| {
+            let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+            req.insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .to_request()
+        };
+
+        let req = as_user(
+            actix_web::test::TestRequest::post()
+                .uri("/organizations")
+                .set_json(serde_json::json!({"name": "Relay team"})),
+            &owner,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let organization: DataResponse<Organization> = actix_web::test::read_body_json(resp).await;
+        let uuid = organization.data.uuid;
+
+        // Owner invites a manager, who accepts.
+        let event = invitation(
+            &owner_keys,
+            INVITATION_KIND,
+            &uuid,
+            &manager_keys,
+            "manager",
+            None,
+        );
+        let invite = |event: &Event, user: &User| {
+            as_user(
+                actix_web::test::TestRequest::post()
+                    .uri(&format!("/organizations/{}/invitations", uuid))
+                    .set_json(serde_json::json!({ "event": event })),
+                user,
+            )
+        };
+        let resp = actix_web::test::call_service(&app, invite(&event, &owner)).await;
+        assert_eq!(resp.status(), 201);
+        let resp = actix_web::test::call_service(&app, invite(&event, &owner)).await;
+        assert_eq!(resp.status(), 409);
+
+        let req = as_user(
+            actix_web::test::TestRequest::get().uri("/organizations/invitations"),
+            &manager,
+        );
+        let invitations: DataResponse<Vec<Invitation>> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(invitations.data.len(), 1);
+        let accept = |invitation: &str, user: &User| {
+            as_user(
+                actix_web::test::TestRequest::post()
+                    .uri(&format!("/organizations/invitations/{}/accept", invitation)),
+                user,
+            )
+        };
+        let resp =
+            actix_web::test::call_service(&app, accept(&invitations.data[0].uuid, &outsider)).await;
+        assert_eq!(resp.status(), 404);
+        let resp =
+            actix_web::test::call_service(&app, accept(&invitations.data[0].uuid, &manager)).await;
+        assert_eq!(resp.status(), 200);
+
+        // Managers can invite viewers but not owners, and only with their own
+        // signature.
+        let event = invitation(
+            &manager_keys,
+            INVITATION_KIND,
+            &uuid,
+            &viewer_keys,
+            "owner",
+            None,
+        );
+        let resp = actix_web::test::call_service(&app, invite(&event, &manager)).await;
+        assert_eq!(resp.status(), 403);
+        let event = invitation(
+            &owner_keys,
+            INVITATION_KIND,
+            &uuid,
+            &viewer_keys,
+            "viewer",
+            None,
+        );
+        let resp = actix_web::test::call_service(&app, invite(&event, &manager)).await;
+        assert_eq!(resp.status(), 400);
+        let event = invitation(
+            &manager_keys,
+            INVITATION_KIND,
+            &uuid,
+            &viewer_keys,
+            "viewer",
+            None,
+        );
+        let resp = actix_web::test::call_service(&app, invite(&event, &manager)).await;
+        assert_eq!(resp.status(), 201);
+        let invitation: DataResponse<Invitation> = actix_web::test::read_body_json(resp).await;
+        let resp =
+            actix_web::test::call_service(&app, accept(&invitation.data.uuid, &viewer)).await;
+        assert_eq!(resp.status(), 200);
+
+        // The manager orders a relay for the organization.
+        let order = test_utils
+            .relay_order_repo
+            .create(CreateRelayOrder {
+                user_npub: manager.npub.clone(),
+                organization_uuid: Some(uuid.clone()),
+                amount: 1000,
+                cloud_provider: crate::cloud_provider::CloudProvider::AWS,
+                instance_type: crate::cloud_provider::InstanceType::AwsT2Nano,
+                implementation: crate::relay::RelayImplementation::Strfry,
+                hostname: "team.relaying.io".to_string(),
+                status: RelayOrderStatus::Paid,
+            })
+            .await
+            .unwrap();
+        let relay = test_utils.create_relay(order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "203.0.113.20")
+            .await
+            .unwrap();
+        assert_eq!(relay.organization_uuid.as_deref(), Some(uuid.as_str()));
+
+        for (user, visible) in [(&owner, true), (&viewer, true), (&outsider, false)] {
+            let req = as_user(actix_web::test::TestRequest::get().uri("/relays"), user);
+            let relays: Vec<Relay> = actix_web::test::call_and_read_body_json(&app, req).await;
+            assert_eq!(relays.iter().any(|r| r.uuid == relay.uuid), visible);
+        }
+        // Closing the manager's account must not take the team's relay down.
+        assert!(test_utils
+            .relay_repo
+            .get_user_relays(&manager.npub)
+            .await
+            .is_empty());
+
+        // Only owners delete relays or the organization.
+        let delete_relay = |user: &User| {
+            as_user(
+                actix_web::test::TestRequest::delete().uri(&format!("/relays/{}", relay.uuid)),
+                user,
+            )
+        };
+        let resp = actix_web::test::call_service(&app, delete_relay(&manager)).await;
+        assert_eq!(resp.status(), 404);
+        let req = as_user(
+            actix_web::test::TestRequest::delete().uri(&format!("/organizations/{}", uuid)),
+            &owner,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        let resp = actix_web::test::call_service(&app, delete_relay(&owner)).await;
+        assert_eq!(resp.status(), 204);
+
+        // The last owner can't step down or leave.
+        let member = |npub: &str| format!("/organizations/{}/members/{}", uuid, npub);
+        let req = as_user(
+            actix_web::test::TestRequest::put()
+                .uri(&member(&owner.npub))
+                .set_json(serde_json::json!({"role": "viewer"})),
+            &owner,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        let req = as_user(
+            actix_web::test::TestRequest::delete().uri(&member(&owner.npub)),
+            &owner,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+
+        // Viewers can leave but not remove others.
+        let req = as_user(
+            actix_web::test::TestRequest::delete().uri(&member(&manager.npub)),
+            &viewer,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+        let req = as_user(
+            actix_web::test::TestRequest::delete().uri(&member(&viewer.npub)),
+            &viewer,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 204);
+        let req = as_user(
+            actix_web::test::TestRequest::get().uri(&format!("/organizations/{}", uuid)),
+            &viewer,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+
+        let req = as_user(
+            actix_web::test::TestRequest::get().uri(&format!("/organizations/{}", uuid)),
+            &owner,
+        );
+        let organization: DataResponse<OrganizationWithMembers> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(organization.data.members.len(), 2);
+
+        let req = as_user(
+            actix_web::test::TestRequest::delete().uri(&format!("/organizations/{}", uuid)),
+            &owner,
+        );
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 204);
+    }
+
+    #[actix_web::test]
+    async fn test_invitation_acceptance_guards() {
+        let test_utils = TestUtils::new().await;
+        let organization_repo = OrganizationRepository::new(test_utils.pool.clone());
+        let (owner_keys, member_keys, invitee_keys) =
+            (Keys::generate(), Keys::generate(), Keys::generate());
+        let owner = register(&test_utils, &owner_keys).await;
+        let member = register(&test_utils, &member_keys).await;
+        let invitee = register(&test_utils, &invitee_keys).await;
+        let organization = organization_repo
+            .create("Relay team", &owner.npub)
+            .await
+            .unwrap();
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(organization_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(configure_routes),
+        )
+        .await;
+        let invite = |inviter: &Keys, invitee: &Keys, role: &str| {
+            let event = invitation(
+                inviter,
+                INVITATION_KIND,
+                &organization.uuid,
+                invitee,
+                role,
+                None,
+            );
+            let claim = parse_invitation(&event).unwrap();
+            let organization_repo = organization_repo.clone();
+            async move {
+                organization_repo
+                    .create_invitation(&claim, &event)
+                    .await
+                    .unwrap()
+                    .uuid
+            }
+        };
+        let accept = |invitation: &str, user: &User| {
+            let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+            actix_web::test::TestRequest::post()
+                .uri(&format!("/organizations/invitations/{}/accept", invitation))
+                .insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .to_request()
+        };
+
+        // A stale viewer invitation can't demote someone who became an owner
+        // in the meantime.
+        let as_viewer = invite(&owner_keys, &member_keys, "viewer").await;
+        let as_owner = invite(&owner_keys, &member_keys, "owner").await;
+        let resp = actix_web::test::call_service(&app, accept(&as_owner, &member)).await;
+        assert_eq!(resp.status(), 200);
+        let resp = actix_web::test::call_service(&app, accept(&as_viewer, &member)).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            organization_repo
+                .role(&organization.uuid, &member.npub)
+                .await,
+            Some(OrganizationRole::Owner)
+        );
+
+        // An inviter demoted after signing can no longer grant the role.
+        let as_manager = invite(&member_keys, &invitee_keys, "manager").await;
+        organization_repo
+            .set_role(&organization.uuid, &member.npub, OrganizationRole::Viewer)
+            .await
+            .unwrap();
+        let resp = actix_web::test::call_service(&app, accept(&as_manager, &invitee)).await;
+        assert_eq!(resp.status(), 403);
+        assert_eq!(
+            organization_repo
+                .role(&organization.uuid, &invitee.npub)
+                .await,
+            None
+        );
+    }
+}
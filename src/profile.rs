@@ -0,0 +1,432 @@
+use chrono::{NaiveDateTime, Utc};
+use futures::StreamExt;
+use nostr::{Event, Filter, Kind, Metadata};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    outbound::{public_client, read_limited},
+    relay_client::RelayClient,
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+/// Largest `nostr.json` accepted during NIP-05 verification.
+const MAX_NIP05_BYTES: usize = 64 * 1024;
+
+/// The parts of a user's kind 0 metadata we cache.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, PartialEq)]
+pub struct Profile {
+    pub hexpub: String,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub nip05: Option<String>,
+    pub nip05_verified: bool,
+    /// `created_at` of the metadata event the profile was built from.
+    pub metadata_created_at: Option<NaiveDateTime>,
+    pub fetched_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileConfig {
+    pub relay_urls: Vec<String>,
+    /// How often profiles are refreshed.
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl ProfileConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let relay_urls = dotenvy::var("PROFILE_RELAY_URLS")
+            .unwrap_or_else(|_| "wss://relay.damus.io,wss://nos.lol".to_string());
+
+        ProfileConfig {
+            relay_urls: relay_urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect(),
+            interval: Duration::from_secs(var("PROFILE_REFRESH_INTERVAL_SECS", 3600)),
+            timeout: Duration::from_secs(var("PROFILE_FETCH_TIMEOUT_SECS", 10)),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct ProfileRepository {
+    pub pool: PgPool,
+}
+
+impl ProfileRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get_one(&self, hexpub: &str) -> Option<Profile> {
+        sqlx::query_as::<_, Profile>("SELECT * FROM user_profiles WHERE hexpub = $1")
+            .bind(hexpub)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn upsert(&self, profile: &Profile) -> Result<Profile, sqlx::Error> {
+        sqlx::query_as::<_, Profile>(
+            "INSERT INTO user_profiles
+                (hexpub, name, picture, nip05, nip05_verified, metadata_created_at, fetched_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (hexpub) DO UPDATE SET
+                name = EXCLUDED.name,
+                picture = EXCLUDED.picture,
+                nip05 = EXCLUDED.nip05,
+                nip05_verified = EXCLUDED.nip05_verified,
+                metadata_created_at = EXCLUDED.metadata_created_at,
+                fetched_at = EXCLUDED.fetched_at
+            RETURNING *",
+        )
+        .bind(&profile.hexpub)
+        .bind(&profile.name)
+        .bind(&profile.picture)
+        .bind(&profile.nip05)
+        .bind(profile.nip05_verified)
+        .bind(profile.metadata_created_at)
+        .bind(profile.fetched_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Hexpubs of active users whose profile is missing or was fetched before
+    /// `before`, oldest first.
+    pub async fn get_stale(&self, before: NaiveDateTime, limit: i64) -> Vec<String> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT users.hexpub FROM users
+            LEFT JOIN user_profiles ON user_profiles.hexpub = users.hexpub
+            WHERE users.deleted_at IS NULL
+                AND (user_profiles.fetched_at IS NULL OR user_profiles.fetched_at < $1)
+            ORDER BY user_profiles.fetched_at ASC NULLS FIRST
+            LIMIT $2",
+        )
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// The newest kind 0 event of `hexpub` across `relay_urls`. Fails only if no
+/// relay could be queried.
+pub async fn fetch_metadata(
+    relay_client: &dyn RelayClient,
+    relay_urls: &[String],
+    hexpub: &str,
+) -> Result<Option<Event>, String> {
+    let filter = Filter::new()
+        .author(hexpub.to_string())
+        .kind(Kind::Metadata)
+        .limit(1);
+
+    let results = futures::future::join_all(
+        relay_urls
+            .iter()
+            .map(|url| relay_client.fetch(url, vec![filter.clone()])),
+    )
+    .await;
+
+    if !results.is_empty() && results.iter().all(Result::is_err) {
+        let errors: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+        return Err(errors.join("; "));
+    }
+
+    Ok(results
+        .into_iter()
+        .filter_map(Result::ok)
+        .flatten()
+        .filter(|event| event.kind == Kind::Metadata && event.pubkey.to_string() == hexpub)
+        .max_by_key(|event| event.created_at))
+}
+
+/// Whether a `nostr.json` document maps `name` to `hexpub`.
+fn nip05_matches(document: &Value, name: &str, hexpub: &str) -> bool {
+    document["names"][name]
+        .as_str()
+        .is_some_and(|value| value.eq_ignore_ascii_case(hexpub))
+}
+
+/// Checks that `nip05` maps to `hexpub` in the domain's `nostr.json`. The
+/// domain comes from user metadata, so only public hosts are fetched over
+/// https, and the document is capped at `MAX_NIP05_BYTES`.
+pub async fn verify_nip05(nip05: &str, hexpub: &str, timeout: Duration) -> Result<bool, String> {
+    let (name, domain) = match nip05.split_once('@') {
+        Some((name, domain)) if !domain.is_empty() => (name, domain),
+        _ => return Err(format!("Invalid NIP-05 identifier {}", nip05)),
+    };
+    let name = if name.is_empty() { "_" } else { name };
+
+    let url = reqwest::Url::parse(&format!("https://{}/.well-known/nostr.json", domain))
+        .map_err(|err| format!("Invalid NIP-05 domain {}: {}", domain, err))?;
+    // NIP-05 requires fetchers to ignore redirects, which the client does.
+    let client = public_client(&url, timeout).await?;
+
+    let response = client
+        .get(url)
+        .query(&[("name", name)])
+        .send()
+        .await
+        .map_err(|err| format!("Failed to fetch nostr.json of {}: {}", domain, err))?
+        .error_for_status()
+        .map_err(|err| format!("Failed to fetch nostr.json of {}: {}", domain, err))?;
+    let body = read_limited(response, MAX_NIP05_BYTES)
+        .await
+        .map_err(|err| format!("Failed to read nostr.json of {}: {}", domain, err))?;
+    let document: Value = serde_json::from_slice(&body)
+        .map_err(|err| format!("Invalid nostr.json of {}: {}", domain, err))?;
+
+    Ok(nip05_matches(&document, name, hexpub))
+}
+
+/// Fetches the user's metadata and re-verifies their NIP-05. If no relay has
+/// newer metadata, the cached fields are kept.
+pub async fn refresh_profile(
+    profile_repo: &ProfileRepository,
+    relay_client: &dyn RelayClient,
+    config: &ProfileConfig,
+    hexpub: &str,
+) -> Result<Profile, String> {
+    let cached = profile_repo.get_one(hexpub).await;
+    let event = fetch_metadata(relay_client, &config.relay_urls, hexpub).await?;
+    let created_at = event
+        .as_ref()
+        .and_then(|event| NaiveDateTime::from_timestamp_opt(event.created_at.as_i64(), 0));
+
+    let mut profile = match (event, cached) {
+        (Some(event), cached)
+            if cached
+                .as_ref()
+                .is_none_or(|cached| cached.metadata_created_at < created_at) =>
+        {
+            let metadata = Metadata::from_json(&event.content).unwrap_or_default();
+            Profile {
+                hexpub: hexpub.to_string(),
+                name: metadata.name,
+                picture: metadata.picture,
+                nip05: metadata.nip05,
+                nip05_verified: false,
+                metadata_created_at: created_at,
+                fetched_at: Utc::now().naive_utc(),
+            }
+        }
+        (_, Some(cached)) => cached,
+        (_, None) => Profile {
+            hexpub: hexpub.to_string(),
+            name: None,
+            picture: None,
+            nip05: None,
+            nip05_verified: false,
+            metadata_created_at: None,
+            fetched_at: Utc::now().naive_utc(),
+        },
+    };
+
+    profile.fetched_at = Utc::now().naive_utc();
+    profile.nip05_verified = match &profile.nip05 {
+        Some(nip05) => {
+            match verify_nip05(nip05, hexpub, config.timeout).await {
+                Ok(verified) => verified,
+                Err(err) => {
+                    eprintln!("Failed to verify NIP-05 of {}: {}", hexpub, err);
+                    false
+                }
+            }
+        }
+        None => false,
+    };
+
+    profile_repo
+        .upsert(&profile)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+pub async fn run_profile_refresher(
+    pool: PgPool,
+    relay_client: Arc<dyn RelayClient>,
+    config: ProfileConfig,
+) {
+    let mut interval = tokio::time::interval(config.interval.min(Duration::from_secs(60)));
+    let profile_repo = ProfileRepository::new(pool);
+
+    loop {
+        interval.tick().await;
+
+        let before =
+            Utc::now().naive_utc() - chrono::Duration::seconds(config.interval.as_secs() as i64);
+        let stale = profile_repo.get_stale(before, 100).await;
+
+        futures::stream::iter(stale)
+            .for_each_concurrent(5, |hexpub| {
+                let profile_repo = &profile_repo;
+                let relay_client = relay_client.as_ref();
+                let config = &config;
+                async move {
+                    if let Err(err) =
+                        refresh_profile(profile_repo, relay_client, config, &hexpub).await
+                    {
+                        eprintln!("Failed to refresh profile of {}: {}", hexpub, err);
+                    }
+                }
+            })
+            .await;
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::relay_client::WebsocketRelayClient;
+    use crate::test_relay::TestRelay;
+    use crate::token::TokenRepository;
+    use crate::util::{DataResponse, TestUtils};
+    use actix_web::{web, App};
+    use nostr::{EventBuilder, Keys};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_refresh_profile_and_me() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let profile_repo = ProfileRepository::new(test_utils.pool.clone());
+        let relay = TestRelay::start().await;
+        let relay_client = WebsocketRelayClient::from_env();
+        let config = ProfileConfig {
+            relay_urls: vec![relay.url.clone(), "ws://127.0.0.1:1".to_string()],
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(2),
+        };
+
+        let stale = profile_repo
+            .get_stale(Utc::now().naive_utc(), i64::MAX)
+            .await;
+        assert!(stale.contains(&user.hexpub));
+
+        let keys = Keys::generate();
+        let other = EventBuilder::set_metadata(Metadata::new().name("mallory"))
+            .to_event(&keys)
+            .unwrap();
+        relay_client.publish(&relay.url, &other).await.unwrap();
+
+        // Metadata of other authors is ignored.
+        let profile = refresh_profile(&profile_repo, &relay_client, &config, &user.hexpub)
+            .await
+            .unwrap();
+        assert_eq!(profile.name, None);
+
+        let keys = Keys::generate();
+        let alice = test_utils
+            .user_repo
+            .upsert(
+                &nostr::prelude::ToBech32::to_bech32(&keys.public_key()).unwrap(),
+                &keys.public_key().to_string(),
+            )
+            .await
+            .unwrap();
+        // The test relay serves a matching document, but local hosts are
+        // never fetched.
+        let host = relay.url.trim_start_matches("ws://").to_string();
+        relay.set_nip11(json!({ "names": { "alice": alice.hexpub } }));
+        let metadata = Metadata::new()
+            .name("alice")
+            .picture(nostr::Url::parse("https://example.com/alice.png").unwrap())
+            .nip05(format!("alice@{}", host));
+        let event = EventBuilder::set_metadata(metadata)
+            .to_event(&keys)
+            .unwrap();
+        relay_client.publish(&relay.url, &event).await.unwrap();
+
+        let profile = refresh_profile(&profile_repo, &relay_client, &config, &alice.hexpub)
+            .await
+            .unwrap();
+        assert_eq!(profile.name.as_deref(), Some("alice"));
+        assert_eq!(
+            profile.picture.as_deref(),
+            Some("https://example.com/alice.png")
+        );
+        assert_eq!(profile.nip05, Some(format!("alice@{}", host)));
+        assert!(!profile.nip05_verified);
+
+        // Refreshing without newer metadata keeps the cached fields.
+        let profile = refresh_profile(&profile_repo, &relay_client, &config, &alice.hexpub)
+            .await
+            .unwrap();
+        assert_eq!(profile.name.as_deref(), Some("alice"));
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.user_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .configure(crate::user::configure_routes),
+        )
+        .await;
+        let token = generate_jwt_by_hex(&alice.hexpub).unwrap();
+        let req = actix_web::test::TestRequest::get()
+            .uri("/me")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let me: DataResponse<crate::user::UserWithProfile> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(me.data.user.npub, alice.npub);
+        assert_eq!(me.data.profile, Some(profile));
+    }
+
+    #[tokio::test]
+    async fn test_verify_nip05() {
+        let hexpub = Keys::generate().public_key().to_string();
+        let document = json!({ "names": { "alice": hexpub.to_uppercase(), "_": "other" } });
+        assert!(nip05_matches(&document, "alice", &hexpub));
+        assert!(!nip05_matches(&document, "_", &hexpub));
+        assert!(!nip05_matches(&document, "bob", &hexpub));
+
+        for nip05 in [
+            "alice@localhost",
+            "alice@127.0.0.1:8080",
+            "alice@169.254.169.254",
+            "alice@[fd00:ec2::254]",
+            "alice@",
+        ] {
+            assert!(
+                verify_nip05(nip05, &hexpub, Duration::from_secs(1))
+                    .await
+                    .is_err(),
+                "{}",
+                nip05
+            );
+        }
+    }
+}
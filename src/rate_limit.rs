@@ -0,0 +1,493 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{token::signing_keys, util::ErrorResponse};
+
+// -----------------------------------------------------------------------------
+// Models
+// -----------------------------------------------------------------------------
+
+/// Routes sharing a bucket per client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Login,
+    Orders,
+    Api,
+}
+
+impl RouteGroup {
+    pub fn for_request(method: &Method, path: &str) -> Self {
+        if path.starts_with("/login") || path == "/token/refresh" {
+            RouteGroup::Login
+        } else if method == Method::POST && path == "/relay_orders" {
+            RouteGroup::Orders
+        } else {
+            RouteGroup::Api
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteGroup::Login => "login",
+            RouteGroup::Orders => "orders",
+            RouteGroup::Api => "api",
+        }
+    }
+}
+
+/// A bucket holding up to `capacity` requests, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitRule {
+    pub capacity: f64,
+    pub period: Duration,
+}
+
+impl RateLimitRule {
+    /// Parses `<requests>/<seconds>`, e.g. `10/60`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (requests, secs) = value.trim().split_once('/')?;
+        let capacity: f64 = requests.trim().parse().ok()?;
+        let secs: u64 = secs.trim().parse().ok()?;
+
+        if capacity < 1.0 || secs == 0 {
+            return None;
+        }
+
+        Some(Self {
+            capacity,
+            period: Duration::from_secs(secs),
+        })
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity / self.period.as_secs_f64()
+    }
+
+    /// Refills `tokens` after `elapsed` and takes one if possible. Returns the
+    /// tokens left and, if the request is rejected, how long until it fits.
+    fn take(&self, tokens: f64, elapsed: Duration) -> (f64, Option<Duration>) {
+        let tokens = (tokens + elapsed.as_secs_f64() * self.refill_per_sec()).min(self.capacity);
+
+        if tokens >= 1.0 {
+            (tokens - 1.0, None)
+        } else {
+            let wait = (1.0 - tokens) / self.refill_per_sec();
+            (tokens, Some(Duration::from_secs_f64(wait)))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub rules: HashMap<RouteGroup, RateLimitRule>,
+    /// Use `X-Forwarded-For`/`Forwarded` for the client IP. Only enable this
+    /// behind a proxy that sets them.
+    pub trust_proxy: bool,
+}
+
+impl RateLimitConfig {
+    /// Reads `RATE_LIMIT_<GROUP>` as `<requests>/<seconds>`. Groups set to
+    /// `off` aren't limited.
+    pub fn from_env() -> Self {
+        let defaults = [
+            (RouteGroup::Login, "10/60"),
+            (RouteGroup::Orders, "10/3600"),
+            (RouteGroup::Api, "600/60"),
+        ];
+
+        let rules = defaults
+            .into_iter()
+            .filter_map(|(group, default)| {
+                let var = format!("RATE_LIMIT_{}", group.as_str().to_uppercase());
+                let value = dotenvy::var(var).unwrap_or_else(|_| default.to_string());
+                RateLimitRule::parse(&value).map(|rule| (group, rule))
+            })
+            .collect();
+
+        RateLimitConfig {
+            rules,
+            trust_proxy: dotenvy::var("RATE_LIMIT_TRUST_PROXY").is_ok_and(|value| value == "true"),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Stores
+// -----------------------------------------------------------------------------
+
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket at `key`. Returns how long to wait if
+    /// the bucket is empty.
+    async fn take(&self, key: &str, rule: &RateLimitRule) -> Result<Option<Duration>, String>;
+}
+
+/// Picks the store from `RATE_LIMIT_STORE`. Use `postgres` when running more
+/// than one replica.
+pub fn store_from_env(pool: PgPool) -> Arc<dyn RateLimitStore> {
+    match dotenvy::var("RATE_LIMIT_STORE")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "postgres" => Arc::new(PgRateLimitStore::new(pool)),
+        _ => Arc::new(MemoryRateLimitStore::new()),
+    }
+}
+
+/// Buckets kept by the memory store before full ones are dropped.
+const MEMORY_STORE_PRUNE_AT: usize = 10_000;
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    /// Tokens, last update and the period of the rule the bucket is for.
+    buckets: Mutex<HashMap<String, (f64, Instant, Duration)>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, rule: &RateLimitRule) -> Result<Option<Duration>, String> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MEMORY_STORE_PRUNE_AT {
+            // A bucket that has refilled completely is the same as no bucket.
+            // Groups refill at different rates, so each goes by its own period.
+            buckets.retain(|_, (_, updated, period)| now.duration_since(*updated) < *period);
+        }
+
+        let (tokens, updated, _) =
+            buckets
+                .get(key)
+                .copied()
+                .unwrap_or((rule.capacity, now, rule.period));
+        let (tokens, retry_after) = rule.take(tokens, now.duration_since(updated));
+        buckets.insert(key.to_string(), (tokens, now, rule.period));
+
+        Ok(retry_after)
+    }
+}
+
+#[derive(Clone)]
+pub struct PgRateLimitStore {
+    pub pool: PgPool,
+}
+
+impl PgRateLimitStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PgRateLimitStore {
+    async fn take(&self, key: &str, rule: &RateLimitRule) -> Result<Option<Duration>, String> {
+        let now = Utc::now().naive_utc();
+        let mut tx = self.pool.begin().await.map_err(|err| err.to_string())?;
+
+        sqlx::query(
+            "INSERT INTO rate_limit_buckets (key, tokens, updated_at) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO NOTHING",
+        )
+        .bind(key)
+        .bind(rule.capacity)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        let (tokens, updated_at): (f64, chrono::NaiveDateTime) = sqlx::query_as(
+            "SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE",
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        let elapsed = (now - updated_at).to_std().unwrap_or_default();
+        let (tokens, retry_after) = rule.take(tokens, elapsed);
+
+        sqlx::query("UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3 WHERE key = $1")
+            .bind(key)
+            .bind(tokens)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        tx.commit().await.map_err(|err| err.to_string())?;
+
+        Ok(retry_after)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Middleware
+// -----------------------------------------------------------------------------
+
+/// Key of the bucket a request draws from. Requests with a valid JWT are
+/// limited per hexpub, everything else per client IP, since NIP-98 keys and
+/// invalid credentials cost nothing to make up.
+pub fn client_key(req: &ServiceRequest, trust_proxy: bool) -> String {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value).trim());
+
+    if let Some(claims) = token.and_then(|token| signing_keys().decode(token).ok()) {
+        return format!("pubkey:{}", claims.hexpub);
+    }
+
+    let connection_info = req.connection_info();
+    let ip = match trust_proxy {
+        true => connection_info.realip_remote_addr(),
+        false => connection_info.peer_addr(),
+    };
+    format!("ip:{}", ip.unwrap_or("unknown"))
+}
+
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    config: Rc<RateLimitConfig>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>, config: RateLimitConfig) -> Self {
+        Self {
+            store,
+            config: Rc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn RateLimitStore>,
+    config: Rc<RateLimitConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let group = RouteGroup::for_request(req.method(), req.path());
+
+            if let Some(rule) = config.rules.get(&group) {
+                let key = format!(
+                    "{}:{}",
+                    group.as_str(),
+                    client_key(&req, config.trust_proxy)
+                );
+
+                match store.take(&key, rule).await {
+                    Ok(Some(retry_after)) => {
+                        let response = HttpResponse::TooManyRequests()
+                            .insert_header((
+                                "Retry-After",
+                                retry_after.as_secs_f64().ceil().to_string(),
+                            ))
+                            .json(ErrorResponse::new("Too many requests".to_string()));
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                    Ok(None) => {}
+                    // Rather serve requests unlimited than fail them all when
+                    // the store is down.
+                    Err(err) => eprintln!("Rate limit store failed: {}", err),
+                }
+            }
+
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::util::TestUtils;
+    use actix_web::{web, App};
+    use nostr::Keys;
+
+    fn rule(capacity: f64, secs: u64) -> RateLimitRule {
+        RateLimitRule {
+            capacity,
+            period: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn test_rule_parse_and_refill() {
+        assert_eq!(RateLimitRule::parse("10/60"), Some(rule(10.0, 60)));
+        assert_eq!(RateLimitRule::parse("off"), None);
+        assert_eq!(RateLimitRule::parse("10/0"), None);
+
+        let rule = rule(2.0, 10);
+        let (tokens, retry_after) = rule.take(2.0, Duration::ZERO);
+        assert_eq!((tokens, retry_after), (1.0, None));
+        let (tokens, _) = rule.take(tokens, Duration::ZERO);
+        let (tokens, retry_after) = rule.take(tokens, Duration::ZERO);
+        assert_eq!(tokens, 0.0);
+        assert_eq!(retry_after, Some(Duration::from_secs(5)));
+
+        // Half the period refills one request, and buckets never overflow.
+        assert_eq!(rule.take(0.0, Duration::from_secs(5)).1, None);
+        assert_eq!(rule.take(0.0, Duration::from_secs(100)).0, 1.0);
+    }
+
+    async fn assert_limits(store: &dyn RateLimitStore) {
+        let key = uuid::Uuid::new_v4().to_string();
+        let rule = rule(2.0, 60);
+
+        assert_eq!(store.take(&key, &rule).await.unwrap(), None);
+        assert_eq!(store.take(&key, &rule).await.unwrap(), None);
+        let retry_after = store.take(&key, &rule).await.unwrap().unwrap();
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+
+        let other = uuid::Uuid::new_v4().to_string();
+        assert_eq!(store.take(&other, &rule).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        assert_limits(&MemoryRateLimitStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_prunes_by_bucket_period() {
+        let store = MemoryRateLimitStore::new();
+        let hourly = rule(1.0, 3600);
+        let fast = RateLimitRule {
+            capacity: 1.0,
+            period: Duration::from_millis(1),
+        };
+
+        assert_eq!(store.take("orders:a", &hourly).await.unwrap(), None);
+        for index in 0..MEMORY_STORE_PRUNE_AT {
+            store
+                .take(&format!("login:{}", index), &fast)
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // Pruning for a fast group drops its refilled buckets but keeps the
+        // hourly bucket that is still empty.
+        store.take("login:new", &fast).await.unwrap();
+        assert!(store.buckets.lock().unwrap().len() < MEMORY_STORE_PRUNE_AT);
+        assert!(store.take("orders:a", &hourly).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_pg_store() {
+        let test_utils = TestUtils::new().await;
+        assert_limits(&PgRateLimitStore::new(test_utils.pool.clone())).await;
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limiter_middleware() {
+        let config = RateLimitConfig {
+            rules: HashMap::from([(RouteGroup::Login, rule(1.0, 60))]),
+            trust_proxy: false,
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(RateLimiter::new(
+                    Arc::new(MemoryRateLimitStore::new()),
+                    config,
+                ))
+                .route("/login", web::post().to(HttpResponse::Ok))
+                .route("/relays", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let login = |ip: &str, token: Option<&str>| {
+            let req = actix_web::test::TestRequest::post()
+                .uri("/login")
+                .peer_addr(ip.parse().unwrap());
+            match token {
+                Some(token) => req
+                    .insert_header(("Authorization", format!("Bearer {}", token)))
+                    .to_request(),
+                None => req.to_request(),
+            }
+        };
+
+        let resp = actix_web::test::call_service(&app, login("10.0.0.1:1000", None)).await;
+        assert_eq!(resp.status(), 200);
+        let resp = actix_web::test::call_service(&app, login("10.0.0.1:1000", None)).await;
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "60");
+
+        // Other clients and authenticated users have their own buckets.
+        let resp = actix_web::test::call_service(&app, login("10.0.0.2:1000", None)).await;
+        assert_eq!(resp.status(), 200);
+        let hexpub = Keys::generate().public_key().to_string();
+        let token = generate_jwt_by_hex(&hexpub).unwrap();
+        let resp = actix_web::test::call_service(&app, login("10.0.0.1:1000", Some(&token))).await;
+        assert_eq!(resp.status(), 200);
+
+        // Groups without a rule aren't limited.
+        for _ in 0..3 {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/relays")
+                .peer_addr("10.0.0.1:1000".parse().unwrap())
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+    }
+}
@@ -1,8 +1,18 @@
 use super::cloud_provider::{CloudProvider, InstanceType};
 use crate::{
-    cloud_provider::{launch_instance, LaunchCloudInstance},
+    audit::{audit, AuditAction},
+    certificate::{
+        bootstrap_script, certificate_hostnames, new_agent_token, request_certificate, AcmeClient,
+        CertificateRepository,
+    },
+    cloud_provider::{launch_instance, InstanceProvider, LaunchCloudInstance},
+    custom_domain::{register_custom_domain, CustomDomainMethod, CustomDomainRepository},
+    dns::{delete_relay_dns, relay_hostname, DnsProvider},
     middleware::AuthorizationService,
+    notification::{notify_relay_team, Notification},
+    organization::OrganizationRole,
     user::UserRepository,
+    util::ErrorResponse,
 };
 use actix_web::{web, HttpResponse, Responder};
 use chrono::NaiveDateTime;
@@ -20,6 +30,9 @@ use uuid::Uuid;
 pub struct Relay {
     pub uuid: String,
     pub user_npub: String,
+    /// Set when the relay belongs to an organization rather than to
+    /// `user_npub`, who then is just the member who ordered it.
+    pub organization_uuid: Option<String>,
     pub relay_order_uuid: String,
     pub name: String,
     pub description: String,
@@ -44,6 +57,7 @@ impl Relay {
         Relay {
             uuid: relay.uuid,
             user_npub: relay.user_npub,
+            organization_uuid: relay.organization_uuid,
             relay_order_uuid: relay.relay_order_uuid,
             name: relay.name,
             description: relay.description,
@@ -65,7 +79,7 @@ impl Relay {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "relay_state", rename_all = "lowercase")]
 pub enum RelayState {
     Rebooting,
@@ -75,7 +89,7 @@ pub enum RelayState {
     Deleted,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "relay_implementation", rename_all = "lowercase")]
 pub enum RelayImplementation {
     Strfry,
@@ -91,10 +105,21 @@ impl RelayImplementation {
             RelayImplementation::Nostream => "nostream",
         }
     }
+
+    /// Machine image the implementation is installed on, empty if unset.
+    pub fn image_id(&self) -> String {
+        let name = match self {
+            RelayImplementation::Strfry => "STRFRY_AMI",
+            RelayImplementation::NostrRelayRs => "RUST_AMI",
+            RelayImplementation::Nostream => "NOSTREAM_AMI",
+        };
+        dotenvy::var(name).unwrap_or_default()
+    }
 }
 
 pub struct CreateRelay {
     pub user_npub: String,
+    pub organization_uuid: Option<String>,
     pub relay_order_uuid: String,
     pub name: String,
     pub description: String,
@@ -119,6 +144,7 @@ pub struct UpdateRelay {
 
 pub struct CreateRelayService {
     pub user_npub: String,
+    pub organization_uuid: Option<String>,
     pub relay_order_uuid: String,
     pub name: String,
     pub description: String,
@@ -158,13 +184,30 @@ impl RelayRepository {
         }
     }
 
-    pub async fn get_one_by_user(self: &Self, uuid: String, npub: String) -> Option<Relay> {
-        let relay =
-            sqlx::query_as::<_, Relay>("SELECT * FROM relays WHERE uuid = $1 AND user_npub = $2")
-                .bind(uuid)
-                .bind(npub)
-                .fetch_optional(&self.pool)
-                .await;
+    /// The relay if `npub` owns it, or it belongs to an organization where
+    /// `npub` holds at least `role`.
+    pub async fn get_one_for_member(
+        self: &Self,
+        uuid: String,
+        npub: String,
+        role: OrganizationRole,
+    ) -> Option<Relay> {
+        let relay = sqlx::query_as::<_, Relay>(
+            "SELECT * FROM relays r
+            WHERE r.uuid = $1
+            AND (
+                (r.organization_uuid IS NULL AND r.user_npub = $2)
+                OR EXISTS (
+                    SELECT 1 FROM organization_members m
+                    WHERE m.organization_uuid = r.organization_uuid AND m.npub = $2 AND m.role >= $3
+                )
+            )",
+        )
+        .bind(uuid)
+        .bind(npub)
+        .bind(role)
+        .fetch_optional(&self.pool)
+        .await;
 
         match relay {
             Ok(Some(relay)) => Some(Relay::from_db_relay(relay)),
@@ -183,9 +226,8 @@ impl RelayRepository {
         }
     }
 
-    pub async fn get_user_relays(self: &Self, npub: &str) -> Vec<Relay> {
-        let relays = sqlx::query_as::<_, Relay>("SELECT * FROM relays WHERE user_npub = $1")
-            .bind(npub)
+    pub async fn get_active(&self) -> Vec<Relay> {
+        let relays = sqlx::query_as::<_, Relay>("SELECT * FROM relays WHERE deleted_at IS NULL")
             .fetch_all(&self.pool)
             .await;
 
@@ -195,6 +237,38 @@ impl RelayRepository {
         }
     }
 
+    /// Relays the user owns personally.
+    pub async fn get_user_relays(self: &Self, npub: &str) -> Vec<Relay> {
+        let relays = sqlx::query_as::<_, Relay>(
+            "SELECT * FROM relays WHERE user_npub = $1 AND organization_uuid IS NULL",
+        )
+        .bind(npub)
+        .fetch_all(&self.pool)
+        .await;
+
+        match relays {
+            Ok(relays) => relays.into_iter().map(Relay::from_db_relay).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Relays the user owns, plus those of every organization they belong to.
+    pub async fn get_member_relays(&self, npub: &str) -> Vec<Relay> {
+        let relays = sqlx::query_as::<_, Relay>(
+            "SELECT * FROM relays
+            WHERE (organization_uuid IS NULL AND user_npub = $1)
+            OR organization_uuid IN (SELECT organization_uuid FROM organization_members WHERE npub = $1)",
+        )
+        .bind(npub)
+        .fetch_all(&self.pool)
+        .await;
+
+        match relays {
+            Ok(relays) => relays.into_iter().map(Relay::from_db_relay).collect(),
+            _ => vec![],
+        }
+    }
+
     pub async fn delete(self: &Self, uuid: &String) -> Result<(), sqlx::Error> {
         sqlx::query("DELETE FROM relays WHERE uuid = $1")
             .bind(uuid)
@@ -206,7 +280,7 @@ impl RelayRepository {
 
     pub async fn soft_delete(self: &Self, uuid: String) -> Result<(), sqlx::Error> {
         sqlx::query("UPDATE relays SET deleted_at = $1 WHERE uuid = $2")
-            .bind(NaiveDateTime::from_timestamp_opt(0, 0))
+            .bind(chrono::Utc::now().naive_utc())
             .bind(uuid)
             .execute(&self.pool)
             .await?;
@@ -214,11 +288,78 @@ impl RelayRepository {
         Ok(())
     }
 
+    pub async fn update_state(&self, uuid: &str, state: RelayState) -> Result<Relay, sqlx::Error> {
+        let db_relay: Relay = sqlx::query_as::<_, Relay>(
+            "UPDATE relays SET state = $1::relay_state, updated_at = $2 WHERE uuid = $3 RETURNING *",
+        )
+        .bind(state)
+        .bind(chrono::Local::now().naive_utc())
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Relay::from_db_relay(db_relay))
+    }
+
+    pub async fn update_instance_ip(&self, uuid: &str, instance_ip: &str) -> Result<Relay, sqlx::Error> {
+        let db_relay: Relay = sqlx::query_as::<_, Relay>(
+            "UPDATE relays SET instance_ip = $1, updated_at = $2 WHERE uuid = $3 RETURNING *",
+        )
+        .bind(instance_ip)
+        .bind(chrono::Local::now().naive_utc())
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Relay::from_db_relay(db_relay))
+    }
+
+    /// Points the relay at another instance. The IP is changed separately,
+    /// together with DNS, by `replace_relay_ip`.
+    pub async fn update_instance(
+        &self,
+        uuid: &str,
+        instance_id: &str,
+        instance_type: InstanceType,
+        implementation: RelayImplementation,
+        cloud_provider: CloudProvider,
+    ) -> Result<Relay, sqlx::Error> {
+        let db_relay: Relay = sqlx::query_as::<_, Relay>(
+            "UPDATE relays
+            SET instance_id = $1, instance_type = $2, implementation = $3, cloud_provider = $4, updated_at = $5
+            WHERE uuid = $6
+            RETURNING *",
+        )
+        .bind(instance_id)
+        .bind(instance_type)
+        .bind(implementation)
+        .bind(cloud_provider)
+        .bind(chrono::Local::now().naive_utc())
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Relay::from_db_relay(db_relay))
+    }
+
+    pub async fn update_custom_domain(&self, uuid: &str, custom_domain: &str) -> Result<Relay, sqlx::Error> {
+        let db_relay: Relay = sqlx::query_as::<_, Relay>(
+            "UPDATE relays SET custom_domain = $1, updated_at = $2 WHERE uuid = $3 RETURNING *",
+        )
+        .bind(custom_domain)
+        .bind(chrono::Local::now().naive_utc())
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Relay::from_db_relay(db_relay))
+    }
+
     pub async fn create(self: &Self, relay: CreateRelay) -> Result<Relay, sqlx::Error> {
         let uuid = Uuid::new_v4();
         let db_relay: Relay = sqlx::query_as::<_, Relay>(
-            "INSERT INTO relays (uuid, user_npub, relay_order_uuid, name, description, subdomain, custom_domain, instance_type, instance_id, instance_ip, implementation, cloud_provider, write_whitelist, read_whitelist, created_at, updated_at, expires_at, state)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8::relay_instance_type, $9, $10, $11::relay_implementation, $12::relay_cloud_provider, $13, $14, $15, $16, $17, $18::relay_state)
+            "INSERT INTO relays (uuid, user_npub, relay_order_uuid, name, description, subdomain, custom_domain, instance_type, instance_id, instance_ip, implementation, cloud_provider, write_whitelist, read_whitelist, created_at, updated_at, expires_at, state, organization_uuid)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8::relay_instance_type, $9, $10, $11::relay_implementation, $12::relay_cloud_provider, $13, $14, $15, $16, $17, $18::relay_state, $19)
             RETURNING *",
         )
         .bind(uuid.to_string())
@@ -239,6 +380,7 @@ impl RelayRepository {
         .bind(chrono::Local::now().naive_utc())
         .bind(relay.expires_at.clone())
         .bind(RelayState::Initializing)
+        .bind(relay.organization_uuid)
         .fetch_one(&self.pool)
         .await?;
 
@@ -274,6 +416,8 @@ impl RelayRepository {
 
 pub async fn create_relay_service(
     pool: &PgPool,
+    dns: &dyn DnsProvider,
+    acme: &dyn AcmeClient,
     relay: CreateRelayService,
 ) -> Result<Relay, String> {
     let repo = UserRepository::new(pool.clone());
@@ -281,24 +425,45 @@ pub async fn create_relay_service(
         return Err("User does not exist".to_string());
     }
 
+    // DNS-01 does not need the instance, so the certificate can be issued up
+    // front and handed to the instance at boot.
+    let subdomain = relay.subdomain.clone().unwrap_or_default();
+    let certificate = if subdomain.is_empty() {
+        None
+    } else {
+        match request_certificate(acme, dns, vec![relay_hostname(&subdomain)]).await {
+            Ok(certificate) => Some(certificate),
+            Err(err) => {
+                eprintln!("Failed to issue certificate for {}: {}", subdomain, err);
+                None
+            }
+        }
+    };
+
+    let enrollment_token = new_agent_token();
     let launch = LaunchCloudInstance {
         name: relay.name.clone(),
         image_id: dotenvy::var("STRFRY_AMI").unwrap(),
         instance_type: relay.instance_type,
         implementation: relay.implementation,
+        user_data: Some(bootstrap_script(&enrollment_token)),
     };
 
     let instance = launch_instance(launch).await;
 
+    let custom_domain = relay.custom_domain.filter(|domain| !domain.is_empty());
+
     match instance {
         Ok(instance) => {
             let create_relay = CreateRelay {
                 user_npub: relay.user_npub,
+                organization_uuid: relay.organization_uuid,
                 relay_order_uuid: relay.relay_order_uuid,
                 name: relay.name,
                 description: relay.description,
                 subdomain: relay.subdomain.unwrap_or_default(),
-                custom_domain: relay.custom_domain.unwrap_or_default(),
+                // Set once the domain passes verification.
+                custom_domain: String::new(),
                 instance_type: relay.instance_type,
                 instance_id: instance.id,
                 instance_ip: instance.ip_address,
@@ -314,12 +479,84 @@ pub async fn create_relay_service(
                 .await
                 .expect("Failed to create relay");
 
+            // The DNS record is published once the relay comes online.
+            let certificate_repo = CertificateRepository::new(pool.clone());
+            if let Err(err) = certificate_repo
+                .create_enrollment(&relay.uuid, &enrollment_token)
+                .await
+            {
+                eprintln!("Failed to enroll the instance of relay {}: {}", relay.uuid, err);
+            }
+
+            if let Some(certificate) = certificate {
+                if certificate.hostnames == certificate_hostnames(pool, &relay).await {
+                    if let Err(err) = certificate_repo.create(&relay.uuid, certificate).await {
+                        eprintln!("Failed to store certificate for relay {}: {}", relay.uuid, err);
+                    }
+                }
+            }
+
+            if let Some(domain) = custom_domain {
+                if let Err(err) =
+                    register_custom_domain(pool, &relay.uuid, &domain, CustomDomainMethod::Txt).await
+                {
+                    eprintln!("Failed to register custom domain for relay {}: {}", relay.uuid, err);
+                }
+            }
+
             Ok(relay)
         }
-        Err(err) => Err(err),
+        Err(err) => {
+            notify_relay_team(
+                pool,
+                relay.organization_uuid.as_deref(),
+                Notification::provisioning_failed(&relay.user_npub, &relay.relay_order_uuid, &relay.name),
+            )
+            .await;
+            Err(err)
+        }
     }
 }
 
+pub async fn terminate_relay_service(
+    pool: &PgPool,
+    dns: &dyn DnsProvider,
+    instances: &dyn InstanceProvider,
+    relay: Relay,
+) -> Result<(), String> {
+    instances.terminate(&relay.instance_id).await?;
+
+    // The instance is gone, so a DNS failure must not keep the relay alive.
+    // Relays that never came online never had a record published.
+    if relay.state != RelayState::Initializing {
+        if let Err(err) = delete_relay_dns(dns, &relay).await {
+            eprintln!("Failed to delete DNS record for relay {}: {}", relay.uuid, err);
+        }
+    }
+
+    CustomDomainRepository::new(pool.clone())
+        .release_for_relay(&relay.uuid)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let repo = RelayRepository::new(pool.clone());
+    repo.update_state(&relay.uuid, RelayState::Deleted)
+        .await
+        .map_err(|err| err.to_string())?;
+    repo.soft_delete(relay.uuid.clone())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    notify_relay_team(
+        pool,
+        relay.organization_uuid.as_deref(),
+        Notification::relay_terminated(&relay),
+    )
+    .await;
+
+    Ok(())
+}
+
 // -----------------------------------------------------------------------------
 // Handlers
 // -----------------------------------------------------------------------------
@@ -328,28 +565,81 @@ pub async fn get_relays_handler(
     auth: AuthorizationService,
     relay_repo: web::Data<RelayRepository>,
 ) -> impl Responder {
-    let relays = relay_repo.get_user_relays(auth.npub().unwrap()).await;
+    let relays = relay_repo.get_member_relays(auth.npub().unwrap()).await;
     HttpResponse::Ok().json(relays)
 }
 
+pub async fn delete_relay_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    dns: web::Data<dyn DnsProvider>,
+    instances: web::Data<dyn InstanceProvider>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Owner,
+        )
+        .await;
+
+    let relay = match relay {
+        Some(relay) => relay,
+        None => return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string())),
+    };
+
+    let uuid = relay.uuid.clone();
+    match terminate_relay_service(&relay_repo.pool, dns.get_ref(), instances.get_ref(), relay).await {
+        Ok(_) => {
+            audit(&relay_repo.pool, auth.npub().unwrap(), AuditAction::RelayDeleted, Some(&uuid)).await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err)),
+    }
+}
+
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/relays")
             .route("", web::get().to(get_relays_handler))
+            .route("/{uuid}", web::delete().to(delete_relay_handler))
     );
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        cloud_provider::terminate_instance,
+        certificate::LocalAcmeServer,
+        cloud_provider::{terminate_instance, LocalInstanceProvider},
+        dns::{sync_relay_dns, DnsRecord, DnsRecordType, LocalDnsProvider},
         relay_order::{CreateRelayOrder, RelayOrderRepository, RelayOrderStatus},
         util::{generate_random_string, TestUtils},
     };
 
     use super::*;
+    use async_trait::async_trait;
     use serde_json::json;
 
+    /// Rejects deleting records it doesn't have, like Route53.
+    struct StrictDnsProvider {
+        records: LocalDnsProvider,
+    }
+
+    #[async_trait]
+    impl DnsProvider for StrictDnsProvider {
+        async fn upsert_record(&self, record: &DnsRecord) -> Result<(), String> {
+            self.records.upsert_record(record).await
+        }
+
+        async fn delete_record(&self, record: &DnsRecord) -> Result<(), String> {
+            match self.records.get(&record.name, record.record_type) {
+                Some(_) => self.records.delete_record(record).await,
+                None => Err(format!("Record {} not found", record.name)),
+            }
+        }
+    }
+
     #[tokio::test]
     pub async fn test_create_relay_service() {
         let test_utils = TestUtils::new().await;
@@ -367,6 +657,7 @@ mod tests {
 
         let create_relay = CreateRelayService {
             user_npub: user.npub.clone(),
+            organization_uuid: None,
             relay_order_uuid: order.uuid.clone(),
             name: name.clone(),
             description: description.clone(),
@@ -380,7 +671,9 @@ mod tests {
             expires_at: expires_at.clone(),
         };
 
-        let relay = create_relay_service(&test_utils.pool, create_relay)
+        let dns = LocalDnsProvider::new();
+        let acme = LocalAcmeServer::new(dns.clone());
+        let relay = create_relay_service(&test_utils.pool, &dns, &acme, create_relay)
             .await
             .expect("Failed to create relay");
 
@@ -440,4 +733,75 @@ mod tests {
 
         test_utils.revert_migrations().await;
     }
+
+    #[tokio::test]
+    async fn test_terminate_relay_without_dns_record() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let dns = StrictDnsProvider {
+            records: LocalDnsProvider::new(),
+        };
+        let instances = LocalInstanceProvider::default();
+
+        // Never came online, so nothing was published.
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "203.0.113.10")
+            .await
+            .unwrap();
+        let (uuid, instance_id) = (relay.uuid.clone(), relay.instance_id.clone());
+        terminate_relay_service(&test_utils.pool, &dns, &instances, relay)
+            .await
+            .unwrap();
+
+        let terminated = test_utils.relay_repo.get_one(&uuid).await.unwrap();
+        assert_eq!(terminated.state, RelayState::Deleted);
+        assert!(terminated.deleted_at.is_some());
+        assert_eq!(*instances.terminated.lock().unwrap(), vec![instance_id]);
+
+        // An online relay whose record is already gone still gets deleted.
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "203.0.113.11")
+            .await
+            .unwrap();
+        let relay = test_utils
+            .relay_repo
+            .update_state(&relay.uuid, RelayState::Online)
+            .await
+            .unwrap();
+        let uuid = relay.uuid.clone();
+        terminate_relay_service(&test_utils.pool, &dns, &instances, relay)
+            .await
+            .unwrap();
+        let terminated = test_utils.relay_repo.get_one(&uuid).await.unwrap();
+        assert_eq!(terminated.state, RelayState::Deleted);
+
+        // A published record is removed.
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "203.0.113.12")
+            .await
+            .unwrap();
+        let relay = test_utils
+            .relay_repo
+            .update_state(&relay.uuid, RelayState::Online)
+            .await
+            .unwrap();
+        sync_relay_dns(&dns, &relay).await.unwrap();
+        let hostname = relay_hostname(&relay.subdomain);
+        assert!(dns.records.get(&hostname, DnsRecordType::A).is_some());
+        terminate_relay_service(&test_utils.pool, &dns, &instances, relay)
+            .await
+            .unwrap();
+        assert!(dns.records.get(&hostname, DnsRecordType::A).is_none());
+
+        test_utils.revert_migrations().await;
+    }
 }
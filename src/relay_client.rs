@@ -0,0 +1,451 @@
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use nostr::prelude::FromSkStr;
+use nostr::{ClientMessage, Event, Filter, Keys, RelayMessage, SubscriptionId};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// -----------------------------------------------------------------------------
+// Relay client
+// -----------------------------------------------------------------------------
+
+/// Talks to nostr relays on behalf of the backend.
+#[async_trait]
+pub trait RelayClient: Send + Sync {
+    /// Publishes an event and waits for the relay to accept it.
+    async fn publish(&self, relay_url: &str, event: &Event) -> Result<(), String>;
+
+    /// Returns the stored events matching `filters`, up to the relay's EOSE.
+    async fn fetch(&self, relay_url: &str, filters: Vec<Filter>) -> Result<Vec<Event>, String>;
+
+    /// Like `fetch`, but connects to one of `addrs` instead of resolving the
+    /// URL's host, so a host checked to be public can't be rebound to an
+    /// internal address before the connection is made.
+    async fn fetch_from(
+        &self,
+        relay_url: &str,
+        addrs: &[SocketAddr],
+        filters: Vec<Filter>,
+    ) -> Result<Vec<Event>, String>;
+
+    /// Streams events matching `filters` until the receiver is dropped.
+    async fn subscribe(
+        &self,
+        relay_url: &str,
+        filters: Vec<Filter>,
+    ) -> Result<mpsc::Receiver<Event>, String>;
+}
+
+/// The backend's own keypair, from `SERVICE_SECRET_KEY` as hex or nsec.
+/// `ANNOUNCE_SECRET_KEY`, its name from when only announcements were signed,
+/// is still read as a fallback. Features that sign events are off without it.
+pub fn service_keys() -> Option<Keys> {
+    let (name, secret_key) = ["SERVICE_SECRET_KEY", "ANNOUNCE_SECRET_KEY"]
+        .into_iter()
+        .find_map(|name| Some((name, dotenvy::var(name).ok()?)))?;
+
+    match Keys::from_sk_str(secret_key.trim()) {
+        Ok(keys) => Some(keys),
+        Err(err) => {
+            eprintln!("Invalid {}: {}", name, err);
+            None
+        }
+    }
+}
+
+/// Publishes to every relay in `relay_urls`. Succeeds if any of them accepted
+/// the event.
+pub async fn publish_to_any(
+    relay_client: &dyn RelayClient,
+    relay_urls: &[String],
+    event: &Event,
+) -> Result<(), String> {
+    let results = futures::future::join_all(
+        relay_urls
+            .iter()
+            .map(|url| relay_client.publish(url, event)),
+    )
+    .await;
+
+    if results.iter().any(Result::is_ok) {
+        return Ok(());
+    }
+
+    Err(results
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>()
+        .join("; "))
+}
+
+pub struct WebsocketRelayClient {
+    timeout: Duration,
+}
+
+/// Opens a websocket to `relay_url`, through one of `addrs` if given. The
+/// handshake still uses the URL's host for TLS and the Host header.
+async fn connect(
+    relay_url: &str,
+    addrs: Option<&[SocketAddr]>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String> {
+    let connected = match addrs {
+        Some(addrs) => {
+            let stream = TcpStream::connect(addrs)
+                .await
+                .map_err(|err| format!("Failed to connect to {}: {}", relay_url, err))?;
+            tokio_tungstenite::client_async_tls(relay_url, stream).await
+        }
+        None => tokio_tungstenite::connect_async(relay_url).await,
+    };
+
+    connected
+        .map(|(socket, _)| socket)
+        .map_err(|err| format!("Failed to connect to {}: {}", relay_url, err))
+}
+
+impl WebsocketRelayClient {
+    pub fn from_env() -> Self {
+        let timeout = dotenvy::var("RELAY_CLIENT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10);
+
+        Self {
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    async fn fetch_with(
+        &self,
+        relay_url: &str,
+        addrs: Option<&[SocketAddr]>,
+        filters: Vec<Filter>,
+    ) -> Result<Vec<Event>, String> {
+        let fetch = async {
+            let mut socket = connect(relay_url, addrs).await?;
+
+            let subscription_id = SubscriptionId::generate();
+            socket
+                .send(Message::Text(
+                    ClientMessage::new_req(subscription_id.clone(), filters).as_json(),
+                ))
+                .await
+                .map_err(|err| format!("Failed to send REQ: {}", err))?;
+
+            let mut events = vec![];
+            while let Some(message) = socket.next().await {
+                let text = match message {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(err) => return Err(format!("Websocket error: {}", err)),
+                };
+
+                match RelayMessage::from_json(text) {
+                    Ok(RelayMessage::Event {
+                        subscription_id: id,
+                        event,
+                    }) if id == subscription_id && event.verify().is_ok() => events.push(*event),
+                    Ok(RelayMessage::EndOfStoredEvents(id)) if id == subscription_id => {
+                        let _ = socket
+                            .send(Message::Text(
+                                ClientMessage::close(subscription_id).as_json(),
+                            ))
+                            .await;
+                        let _ = socket.close(None).await;
+                        return Ok(events);
+                    }
+                    _ => {}
+                }
+            }
+
+            Err(format!("{} closed the connection before EOSE", relay_url))
+        };
+
+        tokio::time::timeout(self.timeout, fetch)
+            .await
+            .map_err(|_| format!("Timed out fetching from {}", relay_url))?
+    }
+}
+
+#[async_trait]
+impl RelayClient for WebsocketRelayClient {
+    async fn publish(&self, relay_url: &str, event: &Event) -> Result<(), String> {
+        let publish = async {
+            let (mut socket, _) = tokio_tungstenite::connect_async(relay_url)
+                .await
+                .map_err(|err| format!("Failed to connect to {}: {}", relay_url, err))?;
+
+            socket
+                .send(Message::Text(
+                    ClientMessage::new_event(event.clone()).as_json(),
+                ))
+                .await
+                .map_err(|err| format!("Failed to send event: {}", err))?;
+
+            while let Some(message) = socket.next().await {
+                let text = match message {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(err) => return Err(format!("Websocket error: {}", err)),
+                };
+
+                if let Ok(RelayMessage::Ok {
+                    event_id,
+                    status,
+                    message,
+                }) = RelayMessage::from_json(text)
+                {
+                    if event_id == event.id {
+                        let _ = socket.close(None).await;
+                        return match status {
+                            true => Ok(()),
+                            false => Err(format!("Event rejected by {}: {}", relay_url, message)),
+                        };
+                    }
+                }
+            }
+
+            Err(format!("{} closed the connection before OK", relay_url))
+        };
+
+        tokio::time::timeout(self.timeout, publish)
+            .await
+            .map_err(|_| format!("Timed out publishing to {}", relay_url))?
+    }
+
+    async fn fetch(&self, relay_url: &str, filters: Vec<Filter>) -> Result<Vec<Event>, String> {
+        self.fetch_with(relay_url, None, filters).await
+    }
+
+    async fn fetch_from(
+        &self,
+        relay_url: &str,
+        addrs: &[SocketAddr],
+        filters: Vec<Filter>,
+    ) -> Result<Vec<Event>, String> {
+        self.fetch_with(relay_url, Some(addrs), filters).await
+    }
+
+    async fn subscribe(
+        &self,
+        relay_url: &str,
+        filters: Vec<Filter>,
+    ) -> Result<mpsc::Receiver<Event>, String> {
+        let (mut socket, _) =
+            tokio::time::timeout(self.timeout, tokio_tungstenite::connect_async(relay_url))
+                .await
+                .map_err(|_| format!("Timed out connecting to {}", relay_url))?
+                .map_err(|err| format!("Failed to connect to {}: {}", relay_url, err))?;
+
+        let subscription_id = SubscriptionId::generate();
+        socket
+            .send(Message::Text(
+                ClientMessage::new_req(subscription_id.clone(), filters).as_json(),
+            ))
+            .await
+            .map_err(|err| format!("Failed to send REQ: {}", err))?;
+
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    _ = tx.closed() => break,
+                    message = socket.next() => message,
+                };
+
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+
+                if let Ok(RelayMessage::Event {
+                    subscription_id: id,
+                    event,
+                }) = RelayMessage::from_json(text)
+                {
+                    if id == subscription_id
+                        && event.verify().is_ok()
+                        && tx.send(*event).await.is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+
+            let _ = socket
+                .send(Message::Text(
+                    ClientMessage::close(subscription_id).as_json(),
+                ))
+                .await;
+            let _ = socket.close(None).await;
+        });
+
+        Ok(rx)
+    }
+}
+
+/// In-process stand-in for a relay: published events are stored and delivered
+/// to every matching subscription.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct LocalRelayClient {
+    events: tokio::sync::broadcast::Sender<(String, Event)>,
+    stored: std::sync::Arc<std::sync::Mutex<Vec<(String, Event)>>>,
+}
+
+#[cfg(test)]
+impl LocalRelayClient {
+    pub fn new() -> Self {
+        let (events, _) = tokio::sync::broadcast::channel(100);
+
+        Self {
+            events,
+            stored: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl RelayClient for LocalRelayClient {
+    async fn publish(&self, relay_url: &str, event: &Event) -> Result<(), String> {
+        event.verify().map_err(|err| err.to_string())?;
+        self.stored
+            .lock()
+            .unwrap()
+            .push((relay_url.to_string(), event.clone()));
+        let _ = self.events.send((relay_url.to_string(), event.clone()));
+        Ok(())
+    }
+
+    /// Like a real relay, answers each filter newest first up to its limit.
+    async fn fetch(&self, relay_url: &str, filters: Vec<Filter>) -> Result<Vec<Event>, String> {
+        let stored = self.stored.lock().unwrap();
+        let mut seen = std::collections::HashSet::new();
+        let mut events: Vec<Event> = vec![];
+        for filter in &filters {
+            let mut matching: Vec<&Event> = stored
+                .iter()
+                .filter(|(url, event)| {
+                    url == relay_url && crate::test_relay::filter_matches(filter, event)
+                })
+                .map(|(_, event)| event)
+                .collect();
+            matching.sort_by_key(|event| std::cmp::Reverse(event.created_at));
+            for event in matching
+                .into_iter()
+                .take(filter.limit.unwrap_or(usize::MAX))
+            {
+                if seen.insert(event.id) {
+                    events.push(event.clone());
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn fetch_from(
+        &self,
+        relay_url: &str,
+        _addrs: &[SocketAddr],
+        filters: Vec<Filter>,
+    ) -> Result<Vec<Event>, String> {
+        self.fetch(relay_url, filters).await
+    }
+
+    async fn subscribe(
+        &self,
+        relay_url: &str,
+        filters: Vec<Filter>,
+    ) -> Result<mpsc::Receiver<Event>, String> {
+        let mut events = self.events.subscribe();
+        let relay_url = relay_url.to_string();
+        let (tx, rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            while let Ok((url, event)) = events.recv().await {
+                let matches = filters
+                    .iter()
+                    .any(|filter| crate::test_relay::filter_matches(filter, &event));
+                if url == relay_url && matches && tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_relay::TestRelay;
+    use nostr::{EventBuilder, Keys, Kind};
+
+    #[tokio::test]
+    async fn test_websocket_publish_and_subscribe() {
+        let relay = TestRelay::start().await;
+        let client = WebsocketRelayClient {
+            timeout: Duration::from_secs(2),
+        };
+        let keys = Keys::generate();
+        let event = EventBuilder::new_text_note("hello", &[])
+            .to_event(&keys)
+            .unwrap();
+
+        client.publish(&relay.url, &event).await.unwrap();
+
+        let fetched = client
+            .fetch(&relay.url, vec![Filter::new().author(keys.public_key().to_string())])
+            .await
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id, event.id);
+
+        let mut events = client
+            .subscribe(&relay.url, vec![Filter::new().kind(Kind::TextNote)])
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(2), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.id, event.id);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_from_pinned_address() {
+        let relay = TestRelay::start().await;
+        let client = WebsocketRelayClient {
+            timeout: Duration::from_secs(2),
+        };
+        let keys = Keys::generate();
+        let event = EventBuilder::new_text_note("hello", &[])
+            .to_event(&keys)
+            .unwrap();
+        client.publish(&relay.url, &event).await.unwrap();
+
+        // The host never resolves; only the pinned address is dialled.
+        let addr: SocketAddr = relay.url.trim_start_matches("ws://").parse().unwrap();
+        let url = format!("ws://relay.invalid:{}", addr.port());
+        assert!(client.fetch(&url, vec![Filter::new()]).await.is_err());
+        let fetched = client
+            .fetch_from(&url, &[addr], vec![Filter::new()])
+            .await
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id, event.id);
+    }
+}
@@ -0,0 +1,517 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    dns::relay_hostname,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayImplementation, RelayRepository},
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RelayInfo {
+    pub relay_uuid: String,
+    pub contact: Option<String>,
+    pub pubkey: Option<String>,
+    pub supported_nips: Json<Vec<u16>>,
+    pub limitation: Json<RelayLimitation>,
+    pub payments_url: Option<String>,
+    pub icon: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl RelayInfo {
+    /// Defaults used until the operator saves their own info.
+    pub fn default_for(relay: &Relay) -> Self {
+        let now = chrono::Local::now().naive_utc();
+        RelayInfo {
+            relay_uuid: relay.uuid.clone(),
+            contact: None,
+            pubkey: None,
+            supported_nips: Json(default_supported_nips(relay.implementation)),
+            limitation: Json(RelayLimitation::default()),
+            payments_url: None,
+            icon: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+/// The `limitation` object of NIP-11.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Validate)]
+pub struct RelayLimitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_message_length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_subscriptions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_filters: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_subid_length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_event_tags: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1))]
+    pub max_content_length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(range(max = 64))]
+    pub min_pow_difficulty: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct UpdateRelayInfo {
+    #[validate(length(min = 1, max = 255))]
+    pub contact: Option<String>,
+    #[validate(custom = "validate_hex_pubkey")]
+    pub pubkey: Option<String>,
+    #[validate(custom = "validate_supported_nips")]
+    pub supported_nips: Vec<u16>,
+    #[validate]
+    pub limitation: Option<RelayLimitation>,
+    #[validate(url, length(max = 255))]
+    pub payments_url: Option<String>,
+    #[validate(url, length(max = 255))]
+    pub icon: Option<String>,
+}
+
+fn validate_hex_pubkey(pubkey: &str) -> Result<(), ValidationError> {
+    if pubkey.len() == 64 && pubkey.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("pubkey must be 64 hex characters"))
+    }
+}
+
+fn validate_supported_nips(nips: &[u16]) -> Result<(), ValidationError> {
+    if nips.iter().all(|nip| *nip >= 1 && *nip <= 999) {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "NIP numbers must be between 1 and 999",
+        ))
+    }
+}
+
+/// A config file for the relay software, ready to be written on the instance.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenderedConfig {
+    pub path: String,
+    pub contents: String,
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct RelayInfoRepository {
+    pub pool: PgPool,
+}
+
+impl RelayInfoRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get_one(&self, relay_uuid: &str) -> Option<RelayInfo> {
+        sqlx::query_as::<_, RelayInfo>("SELECT * FROM relay_infos WHERE relay_uuid = $1")
+            .bind(relay_uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    pub async fn upsert(
+        &self,
+        relay_uuid: &str,
+        info: UpdateRelayInfo,
+    ) -> Result<RelayInfo, sqlx::Error> {
+        let mut supported_nips = info.supported_nips;
+        supported_nips.sort_unstable();
+        supported_nips.dedup();
+
+        sqlx::query_as::<_, RelayInfo>(
+            "INSERT INTO relay_infos (relay_uuid, contact, pubkey, supported_nips, limitation, payments_url, icon)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (relay_uuid) DO UPDATE
+            SET contact = $2, pubkey = $3, supported_nips = $4, limitation = $5, payments_url = $6, icon = $7, updated_at = CURRENT_TIMESTAMP
+            RETURNING *",
+        )
+        .bind(relay_uuid)
+        .bind(info.contact)
+        .bind(info.pubkey.map(|pubkey| pubkey.to_lowercase()))
+        .bind(Json(supported_nips))
+        .bind(Json(info.limitation.unwrap_or_default()))
+        .bind(info.payments_url)
+        .bind(info.icon)
+        .fetch_one(&self.pool)
+        .await
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+pub fn default_supported_nips(implementation: RelayImplementation) -> Vec<u16> {
+    match implementation {
+        RelayImplementation::Strfry => vec![1, 2, 4, 9, 11, 22, 28, 40, 70],
+        RelayImplementation::NostrRelayRs => vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40],
+        RelayImplementation::Nostream => vec![1, 2, 4, 9, 11, 12, 15, 16, 20, 22, 28, 33, 40],
+    }
+}
+
+pub fn software_url(implementation: RelayImplementation) -> &'static str {
+    match implementation {
+        RelayImplementation::Strfry => "git+https://github.com/hoytech/strfry.git",
+        RelayImplementation::NostrRelayRs => "https://git.sr.ht/~gheartsfield/nostr-rs-relay",
+        RelayImplementation::Nostream => "git+https://github.com/Cameri/nostream.git",
+    }
+}
+
+/// The NIP-11 document the relay will serve.
+pub fn render_nip11(relay: &Relay, info: &RelayInfo) -> Value {
+    let mut document = json!({
+        "name": relay.name,
+        "description": relay.description,
+        "supported_nips": info.supported_nips.0,
+        "software": software_url(relay.implementation),
+    });
+
+    let optional = [
+        ("pubkey", &info.pubkey),
+        ("contact", &info.contact),
+        ("payments_url", &info.payments_url),
+        ("icon", &info.icon),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            document[key] = json!(value);
+        }
+    }
+
+    if info.limitation.0 != RelayLimitation::default() {
+        document["limitation"] = json!(info.limitation.0);
+    }
+
+    document
+}
+
+/// Quotes a value for the relay config files. All three formats accept
+/// JSON-style double quoted strings.
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+/// Renders the operator's settings into the config format of the relay's
+/// implementation.
+pub fn render_relay_config(relay: &Relay, info: &RelayInfo) -> RenderedConfig {
+    let limitation = &info.limitation.0;
+    let relay_url = format!("wss://{}", relay_hostname(&relay.subdomain));
+    let nips = info
+        .supported_nips
+        .0
+        .iter()
+        .map(|nip| nip.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let optional = |value: &Option<String>| quote(value.as_deref().unwrap_or_default());
+
+    match relay.implementation {
+        RelayImplementation::Strfry => {
+            let mut contents = format!(
+                "relay {{\n    info {{\n        name = {}\n        description = {}\n        pubkey = {}\n        contact = {}\n        icon = {}\n        nips = \"[{}]\"\n    }}\n",
+                quote(&relay.name),
+                quote(&relay.description),
+                optional(&info.pubkey),
+                optional(&info.contact),
+                optional(&info.icon),
+                nips,
+            );
+            if let Some(value) = limitation.max_message_length {
+                contents += &format!("    maxWebsocketPayloadSize = {}\n", value);
+            }
+            if let Some(value) = limitation.max_subscriptions {
+                contents += &format!("    maxSubsPerConnection = {}\n", value);
+            }
+            if let Some(value) = limitation.max_limit {
+                contents += &format!("    maxFilterLimit = {}\n", value);
+            }
+            contents += "}\n";
+
+            RenderedConfig {
+                path: "/etc/strfry.conf.d/info.conf".to_string(),
+                contents,
+            }
+        }
+        RelayImplementation::NostrRelayRs => {
+            let mut contents = format!(
+                "[info]\nrelay_url = {}\nname = {}\ndescription = {}\npubkey = {}\ncontact = {}\nrelay_icon = {}\n",
+                quote(&relay_url),
+                quote(&relay.name),
+                quote(&relay.description),
+                optional(&info.pubkey),
+                optional(&info.contact),
+                optional(&info.icon),
+            );
+            contents += "\n[limits]\n";
+            if let Some(value) = limitation.max_message_length {
+                contents += &format!("max_ws_message_bytes = {}\n", value);
+            }
+            if let Some(value) = limitation.max_content_length {
+                contents += &format!("max_event_bytes = {}\n", value);
+            }
+            if let Some(value) = limitation.max_subscriptions {
+                contents += &format!("max_subscriptions = {}\n", value);
+            }
+            if let Some(value) = &info.payments_url {
+                contents += &format!("\n[pay_to_relay]\nterms_message = {}\n", quote(value));
+            }
+
+            RenderedConfig {
+                path: "/etc/nostr-rs-relay/config.toml".to_string(),
+                contents,
+            }
+        }
+        RelayImplementation::Nostream => {
+            let mut contents = format!(
+                "info:\n  relay_url: {}\n  name: {}\n  description: {}\n  pubkey: {}\n  contact: {}\n",
+                quote(&relay_url),
+                quote(&relay.name),
+                quote(&relay.description),
+                optional(&info.pubkey),
+                optional(&info.contact),
+            );
+            contents += "limits:\n";
+            if let Some(value) = limitation.max_message_length {
+                contents += &format!("  connection:\n    maxPayloadSize: {}\n", value);
+            }
+            contents += "  event:\n";
+            if let Some(value) = limitation.max_content_length {
+                contents += &format!("    content:\n      maxLength: {}\n", value);
+            }
+            if let Some(value) = limitation.min_pow_difficulty {
+                contents += &format!("    eventId:\n      minLeadingZeroBits: {}\n", value);
+            }
+            contents += "  client:\n    subscription:\n";
+            if let Some(value) = limitation.max_subscriptions {
+                contents += &format!("      maxSubscriptions: {}\n", value);
+            }
+            if let Some(value) = limitation.max_filters {
+                contents += &format!("      maxFilters: {}\n", value);
+            }
+
+            RenderedConfig {
+                path: "/etc/nostream/settings.yaml".to_string(),
+                contents,
+            }
+        }
+    }
+}
+
+/// The saved info for the relay, or the defaults when none was saved yet.
+pub async fn get_relay_info(pool: &PgPool, relay: &Relay) -> RelayInfo {
+    RelayInfoRepository::new(pool.clone())
+        .get_one(&relay.uuid)
+        .await
+        .unwrap_or_else(|| RelayInfo::default_for(relay))
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn update_relay_info_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+    data: web::Json<UpdateRelayInfo>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Manager,
+        )
+        .await;
+
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    if let Err(errors) = data.validate() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(errors.to_string()));
+    }
+
+    match RelayInfoRepository::new(relay_repo.pool.clone())
+        .upsert(&relay.uuid, data.into_inner())
+        .await
+    {
+        Ok(info) => HttpResponse::Ok().json(DataResponse::new(info)),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
+    }
+}
+
+async fn preview_relay_info_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+
+    match relay {
+        Some(relay) => {
+            let info = get_relay_info(&relay_repo.pool, &relay).await;
+            HttpResponse::Ok()
+                .content_type("application/nostr+json")
+                .json(render_nip11(&relay, &info))
+        }
+        None => HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string())),
+    }
+}
+
+async fn get_relay_config_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+
+    match relay {
+        Some(relay) => {
+            let info = get_relay_info(&relay_repo.pool, &relay).await;
+            HttpResponse::Ok().json(DataResponse::new(render_relay_config(&relay, &info)))
+        }
+        None => HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string())),
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/info").route(web::put().to(update_relay_info_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/info/preview")
+            .route(web::get().to(preview_relay_info_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/info/config").route(web::get().to(get_relay_config_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestUtils;
+
+    fn update(pubkey: Option<&str>, icon: Option<&str>, nips: Vec<u16>) -> UpdateRelayInfo {
+        UpdateRelayInfo {
+            contact: Some("admin@example.com".to_string()),
+            pubkey: pubkey.map(|pubkey| pubkey.to_string()),
+            supported_nips: nips,
+            limitation: Some(RelayLimitation {
+                max_message_length: Some(16384),
+                max_subscriptions: Some(20),
+                auth_required: Some(false),
+                ..Default::default()
+            }),
+            payments_url: None,
+            icon: icon.map(|icon| icon.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_validate_update_relay_info() {
+        let pubkey = "a".repeat(64);
+        assert!(update(
+            Some(&pubkey),
+            Some("https://example.com/icon.png"),
+            vec![1, 11]
+        )
+        .validate()
+        .is_ok());
+        assert!(update(Some("npub1xyz"), None, vec![1]).validate().is_err());
+        assert!(update(None, Some("not a url"), vec![1]).validate().is_err());
+        assert!(update(None, None, vec![0]).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upsert_and_render_relay_info() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let repo = RelayInfoRepository::new(test_utils.pool.clone());
+
+        let info = repo
+            .upsert(
+                &relay.uuid,
+                update(Some(&user.hexpub), None, vec![11, 1, 11]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(info.supported_nips.0, vec![1, 11]);
+
+        let document = render_nip11(&relay, &info);
+        assert_eq!(document["name"], "test relay");
+        assert_eq!(document["pubkey"], user.hexpub.as_str());
+        assert_eq!(document["supported_nips"], json!([1, 11]));
+        assert_eq!(document["limitation"]["max_subscriptions"], 20);
+        assert!(document.get("icon").is_none());
+
+        let config = render_relay_config(&relay, &info);
+        assert!(config.contents.contains("name = \"test relay\""));
+        assert!(config.contents.contains("maxSubsPerConnection = 20"));
+
+        let info = repo
+            .upsert(&relay.uuid, update(None, None, vec![1]))
+            .await
+            .unwrap();
+        assert!(info.pubkey.is_none());
+    }
+}
@@ -0,0 +1,1025 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, Utc};
+use nostr::{Event, EventId, Timestamp};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    backup::{dump_events, restore_events},
+    certificate::{bootstrap_script, new_agent_token, CertificateRepository},
+    cloud_provider::{
+        CloudInstance, CloudProvider, InstanceProvider, InstanceType, LaunchCloudInstance,
+    },
+    dns::{replace_relay_ip, DnsProvider},
+    health::probe_relay,
+    middleware::AuthorizationService,
+    organization::OrganizationRole,
+    relay::{Relay, RelayImplementation, RelayRepository},
+    relay_client::RelayClient,
+    util::{DataResponse, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "relay_migration_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStatus {
+    Running,
+    Completed,
+    /// The migration failed and so did its rollback; needs an operator.
+    Failed,
+    RolledBack,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "relay_migration_step", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStep {
+    Provisioning,
+    Copying,
+    SwitchingDns,
+    Verifying,
+    /// Copying events written during the migration, then terminating the old
+    /// instance.
+    Terminating,
+    Done,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RelayMigration {
+    pub uuid: String,
+    pub relay_uuid: String,
+    pub npub: String,
+    pub status: MigrationStatus,
+    pub step: MigrationStep,
+    pub from_implementation: RelayImplementation,
+    pub from_instance_type: InstanceType,
+    pub from_instance_id: String,
+    pub from_instance_ip: String,
+    pub to_implementation: RelayImplementation,
+    pub to_instance_type: InstanceType,
+    pub new_instance_id: Option<String>,
+    pub new_instance_ip: Option<String>,
+    pub copied_events: i64,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+/// The cloud provider follows from the instance type.
+#[derive(Debug, Deserialize)]
+pub struct CreateMigrationDto {
+    pub implementation: RelayImplementation,
+    pub instance_type: InstanceType,
+}
+
+pub struct MigrationConfig {
+    /// Port the relay listens on inside the instance. Instances are reached
+    /// directly since DNS only points at the new one halfway through.
+    pub instance_port: u16,
+    pub probe_timeout: Duration,
+    /// Health probes while the new instance boots, before anything is copied.
+    pub ready_attempts: u32,
+    /// Health probes of the new instance after the DNS switch.
+    pub verify_attempts: u32,
+    pub verify_delay: Duration,
+}
+
+impl MigrationConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            dotenvy::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        MigrationConfig {
+            instance_port: var("RELAY_INSTANCE_PORT", 7777) as u16,
+            probe_timeout: Duration::from_secs(var("HEALTH_CHECK_TIMEOUT_SECS", 10)),
+            ready_attempts: var("MIGRATION_READY_ATTEMPTS", 40) as u32,
+            verify_attempts: var("MIGRATION_VERIFY_ATTEMPTS", 10) as u32,
+            verify_delay: Duration::from_secs(var("MIGRATION_VERIFY_DELAY_SECS", 15)),
+        }
+    }
+
+    pub fn instance_url(&self, ip: &str) -> String {
+        format!("ws://{}:{}", ip, self.instance_port)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct RelayMigrationRepository {
+    pub pool: PgPool,
+}
+
+impl RelayMigrationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        relay: &Relay,
+        npub: &str,
+        dto: &CreateMigrationDto,
+    ) -> Result<RelayMigration, sqlx::Error> {
+        sqlx::query_as::<_, RelayMigration>(
+            "INSERT INTO relay_migrations
+            (uuid, relay_uuid, npub, status, step, from_implementation, from_instance_type,
+             from_instance_id, from_instance_ip, to_implementation, to_instance_type)
+            VALUES ($1, $2, $3, 'running', 'provisioning', $4, $5, $6, $7, $8, $9)
+            RETURNING *",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&relay.uuid)
+        .bind(npub)
+        .bind(relay.implementation)
+        .bind(relay.instance_type)
+        .bind(&relay.instance_id)
+        .bind(&relay.instance_ip)
+        .bind(dto.implementation)
+        .bind(dto.instance_type)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_one(&self, uuid: &str, relay_uuid: &str) -> Option<RelayMigration> {
+        sqlx::query_as::<_, RelayMigration>(
+            "SELECT * FROM relay_migrations WHERE uuid = $1 AND relay_uuid = $2",
+        )
+        .bind(uuid)
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Running migrations of every relay.
+    pub async fn get_all_running(&self) -> Vec<RelayMigration> {
+        sqlx::query_as::<_, RelayMigration>(
+            "SELECT * FROM relay_migrations WHERE status = 'running' ORDER BY created_at",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn get_running(&self, relay_uuid: &str) -> Option<RelayMigration> {
+        sqlx::query_as::<_, RelayMigration>(
+            "SELECT * FROM relay_migrations WHERE relay_uuid = $1 AND status = 'running'",
+        )
+        .bind(relay_uuid)
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    /// The relay's migrations, newest first.
+    pub async fn get_for_relay(&self, relay_uuid: &str) -> Vec<RelayMigration> {
+        sqlx::query_as::<_, RelayMigration>(
+            "SELECT * FROM relay_migrations WHERE relay_uuid = $1 ORDER BY created_at DESC",
+        )
+        .bind(relay_uuid)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+    }
+
+    pub async fn set_step(&self, uuid: &str, step: MigrationStep) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE relay_migrations SET step = $2, updated_at = $3 WHERE uuid = $1")
+            .bind(uuid)
+            .bind(step)
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_new_instance(
+        &self,
+        uuid: &str,
+        instance: &CloudInstance,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE relay_migrations
+            SET new_instance_id = $2, new_instance_ip = $3, updated_at = $4
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(&instance.id)
+        .bind(&instance.ip_address)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_copied_events(&self, uuid: &str, count: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE relay_migrations
+            SET copied_events = copied_events + $2, updated_at = $3
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(count)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn finish(
+        &self,
+        uuid: &str,
+        status: MigrationStatus,
+        error: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let step = if status == MigrationStatus::Completed {
+            Some(MigrationStep::Done)
+        } else {
+            None
+        };
+
+        sqlx::query(
+            "UPDATE relay_migrations
+            SET status = $2, step = COALESCE($3, step), error = $4, updated_at = $5, completed_at = $5
+            WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .bind(status)
+        .bind(step)
+        .bind(error)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// What has to be undone if the migration fails.
+#[derive(Default)]
+struct Progress {
+    new_instance: Option<CloudInstance>,
+    dns_switched: bool,
+    /// Events already in the new instance, skipped by the second copy.
+    copied: HashSet<EventId>,
+}
+
+struct Migrator<'a> {
+    pool: &'a PgPool,
+    dns: &'a dyn DnsProvider,
+    instances: &'a dyn InstanceProvider,
+    relay_client: &'a dyn RelayClient,
+    config: &'a MigrationConfig,
+    repo: RelayMigrationRepository,
+    migration: &'a RelayMigration,
+}
+
+impl Migrator<'_> {
+    async fn set_step(&self, step: MigrationStep) -> Result<(), String> {
+        self.repo
+            .set_step(&self.migration.uuid, step)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// Copies the old instance's events into the new one. Any rejected event
+    /// fails the migration rather than losing data.
+    async fn copy_events(
+        &self,
+        new_ip: &str,
+        since: Option<Timestamp>,
+        copied_ids: &mut HashSet<EventId>,
+    ) -> Result<(), String> {
+        let old_url = self.config.instance_url(&self.migration.from_instance_ip);
+        let events: Vec<Event> = dump_events(self.relay_client, &old_url, since)
+            .await?
+            .into_iter()
+            .filter(|event| copied_ids.insert(event.id))
+            .collect();
+        let (copied, rejected) =
+            restore_events(self.relay_client, &self.config.instance_url(new_ip), events).await;
+
+        self.repo
+            .add_copied_events(&self.migration.uuid, copied)
+            .await
+            .map_err(|err| err.to_string())?;
+        match rejected {
+            0 => Ok(()),
+            _ => Err(format!(
+                "{} events were rejected by the new instance",
+                rejected
+            )),
+        }
+    }
+
+    /// Probes the new instance until it answers, up to `attempts` times.
+    async fn verify(&self, new_ip: &str, attempts: u32) -> Result<(), String> {
+        let url = self.config.instance_url(new_ip);
+        let mut error = None;
+
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                tokio::time::sleep(self.config.verify_delay).await;
+            }
+            let probe = probe_relay(&url, self.config.probe_timeout).await;
+            if probe.available {
+                return Ok(());
+            }
+            error = probe.error;
+        }
+
+        Err(format!(
+            "New instance failed its health check: {}",
+            error.unwrap_or_default()
+        ))
+    }
+
+    /// Everything up to terminating the old instance, recording in `progress`
+    /// what a rollback has to undo.
+    async fn migrate(&self, relay: Relay, progress: &mut Progress) -> Result<(), String> {
+        let enrollment_token = new_agent_token();
+        CertificateRepository::new(self.pool.clone())
+            .create_enrollment(&relay.uuid, &enrollment_token)
+            .await
+            .map_err(|err| err.to_string())?;
+        let launch = LaunchCloudInstance {
+            name: relay.name.clone(),
+            image_id: self.migration.to_implementation.image_id(),
+            instance_type: self.migration.to_instance_type,
+            implementation: self.migration.to_implementation,
+            user_data: Some(bootstrap_script(&enrollment_token)),
+        };
+        let instance = self.instances.launch(launch).await?;
+        let new_id = instance.id.clone();
+        let new_ip = instance.ip_address.clone();
+        progress.new_instance = Some(instance);
+        self.repo
+            .set_new_instance(
+                &self.migration.uuid,
+                progress.new_instance.as_ref().unwrap(),
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+
+        // Launching only waits for an address; the relay itself comes up
+        // once the instance has booted and enrolled.
+        self.verify(&new_ip, self.config.ready_attempts).await?;
+
+        self.set_step(MigrationStep::Copying).await?;
+        let copy_started = Timestamp::now();
+        self.copy_events(&new_ip, None, &mut progress.copied)
+            .await?;
+
+        self.set_step(MigrationStep::SwitchingDns).await?;
+        progress.dns_switched = true;
+        let relay = replace_relay_ip(self.pool, self.dns, relay, &new_ip).await?;
+        RelayRepository::new(self.pool.clone())
+            .update_instance(
+                &relay.uuid,
+                &new_id,
+                self.migration.to_instance_type,
+                self.migration.to_implementation,
+                self.migration.to_instance_type.cloud_provider(),
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+
+        self.set_step(MigrationStep::Verifying).await?;
+        self.verify(&new_ip, self.config.verify_attempts).await?;
+
+        self.set_step(MigrationStep::Terminating).await?;
+        self.copy_events(&new_ip, Some(copy_started), &mut progress.copied)
+            .await
+    }
+
+    /// Terminates the old instance once the relay runs on the new one. The
+    /// migration is completed even if that fails, but the error is kept.
+    async fn complete(&self) -> (MigrationStatus, Option<String>) {
+        match self.instances.terminate(&self.migration.from_instance_id).await {
+            Ok(()) => (MigrationStatus::Completed, None),
+            Err(err) => (
+                MigrationStatus::Completed,
+                Some(format!(
+                    "Old instance {} could not be terminated: {}",
+                    self.migration.from_instance_id, err
+                )),
+            ),
+        }
+    }
+
+    /// Points the relay back at the old instance and terminates the new one.
+    async fn roll_back(
+        &self,
+        from_cloud_provider: CloudProvider,
+        progress: Progress,
+    ) -> Result<(), String> {
+        if progress.dns_switched {
+            let relay_repo = RelayRepository::new(self.pool.clone());
+            let current = relay_repo
+                .get_one(&self.migration.relay_uuid)
+                .await
+                .ok_or("Relay not found")?;
+            replace_relay_ip(
+                self.pool,
+                self.dns,
+                current,
+                &self.migration.from_instance_ip,
+            )
+            .await?;
+            relay_repo
+                .update_instance(
+                    &self.migration.relay_uuid,
+                    &self.migration.from_instance_id,
+                    self.migration.from_instance_type,
+                    self.migration.from_implementation,
+                    from_cloud_provider,
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+
+        if let Some(instance) = progress.new_instance {
+            self.instances.terminate(&instance.id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Moves the relay to a new instance, keeping its hostname and events.
+/// Anything failing before the old instance is terminated is rolled back.
+pub async fn run_migration(
+    pool: PgPool,
+    dns: Arc<dyn DnsProvider>,
+    instances: Arc<dyn InstanceProvider>,
+    relay_client: Arc<dyn RelayClient>,
+    config: Arc<MigrationConfig>,
+    migration: RelayMigration,
+) {
+    let repo = RelayMigrationRepository::new(pool.clone());
+    let migrator = Migrator {
+        pool: &pool,
+        dns: dns.as_ref(),
+        instances: instances.as_ref(),
+        relay_client: relay_client.as_ref(),
+        config: config.as_ref(),
+        repo: repo.clone(),
+        migration: &migration,
+    };
+
+    let relay = match RelayRepository::new(pool.clone())
+        .get_one(&migration.relay_uuid)
+        .await
+    {
+        Some(relay) => relay,
+        None => {
+            let _ = repo
+                .finish(
+                    &migration.uuid,
+                    MigrationStatus::Failed,
+                    Some("Relay not found".to_string()),
+                )
+                .await;
+            return;
+        }
+    };
+
+    let from_cloud_provider = relay.cloud_provider;
+    let mut progress = Progress::default();
+    let (status, error) = match migrator.migrate(relay, &mut progress).await {
+        Ok(()) => migrator.complete().await,
+        Err(err) => {
+            eprintln!("Migration {} failed: {}", migration.uuid, err);
+            match migrator.roll_back(from_cloud_provider, progress).await {
+                Ok(()) => (MigrationStatus::RolledBack, Some(err)),
+                Err(rollback_err) => {
+                    eprintln!(
+                        "Rollback of migration {} failed: {}",
+                        migration.uuid, rollback_err
+                    );
+                    (
+                        MigrationStatus::Failed,
+                        Some(format!("{}; rollback failed: {}", err, rollback_err)),
+                    )
+                }
+            }
+        }
+    };
+
+    if let Err(err) = repo.finish(&migration.uuid, status, error).await {
+        eprintln!("Failed to record migration {}: {}", migration.uuid, err);
+    }
+}
+
+/// Settles migrations that were running when the server stopped, so their
+/// relays can be migrated again. Once in `Terminating` the new instance is
+/// verified and serving, so the late events are copied again and the
+/// migration completes; earlier steps are rolled back.
+pub async fn recover_running_migrations(
+    pool: PgPool,
+    dns: Arc<dyn DnsProvider>,
+    instances: Arc<dyn InstanceProvider>,
+    relay_client: Arc<dyn RelayClient>,
+    config: Arc<MigrationConfig>,
+) {
+    let repo = RelayMigrationRepository::new(pool.clone());
+
+    for migration in repo.get_all_running().await {
+        let migrator = Migrator {
+            pool: &pool,
+            dns: dns.as_ref(),
+            instances: instances.as_ref(),
+            relay_client: relay_client.as_ref(),
+            config: config.as_ref(),
+            repo: repo.clone(),
+            migration: &migration,
+        };
+
+        let (status, error) = match (&migration.new_instance_ip, migration.step) {
+            (Some(new_ip), MigrationStep::Terminating) => {
+                let since = Timestamp::from(migration.created_at.timestamp() as u64);
+                match migrator
+                    .copy_events(new_ip, Some(since), &mut HashSet::new())
+                    .await
+                {
+                    Ok(()) => migrator.complete().await,
+                    Err(err) => (
+                        MigrationStatus::Failed,
+                        Some(format!("Interrupted by a restart; final copy failed: {}", err)),
+                    ),
+                }
+            }
+            _ => {
+                let progress = Progress {
+                    new_instance: migration.new_instance_id.clone().zip(
+                        migration.new_instance_ip.clone(),
+                    )
+                    .map(|(id, ip_address)| CloudInstance { id, ip_address }),
+                    dns_switched: matches!(
+                        migration.step,
+                        MigrationStep::SwitchingDns
+                            | MigrationStep::Verifying
+                            | MigrationStep::Terminating
+                    ),
+                    copied: HashSet::new(),
+                };
+                match migrator
+                    .roll_back(migration.from_instance_type.cloud_provider(), progress)
+                    .await
+                {
+                    Ok(()) => (
+                        MigrationStatus::RolledBack,
+                        Some("Interrupted by a restart".to_string()),
+                    ),
+                    Err(err) => (
+                        MigrationStatus::Failed,
+                        Some(format!("Interrupted by a restart; rollback failed: {}", err)),
+                    ),
+                }
+            }
+        };
+
+        eprintln!(
+            "Recovered interrupted migration {}: {:?}",
+            migration.uuid, status
+        );
+        if let Err(err) = repo.finish(&migration.uuid, status, error).await {
+            eprintln!("Failed to record migration {}: {}", migration.uuid, err);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+#[allow(clippy::too_many_arguments)]
+async fn create_migration_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    dns: web::Data<dyn DnsProvider>,
+    instances: web::Data<dyn InstanceProvider>,
+    relay_client: web::Data<dyn RelayClient>,
+    config: web::Data<MigrationConfig>,
+    path: web::Path<String>,
+    body: web::Json<CreateMigrationDto>,
+) -> impl Responder {
+    let npub = auth.npub().unwrap().to_string();
+    let relay = relay_repo
+        .get_one_for_member(path.into_inner(), npub.clone(), OrganizationRole::Owner)
+        .await;
+    let relay = match relay {
+        Some(relay) if relay.deleted_at.is_none() => relay,
+        _ => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    if relay.implementation == body.implementation && relay.instance_type == body.instance_type {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "The relay already runs on this implementation and instance type".to_string(),
+        ));
+    }
+    let provider = body.instance_type.cloud_provider();
+    if !instances.supports(provider) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "Migrating to {} instances is not supported yet",
+            provider.as_str()
+        )));
+    }
+
+    let repo = RelayMigrationRepository::new(relay_repo.pool.clone());
+    if repo.get_running(&relay.uuid).await.is_some() {
+        return HttpResponse::Conflict().json(ErrorResponse::new(
+            "The relay is already being migrated".to_string(),
+        ));
+    }
+
+    let migration = match repo.create(&relay, &npub, &body).await {
+        Ok(migration) => migration,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string()))
+        }
+    };
+    tokio::spawn(run_migration(
+        relay_repo.pool.clone(),
+        dns.into_inner(),
+        instances.into_inner(),
+        relay_client.into_inner(),
+        config.into_inner(),
+        migration.clone(),
+    ));
+
+    HttpResponse::Accepted().json(DataResponse::new(migration))
+}
+
+async fn get_migrations_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let relay = relay_repo
+        .get_one_for_member(
+            path.into_inner(),
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    let migrations = RelayMigrationRepository::new(relay_repo.pool.clone())
+        .get_for_relay(&relay.uuid)
+        .await;
+    HttpResponse::Ok().json(DataResponse::new(migrations))
+}
+
+async fn get_migration_handler(
+    auth: AuthorizationService,
+    relay_repo: web::Data<RelayRepository>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (relay_uuid, migration_uuid) = path.into_inner();
+    let relay = relay_repo
+        .get_one_for_member(
+            relay_uuid,
+            auth.npub().unwrap().to_string(),
+            OrganizationRole::Viewer,
+        )
+        .await;
+    let relay = match relay {
+        Some(relay) => relay,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("Relay not found".to_string()))
+        }
+    };
+
+    match RelayMigrationRepository::new(relay_repo.pool.clone())
+        .get_one(&migration_uuid, &relay.uuid)
+        .await
+    {
+        Some(migration) => HttpResponse::Ok().json(DataResponse::new(migration)),
+        None => {
+            HttpResponse::NotFound().json(ErrorResponse::new("Migration not found".to_string()))
+        }
+    }
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relays/{uuid}/migrations")
+            .route(web::get().to(get_migrations_handler))
+            .route(web::post().to(create_migration_handler)),
+    )
+    .service(
+        web::resource("/relays/{uuid}/migrations/{migration_uuid}")
+            .route(web::get().to(get_migration_handler)),
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::generate_jwt_by_hex;
+    use crate::cloud_provider::LocalInstanceProvider;
+    use crate::dns::{relay_hostname, DnsRecordType, LocalDnsProvider};
+    use crate::relay_client::LocalRelayClient;
+    use crate::test_relay::TestRelay;
+    use crate::token::TokenRepository;
+    use crate::util::TestUtils;
+    use actix_web::App;
+    use nostr::{EventBuilder, Filter, Keys};
+
+    /// Migrates a relay to a new instance that refuses its first
+    /// `starting_probes` probes, or never answers if not `healthy`.
+    async fn migrate(healthy: bool, starting_probes: usize) -> (TestUtils, Relay, RelayMigration) {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let order = test_utils.create_relay_order(&user.npub).await;
+        let relay = test_utils.create_relay(order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "10.0.0.1")
+            .await
+            .unwrap();
+
+        // The new instance answers health probes on the test relay's port.
+        let test_relay = TestRelay::start().await;
+        test_relay.refuse_websockets(starting_probes);
+        if !healthy {
+            test_relay.stop();
+        }
+        let port = test_relay.url.rsplit(':').next().unwrap().parse().unwrap();
+        let config = MigrationConfig {
+            instance_port: port,
+            probe_timeout: Duration::from_secs(2),
+            ready_attempts: 5,
+            verify_attempts: 2,
+            verify_delay: Duration::from_millis(10),
+        };
+
+        let client = LocalRelayClient::new();
+        let keys = Keys::generate();
+        for index in 0..2 {
+            let event = EventBuilder::new_text_note(format!("note {}", index), &[])
+                .to_event(&keys)
+                .unwrap();
+            client
+                .publish(&config.instance_url("10.0.0.1"), &event)
+                .await
+                .unwrap();
+        }
+
+        let dns = Arc::new(LocalDnsProvider::new());
+        let instances = Arc::new(LocalInstanceProvider::default());
+        let relay_client: Arc<dyn RelayClient> = Arc::new(client.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.relay_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(
+                    test_utils.pool.clone(),
+                )))
+                .app_data(web::Data::from(dns.clone() as Arc<dyn DnsProvider>))
+                .app_data(web::Data::from(
+                    instances.clone() as Arc<dyn InstanceProvider>
+                ))
+                .app_data(web::Data::from(relay_client))
+                .app_data(web::Data::new(config))
+                .configure(configure_routes),
+        )
+        .await;
+        let jwt = generate_jwt_by_hex(&user.hexpub).unwrap();
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/relays/{}/migrations", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "implementation": "Nostream",
+                "instance_type": "GcpN1Standard1",
+            }))
+            .to_request();
+        let migration: DataResponse<RelayMigration> =
+            actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(migration.data.status, MigrationStatus::Running);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/relays/{}/migrations", relay.uuid))
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "implementation": "Nostream",
+                "instance_type": "GcpN1Standard1",
+            }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+
+        let repo = RelayMigrationRepository::new(test_utils.pool.clone());
+        let mut finished = None;
+        for _ in 0..50 {
+            finished = repo
+                .get_one(&migration.data.uuid, &relay.uuid)
+                .await
+                .filter(|migration| migration.status != MigrationStatus::Running);
+            if finished.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let migration = finished.expect("Migration did not finish");
+
+        let hostname = relay_hostname(&relay.subdomain);
+        let record = dns.get(&hostname, DnsRecordType::A);
+        let terminated = instances.terminated.lock().unwrap().clone();
+        if healthy {
+            assert_eq!(record.unwrap().value, "127.0.0.1");
+            assert_eq!(terminated, vec![relay.instance_id.clone()]);
+            let copied = client
+                .fetch(&format!("ws://127.0.0.1:{}", port), vec![Filter::new()])
+                .await
+                .unwrap();
+            assert_eq!(copied.len(), 2);
+        } else {
+            // Given up on before DNS was touched.
+            assert!(record.is_none());
+            assert_eq!(terminated, vec!["i-local-1".to_string()]);
+        }
+
+        (test_utils, relay, migration)
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_relay() {
+        let (test_utils, relay, migration) = migrate(true, 0).await;
+        assert_eq!(migration.status, MigrationStatus::Completed);
+        assert_eq!(migration.step, MigrationStep::Done);
+        assert_eq!(migration.copied_events, 2);
+        assert!(migration.error.is_none());
+
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(relay.instance_id, "i-local-1");
+        assert_eq!(relay.instance_ip, "127.0.0.1");
+        assert_eq!(relay.implementation, RelayImplementation::Nostream);
+        assert_eq!(relay.instance_type, InstanceType::GcpN1Standard1);
+        assert_eq!(relay.cloud_provider, CloudProvider::GCP);
+    }
+
+    #[actix_web::test]
+    async fn test_migration_rolls_back_when_unhealthy() {
+        let (test_utils, relay, migration) = migrate(false, 0).await;
+        assert_eq!(migration.status, MigrationStatus::RolledBack);
+        assert_eq!(migration.step, MigrationStep::Provisioning);
+        assert_eq!(migration.copied_events, 0);
+        assert!(migration.error.unwrap().contains("health check"));
+
+        let current = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        assert_eq!(current.instance_id, relay.instance_id);
+        assert_eq!(current.instance_ip, "10.0.0.1");
+        assert_eq!(current.implementation, RelayImplementation::Strfry);
+        assert_eq!(current.cloud_provider, CloudProvider::AWS);
+    }
+
+    #[actix_web::test]
+    async fn test_migration_waits_for_new_instance() {
+        // More refused probes than the final verification allows, so this
+        // only completes if nothing is copied until the instance is up.
+        let (_test_utils, _relay, migration) = migrate(true, 3).await;
+        assert_eq!(migration.status, MigrationStatus::Completed);
+        assert_eq!(migration.copied_events, 2);
+        assert!(migration.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recover_running_migrations() {
+        let test_utils = TestUtils::new().await;
+        let user = test_utils.create_user().await;
+        let repo = RelayMigrationRepository::new(test_utils.pool.clone());
+        let dns = Arc::new(LocalDnsProvider::new());
+        let instances = Arc::new(LocalInstanceProvider::default());
+        let client = LocalRelayClient::new();
+        let config = Arc::new(MigrationConfig {
+            instance_port: 7777,
+            probe_timeout: Duration::from_secs(1),
+            ready_attempts: 1,
+            verify_attempts: 1,
+            verify_delay: Duration::from_millis(10),
+        });
+        let dto = CreateMigrationDto {
+            implementation: RelayImplementation::Nostream,
+            instance_type: InstanceType::GcpN1Standard1,
+        };
+
+        let mut relays = vec![];
+        let mut migrations = vec![];
+        for (index, step) in [MigrationStep::Verifying, MigrationStep::Terminating]
+            .into_iter()
+            .enumerate()
+        {
+            let order = test_utils.create_relay_order(&user.npub).await;
+            let relay = test_utils.create_relay(order).await;
+            let relay = test_utils
+                .relay_repo
+                .update_instance_ip(&relay.uuid, &format!("10.0.{}.1", index))
+                .await
+                .unwrap();
+            let migration = repo.create(&relay, &user.npub, &dto).await.unwrap();
+            let new_instance = CloudInstance {
+                id: format!("i-new-{}", index),
+                ip_address: format!("10.0.{}.2", index),
+            };
+            repo.set_new_instance(&migration.uuid, &new_instance)
+                .await
+                .unwrap();
+            repo.set_step(&migration.uuid, step).await.unwrap();
+            let relay = replace_relay_ip(
+                &test_utils.pool,
+                dns.as_ref(),
+                relay,
+                &new_instance.ip_address,
+            )
+            .await
+            .unwrap();
+            relays.push(relay);
+            migrations.push(migration);
+        }
+
+        let late = EventBuilder::new_text_note("written during the migration", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        client
+            .publish(&config.instance_url("10.0.1.1"), &late)
+            .await
+            .unwrap();
+
+        recover_running_migrations(
+            test_utils.pool.clone(),
+            dns.clone(),
+            instances.clone(),
+            Arc::new(client.clone()),
+            config.clone(),
+        )
+        .await;
+        assert!(repo.get_all_running().await.is_empty());
+
+        // Interrupted before the switch was verified: rolled back.
+        let rolled_back = repo
+            .get_one(&migrations[0].uuid, &relays[0].uuid)
+            .await
+            .unwrap();
+        assert_eq!(rolled_back.status, MigrationStatus::RolledBack);
+        let relay = test_utils.relay_repo.get_one(&relays[0].uuid).await.unwrap();
+        assert_eq!(relay.instance_ip, "10.0.0.1");
+        assert_eq!(relay.instance_id, relays[0].instance_id);
+        let record = dns
+            .get(&relay_hostname(&relay.subdomain), DnsRecordType::A)
+            .unwrap();
+        assert_eq!(record.value, "10.0.0.1");
+
+        // Interrupted while finishing up: completed.
+        let completed = repo
+            .get_one(&migrations[1].uuid, &relays[1].uuid)
+            .await
+            .unwrap();
+        assert_eq!(completed.status, MigrationStatus::Completed);
+        let copied = client
+            .fetch(&config.instance_url("10.0.1.2"), vec![Filter::new()])
+            .await
+            .unwrap();
+        assert_eq!(copied.len(), 1);
+
+        let terminated = instances.terminated.lock().unwrap().clone();
+        assert_eq!(
+            terminated,
+            vec!["i-new-0".to_string(), relays[1].instance_id.clone()]
+        );
+    }
+}
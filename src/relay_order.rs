@@ -12,6 +12,8 @@ use std::fmt;
 use serde_json::json;
 
 use crate::middleware::AuthorizationService;
+use crate::notification::{notify, Notification};
+use crate::organization::{OrganizationRepository, OrganizationRole};
 use crate::relay_order;
 use crate::user::UserRepository;
 use crate::{
@@ -49,6 +51,7 @@ impl fmt::Display for RelayOrderRepositoryError {
 pub struct RelayOrder {
     pub uuid: String,
     pub user_npub: String,
+    pub organization_uuid: Option<String>,
     pub amount: i32,
     pub cloud_provider: CloudProvider,
     pub instance_type: InstanceType,
@@ -64,6 +67,7 @@ impl RelayOrder {
         RelayOrder {
             uuid: relay_order.uuid,
             user_npub: relay_order.user_npub,
+            organization_uuid: relay_order.organization_uuid,
             amount: relay_order.amount,
             cloud_provider: relay_order.cloud_provider,
             instance_type: relay_order.instance_type,
@@ -78,22 +82,31 @@ impl RelayOrder {
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateRelayOrder {
+    /// Set from the caller by the handler; ignored in request bodies.
+    #[serde(default)]
     pub user_npub: String,
+    /// Orders the relay for an organization the caller manages.
+    #[serde(default)]
+    pub organization_uuid: Option<String>,
     pub amount: i32,
     pub cloud_provider: CloudProvider,
     pub instance_type: InstanceType,
     pub implementation: RelayImplementation,
     pub hostname: String,
+    /// Orders created through the API always start out pending.
+    #[serde(default)]
     pub status: RelayOrderStatus,
 }
 
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Default, Deserialize, Serialize, sqlx::Type)]
 #[sqlx(type_name = "relay_order_status", rename_all = "lowercase")]
 pub enum RelayOrderStatus {
+    #[default]
     Pending,
     Paid,
     Redeemed,
+    Cancelled,
 }
 
 impl RelayOrderStatus {
@@ -102,6 +115,7 @@ impl RelayOrderStatus {
             RelayOrderStatus::Pending => "pending",
             RelayOrderStatus::Paid => "paid",
             RelayOrderStatus::Redeemed => "redeemed",
+            RelayOrderStatus::Cancelled => "cancelled",
         }
     }
 }
@@ -112,6 +126,7 @@ impl ToString for RelayOrderStatus {
             RelayOrderStatus::Pending => "pending".to_string(),
             RelayOrderStatus::Paid => "paid".to_string(),
             RelayOrderStatus::Redeemed => "redeemed".to_string(),
+            RelayOrderStatus::Cancelled => "cancelled".to_string(),
         }
     }
 }
@@ -204,9 +219,9 @@ impl RelayOrderRepository {
         let uuid = uuid::Uuid::new_v4().to_string();
         let relay_order: RelayOrder = sqlx::query_as::<_, RelayOrder>(
             "
-            INSERT INTO relay_orders (uuid, user_npub, amount, cloud_provider, instance_type, implementation, hostname, status)
-            VALUES ($1, $2, $3, $4::relay_cloud_provider, $5::relay_instance_type, $6::relay_implementation, $7, $8::relay_order_status)
-            RETURNING uuid, user_npub, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
+            INSERT INTO relay_orders (uuid, user_npub, amount, cloud_provider, instance_type, implementation, hostname, status, organization_uuid)
+            VALUES ($1, $2, $3, $4::relay_cloud_provider, $5::relay_instance_type, $6::relay_implementation, $7, $8::relay_order_status, $9)
+            RETURNING uuid, user_npub, organization_uuid, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
             ")
             .bind(uuid)
             .bind(relay_order.user_npub)
@@ -216,6 +231,7 @@ impl RelayOrderRepository {
             .bind(relay_order.implementation.as_str())
             .bind(relay_order.hostname)
             .bind(relay_order.status)
+            .bind(relay_order.organization_uuid)
             .fetch_one(&self.pool)
             .await?;
 
@@ -225,7 +241,7 @@ impl RelayOrderRepository {
     pub async fn get_one(&self, uuid: &String) -> Result<RelayOrder, RelayOrderRepositoryError> {
         let relay_order: RelayOrder = sqlx::query_as::<_, RelayOrder>(
             "
-            SELECT uuid, user_npub, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
+            SELECT uuid, user_npub, organization_uuid, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
             FROM relay_orders
             WHERE uuid = $1
             ")
@@ -239,7 +255,7 @@ impl RelayOrderRepository {
     pub async fn get_all(&self) -> Result<Vec<RelayOrder>, RelayOrderRepositoryError> {
         let relay_orders: Vec<RelayOrder> = sqlx::query_as::<_, RelayOrder>(
             "
-            SELECT uuid, user_npub, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
+            SELECT uuid, user_npub, organization_uuid, amount, cloud_provider, instance_type, implementation, hostname, status, created_at, updated_at
             FROM relay_orders
             ",
         )
@@ -285,6 +301,23 @@ impl RelayOrderRepository {
 
         Ok(())
     }
+
+    /// Cancels the user's unpaid orders. Returns how many were cancelled.
+    pub async fn cancel_pending(&self, user_npub: &str) -> Result<u64, RelayOrderRepositoryError> {
+        let result = sqlx::query(
+            "
+            UPDATE relay_orders
+            SET status = 'cancelled', updated_at = $2
+            WHERE user_npub = $1 AND status = 'pending'
+            ",
+        )
+        .bind(user_npub)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
 }
 
 async fn create_nodeless_invoice(order: RelayOrder) -> Result<NodelessResponse, reqwest::Error> {
@@ -324,17 +357,31 @@ async fn create_nodeless_invoice(order: RelayOrder) -> Result<NodelessResponse,
 }
 
 async fn create_relay_order_handler(
-    _auth: AuthorizationService,
+    auth: AuthorizationService,
     relay_order_repo: web::Data<RelayOrderRepository>,
     user_repo: web::Data<UserRepository>,
     data: web::Json<CreateRelayOrder>,
 ) -> impl Responder {
+    let mut data = data.into_inner();
+    data.user_npub = auth.npub().unwrap().to_string();
+    data.status = RelayOrderStatus::Pending;
 
     if !user_repo.user_exists(&data.user_npub).await {
         return HttpResponse::BadRequest().json(ErrorResponse::new("User does not exist".to_string()));
     }
 
-    let order = relay_order_repo.create(data.into_inner()).await;
+    if let Some(organization_uuid) = &data.organization_uuid {
+        let role = OrganizationRepository::new(relay_order_repo.pool.clone())
+            .role(organization_uuid, auth.npub().unwrap())
+            .await;
+        if !matches!(role, Some(role) if role >= OrganizationRole::Manager) {
+            return HttpResponse::Forbidden().json(ErrorResponse::new(
+                "Only organization managers can order relays for it".to_string(),
+            ));
+        }
+    }
+
+    let order = relay_order_repo.create(data).await;
     let nodeless = create_nodeless_invoice(order.unwrap()).await;
 
     match nodeless {
@@ -390,7 +437,15 @@ pub async fn nodeless_webhook_handler(
             .await;
 
         match order {
-            Ok(_) => HttpResponse::Ok().body("Order status updated successfully"),
+            Ok(_) => {
+                if let Ok(order) = relay_order_repo
+                    .get_one(&payload["metadata"]["order_uuid"].as_str().unwrap().to_string())
+                    .await
+                {
+                    notify(&relay_order_repo.pool, Notification::order_paid(&order)).await;
+                }
+                HttpResponse::Ok().body("Order status updated successfully")
+            }
             Err(e) => {
                 eprintln!("Failed to update relay order status: {}", e);
                 HttpResponse::InternalServerError().body("Failed to update relay order status")
@@ -402,7 +457,7 @@ pub async fn nodeless_webhook_handler(
     }
 }
 
-fn calculate_hmac_sha256(payload: &[u8], secret: &str) -> String {
+pub fn calculate_hmac_sha256(payload: &[u8], secret: &str) -> String {
     type HmacSha256 = Hmac<Sha256>;
 
     let mut mac =
@@ -430,6 +485,7 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
 mod tests {
     use super::RelayOrderRepository;
     use crate::auth::generate_jwt_by_hex;
+    use crate::token::TokenRepository;
     use crate::relay;
     use crate::relay_order::{
         create_relay_order_handler, nodeless_webhook_handler, CreateRelayOrder, RelayOrder,
@@ -464,6 +520,7 @@ mod tests {
 
         let create = CreateRelayOrder {
             user_npub: npub.clone(),
+            organization_uuid: None,
             amount: 1,
             cloud_provider: CloudProvider::AWS,
             instance_type: InstanceType::AwsT2Nano,
@@ -511,6 +568,7 @@ mod tests {
                 .app_data(Data::new(test_utils.pool.clone()))
                 .app_data(Data::new(relay_order_repo))
                 .app_data(Data::new(user_repo))
+                .app_data(Data::new(TokenRepository::new(test_utils.pool.clone())))
                 .route(
                     "/relay_orders",
                     actix_web::web::post().to(create_relay_order_handler),
@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use lru::LruCache;
+use sqlx::PgPool;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// -----------------------------------------------------------------------------
+// Replay guard
+// -----------------------------------------------------------------------------
+
+/// Remembers accepted NIP-98 event ids until their validity window passes, so
+/// a captured `Authorization` header can only be used once.
+#[async_trait]
+pub trait ReplayGuard: Send + Sync {
+    /// Records `event_id` as used until `expires_at`. Fails if it was already
+    /// used and hasn't expired yet.
+    async fn check_and_remember(
+        &self,
+        event_id: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<(), String>;
+}
+
+const REPLAY_ERROR: &str = "event already used";
+
+/// Picks the store from `NIP98_REPLAY_STORE`. Use `postgres` when running more
+/// than one replica, since the in-memory cache isn't shared between them.
+pub fn guard_from_env(pool: PgPool) -> Arc<dyn ReplayGuard> {
+    match dotenvy::var("NIP98_REPLAY_STORE")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "postgres" => Arc::new(PgReplayGuard::new(pool)),
+        _ => {
+            let capacity = dotenvy::var("NIP98_REPLAY_CACHE_SIZE")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(10_000);
+            Arc::new(MemoryReplayGuard::new(capacity).with_fallback(PgReplayGuard::new(pool)))
+        }
+    }
+}
+
+/// LRU of seen event ids for single-node setups. Ids are never evicted
+/// before they expire: once the cache is full of live ids, new ones go to
+/// the fallback store, or are rejected without one.
+pub struct MemoryReplayGuard {
+    seen: Mutex<LruCache<String, NaiveDateTime>>,
+    fallback: Option<PgReplayGuard>,
+    /// Until when ids may have been recorded in the fallback store.
+    spilled_until: Mutex<Option<NaiveDateTime>>,
+}
+
+impl MemoryReplayGuard {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            seen: Mutex::new(LruCache::new(capacity)),
+            fallback: None,
+            spilled_until: Mutex::new(None),
+        }
+    }
+
+    pub fn with_fallback(mut self, fallback: PgReplayGuard) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+}
+
+#[async_trait]
+impl ReplayGuard for MemoryReplayGuard {
+    async fn check_and_remember(
+        &self,
+        event_id: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<(), String> {
+        let now = Utc::now().naive_utc();
+
+        let full = {
+            let mut seen = self.seen.lock().unwrap();
+            if seen.get(event_id).is_some_and(|expiry| *expiry > now) {
+                return Err(REPLAY_ERROR.to_string());
+            }
+
+            let full = seen.len() == seen.cap().get()
+                && seen.peek_lru().is_some_and(|(_, expiry)| *expiry > now);
+            if !full {
+                seen.put(event_id.to_string(), expires_at);
+            }
+            full
+        };
+
+        let spilled = {
+            let mut spilled_until = self.spilled_until.lock().unwrap();
+            if full {
+                *spilled_until = (*spilled_until).max(Some(expires_at));
+            }
+            spilled_until.is_some_and(|until| until > now)
+        };
+
+        match &self.fallback {
+            Some(fallback) if spilled => fallback.check_and_remember(event_id, expires_at).await,
+            None if full => Err("too many recent logins, try again shortly".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Seen event ids shared through Postgres for multi-replica setups. Expired
+/// rows are removed by `run_seen_event_pruner`.
+#[derive(Clone)]
+pub struct PgReplayGuard {
+    pub pool: PgPool,
+}
+
+impl PgReplayGuard {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn prune(&self) -> Result<u64, sqlx::Error> {
+        sqlx::query("DELETE FROM nip98_seen_events WHERE expires_at <= $1")
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl ReplayGuard for PgReplayGuard {
+    async fn check_and_remember(
+        &self,
+        event_id: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<(), String> {
+        // An expired row that wasn't pruned yet doesn't count as a replay.
+        let inserted = sqlx::query(
+            "INSERT INTO nip98_seen_events (event_id, expires_at) VALUES ($1, $2)
+            ON CONFLICT (event_id) DO UPDATE SET expires_at = EXCLUDED.expires_at
+            WHERE nip98_seen_events.expires_at <= $3",
+        )
+        .bind(event_id)
+        .bind(expires_at)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
+        .await
+        .map_err(|err| format!("Failed to record seen event: {}", err))?;
+
+        if inserted.rows_affected() == 0 {
+            return Err(REPLAY_ERROR.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn run_seen_event_pruner(pool: PgPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    let guard = PgReplayGuard::new(pool);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = guard.prune().await {
+            eprintln!("Failed to prune seen NIP-98 events: {}", err);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestUtils;
+    use chrono::Duration;
+
+    async fn assert_rejects_replays(guard: &dyn ReplayGuard) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = Utc::now().naive_utc();
+
+        guard
+            .check_and_remember(&id, now + Duration::seconds(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            guard
+                .check_and_remember(&id, now + Duration::seconds(10))
+                .await,
+            Err(REPLAY_ERROR.to_string())
+        );
+
+        // Expired ids can't be replayed through NIP-98 anyway, so they are
+        // forgotten.
+        let expired = uuid::Uuid::new_v4().to_string();
+        guard
+            .check_and_remember(&expired, now - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert!(guard
+            .check_and_remember(&expired, now + Duration::seconds(10))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_memory_replay_guard() {
+        assert_rejects_replays(&MemoryReplayGuard::new(100)).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_replay_guard_never_evicts_live_ids() {
+        let now = Utc::now().naive_utc();
+        let live = uuid::Uuid::new_v4().to_string();
+        let flood = uuid::Uuid::new_v4().to_string();
+
+        let guard = MemoryReplayGuard::new(1);
+        guard
+            .check_and_remember(&live, now + Duration::seconds(10))
+            .await
+            .unwrap();
+        assert!(guard
+            .check_and_remember(&flood, now + Duration::seconds(10))
+            .await
+            .is_err());
+        assert!(guard
+            .check_and_remember(&live, now + Duration::seconds(10))
+            .await
+            .is_err());
+
+        let test_utils = TestUtils::new().await;
+        let guard =
+            MemoryReplayGuard::new(1).with_fallback(PgReplayGuard::new(test_utils.pool.clone()));
+        guard
+            .check_and_remember(&live, now + Duration::seconds(10))
+            .await
+            .unwrap();
+        guard
+            .check_and_remember(&flood, now + Duration::seconds(10))
+            .await
+            .unwrap();
+        for id in [&live, &flood] {
+            assert_eq!(
+                guard
+                    .check_and_remember(id, now + Duration::seconds(10))
+                    .await,
+                Err(REPLAY_ERROR.to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pg_replay_guard() {
+        let test_utils = TestUtils::new().await;
+        let guard = PgReplayGuard::new(test_utils.pool.clone());
+        assert_rejects_replays(&guard).await;
+
+        let expired = uuid::Uuid::new_v4().to_string();
+        guard
+            .check_and_remember(&expired, Utc::now().naive_utc() - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert!(guard.prune().await.unwrap() >= 1);
+    }
+}
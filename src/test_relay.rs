@@ -0,0 +1,186 @@
+use futures::{SinkExt, StreamExt};
+use nostr::{ClientMessage, Event, Filter, RelayMessage};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+/// A minimal in-process nostr relay for tests. It stores published events,
+/// answers REQs from its store and serves a NIP-11 document to plain HTTP
+/// requests on the same port.
+pub struct TestRelay {
+    pub url: String,
+    nip11: Arc<Mutex<Value>>,
+    refused: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl TestRelay {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let events = Arc::new(Mutex::new(vec![]));
+        let nip11 = Arc::new(Mutex::new(json!({ "name": "test relay" })));
+        let refused = Arc::new(AtomicUsize::new(0));
+
+        let handle = tokio::spawn({
+            let events = events.clone();
+            let nip11 = nip11.clone();
+            let refused = refused.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        events.clone(),
+                        nip11.clone(),
+                        refused.clone(),
+                    ));
+                }
+            }
+        });
+
+        Self {
+            url,
+            nip11,
+            refused,
+            handle,
+        }
+    }
+
+    pub fn set_nip11(&self, document: Value) {
+        *self.nip11.lock().unwrap() = document;
+    }
+
+    /// Drops the next `count` websocket connections, as if the relay were
+    /// still starting up.
+    pub fn refuse_websockets(&self, count: usize) {
+        self.refused.store(count, Ordering::SeqCst);
+    }
+
+    /// Stops accepting connections, as if the relay went down.
+    pub fn stop(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+pub fn filter_matches(filter: &Filter, event: &Event) -> bool {
+    let id = event.id.to_hex();
+    let author = event.pubkey.to_string();
+
+    filter
+        .ids
+        .as_ref()
+        .is_none_or(|ids| ids.iter().any(|prefix| id.starts_with(prefix)))
+        && filter
+            .authors
+            .as_ref()
+            .is_none_or(|authors| authors.iter().any(|prefix| author.starts_with(prefix)))
+        && filter
+            .kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&event.kind))
+        && filter.since.is_none_or(|since| event.created_at >= since)
+        && filter.until.is_none_or(|until| event.created_at <= until)
+        && filter.pubkeys.as_ref().is_none_or(|pubkeys| {
+            event.tags.iter().any(|tag| {
+                let tag = tag.as_vec();
+                tag.len() > 1
+                    && tag[0] == "p"
+                    && pubkeys.iter().any(|pubkey| pubkey.to_string() == tag[1])
+            })
+        })
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    events: Arc<Mutex<Vec<Event>>>,
+    nip11: Arc<Mutex<Value>>,
+    refused: Arc<AtomicUsize>,
+) {
+    let mut buffer = [0u8; 2048];
+    let read = match stream.peek(&mut buffer).await {
+        Ok(read) => read,
+        Err(_) => return,
+    };
+    let request = String::from_utf8_lossy(&buffer[..read]).to_lowercase();
+
+    if !request.contains("upgrade: websocket") {
+        let mut stream = stream;
+        let _ = stream.read(&mut buffer).await;
+        let body = nip11.lock().unwrap().to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/nostr+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        return;
+    }
+
+    let refuse = refused
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            count.checked_sub(1)
+        })
+        .is_ok();
+    if refuse {
+        return;
+    }
+
+    let mut socket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+
+    while let Some(Ok(message)) = socket.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let replies = match ClientMessage::from_json(text) {
+            Ok(ClientMessage::Req {
+                subscription_id,
+                filters,
+            }) => {
+                let stored = events.lock().unwrap().clone();
+                let mut replies = vec![];
+                for filter in &filters {
+                    let matching = stored.iter().filter(|event| filter_matches(filter, event));
+                    let limit = filter.limit.unwrap_or(usize::MAX);
+                    for event in matching.take(limit) {
+                        replies.push(RelayMessage::new_event(
+                            subscription_id.clone(),
+                            event.clone(),
+                        ));
+                    }
+                }
+                replies.push(RelayMessage::new_eose(subscription_id));
+                replies
+            }
+            Ok(ClientMessage::Event(event)) => {
+                let accepted = event.verify().is_ok();
+                if accepted {
+                    events.lock().unwrap().push(*event.clone());
+                }
+                vec![RelayMessage::new_ok(event.id, accepted, "")]
+            }
+            _ => vec![],
+        };
+
+        for reply in replies {
+            if socket.send(Message::Text(reply.as_json())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::{
+    auth::{Claims, LoginResponse},
+    middleware::AuthorizationService,
+    user::{UserRepository, UserRole},
+    util::{bech32_encode, ErrorResponse},
+};
+
+// -----------------------------------------------------------------------------
+// Models & DTOs
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct RefreshToken {
+    pub token_hash: String,
+    pub hexpub: String,
+    pub npub: String,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenDto {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct LogoutDto {
+    pub refresh_token: Option<String>,
+}
+
+/// Kid used for tokens signed with the legacy `JWT_SECRET`, which carry no
+/// `kid` header.
+const LEGACY_KID: &str = "default";
+
+// -----------------------------------------------------------------------------
+// Signing keys
+// -----------------------------------------------------------------------------
+
+/// HS256 secrets by `kid`. New tokens are signed with the active key, while
+/// tokens signed with any other listed key stay valid until they expire, so a
+/// secret can be rotated by adding a new key, activating it and dropping the
+/// old one after the token lifetime.
+pub struct SigningKeys {
+    active_kid: String,
+    secrets: HashMap<String, String>,
+}
+
+impl SigningKeys {
+    pub fn new(active_kid: &str, secrets: HashMap<String, String>) -> Self {
+        Self {
+            active_kid: active_kid.to_string(),
+            secrets,
+        }
+    }
+
+    /// Reads `JWT_SIGNING_KEYS` as `kid:secret` pairs separated by commas and
+    /// `JWT_ACTIVE_KID`. `JWT_SECRET` stays valid under the `default` kid.
+    pub fn from_env() -> Self {
+        let mut secrets: HashMap<String, String> = dotenvy::var("JWT_SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.trim().split_once(':'))
+            .map(|(kid, secret)| (kid.to_string(), secret.to_string()))
+            .collect();
+
+        if let Ok(secret) = dotenvy::var("JWT_SECRET") {
+            secrets.entry(LEGACY_KID.to_string()).or_insert(secret);
+        }
+
+        let active_kid = dotenvy::var("JWT_ACTIVE_KID")
+            .ok()
+            .filter(|kid| secrets.contains_key(kid))
+            .unwrap_or_else(|| LEGACY_KID.to_string());
+
+        Self::new(&active_kid, secrets)
+    }
+
+    pub fn encode(&self, claims: &Claims) -> Result<String, String> {
+        let secret = self
+            .secrets
+            .get(&self.active_kid)
+            .ok_or("No active signing key")?;
+        let header = Header {
+            kid: Some(self.active_kid.clone()),
+            ..Default::default()
+        };
+
+        encode(&header, claims, &EncodingKey::from_secret(secret.as_ref()))
+            .map_err(|err| format!("Failed to generate token: {}", err))
+    }
+
+    pub fn decode(&self, token: &str) -> Result<Claims, String> {
+        let header = decode_header(token).map_err(|_| "Invalid token")?;
+        let kid = header.kid.unwrap_or_else(|| LEGACY_KID.to_string());
+        let secret = self.secrets.get(&kid).ok_or("Unknown signing key")?;
+
+        decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_ref()),
+            &Validation::default(),
+        )
+        .map(|token_data| token_data.claims)
+        .map_err(|_| "Invalid token".to_string())
+    }
+}
+
+/// Signing keys loaded once from the environment.
+pub fn signing_keys() -> &'static SigningKeys {
+    static KEYS: OnceLock<SigningKeys> = OnceLock::new();
+    KEYS.get_or_init(SigningKeys::from_env)
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct TokenRepository {
+    pub pool: PgPool,
+}
+
+impl TokenRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Stores a new refresh token and returns it. Only its hash is kept.
+    pub async fn create_refresh_token(
+        &self,
+        hexpub: &str,
+        npub: &str,
+    ) -> Result<String, sqlx::Error> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let expires_at = Utc::now().naive_utc() + chrono::Duration::days(refresh_token_ttl_days());
+
+        sqlx::query(
+            "INSERT INTO refresh_tokens (token_hash, hexpub, npub, expires_at)
+            VALUES ($1, $2, $3, $4)",
+        )
+        .bind(hash_token(&token))
+        .bind(hexpub)
+        .bind(npub)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Revokes a valid refresh token and returns it, so it can be exchanged
+    /// exactly once.
+    pub async fn consume_refresh_token(&self, token: &str) -> Option<RefreshToken> {
+        sqlx::query_as::<_, RefreshToken>(
+            "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > $2
+            RETURNING *",
+        )
+        .bind(hash_token(token))
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or(None)
+    }
+
+    pub async fn revoke_refresh_token(&self, token: &str, hexpub: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND hexpub = $2 AND revoked_at IS NULL",
+        )
+        .bind(hash_token(token))
+        .bind(hexpub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes every refresh token of the user, e.g. when they close their
+    /// account.
+    pub async fn revoke_user_refresh_tokens(&self, hexpub: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE hexpub = $1 AND revoked_at IS NULL",
+        )
+        .bind(hexpub)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes an access token until it would have expired anyway.
+    pub async fn revoke_jti(&self, jti: &str, exp: usize) -> Result<(), sqlx::Error> {
+        let expires_at = Utc
+            .timestamp_opt(exp as i64, 0)
+            .single()
+            .unwrap_or_else(Utc::now)
+            .naive_utc();
+
+        sqlx::query(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING",
+        )
+        .bind(jti)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < $1")
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, jti: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1)")
+            .bind(jti)
+            .fetch_one(&self.pool)
+            .await
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn access_token_ttl_secs() -> i64 {
+    dotenvy::var("JWT_ACCESS_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(24 * 60 * 60)
+}
+
+fn refresh_token_ttl_days() -> i64 {
+    dotenvy::var("REFRESH_TOKEN_TTL_DAYS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(30)
+}
+
+/// Signs an access token with a fresh `jti`.
+pub fn issue_access_token(hexpub: &str, role: UserRole) -> Result<String, String> {
+    let expiration = Utc::now() + chrono::Duration::seconds(access_token_ttl_secs());
+
+    let claims = Claims {
+        hexpub: hexpub.to_string(),
+        npub: bech32_encode(&hexpub.to_string()).map_err(|err| err.to_string())?,
+        exp: expiration.timestamp() as usize,
+        jti: uuid::Uuid::new_v4().to_string(),
+        role,
+    };
+
+    signing_keys().encode(&claims)
+}
+
+/// Issues an access and refresh token pair. The access token carries the
+/// user's current role for clients; requests are authorized with the role
+/// stored at the time.
+pub async fn issue_tokens(
+    token_repo: &TokenRepository,
+    hexpub: &str,
+) -> Result<LoginResponse, String> {
+    let role = UserRepository::new(token_repo.pool.clone())
+        .get_role(hexpub)
+        .await;
+    let token = issue_access_token(hexpub, role)?;
+    let npub = bech32_encode(&hexpub.to_string()).map_err(|err| err.to_string())?;
+    let refresh_token = token_repo
+        .create_refresh_token(hexpub, &npub)
+        .await
+        .map_err(|err| format!("Failed to create refresh token: {}", err))?;
+
+    Ok(LoginResponse {
+        token,
+        refresh_token,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Handlers
+// -----------------------------------------------------------------------------
+
+async fn refresh_handler(
+    token_repo: web::Data<TokenRepository>,
+    body: web::Json<RefreshTokenDto>,
+) -> impl Responder {
+    let refresh_token = match token_repo.consume_refresh_token(&body.refresh_token).await {
+        Some(refresh_token) => refresh_token,
+        None => {
+            return HttpResponse::Unauthorized()
+                .json(ErrorResponse::new("Invalid refresh token".to_string()))
+        }
+    };
+
+    match issue_tokens(&token_repo, &refresh_token.hexpub).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(err) => {
+            eprintln!("{}", err);
+            HttpResponse::InternalServerError().json(ErrorResponse::new(err))
+        }
+    }
+}
+
+async fn logout_handler(
+    auth: AuthorizationService,
+    token_repo: web::Data<TokenRepository>,
+    body: Option<web::Json<LogoutDto>>,
+) -> impl Responder {
+    if let (Some(jti), Some(exp)) = (auth.jti(), auth.exp()) {
+        if let Err(err) = token_repo.revoke_jti(jti, exp).await {
+            eprintln!("Failed to revoke token: {}", err);
+            return HttpResponse::InternalServerError()
+                .json(ErrorResponse::new("Failed to revoke token".to_string()));
+        }
+    }
+
+    let refresh_token = body.and_then(|body| body.into_inner().refresh_token);
+    if let Some(refresh_token) = refresh_token {
+        if let Err(err) = token_repo
+            .revoke_refresh_token(&refresh_token, auth.hexpub().unwrap())
+            .await
+        {
+            eprintln!("Failed to revoke refresh token: {}", err);
+            return HttpResponse::InternalServerError()
+                .json(ErrorResponse::new("Failed to revoke token".to_string()));
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/token/refresh").route(web::post().to(refresh_handler)))
+        .service(web::resource("/logout").route(web::post().to(logout_handler)));
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestUtils;
+    use actix_web::{web::Data, App};
+    use nostr::Keys;
+
+    fn claims(jti: &str) -> Claims {
+        let hexpub = Keys::generate().public_key().to_string();
+        Claims {
+            npub: bech32_encode(&hexpub).unwrap(),
+            hexpub,
+            exp: (Utc::now().timestamp() + 60) as usize,
+            jti: jti.to_string(),
+            role: UserRole::User,
+        }
+    }
+
+    #[test]
+    fn test_signing_key_rotation() {
+        let old = SigningKeys::new(
+            "k1",
+            HashMap::from([("k1".to_string(), "first".to_string())]),
+        );
+        let rotated = SigningKeys::new(
+            "k2",
+            HashMap::from([
+                ("k1".to_string(), "first".to_string()),
+                ("k2".to_string(), "second".to_string()),
+            ]),
+        );
+        let retired = SigningKeys::new(
+            "k2",
+            HashMap::from([("k2".to_string(), "second".to_string())]),
+        );
+
+        let old_token = old.encode(&claims("a")).unwrap();
+        let new_token = rotated.encode(&claims("b")).unwrap();
+        assert_eq!(
+            decode_header(&new_token).unwrap().kid.as_deref(),
+            Some("k2")
+        );
+
+        // Tokens signed before the rotation stay valid until the old key is
+        // dropped.
+        assert_eq!(rotated.decode(&old_token).unwrap().jti, "a");
+        assert_eq!(rotated.decode(&new_token).unwrap().jti, "b");
+        assert!(retired.decode(&old_token).is_err());
+        assert!(old.decode(&new_token).is_err());
+
+        // Tokens without a kid were signed with the legacy secret.
+        let legacy_token = encode(
+            &Header::default(),
+            &claims("c"),
+            &EncodingKey::from_secret("legacy".as_ref()),
+        )
+        .unwrap();
+        let with_legacy = SigningKeys::new(
+            LEGACY_KID,
+            HashMap::from([(LEGACY_KID.to_string(), "legacy".to_string())]),
+        );
+        assert_eq!(with_legacy.decode(&legacy_token).unwrap().jti, "c");
+    }
+
+    #[actix_web::test]
+    async fn test_refresh_and_logout() {
+        let test_utils = TestUtils::new().await;
+        let token_repo = TokenRepository::new(test_utils.pool.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(token_repo.clone()))
+                .configure(configure_routes)
+                .route(
+                    "/whoami",
+                    web::get().to(|auth: AuthorizationService| async move {
+                        HttpResponse::Ok().body(auth.hexpub().unwrap().clone())
+                    }),
+                ),
+        )
+        .await;
+
+        let hexpub = Keys::generate().public_key().to_string();
+        let tokens = issue_tokens(&token_repo, &hexpub).await.unwrap();
+
+        // A refresh token can be exchanged once.
+        let refresh = |refresh_token: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/token/refresh")
+                .set_json(RefreshTokenDto {
+                    refresh_token: refresh_token.to_string(),
+                })
+                .to_request()
+        };
+        let resp = actix_web::test::call_service(&app, refresh(&tokens.refresh_token)).await;
+        assert_eq!(resp.status(), 200);
+        let refreshed: LoginResponse = actix_web::test::read_body_json(resp).await;
+        let resp = actix_web::test::call_service(&app, refresh(&tokens.refresh_token)).await;
+        assert_eq!(resp.status(), 401);
+
+        let whoami = |token: &str| {
+            actix_web::test::TestRequest::get()
+                .uri("/whoami")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request()
+        };
+        let resp = actix_web::test::call_service(&app, whoami(&refreshed.token)).await;
+        assert_eq!(resp.status(), 200);
+
+        // Logging out revokes both the access and the refresh token.
+        let req = actix_web::test::TestRequest::post()
+            .uri("/logout")
+            .insert_header(("Authorization", format!("Bearer {}", refreshed.token)))
+            .set_json(LogoutDto {
+                refresh_token: Some(refreshed.refresh_token.clone()),
+            })
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 204);
+
+        let resp = actix_web::test::call_service(&app, whoami(&refreshed.token)).await;
+        assert_eq!(resp.status(), 401);
+        let resp = actix_web::test::call_service(&app, refresh(&refreshed.refresh_token)).await;
+        assert_eq!(resp.status(), 401);
+
+        // Other sessions of the same user are unaffected.
+        let resp = actix_web::test::call_service(&app, whoami(&tokens.token)).await;
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -1,17 +1,39 @@
 use actix_web::{web, HttpResponse, Responder};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use nostr::prelude::FromBech32;
 use secp256k1::XOnlyPublicKey;
 use sqlx::postgres::PgPool;
 use sqlx::FromRow;
 use serde::{Deserialize, Serialize};
 
+use crate::api_key::ApiKeyRepository;
+use crate::cloud_provider::InstanceProvider;
+use crate::dns::DnsProvider;
+use crate::middleware::{Admin, AuthorizationService, Staff};
+use crate::profile::{Profile, ProfileRepository};
+use crate::relay::{terminate_relay_service, RelayRepository};
+use crate::relay_order::RelayOrderRepository;
+use crate::token::TokenRepository;
 use crate::util::{DataResponse, ErrorResponse, bech32_encode};
 
 // -----------------------------------------------------------------------------
 // Models & DTOs
 // -----------------------------------------------------------------------------
 
+/// Roles are ordered by privilege, so `support` can do what `user` can and
+/// `admin` what `support` can.
+#[derive(
+    Debug, Deserialize, Serialize, sqlx::Type, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default,
+)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    #[default]
+    User,
+    Support,
+    Admin,
+}
+
 #[derive(Serialize, Deserialize, FromRow, Clone)]
 pub struct User {
     pub npub: String,
@@ -19,6 +41,7 @@ pub struct User {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
+    pub role: UserRole,
 }
 
 impl User {
@@ -29,13 +52,22 @@ impl User {
             created_at: db_user.created_at,
             updated_at: db_user.updated_at,
             deleted_at: db_user.deleted_at,
+            role: db_user.role,
         }
     }
 }
 
-#[derive(serde::Deserialize)]
-struct CreateUserDto {
-    pub hexpub: String,
+/// A user along with their cached kind 0 profile, if it was fetched yet.
+#[derive(Serialize, Deserialize)]
+pub struct UserWithProfile {
+    #[serde(flatten)]
+    pub user: User,
+    pub profile: Option<Profile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateUserRoleDto {
+    pub role: UserRole,
 }
 
 // -----------------------------------------------------------------------------
@@ -53,10 +85,12 @@ impl UserRepository {
     }
 
     pub async fn get_one(&self, user_npub: &str) -> Option<User> {
-        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE npub = $1")
-            .bind(user_npub)
-            .fetch_optional(&self.pool)
-            .await;
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE npub = $1 AND deleted_at IS NULL",
+        )
+        .bind(user_npub)
+        .fetch_optional(&self.pool)
+        .await;
 
         match user {
             Ok(Some(user)) => Some(User::from_db_user(user)),
@@ -65,7 +99,7 @@ impl UserRepository {
     }
 
     pub async fn get_all(&self) -> Vec<User> {
-        let db_users = sqlx::query_as::<_, User>("SELECT * FROM users")
+        let db_users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE deleted_at IS NULL")
             .fetch_all(&self.pool)
             .await
             .unwrap();
@@ -73,32 +107,102 @@ impl UserRepository {
         db_users.into_iter().map(User::from_db_user).collect()
     }
 
-    pub async fn create(&self, npub: &str, hexpub: &str) -> Result<User, sqlx::Error> {
-        let db_user: User =
-            sqlx::query_as::<_, User>("INSERT INTO users (npub, hexpub) VALUES ($1, $2) RETURNING *")
-                .bind(npub)
-                .bind(hexpub)
-                .fetch_one(&self.pool)
-                .await?;
-
-        Ok(User::from_db_user(db_user))
+    /// Creates the user on first login. Existing users are returned as is,
+    /// including ones who closed their account.
+    pub async fn upsert(&self, npub: &str, hexpub: &str) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "INSERT INTO users (npub, hexpub) VALUES ($1, $2)
+            ON CONFLICT (npub) DO UPDATE SET npub = EXCLUDED.npub
+            RETURNING *",
+        )
+        .bind(npub)
+        .bind(hexpub)
+        .fetch_one(&self.pool)
+        .await
     }
 
     pub async fn delete(&self, user_npub: &str) -> Result<(), sqlx::Error> {
-        sqlx::query("UPDATE users SET deleted_at = $1 WHERE npub = $2")
-            .bind(NaiveDateTime::from_timestamp_opt(0, 0))
-            .bind(user_npub)
-            .execute(&self.pool)
-            .await?;
+        sqlx::query(
+            "UPDATE users SET deleted_at = $1, updated_at = $1 WHERE npub = $2 AND deleted_at IS NULL",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(user_npub)
+        .execute(&self.pool)
+        .await?;
 
         Ok(())
     }
 
-    pub async fn user_exists(&self, user_npub: &String) -> bool {
-        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE npub = $1")
-            .bind(user_npub)
+    /// Undeletes a user deleted after `deleted_after`.
+    pub async fn restore(
+        &self,
+        hexpub: &str,
+        deleted_after: NaiveDateTime,
+    ) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "UPDATE users SET deleted_at = NULL, updated_at = $3
+            WHERE hexpub = $1 AND deleted_at > $2
+            RETURNING *",
+        )
+        .bind(hexpub)
+        .bind(deleted_after)
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Looks the user up whether or not they closed their account.
+    pub async fn get_by_hexpub(&self, hexpub: &str) -> Option<User> {
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE hexpub = $1")
+            .bind(hexpub)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap_or(None)
+    }
+
+    /// The role of the user with `hexpub`, or `user` for unknown pubkeys and
+    /// closed accounts.
+    pub async fn get_role(&self, hexpub: &str) -> UserRole {
+        sqlx::query_scalar::<_, UserRole>(
+            "SELECT role FROM users WHERE hexpub = $1 AND deleted_at IS NULL",
+        )
+            .bind(hexpub)
             .fetch_optional(&self.pool)
-            .await;
+            .await
+            .unwrap_or(None)
+            .unwrap_or_default()
+    }
+
+    pub async fn set_role(&self, user_npub: &str, role: UserRole) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "UPDATE users SET role = $1, updated_at = CURRENT_TIMESTAMP WHERE npub = $2 RETURNING *",
+        )
+        .bind(role)
+        .bind(user_npub)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Creates the user if needed and makes them an admin.
+    pub async fn seed_admin(&self, npub: &str, hexpub: &str) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "INSERT INTO users (npub, hexpub, role) VALUES ($1, $2, 'admin')
+            ON CONFLICT (npub) DO UPDATE SET role = 'admin', updated_at = CURRENT_TIMESTAMP
+            RETURNING *",
+        )
+        .bind(npub)
+        .bind(hexpub)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn user_exists(&self, user_npub: &String) -> bool {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE npub = $1 AND deleted_at IS NULL",
+        )
+        .bind(user_npub)
+        .fetch_optional(&self.pool)
+        .await;
 
         match user {
             Ok(Some(_)) => true,
@@ -107,63 +211,217 @@ impl UserRepository {
     }
 }
 
+// -----------------------------------------------------------------------------
+// Service
+// -----------------------------------------------------------------------------
+
+/// Grants the admin role to every npub in the comma separated `npubs`, so the
+/// first admin can be bootstrapped from `ADMIN_NPUBS`.
+pub async fn seed_admins(user_repo: &UserRepository, npubs: &str) {
+    for npub in npubs.split(',').map(str::trim).filter(|npub| !npub.is_empty()) {
+        let hexpub = match XOnlyPublicKey::from_bech32(npub) {
+            Ok(pubkey) => pubkey.to_string(),
+            Err(err) => {
+                eprintln!("Invalid admin npub {}: {}", npub, err);
+                continue;
+            }
+        };
+
+        if let Err(err) = user_repo.seed_admin(npub, &hexpub).await {
+            eprintln!("Failed to seed admin {}: {}", npub, err);
+        }
+    }
+}
+
+/// Days during which a closed account can be restored.
+pub fn restore_window_days() -> i64 {
+    dotenvy::var("ACCOUNT_RESTORE_WINDOW_DAYS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(30)
+}
+
+/// Whether a closed account is still within its restore window.
+pub fn is_restorable(user: &User) -> bool {
+    let window = chrono::Duration::days(restore_window_days());
+    user.deleted_at
+        .is_some_and(|deleted_at| deleted_at > Utc::now().naive_utc() - window)
+}
+
+/// Registers the owner of a verified pubkey, so logging in is all it takes to
+/// get an account. Callers must check `deleted_at` before logging them in.
+pub async fn register_user(user_repo: &UserRepository, hexpub: &str) -> Result<User, String> {
+    let npub = bech32_encode(&hexpub.to_string()).map_err(|err| err.to_string())?;
+
+    user_repo
+        .upsert(&npub, hexpub)
+        .await
+        .map_err(|err| format!("Failed to register user: {}", err))
+}
+
+/// Terminates the user's relays, cancels their pending orders, revokes their
+/// refresh tokens and API keys and marks them deleted. Relays that fail to terminate leave
+/// the account open, so closing it again retries them.
+pub async fn close_account(
+    pool: &PgPool,
+    dns: &dyn DnsProvider,
+    instances: &dyn InstanceProvider,
+    user: &User,
+) -> Result<(), String> {
+    let relays = RelayRepository::new(pool.clone())
+        .get_user_relays(&user.npub)
+        .await
+        .into_iter()
+        .filter(|relay| relay.deleted_at.is_none());
+
+    let mut errors = vec![];
+    for relay in relays {
+        let uuid = relay.uuid.clone();
+        if let Err(err) = terminate_relay_service(pool, dns, instances, relay).await {
+            eprintln!("Failed to terminate relay {}: {}", uuid, err);
+            errors.push(format!("relay {}: {}", uuid, err));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(format!("Failed to terminate relays: {}", errors.join("; ")));
+    }
+
+    RelayOrderRepository::new(pool.clone())
+        .cancel_pending(&user.npub)
+        .await
+        .map_err(|err| err.to_string())?;
+    TokenRepository::new(pool.clone())
+        .revoke_user_refresh_tokens(&user.hexpub)
+        .await
+        .map_err(|err| err.to_string())?;
+    ApiKeyRepository::new(pool.clone())
+        .revoke_user_keys(&user.hexpub)
+        .await
+        .map_err(|err| err.to_string())?;
+    UserRepository::new(pool.clone())
+        .delete(&user.npub)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Reopens an account closed within the restore window. Terminated relays,
+/// cancelled orders and revoked API keys stay that way.
+pub async fn restore_account(user_repo: &UserRepository, hexpub: &str) -> Result<Option<User>, String> {
+    let deleted_after = Utc::now().naive_utc() - chrono::Duration::days(restore_window_days());
+
+    user_repo
+        .restore(hexpub, deleted_after)
+        .await
+        .map_err(|err| format!("Failed to restore account: {}", err))
+}
+
 // -----------------------------------------------------------------------------
 // Handlers
 // -----------------------------------------------------------------------------
 
-async fn get_user_handler(user_repo: web::Data<UserRepository>, path: web::Path<String>) -> impl Responder {
+async fn get_user_handler(
+    auth: AuthorizationService,
+    user_repo: web::Data<UserRepository>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if !auth.is_self_or(&path, UserRole::Support) {
+        return HttpResponse::Forbidden().json(ErrorResponse::new("Forbidden".to_string()));
+    }
+
     let user = user_repo.get_one(&path).await;
     match user {
-        Some(user) => HttpResponse::Ok().json(user),
+        Some(user) => {
+            let profile = ProfileRepository::new(user_repo.pool.clone())
+                .get_one(&user.hexpub)
+                .await;
+            HttpResponse::Ok().json(UserWithProfile { user, profile })
+        }
         None => HttpResponse::NotFound().finish(),
     }
 }
 
-async fn get_all_users_handler(user_repo: web::Data<UserRepository>) -> impl Responder {
+async fn get_me_handler(
+    auth: AuthorizationService,
+    user_repo: web::Data<UserRepository>,
+) -> impl Responder {
+    let user = match user_repo.get_one(auth.npub().unwrap()).await {
+        Some(user) => user,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse::new("User not found".to_string()))
+        }
+    };
+    let profile = ProfileRepository::new(user_repo.pool.clone())
+        .get_one(&user.hexpub)
+        .await;
+
+    HttpResponse::Ok().json(DataResponse::new(UserWithProfile { user, profile }))
+}
+
+async fn get_all_users_handler(_staff: Staff, user_repo: web::Data<UserRepository>) -> impl Responder {
     let users = user_repo.get_all().await;
     HttpResponse::Ok().json(DataResponse::new(users))
 }
 
-async fn create_user_handler(
+async fn delete_user_handler(
+    auth: AuthorizationService,
     user_repo: web::Data<UserRepository>,
-    user: web::Json<CreateUserDto>,
+    token_repo: web::Data<TokenRepository>,
+    dns: web::Data<dyn DnsProvider>,
+    instances: web::Data<dyn InstanceProvider>,
+    path: web::Path<String>,
 ) -> impl Responder {
+    let user_npub = path.into_inner();
+    if !auth.is_self_or(&user_npub, UserRole::Admin) {
+        return HttpResponse::Forbidden().json(ErrorResponse::new("Forbidden".to_string()));
+    }
 
-    let user_npub = bech32_encode(&user.hexpub);
+    let user = match user_repo.get_one(&user_npub).await {
+        Some(user) => user,
+        None => return HttpResponse::NotFound().json(ErrorResponse::new("User not found".to_string())),
+    };
 
-    eprintln!("user_npub: {:?}", user_npub);
+    if let Err(err) = close_account(&user_repo.pool, dns.get_ref(), instances.get_ref(), &user).await {
+        return HttpResponse::InternalServerError().json(ErrorResponse::new(err));
+    }
 
-    match user_npub {
-        Ok(user_npub) => {
-            match user_repo.create(&user_npub, &user.hexpub).await {
-                Ok(created_user) => HttpResponse::Created().json(DataResponse::new(created_user)),
-                Err(e) => {
-                    HttpResponse::BadRequest().json(ErrorResponse::new(e.to_string()))
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("Invalid hex pub key".to_string()));
+    // Other access tokens of the user run out on their own.
+    if let (Some(jti), Some(exp), true) = (auth.jti(), auth.exp(), auth.npub() == Some(&user_npub)) {
+        if let Err(err) = token_repo.revoke_jti(jti, exp).await {
+            eprintln!("Failed to revoke token: {}", err);
         }
     }
+
+    HttpResponse::NoContent().finish()
 }
 
-async fn delete_user_handler(user_repo: web::Data<UserRepository>, path: web::Path<String>) -> impl Responder {
-    let user_npub = path.into_inner();
-    match user_repo.delete(&user_npub).await {
-        Ok(_) => HttpResponse::NoContent().finish(),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+async fn update_user_role_handler(
+    admin: Admin,
+    user_repo: web::Data<UserRepository>,
+    path: web::Path<String>,
+    body: web::Json<UpdateUserRoleDto>,
+) -> impl Responder {
+    // Keeps the last admin from locking everyone out by accident.
+    if admin.npub() == Some(&path) {
+        return HttpResponse::BadRequest()
+            .json(ErrorResponse::new("Admins can't change their own role".to_string()));
+    }
+
+    match user_repo.set_role(&path, body.role).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(DataResponse::new(user)),
+        Ok(None) => HttpResponse::NotFound().json(ErrorResponse::new("User not found".to_string())),
+        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse::new(err.to_string())),
     }
 }
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(
+    cfg.route("/me", web::get().to(get_me_handler))
+    .service(web::resource("/users/{user_npub}/role").route(web::put().to(update_user_role_handler)))
+    .service(
         web::resource("/users/{user_npub}")
             .route(web::get().to(get_user_handler))
             .route(web::delete().to(delete_user_handler)),
     )
-    .route("/users", web::get().to(get_all_users_handler))
-    .route("/users", web::post().to(create_user_handler));
+    .route("/users", web::get().to(get_all_users_handler));
 }
 
 // -----------------------------------------------------------------------------
@@ -177,7 +435,6 @@ mod tests {
     use crate::util::{generate_random_string, TestUtils};
 
     use super::*;
-    use std::env;
 
     #[tokio::test]
     async fn test_create_and_get_user() {
@@ -190,4 +447,229 @@ mod tests {
 
         assert!(user.npub.len() > 1);
     }
+
+    #[actix_web::test]
+    async fn test_user_routes_enforce_roles() {
+        use crate::token::{issue_access_token, TokenRepository};
+        use actix_web::{test, App};
+
+        let test_utils = TestUtils::new().await;
+        let alice = test_utils.create_user().await;
+        let bob = test_utils.create_user().await;
+        let support = test_utils.create_user().await;
+        let support = test_utils
+            .user_repo
+            .set_role(&support.npub, UserRole::Support)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let admin_keys = nostr::Keys::generate();
+        let admin_npub = nostr::prelude::ToBech32::to_bech32(&admin_keys.public_key()).unwrap();
+        seed_admins(&test_utils.user_repo, &format!("{}, not-an-npub", admin_npub)).await;
+        let admin = test_utils.user_repo.get_one(&admin_npub).await.unwrap();
+        assert_eq!(admin.role, UserRole::Admin);
+
+        let dns: std::sync::Arc<dyn DnsProvider> =
+            std::sync::Arc::new(crate::dns::LocalDnsProvider::new());
+        let instances: std::sync::Arc<dyn InstanceProvider> =
+            std::sync::Arc::new(crate::cloud_provider::LocalInstanceProvider::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.user_repo.clone()))
+                .app_data(web::Data::new(TokenRepository::new(test_utils.pool.clone())))
+                .app_data(web::Data::from(dns))
+                .app_data(web::Data::from(instances))
+                .configure(configure_routes),
+        )
+        .await;
+        let call = |method: &str, uri: String, user: &User, body: Option<serde_json::Value>| {
+            let token = issue_access_token(&user.hexpub, user.role).unwrap();
+            let req = test::TestRequest::default()
+                .method(method.parse().unwrap())
+                .uri(&uri)
+                .insert_header(("Authorization", format!("Bearer {}", token)));
+            match body {
+                Some(body) => req.set_json(body).to_request(),
+                None => req.to_request(),
+            }
+        };
+
+        let cases = [
+            ("GET", "/users".to_string(), &alice, None, 403),
+            ("GET", "/users".to_string(), &support, None, 200),
+            ("GET", format!("/users/{}", alice.npub), &alice, None, 200),
+            ("GET", format!("/users/{}", bob.npub), &alice, None, 403),
+            ("GET", format!("/users/{}", bob.npub), &support, None, 200),
+            ("DELETE", format!("/users/{}", bob.npub), &support, None, 403),
+            (
+                "PUT",
+                format!("/users/{}/role", bob.npub),
+                &support,
+                Some(serde_json::json!({ "role": "admin" })),
+                403,
+            ),
+            (
+                "PUT",
+                format!("/users/{}/role", bob.npub),
+                &admin,
+                Some(serde_json::json!({ "role": "support" })),
+                200,
+            ),
+            ("DELETE", format!("/users/{}", alice.npub), &alice, None, 204),
+        ];
+
+        for (method, uri, user, body, status) in cases {
+            let resp = test::call_service(&app, call(method, uri.clone(), user, body)).await;
+            assert_eq!(resp.status(), status, "{} {}", method, uri);
+        }
+
+        let bob = test_utils.user_repo.get_one(&bob.npub).await.unwrap();
+        assert_eq!(bob.role, UserRole::Support);
+
+        // A demoted admin loses access right away, even with an older token.
+        test_utils
+            .user_repo
+            .set_role(&admin.npub, UserRole::User)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, call("GET", "/users".to_string(), &admin, None)).await;
+        assert_eq!(resp.status(), 403);
+
+        // Unauthenticated requests are rejected outright.
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_close_and_restore_account() {
+        use crate::auth::{generate_jwt_by_hex, nip98_header};
+        use crate::cloud_provider::LocalInstanceProvider;
+        use crate::replay_guard::{MemoryReplayGuard, ReplayGuard};
+        use crate::token::{issue_tokens, TokenRepository};
+        use actix_web::{test, App};
+        use std::sync::Arc;
+
+        let test_utils = TestUtils::new().await;
+        let keys = nostr::Keys::generate();
+        let alice = test_utils
+            .user_repo
+            .upsert(
+                &nostr::prelude::ToBech32::to_bech32(&keys.public_key()).unwrap(),
+                &keys.public_key().to_string(),
+            )
+            .await
+            .unwrap();
+        let relay_order = test_utils.create_relay_order(&alice.npub).await;
+        let relay = test_utils.create_relay(relay_order).await;
+        let relay = test_utils
+            .relay_repo
+            .update_instance_ip(&relay.uuid, "203.0.113.10")
+            .await
+            .unwrap();
+        let pending_order = test_utils.create_relay_order(&alice.npub).await;
+        let token_repo = TokenRepository::new(test_utils.pool.clone());
+        let tokens = issue_tokens(&token_repo, &alice.hexpub).await.unwrap();
+        let jwt = generate_jwt_by_hex(&alice.hexpub).unwrap();
+        let other_jwt = generate_jwt_by_hex(&alice.hexpub).unwrap();
+        let api_key_repo = ApiKeyRepository::new(test_utils.pool.clone());
+        let api_key = api_key_repo
+            .create(
+                &alice.hexpub,
+                &alice.npub,
+                &crate::api_key::CreateApiKeyDto {
+                    name: "ci".to_string(),
+                    scopes: vec!["relays:read".to_string()],
+                    expires_in_days: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let instances = LocalInstanceProvider::default();
+        let dns: Arc<dyn DnsProvider> = Arc::new(crate::dns::LocalDnsProvider::new());
+        let guard: Arc<dyn ReplayGuard> = Arc::new(MemoryReplayGuard::new(10));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_utils.user_repo.clone()))
+                .app_data(web::Data::new(token_repo.clone()))
+                .app_data(web::Data::from(dns))
+                .app_data(web::Data::from(
+                    Arc::new(instances.clone()) as Arc<dyn InstanceProvider>
+                ))
+                .app_data(web::Data::from(guard))
+                .configure(crate::auth::configure_routes)
+                .configure(configure_routes),
+        )
+        .await;
+        let bearer = |req: test::TestRequest| {
+            req.insert_header(("Authorization", format!("Bearer {}", jwt)))
+                .to_request()
+        };
+        let nip98 = |path: &str| {
+            let url = format!("http://api.relaying.io{}", path);
+            test::TestRequest::post()
+                .uri(path)
+                .insert_header(("Host", "api.relaying.io"))
+                .insert_header(("Authorization", nip98_header(&keys, "POST", &url, None)))
+                .to_request()
+        };
+
+        let req = bearer(test::TestRequest::delete().uri(&format!("/users/{}", alice.npub)));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 204);
+
+        assert_eq!(*instances.terminated.lock().unwrap(), vec![relay.instance_id.clone()]);
+        let relay = test_utils.relay_repo.get_one(&relay.uuid).await.unwrap();
+        let deleted_at = relay.deleted_at.unwrap();
+        assert!(Utc::now().naive_utc() - deleted_at < chrono::Duration::minutes(1));
+        let order = test_utils
+            .relay_order_repo
+            .get_one(&pending_order.uuid)
+            .await
+            .unwrap();
+        assert_eq!(order.status.as_str(), "cancelled");
+
+        assert!(test_utils.user_repo.get_one(&alice.npub).await.is_none());
+        assert!(!test_utils.user_repo.user_exists(&alice.npub).await);
+        assert!(token_repo.consume_refresh_token(&tokens.refresh_token).await.is_none());
+        let resp = test::call_service(&app, bearer(test::TestRequest::get().uri("/me"))).await;
+        assert_eq!(resp.status(), 401);
+        // Tokens issued before closing don't outlive the account.
+        let req = test::TestRequest::get()
+            .uri("/me")
+            .insert_header(("Authorization", format!("Bearer {}", other_jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        assert!(api_key_repo.authenticate(&api_key.key).await.is_none());
+
+        let resp = test::call_service(&app, nip98("/login")).await;
+        assert_eq!(resp.status(), 403);
+
+        let resp = test::call_service(&app, nip98("/login/restore")).await;
+        assert_eq!(resp.status(), 200);
+        assert!(test_utils.user_repo.get_one(&alice.npub).await.is_some());
+
+        // Accounts closed before the restore window are gone for good.
+        test_utils.user_repo.delete(&alice.npub).await.unwrap();
+        sqlx::query("UPDATE users SET deleted_at = $1 WHERE npub = $2")
+            .bind(Utc::now().naive_utc() - chrono::Duration::days(restore_window_days() + 1))
+            .bind(&alice.npub)
+            .execute(&test_utils.pool)
+            .await
+            .unwrap();
+        let restored = restore_account(&test_utils.user_repo, &alice.hexpub)
+            .await
+            .unwrap();
+        assert!(restored.is_none());
+        // A query string keeps the signed events distinct from the ones above.
+        for path in ["/login?again", "/login/restore?again"] {
+            let resp = test::call_service(&app, nip98(path)).await;
+            assert_eq!(resp.status(), 403, "{}", path);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!(body.error, "Account permanently closed", "{}", path);
+        }
+    }
 }
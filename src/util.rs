@@ -99,7 +99,7 @@ impl TestUtils {
         let hexpub = keys.public_key().to_string();
         let user = self
             .user_repo
-            .create(&npub, hexpub.clone().as_str())
+            .upsert(&npub, hexpub.clone().as_str())
             .await
             .unwrap();
 
@@ -113,6 +113,7 @@ impl TestUtils {
     pub async fn create_relay_order(&self, npub: &str) -> RelayOrder {
         let order = CreateRelayOrder {
             user_npub: npub.to_string(),
+            organization_uuid: None,
             cloud_provider: CloudProvider::AWS,
             instance_type: InstanceType::AwsT2Nano,
             amount: 1000,
@@ -130,11 +131,12 @@ impl TestUtils {
         // Create a relay to update
         let relay = CreateRelay {
             user_npub: order.user_npub,
+            organization_uuid: order.organization_uuid,
             relay_order_uuid: order.uuid,
             name: "test relay".to_string(),
             description: "test description".to_string(),
             subdomain: generate_random_string(10).await,
-            custom_domain: generate_random_string(10).await,
+            custom_domain: String::new(),
             instance_type: InstanceType::AwsT2Nano,
             instance_id: generate_random_string(10).await,
             instance_ip: generate_random_string(10).await,
@@ -152,6 +154,33 @@ impl TestUtils {
 
     pub async fn revert_migrations(self: &Self) -> Result<(), sqlx::Error> {
         let drop_query = "
+            DROP TABLE IF EXISTS audit_events CASCADE;
+            DROP TABLE IF EXISTS relay_agent_tokens CASCADE;
+            DROP TABLE IF EXISTS relay_metrics CASCADE;
+            DROP TABLE IF EXISTS event_import_seen CASCADE;
+            DROP TABLE IF EXISTS event_import_sources CASCADE;
+            DROP TABLE IF EXISTS event_imports CASCADE;
+            DROP TABLE IF EXISTS relay_migrations CASCADE;
+            DROP TABLE IF EXISTS relay_restores CASCADE;
+            DROP TABLE IF EXISTS relay_backups CASCADE;
+            DROP TABLE IF EXISTS notification_deliveries CASCADE;
+            DROP TABLE IF EXISTS notification_preferences CASCADE;
+            DROP TABLE IF EXISTS relay_announcements CASCADE;
+            DROP TABLE IF EXISTS organization_invitations CASCADE;
+            DROP TABLE IF EXISTS organization_members CASCADE;
+            DROP TABLE IF EXISTS organizations CASCADE;
+            DROP TABLE IF EXISTS export_jobs CASCADE;
+            DROP TABLE IF EXISTS user_profiles CASCADE;
+            DROP TABLE IF EXISTS rate_limit_buckets CASCADE;
+            DROP TABLE IF EXISTS api_keys CASCADE;
+            DROP TABLE IF EXISTS nip46_sessions CASCADE;
+            DROP TABLE IF EXISTS revoked_tokens CASCADE;
+            DROP TABLE IF EXISTS refresh_tokens CASCADE;
+            DROP TABLE IF EXISTS nip98_seen_events CASCADE;
+            DROP TABLE IF EXISTS relay_health_checks CASCADE;
+            DROP TABLE IF EXISTS relay_infos CASCADE;
+            DROP TABLE IF EXISTS certificates CASCADE;
+            DROP TABLE IF EXISTS custom_domains CASCADE;
             DROP TABLE IF EXISTS relay_orders CASCADE;
             DROP TABLE IF EXISTS relays CASCADE;
             DROP TABLE IF EXISTS users CASCADE;